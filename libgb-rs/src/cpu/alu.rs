@@ -0,0 +1,447 @@
+use super::FlagRegister;
+
+/// Add a value (and optionally the carry flag) to an 8-bit number, calculating the flags the
+/// same way the SM83 does for ADD and ADC
+///
+/// Parameters:
+/// - `a`: the number being added to (register A)
+/// - `value`: the number to add
+/// - `carry`: whether to also add 1 for the incoming carry flag (ADC)
+///
+/// Returns the result of the addition and the new flags
+pub fn add8(a: u8, value: u8, carry: bool) -> (u8, FlagRegister) {
+    let carry = carry as u8;
+    let result = a.wrapping_add(value).wrapping_add(carry);
+
+    // same as with subtraction, the carry-in can overflow a nibble or byte on its own
+    let flags = FlagRegister {
+        zero: result == 0,
+        subtract: false,
+        half_carry: (a & 0xF) + (value & 0xF) + carry > 0xF,
+        carry: (a as u16) + (value as u16) + (carry as u16) > 0xFF,
+    };
+
+    (result, flags)
+}
+
+/// Subtract a value (and optionally the carry flag) from an 8-bit number, calculating the flags
+/// the same way the SM83 does for SUB, SBC, and CP
+///
+/// Parameters:
+/// - `a`: the number being subtracted from (register A)
+/// - `value`: the number to subtract
+/// - `carry`: whether to also subtract 1 for the incoming carry flag (SBC)
+///
+/// Returns the result of the subtraction and the new flags
+pub fn sub8(a: u8, value: u8, carry: bool) -> (u8, FlagRegister) {
+    let carry = carry as u8;
+    let result = a.wrapping_sub(value).wrapping_sub(carry);
+
+    // the carry-in has to be part of both borrow checks, since it can cause a borrow on its own
+    // (e.g. 0x10 - 0x00 - 1 borrows from bit 4 even though 0x0 >= 0x0)
+    let flags = FlagRegister {
+        zero: result == 0,
+        subtract: true,
+        half_carry: (a & 0xF) < (value & 0xF) + carry,
+        carry: (a as u16) < (value as u16) + (carry as u16),
+    };
+
+    (result, flags)
+}
+
+/// Add 1 to an 8-bit number, calculating the flags the same way the SM83 does for INC r8
+///
+/// Parameters:
+/// - `value`: the number to increment
+/// - `carry`: the current carry flag, which INC leaves alone
+///
+/// Returns the incremented number and the new flags
+pub fn increment8(value: u8, carry: bool) -> (u8, FlagRegister) {
+    let (result, flags) = add8(value, 1, false);
+    (result, FlagRegister { carry, ..flags })
+}
+
+/// Subtract 1 from an 8-bit number, calculating the flags the same way the SM83 does for DEC r8
+///
+/// Parameters:
+/// - `value`: the number to decrement
+/// - `carry`: the current carry flag, which DEC leaves alone
+///
+/// Returns the decremented number and the new flags
+pub fn decrement8(value: u8, carry: bool) -> (u8, FlagRegister) {
+    let (result, flags) = sub8(value, 1, false);
+    (result, FlagRegister { carry, ..flags })
+}
+
+/// Bitwise AND an 8-bit number with a value (AND), which always sets the half carry flag
+///
+/// Returns the result and the new flags
+pub fn and8(a: u8, value: u8) -> (u8, FlagRegister) {
+    logic_result(a & value, true)
+}
+
+/// Bitwise OR an 8-bit number with a value (OR)
+///
+/// Returns the result and the new flags
+pub fn or8(a: u8, value: u8) -> (u8, FlagRegister) {
+    logic_result(a | value, false)
+}
+
+/// Bitwise XOR an 8-bit number with a value (XOR)
+///
+/// Returns the result and the new flags
+pub fn xor8(a: u8, value: u8) -> (u8, FlagRegister) {
+    logic_result(a ^ value, false)
+}
+
+/// Every bitwise operation sets the flags the same way: only the zero flag depends on the
+/// result, and AND is the only one which sets half carry
+fn logic_result(result: u8, half_carry: bool) -> (u8, FlagRegister) {
+    let flags = FlagRegister {
+        zero: result == 0,
+        subtract: false,
+        half_carry,
+        carry: false,
+    };
+
+    (result, flags)
+}
+
+/// Add a value to a 16-bit number, calculating the flags the same way the SM83 does for
+/// ADD HL, r16. The carries come from the upper byte, so half carry is out of bit 11.
+///
+/// Parameters:
+/// - `hl`: the number being added to (register HL)
+/// - `value`: the number to add
+///
+/// Returns the result of the addition and the new flags. ADD HL, r16 doesn't change the zero
+/// flag, so it's returned as cleared and the caller has to keep the old one.
+pub fn add16(hl: u16, value: u16) -> (u16, FlagRegister) {
+    let result = hl.wrapping_add(value);
+
+    let flags = FlagRegister {
+        zero: false,
+        subtract: false,
+        half_carry: (hl & 0xFFF) + (value & 0xFFF) > 0xFFF,
+        carry: (hl as u32) + (value as u32) > 0xFFFF,
+    };
+
+    (result, flags)
+}
+
+/// Add a signed offset to the stack pointer, calculating the flags the same way the SM83 does
+/// for ADD SP, e8 and LD HL, SP + e8. The offset is added to the low byte as if it were
+/// unsigned, so the carries come from bit 3 and bit 7 rather than the upper byte.
+///
+/// Parameters:
+/// - `sp`: the stack pointer
+/// - `offset`: the signed number to add
+///
+/// Returns the result of the addition and the new flags, where zero and subtract are cleared
+pub fn add_sp(sp: u16, offset: i8) -> (u16, FlagRegister) {
+    let result = sp.wrapping_add(offset as u16);
+    let low = sp & 0xFF;
+    let offset = offset as u8 as u16;
+
+    let flags = FlagRegister {
+        zero: false,
+        subtract: false,
+        half_carry: (low & 0xF) + (offset & 0xF) > 0xF,
+        carry: low + offset > 0xFF,
+    };
+
+    (result, flags)
+}
+
+/// Rotate an 8-bit number left by 1 bit, calculating the flags the same way the SM83 does for RLC
+/// and RL
+///
+/// Parameters:
+/// - `value`: the number to rotate
+/// - `circular`: whether bit 7 wraps around into bit 0 (RLC), rather than the incoming carry
+///   flag being rotated in (RL)
+/// - `carry`: the incoming carry flag
+///
+/// Returns the rotated number and the new flags
+pub fn rotate_left(value: u8, circular: bool, carry: bool) -> (u8, FlagRegister) {
+    let bit_in = if circular { value >> 7 } else { carry as u8 };
+    shift_result((value << 1) | bit_in, value & 0x80 != 0)
+}
+
+/// Rotate an 8-bit number right by 1 bit, calculating the flags the same way the SM83 does for RRC
+/// and RR
+///
+/// Parameters:
+/// - `value`: the number to rotate
+/// - `circular`: whether bit 0 wraps around into bit 7 (RRC), rather than the incoming carry
+///   flag being rotated in (RR)
+/// - `carry`: the incoming carry flag
+///
+/// Returns the rotated number and the new flags
+pub fn rotate_right(value: u8, circular: bool, carry: bool) -> (u8, FlagRegister) {
+    let bit_in = if circular { value & 1 } else { carry as u8 };
+    shift_result((value >> 1) | (bit_in << 7), value & 1 != 0)
+}
+
+/// Shift an 8-bit number left by 1 bit (SLA), with 0 shifted into bit 0
+///
+/// Returns the shifted number and the new flags
+pub fn shift_left_arithmetic(value: u8) -> (u8, FlagRegister) {
+    shift_result(value << 1, value & 0x80 != 0)
+}
+
+/// Shift an 8-bit number right by 1 bit (SRA), keeping the sign bit (bit 7) the same
+///
+/// Returns the shifted number and the new flags
+pub fn shift_right_arithmetic(value: u8) -> (u8, FlagRegister) {
+    shift_result((value >> 1) | (value & 0x80), value & 1 != 0)
+}
+
+/// Shift an 8-bit number right by 1 bit (SRL), with 0 shifted into bit 7
+///
+/// Returns the shifted number and the new flags
+pub fn shift_right_logical(value: u8) -> (u8, FlagRegister) {
+    shift_result(value >> 1, value & 1 != 0)
+}
+
+/// Swap the upper and lower nibbles of an 8-bit number (SWAP)
+///
+/// Returns the swapped number and the new flags
+pub fn swap(value: u8) -> (u8, FlagRegister) {
+    shift_result(value.rotate_left(4), false)
+}
+
+/// Every rotate and shift sets the flags the same way: carry holds the bit which was shifted
+/// out, and only the zero flag depends on the result
+fn shift_result(result: u8, carry: bool) -> (u8, FlagRegister) {
+    let flags = FlagRegister {
+        zero: result == 0,
+        subtract: false,
+        half_carry: false,
+        carry,
+    };
+
+    (result, flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add8_half_carry() {
+        let (result, flags) = add8(0x0F, 0x01, false);
+
+        assert_eq!(result, 0x10, "0x0F + 0x01 should be 0x10");
+        assert!(flags.half_carry, "Bit 3 should carry into bit 4");
+        assert!(!flags.carry, "Nothing should carry out of the byte");
+        assert!(!flags.subtract, "Adding should always clear the subtract flag");
+        assert!(!flags.zero, "The result isn't zero");
+    }
+
+    #[test]
+    fn test_add8_carry_in_wraps() {
+        let (result, flags) = add8(0xFF, 0x00, true);
+
+        assert_eq!(result, 0x00, "0xFF + 0x00 + 1 should wrap around to 0x00");
+        assert!(flags.zero, "The wrapped result is zero");
+        assert!(flags.half_carry, "The carry-in should carry out of the lower nibble");
+        assert!(flags.carry, "The carry-in should carry out of the byte");
+    }
+
+    #[test]
+    fn test_add8_carry_in_to_max() {
+        let (result, flags) = add8(0xF0, 0x0E, true);
+
+        assert_eq!(result, 0xFF, "0xF0 + 0x0E + 1 should be 0xFF");
+        assert!(!flags.half_carry && !flags.carry, "Reaching 0xFF shouldn't carry");
+    }
+
+    #[test]
+    fn test_sub8_carry_in_borrows_nibble() {
+        let (result, flags) = sub8(0x10, 0x00, true);
+
+        assert_eq!(result, 0x0F, "0x10 - 0x00 - 1 should be 0x0F");
+        assert!(flags.half_carry, "The carry-in should borrow from bit 4");
+        assert!(!flags.carry, "Nothing should be borrowed from outside of the byte");
+        assert!(flags.subtract, "Subtracting should always set the subtract flag");
+        assert!(!flags.zero, "The result isn't zero");
+    }
+
+    #[test]
+    fn test_sub8_carry_in_wraps() {
+        let (result, flags) = sub8(0x00, 0xFF, true);
+
+        assert_eq!(result, 0x00, "0x00 - 0xFF - 1 should wrap around to 0x00");
+        assert!(flags.zero, "The wrapped result is zero");
+        assert!(flags.half_carry, "The lower nibble should borrow");
+        assert!(flags.carry, "The whole byte should borrow");
+    }
+
+    #[test]
+    fn test_sub8_without_carry_in() {
+        let (result, flags) = sub8(0x10, 0x00, false);
+
+        assert_eq!(result, 0x10, "Subtracting 0 should do nothing");
+        assert!(!flags.half_carry, "Nothing should be borrowed without a carry-in");
+        assert!(!flags.carry, "Nothing should be borrowed without a carry-in");
+    }
+
+    #[test]
+    fn test_sub8_equal_values() {
+        let (result, flags) = sub8(0x42, 0x42, false);
+
+        assert_eq!(result, 0, "Subtracting a number from itself should be 0");
+        assert!(flags.zero, "The result is zero");
+        assert!(!flags.half_carry && !flags.carry, "Equal values shouldn't borrow");
+    }
+
+    #[test]
+    fn test_increment8_half_carry() {
+        let (result, flags) = increment8(0x0F, false);
+
+        assert_eq!(result, 0x10, "0x0F + 1 should be 0x10");
+        assert!(flags.half_carry, "Bit 3 should carry into bit 4");
+        assert!(!flags.subtract && !flags.zero, "Subtract and zero should be cleared");
+
+        let (result, flags) = increment8(0x10, false);
+
+        assert_eq!(result, 0x11, "0x10 + 1 should be 0x11");
+        assert!(!flags.half_carry, "Nothing should carry out of the lower nibble");
+    }
+
+    #[test]
+    fn test_increment8_keeps_carry() {
+        let (result, flags) = increment8(0xFF, false);
+
+        assert_eq!(result, 0x00, "0xFF + 1 should wrap around to 0x00");
+        assert!(flags.zero && flags.half_carry, "The wrapped result is zero, and carries bit 3");
+        assert!(!flags.carry, "INC shouldn't set the carry flag when it wraps");
+
+        let (_, flags) = increment8(0x42, true);
+
+        assert!(flags.carry, "INC shouldn't clear the carry flag");
+    }
+
+    #[test]
+    fn test_decrement8_half_carry() {
+        let (result, flags) = decrement8(0x00, false);
+
+        assert_eq!(result, 0xFF, "0x00 - 1 should wrap around to 0xFF");
+        assert!(flags.half_carry, "The lower nibble should borrow from bit 4");
+        assert!(flags.subtract, "DEC should set the subtract flag");
+        assert!(!flags.carry, "DEC shouldn't set the carry flag when it wraps");
+
+        let (result, flags) = decrement8(0x10, true);
+
+        assert_eq!(result, 0x0F, "0x10 - 1 should be 0x0F");
+        assert!(flags.half_carry, "The lower nibble should borrow from bit 4");
+        assert!(flags.carry, "DEC shouldn't clear the carry flag");
+
+        let (result, flags) = decrement8(0x01, false);
+
+        assert_eq!(result, 0x00, "0x01 - 1 should be 0x00");
+        assert!(flags.zero, "The result is zero");
+        assert!(!flags.half_carry, "Nothing should be borrowed from bit 4");
+    }
+
+    #[test]
+    fn test_logic_flags() {
+        let cases = [
+            ("AND", and8(0xF0, 0x0F), 0x00, 0xA0),
+            ("AND of equal values", and8(0xFF, 0xFF), 0xFF, 0x20),
+            ("AND without a carry out of bit 3", and8(0x01, 0x01), 0x01, 0x20),
+            ("OR", or8(0xF0, 0x0F), 0xFF, 0x00),
+            ("OR of 0", or8(0x00, 0x00), 0x00, 0x80),
+            ("XOR", xor8(0xFF, 0x0F), 0xF0, 0x00),
+            ("XOR A, A", xor8(0x42, 0x42), 0x00, 0x80),
+        ];
+
+        for (name, (result, flags), expected, expected_flags) in cases {
+            assert_eq!(result, expected, "{name} should produce {expected:#04X}");
+            assert_eq!(u8::from(flags), expected_flags, "{name} should set the flags correctly");
+        }
+    }
+
+    #[test]
+    fn test_add16_carries() {
+        let (result, flags) = add16(0x0800, 0x0800);
+
+        assert_eq!(result, 0x1000, "0x0800 + 0x0800 should be 0x1000");
+        assert!(flags.half_carry, "Bit 11 should carry into bit 12");
+        assert!(!flags.carry, "Nothing should carry out of bit 15");
+
+        let (result, flags) = add16(0x8000, 0x8000);
+
+        assert_eq!(result, 0x0000, "0x8000 + 0x8000 should wrap around to 0x0000");
+        assert!(!flags.half_carry, "Nothing should carry out of bit 11");
+        assert!(flags.carry, "Bit 15 should carry out");
+    }
+
+    #[test]
+    fn test_add16_ignores_low_byte_carry() {
+        let (result, flags) = add16(0x00FF, 0x0001);
+
+        assert_eq!(result, 0x0100, "0x00FF + 0x0001 should be 0x0100");
+        assert!(!flags.half_carry && !flags.carry, "Carrying out of the low byte isn't a carry");
+    }
+
+    #[test]
+    fn test_add_sp_positive_offset() {
+        let (result, flags) = add_sp(0x00F8, 8);
+
+        assert_eq!(result, 0x0100, "0x00F8 + 8 should cross into the next page");
+        assert!(flags.half_carry, "0x8 + 0x8 should carry out of bit 3");
+        assert!(flags.carry, "0xF8 + 0x08 should carry out of bit 7");
+        assert!(!flags.zero && !flags.subtract, "Zero and subtract should always be cleared");
+    }
+
+    #[test]
+    fn test_add_sp_negative_offset() {
+        let (result, flags) = add_sp(0x0100, -1);
+
+        assert_eq!(result, 0x00FF, "0x0100 - 1 should cross into the previous page");
+        assert!(!flags.half_carry, "0x0 + 0xF doesn't carry out of bit 3");
+        assert!(!flags.carry, "0x00 + 0xFF doesn't carry out of bit 7");
+
+        let (result, flags) = add_sp(0x1234, -0x10);
+
+        assert_eq!(result, 0x1224, "0x1234 - 0x10 should be 0x1224");
+        assert!(!flags.half_carry, "0x4 + 0x0 doesn't carry out of bit 3");
+        assert!(flags.carry, "0x34 + 0xF0 carries out of bit 7, even though it's a subtraction");
+    }
+
+    #[test]
+    fn test_add_sp_ignores_upper_byte() {
+        let (result, flags) = add_sp(0xFFFF, 1);
+
+        assert_eq!(result, 0x0000, "0xFFFF + 1 should wrap around to 0x0000");
+        assert!(flags.half_carry && flags.carry, "Only the low byte decides the carries");
+        assert!(!flags.zero, "The zero flag is cleared even when the result is 0");
+    }
+
+    #[test]
+    fn test_shifts() {
+        // 0x85 is 1000_0101, so a bit gets shifted out of either end
+        let cases = [
+            ("RLC", rotate_left(0x85, true, false), 0x0B, 0x10),
+            ("RRC", rotate_right(0x85, true, false), 0xC2, 0x10),
+            ("RL", rotate_left(0x85, false, false), 0x0A, 0x10),
+            ("RL with carry", rotate_left(0x85, false, true), 0x0B, 0x10),
+            ("RR", rotate_right(0x85, false, false), 0x42, 0x10),
+            ("RR with carry", rotate_right(0x85, false, true), 0xC2, 0x10),
+            ("SLA", shift_left_arithmetic(0x85), 0x0A, 0x10),
+            ("SRA", shift_right_arithmetic(0x85), 0xC2, 0x10),
+            ("SRL", shift_right_logical(0x85), 0x42, 0x10),
+            ("SWAP", swap(0x85), 0x58, 0x00),
+            ("RLC of 0", rotate_left(0x00, true, true), 0x00, 0x80),
+            ("SLA to 0", shift_left_arithmetic(0x80), 0x00, 0x90),
+            ("SWAP of 0", swap(0x00), 0x00, 0x80),
+        ];
+
+        for (name, (result, flags), expected, expected_flags) in cases {
+            assert_eq!(result, expected, "{name} should produce {expected:#04X}");
+            assert_eq!(u8::from(flags), expected_flags, "{name} should set the flags correctly");
+        }
+    }
+}
@@ -1,9 +1,6 @@
 use crate::{GameBoySystem, GameBoySystemError};
-use crate::cpu::instructions::{Instruction, Operation};
+use crate::cpu::instructions::{Instruction, MemoryAddress, Operand8, Operation};
 
-use super::{CpuRegister, FlagRegister};
-
-const REG_A: u8 = 0;
 const REG_MEM_READ: u8 = 6;
 
 impl GameBoySystem {
@@ -13,6 +10,19 @@ impl GameBoySystem {
     // It should be impossible for a library user to reach these panics. In a situation caused
     // by invalid parameters or an invalid GameBoy state, an error will be returned instead of
     // panicking.
+    //
+    // Hosts which can't afford a panic at all (e.g. sandboxed environments) can enable
+    // panic-free decoding with `set_panic_free_decode`, which reports any broken invariant as an
+    // InvalidInstructionError instead.
+
+    /// Handle an internal decoder invariant being broken. This panics by default, or returns an
+    /// InvalidInstructionError for the given instruction when panic-free decoding is enabled.
+    fn decode_invariant(&self, instruction: u8, message: &str) -> GameBoySystemError {
+        if !self.panic_free_decode {
+            panic!("{message}");
+        }
+        GameBoySystemError::InvalidInstructionError(instruction)
+    }
 
     /// Highly recommend looking at the following page: 
     ///
@@ -21,7 +31,55 @@ impl GameBoySystem {
     /// It has tables showing the bit-layout of the instructions which is the basis of most of the
     /// bitwise logic/bitshifting going on here
     pub fn load_instruction(&mut self) -> Result<Instruction, GameBoySystemError>{
-        let instruction = self.fetch_byte()?;
+        let address = self.registers.pc;
+        let mut cursor = address;
+        let instruction = self.fetch_byte(&mut cursor);
+        if self.halt_bug {
+            // the pc fails to move past the opcode, so the same byte is read again next
+            self.halt_bug = false;
+            cursor = address;
+        }
+        self.finish_fetch(address, cursor);
+        let instruction = instruction?;
+        self.check_test_trap(address, instruction);
+
+        let result = self.decode_instruction(instruction, &mut cursor);
+        self.finish_fetch(self.registers.pc, cursor);
+        let result = result?;
+        self.run_step_callback(address, &result.op);
+
+        Ok(result)
+    }
+
+    /// Decode the instruction at the given address without running it or changing any state,
+    /// which allows a debugger to preview upcoming instructions. Registers and memory are only
+    /// read when an instruction runs, except for the flags which decide whether a conditional
+    /// jump, call or return is taken, so those are decoded using their current values.
+    ///
+    /// Parameters:
+    /// - `address`: the address of the first byte of the instruction
+    ///
+    /// Returns the decoded instruction along with the number of bytes it takes up
+    pub fn peek_instruction(&self, address: u16) -> Result<(Instruction, u16), GameBoySystemError> {
+        let mut cursor = address;
+        let instruction = self.fetch_byte(&mut cursor)?;
+        let result = self.decode_instruction(instruction, &mut cursor)?;
+
+        Ok((result, cursor.wrapping_sub(address)))
+    }
+
+    /// Move the program counter past the bytes which were fetched between `start` and `end`,
+    /// marking them as code
+    fn finish_fetch(&mut self, start: u16, end: u16) {
+        for offset in 0..end.wrapping_sub(start) {
+            self.mark_code(start.wrapping_add(offset));
+        }
+        self.registers.pc = end;
+    }
+
+    fn decode_instruction(
+        &self, instruction: u8, cursor: &mut u16
+    ) -> Result<Instruction, GameBoySystemError> {
         let block = (instruction & 0xC0) >> 6;
 
         if instruction == 0 {
@@ -31,7 +89,8 @@ impl GameBoySystem {
             });
         }
         else if instruction == 0x10 {
-            self.registers.pc += 1;
+            // STOP is followed by a byte which is skipped
+            *cursor = cursor.wrapping_add(1);
             return Ok(Instruction {
                 op: Operation::Stop,
                 cycles: 1
@@ -39,27 +98,36 @@ impl GameBoySystem {
         }
         
         match block {
-            0 => self.load_block_0(instruction),
+            0 => self.load_block_0(instruction, cursor),
             1 => self.load_block_1(instruction),
             2 => self.load_block_2(instruction),
-            3 => self.load_block_3(instruction),
-            _ => panic!("logic error while extracting block from instruction {instruction:#X}")
+            3 => self.load_block_3(instruction, cursor),
+            _ => Err(self.decode_invariant(
+                instruction,
+                &format!("logic error while extracting block from instruction {instruction:#X}")
+            ))
         }
     }
 
-    fn load_block_0(&mut self, instruction: u8) -> Result<Instruction, GameBoySystemError> {
-        assert!(instruction & 0xC0 == 0, "Should only call when first 2 bits are 0");
+    fn load_block_0(
+        &self, instruction: u8, cursor: &mut u16
+    ) -> Result<Instruction, GameBoySystemError> {
+        if instruction & 0xC0 != 0 {
+            return Err(
+                self.decode_invariant(instruction, "Should only call when first 2 bits are 0")
+            );
+        }
         let fn3 = instruction & 0x07;
         if fn3 == 0 && (instruction & 0xF0) != 0 {
-            return self.load_jump_relative(instruction);
+            return self.load_jump_relative(instruction, cursor);
         }
         if fn3 < 4 {
-            return self.load_block_0_16bit(instruction);        
+            return self.load_block_0_16bit(instruction, cursor);        
         } else if fn3 == 7 {
-            return Ok(self.load_block_0_alu(instruction));
+            return self.load_block_0_alu(instruction);
         }
 
-        let reg = (instruction >> 4) & 0x03;
+        let reg = (instruction >> 3) & 0x07;
         let mut cycles = 1;
         if reg == REG_MEM_READ {
             // doing anything on [HL] takes more cycles
@@ -74,21 +142,26 @@ impl GameBoySystem {
             op: match fn3 {
                 4 => Operation::Increment8(reg),
                 5 => Operation::Decrement8(reg),
-                6 => Operation::Load8(reg, self.fetch_byte()?),
-                _ => panic!("Invalid block 0 fn3 code for instruction {instruction:#X}")
+                6 => Operation::Load8(reg, Operand8::Immediate(self.fetch_byte(cursor)?)),
+                _ => return Err(self.decode_invariant(
+                    instruction,
+                    &format!("Invalid block 0 fn3 code for instruction {instruction:#X}")
+                ))
             }
         };
 
         Ok(result)
     }
 
-    fn load_jump_relative(&mut self, instruction: u8) -> Result<Instruction, GameBoySystemError> {
+    fn load_jump_relative(
+        &self, instruction: u8, cursor: &mut u16
+    ) -> Result<Instruction, GameBoySystemError> {
         let jump_type = instruction & 0x20; // the only distinguishing bit between jr and jr [cond]
 
         // the double cast is done to sign extend into a 16-bit integer. This allows for 16-bit
         // overflow addition of negative numbers (which is effectively subtraction)
-        let offset = (self.fetch_byte()? as i8) as u16;
-        let address = self.registers.pc.overflowing_add(offset).0;
+        let offset = (self.fetch_byte(cursor)? as i8) as u16;
+        let address = cursor.overflowing_add(offset).0;
         let result = Instruction { cycles: 3, op: Operation::Jump(address) };
 
         if jump_type == 0 {
@@ -104,70 +177,66 @@ impl GameBoySystem {
     }
 
     fn get_cond_flag(&self, flag_code: u8) -> bool {
-        let flag_register: FlagRegister = self.registers.get_register(CpuRegister::F)
-            .into();
-        match flag_code {
-            0 => !flag_register.zero,
-            1 => flag_register.zero,
-            2 => !flag_register.carry,
-            3 => flag_register.carry,
-            _ => panic!("Impossible flag code found")
+        // only the lower 2 bits are used, so every flag code is valid
+        match flag_code & 3 {
+            0 => !self.registers.zero_flag(),
+            1 => self.registers.zero_flag(),
+            2 => !self.registers.carry_flag(),
+            _ => self.registers.carry_flag(),
         }
     }
 
-    fn load_block_0_16bit(&mut self, instruction: u8) -> Result<Instruction, GameBoySystemError> {
+    fn load_block_0_16bit(
+        &self, instruction: u8, cursor: &mut u16
+    ) -> Result<Instruction, GameBoySystemError> {
         // use a 4-bit opcode for these instructions
         let fn4 = instruction & 0x0F;
-        let register = (instruction & 0x18) >> 3;
+        let register = (instruction >> 4) & 0x03;
         // NOTE - ordering is grouped based on the instruction, not a numeric ordering of fn4 codes
         let (op, cycles) = match fn4 {
-            1 => (Operation::Load16(register, self.fetch_imm16()?), 3),
-            2 => (
-                Operation::Store8(
-                    self.get_r16_mem(register),
-                    self.registers.get_register(CpuRegister::A)
-                ), 2
-            ),
-            0xA => {
-                let address = self.get_r16_mem(register);
-                (
-                    Operation::Load8(
-                        7, /* Register A */
-                        self.memory.load_byte(address)
-                            .ok_or(GameBoySystemError::MemoryReadError(address))?
-                    ), 2
-                )
-            },
-            8 => (Operation::Store16(self.fetch_imm16()?, self.registers.sp), 5),
+            1 => (Operation::Load16(register, self.fetch_imm16(cursor)?), 3),
+            2 => (Operation::StoreAccumulator(MemoryAddress::Register(register)), 2),
+            0xA => (Operation::LoadAccumulator(MemoryAddress::Register(register)), 2),
+            8 => (Operation::StoreStackPointer(self.fetch_imm16(cursor)?), 5),
             3 => (Operation::Increment16(register), 2),
             0xB => (Operation::Decrement16(register), 2),
-            9 => (Operation::Add16(self.get_r16(register)), 2),
-            _ => panic!("Invalid block 0 function 4 in instruction {instruction}")
+            9 => (Operation::Add16(register), 2),
+            _ => return Err(self.decode_invariant(
+                instruction,
+                &format!("Invalid block 0 function 4 in instruction {instruction}")
+            ))
         };
 
         Ok(Instruction { op, cycles })
     }
 
-    fn load_block_0_alu(&self, instruction: u8) -> Instruction {
-        Instruction {
+    fn load_block_0_alu(&self, instruction: u8) -> Result<Instruction, GameBoySystemError> {
+        Ok(Instruction {
             cycles: 1,
             op: match instruction {
                 // TODO - I smell a pattern here
-                0x07 => Operation::RotateLeft(0, true),
-                0x0F => Operation::RotateRight(0, true),
-                0x17 => Operation::RotateLeft(0, false),
-                0x1F => Operation::RotateRight(0, false),
+                0x07 => Operation::RotateAccumulatorLeft(true),
+                0x0F => Operation::RotateAccumulatorRight(true),
+                0x17 => Operation::RotateAccumulatorLeft(false),
+                0x1F => Operation::RotateAccumulatorRight(false),
                 0x27 => Operation::DAA,
                 0x2F => Operation::Complement,
                 0x37 => Operation::SetCarryFlag,
                 0x3F => Operation::ComplementCarryFlag,
-                _ => panic!("Invalid Block 0 ALU instruction {instruction:#X}")
+                _ => return Err(self.decode_invariant(
+                    instruction,
+                    &format!("Invalid Block 0 ALU instruction {instruction:#X}")
+                ))
             }
-        }
+        })
     }
 
-    fn load_block_1(&mut self, instruction: u8) -> Result<Instruction, GameBoySystemError> {
-        assert!(instruction & 0xC0 == 0x40, "Should not be able to call when block is not 1");
+    fn load_block_1(&self, instruction: u8) -> Result<Instruction, GameBoySystemError> {
+        if instruction & 0xC0 != 0x40 {
+            return Err(self.decode_invariant(
+                instruction, "Should not be able to call when block is not 1"
+            ));
+        }
 
         let src_reg = instruction & 7;
         let dest_reg = (instruction >> 3) & 7;
@@ -177,16 +246,20 @@ impl GameBoySystem {
             return Ok(Instruction { op: Operation::Halt, cycles: 1 });
         } 
         Ok(Instruction {
-            op: Operation::Load8(dest_reg, self.get_r8(src_reg)?),
+            op: Operation::Load8(dest_reg, Operand8::Register(src_reg)),
             cycles: 2
         })
     }
 
     fn load_block_2(&self, instruction: u8) -> Result<Instruction, GameBoySystemError> {
-        assert!(instruction & 0xC0 == 0x80, "Should not be able to call when block is not 2");
+        if instruction & 0xC0 != 0x80 {
+            return Err(self.decode_invariant(
+                instruction, "Should not be able to call when block is not 2"
+            ));
+        }
         // 8-bit logic arithmetic
         let register = instruction & 7;
-        let value = self.get_r8(register)?;
+        let value = Operand8::Register(register);
         let opcode = instruction >> 3;
 
         let cycles = if register == REG_MEM_READ { 2 } else { 1 };
@@ -200,107 +273,84 @@ impl GameBoySystem {
             0x15 => Operation::Xor8(value),
             0x16 => Operation::Or8(value),
             0x17 => Operation::Compare8(value),
-            _ => panic!("Should not be able to get to invalid block 2 opcode")
+            _ => return Err(self.decode_invariant(
+                instruction, "Should not be able to get to invalid block 2 opcode"
+            ))
         };
 
         Ok(Instruction { op: operation, cycles })
     }
 
-    fn load_block_3(&mut self, instruction: u8) -> Result<Instruction, GameBoySystemError> {
-        assert!(instruction & 0xC0 == 0xC0, "Should not be able to call when block is not 3");
+    fn load_block_3(
+        &self, instruction: u8, cursor: &mut u16
+    ) -> Result<Instruction, GameBoySystemError> {
+        if instruction & 0xC0 != 0xC0 {
+            return Err(self.decode_invariant(
+                instruction, "Should not be able to call when block is not 3"
+            ));
+        }
 
         let fn3 = instruction & 7;
         let tgt = instruction & 0x38;
 
         if instruction == 0xCB {
-            return self.load_prefixed();
+            return self.load_prefixed(cursor);
         } else if fn3 == 6 {
-            return self.load_block_3_alu(instruction);
+            return self.load_block_3_alu(instruction, cursor);
         } else if fn3 == 7 && (instruction & 0x2) != 0 {
             return Ok(Instruction { op: Operation::Call(tgt as u16), cycles: 4});
         }
 
         let fn4 = instruction & 0xF;
         if fn4 == 1 || fn4 == 5 {
-            return Ok(self.load_block_3_stack(instruction));
+            return self.load_block_3_stack(instruction);
         }
 
-        if instruction & 1 == 0 {
-            return self.load_block_3_cond(instruction)
+        // only 0xC0 - 0xDF are conditional, the even opcodes above that are loads
+        if instruction & 1 == 0 && instruction < 0xE0 {
+            return self.load_block_3_cond(instruction, cursor)
         }
 
         // I kind of hate this but it's fine :upside_down:
         match instruction {
             0xC9 => Ok(Instruction { op: Operation::Return(false), cycles: 4 }),
             0xD9 => Ok(Instruction { op: Operation::Return(true), cycles: 4 }),
-            0xC3 => Ok(Instruction { op: Operation::Jump(self.fetch_imm16()?), cycles: 4 }),
-            0xE9 => Ok(
-                Instruction { 
-                    op: Operation::Jump(
-                            self.registers.get_joined_registers(CpuRegister::H, CpuRegister::L)
-                    ),
-                    cycles: 1
-                }
-            ),
-            0xCD => Ok(Instruction { op: Operation::Call(self.fetch_imm16()?), cycles: 6 }),
-            0xE0 => Ok(Instruction {
-                op: Operation::Store8(
-                    0xFF00 + (self.fetch_byte()? as u16),
-                    self.registers.get_register(CpuRegister::A)
-                ),
-                cycles: 3
-            }),
-            0xE2 => Ok(Instruction { 
-                op: Operation::Store8(
-                    0xFF00 + (self.registers.get_register(CpuRegister::C) as u16),
-                    self.registers.get_register(CpuRegister::A)
-                ),
+            0xC3 => Ok(Instruction { op: Operation::Jump(self.fetch_imm16(cursor)?), cycles: 4 }),
+            0xE9 => Ok(Instruction { op: Operation::JumpToHL, cycles: 1 }),
+            0xCD => Ok(Instruction { op: Operation::Call(self.fetch_imm16(cursor)?), cycles: 6 }),
+            0xE0 => {
+                let address = MemoryAddress::Immediate(0xFF00 + (self.fetch_byte(cursor)? as u16));
+                Ok(Instruction { op: Operation::StoreAccumulator(address), cycles: 3 })
+            },
+            0xE2 => Ok(Instruction {
+                op: Operation::StoreAccumulator(MemoryAddress::HighC),
                 cycles: 2
             }),
-            0xEA => Ok(Instruction {
-                op: Operation::Store8(
-                    self.fetch_imm16()?,
-                    self.registers.get_register(CpuRegister::A)
-                ),
-                cycles: 4
-            }),
+            0xEA => {
+                let address = MemoryAddress::Immediate(self.fetch_imm16(cursor)?);
+                Ok(Instruction { op: Operation::StoreAccumulator(address), cycles: 4 })
+            },
             0xF0 => {
-                let byte = self.fetch_byte()?;
-                let addr = 0xFF00 + (byte as u16);
-                let mem_value = self.memory.load_byte(addr)
-                    .ok_or(GameBoySystemError::MemoryReadError(addr))?;
-                Ok(Instruction { op: Operation::Load8(REG_A, mem_value), cycles: 3 })
+                let address = MemoryAddress::Immediate(0xFF00 + (self.fetch_byte(cursor)? as u16));
+                Ok(Instruction { op: Operation::LoadAccumulator(address), cycles: 3 })
             },
-            0xF2 => {
-                let byte = self.registers.get_register(CpuRegister::C);
-                let addr = 0xFF00 + (byte as u16);
-                let mem_value = self.memory.load_byte(addr)
-                    .ok_or(GameBoySystemError::MemoryReadError(addr))?;
-                Ok(Instruction { op: Operation::Load8(REG_A, mem_value), cycles: 3 })
-            }
+            0xF2 => Ok(Instruction {
+                op: Operation::LoadAccumulator(MemoryAddress::HighC),
+                cycles: 3
+            }),
             0xFA => {
-                let addr = self.fetch_imm16()?;
-                let mem_val = self.memory.load_byte(addr)
-                    .ok_or(GameBoySystemError::MemoryReadError(addr))?;
-                Ok(Instruction { op: Operation::Load8(REG_A, mem_val), cycles: 4 }) }
+                let address = MemoryAddress::Immediate(self.fetch_imm16(cursor)?);
+                Ok(Instruction { op: Operation::LoadAccumulator(address), cycles: 4 })
+            },
             0xE8 => Ok(Instruction { 
-                op: Operation::AddStackPointer(self.fetch_byte()? as i8),
+                op: Operation::AddStackPointer(self.fetch_byte(cursor)? as i8),
                 cycles: 4
             }),
-            0xF8 => {
-                let imm8 = self.fetch_byte()? as i8;
-                let new_val = self.registers.sp.overflowing_add(imm8 as u16).0;
-                Ok(Instruction { 
-                    op: Operation::Load16(2, new_val),
-                    cycles: 3
-                })
-            },
-            0xF9 => Ok(Instruction { 
-                op: Operation::SetStackPointer(
-                    self.registers.get_joined_registers(CpuRegister::H, CpuRegister::L)
-                ),
-                cycles: 2
+            0xF8 => Ok(Instruction {
+                op: Operation::LoadStackPointerOffset(self.fetch_byte(cursor)? as i8),
+                cycles: 3
             }),
+            0xF9 => Ok(Instruction { op: Operation::SetStackPointer, cycles: 2 }),
             0xF3 => Ok(Instruction { op: Operation::DisableInterrupts, cycles: 1 }),
             0xFB => Ok(Instruction { op: Operation::EnableInterrupts, cycles: 1 }),
             _ => Err(GameBoySystemError::InvalidInstructionError(instruction))
@@ -308,9 +358,9 @@ impl GameBoySystem {
     }
 
     fn load_block_3_alu(
-        &mut self, instruction: u8
+        &self, instruction: u8, cursor: &mut u16
     ) -> Result<Instruction, GameBoySystemError> {
-        let imm8 = self.fetch_byte()?;
+        let imm8 = Operand8::Immediate(self.fetch_byte(cursor)?);
         let fn3 = (instruction >> 3) & 7;
         let op = match fn3 {
             0 => Operation::Add8(imm8, false),
@@ -321,47 +371,64 @@ impl GameBoySystem {
             5 => Operation::Xor8(imm8),
             6 => Operation::Or8(imm8),
             7 => Operation::Compare8(imm8),
-            x => panic!("Found invalid function 3 code {x} in instruction {instruction:#X}")
+            x => return Err(self.decode_invariant(
+                instruction,
+                &format!("Found invalid function 3 code {x} in instruction {instruction:#X}")
+            ))
         };
 
         Ok(Instruction { op, cycles: 2 })
     }
 
-    fn load_block_3_stack(&mut self, instruction: u8) -> Instruction {
+    fn load_block_3_stack(&self, instruction: u8) -> Result<Instruction, GameBoySystemError> {
         let r16stk = (instruction >> 4) & 3;
         match instruction & 0xF {
-            1 => Instruction { op: Operation::PopStack(r16stk), cycles: 3 },
-            5 => Instruction { op: Operation::PushStack(r16stk), cycles: 3 },
-            _ => panic!("Invalid instruction {instruction:#X} passed to load stack")
+            1 => Ok(Instruction { op: Operation::PopStack(r16stk), cycles: 3 }),
+            5 => Ok(Instruction { op: Operation::PushStack(r16stk), cycles: 4 }),
+            _ => Err(self.decode_invariant(
+                instruction, &format!("Invalid instruction {instruction:#X} passed to load stack")
+            ))
         }
     }
 
-    fn load_block_3_cond(&mut self, instruction: u8) -> Result<Instruction, GameBoySystemError> {
+    fn load_block_3_cond(
+        &self, instruction: u8, cursor: &mut u16
+    ) -> Result<Instruction, GameBoySystemError> {
         let fn3 = instruction & 7;
         let cond_flag = self.get_cond_flag((instruction >> 3) & 3);
         // Don't do anything if the condition is not met
         if !cond_flag {
+            // JP and CALL still need to skip over their target address
+            if fn3 != 0 {
+                self.fetch_imm16(cursor)?;
+            }
             return Ok(Instruction {
                 op: Operation::NOP,
                 cycles: match fn3 {
                     0 => 2,
                     2 => 3,
                     4 => 3,
-                    _ => panic!("Invalid instruction {instruction:#X} passed to block 3 cond")
+                    _ => return Err(self.decode_invariant(
+                        instruction,
+                        &format!("Invalid instruction {instruction:#X} passed to block 3 cond")
+                    ))
                 }
             });
         }
         
         match fn3 {
             0 => Ok(Instruction { op: Operation::Return(false), cycles: 5 }),
-            2 => Ok(Instruction { op: Operation::Jump(self.fetch_imm16()?), cycles: 4 }),
-            4 => Ok(Instruction { op: Operation::Call(self.fetch_imm16()?), cycles: 6 }),
-            _ => panic!("Invalid instruction {instruction:#X} passed to block 3 cond")
+            2 => Ok(Instruction { op: Operation::Jump(self.fetch_imm16(cursor)?), cycles: 4 }),
+            4 => Ok(Instruction { op: Operation::Call(self.fetch_imm16(cursor)?), cycles: 6 }),
+            _ => Err(self.decode_invariant(
+                instruction,
+                &format!("Invalid instruction {instruction:#X} passed to block 3 cond")
+            ))
         }
     }
 
-    fn load_prefixed(&mut self) -> Result<Instruction, GameBoySystemError> {
-        let instruction = self.fetch_byte()?;
+    fn load_prefixed(&self, cursor: &mut u16) -> Result<Instruction, GameBoySystemError> {
+        let instruction = self.fetch_byte(cursor)?;
         let fn2 = instruction >> 6;
         let index = (instruction >> 3) & 7;
         let register = instruction & 7;
@@ -373,26 +440,40 @@ impl GameBoySystem {
             cycles = 4;
         }
         match fn2 {
-            0 => Ok(Instruction { op: self.load_prefixed_alu(index, register), cycles }),
+            0 => Ok(Instruction {
+                op: self.load_prefixed_alu(instruction, index, register)?,
+                cycles
+            }),
             1 => Ok(Instruction { op: Operation::TestBit(register, index), cycles }),
             2 => Ok(Instruction { op: Operation::ResetBit(register, index), cycles }),
             3 => Ok(Instruction { op: Operation::SetBit(register, index), cycles }),
-            x => panic!("Found invalid prefixed fn2 code {x} in instruction {instruction:#X}")
+            x => Err(self.decode_invariant(
+                instruction,
+                &format!("Found invalid prefixed fn2 code {x} in instruction {instruction:#X}")
+            ))
         }
     }
 
-    fn load_prefixed_alu(&mut self, fn3: u8, register: u8) -> Operation {
-        assert!(register < 8, "invalid register should never be provided");
+    fn load_prefixed_alu(
+        &self, instruction: u8, fn3: u8, register: u8
+    ) -> Result<Operation, GameBoySystemError> {
+        if register >= 8 {
+            return Err(
+                self.decode_invariant(instruction, "invalid register should never be provided")
+            );
+        }
         match fn3 {
-            0 => Operation::RotateLeft(register, true),
-            1 => Operation::RotateRight(register, true),
-            2 => Operation::RotateLeft(register, false),
-            3 => Operation::RotateRight(register, false),
-            4 => Operation::ShiftLeftArithmetic(register),
-            5 => Operation::ShiftRightArithmetic(register),
-            6 => Operation::SwapBits(register),
-            7 => Operation::ShiftRightLogical(register),
-            x => panic!("Invalid prefixed alu function 3 code {x}")
+            0 => Ok(Operation::RotateLeft(register, true)),
+            1 => Ok(Operation::RotateRight(register, true)),
+            2 => Ok(Operation::RotateLeft(register, false)),
+            3 => Ok(Operation::RotateRight(register, false)),
+            4 => Ok(Operation::ShiftLeftArithmetic(register)),
+            5 => Ok(Operation::ShiftRightArithmetic(register)),
+            6 => Ok(Operation::SwapBits(register)),
+            7 => Ok(Operation::ShiftRightLogical(register)),
+            x => Err(self.decode_invariant(
+                instruction, &format!("Invalid prefixed alu function 3 code {x}")
+            ))
         }
     }
 }
@@ -401,14 +482,15 @@ impl GameBoySystem {
 mod tests {
     use rand::random;
 
-    use crate::GameBoySystem;
+    use crate::{GameBoySystem, GameBoySystemError};
+    use crate::cpu::CpuRegister;
+    use crate::cpu::instructions::{Instruction, MemoryAddress, Operation};
     use crate::memory::MockMemoryController;
+    use crate::test_utils::init_program;
 
     #[test]
     fn fuzz_test_instructions() {
         let mut mem = MockMemoryController::new();
-        mem.expect_load_half_word()
-            .return_const(0xFFFF);
         mem.expect_load_byte()
             .returning(|_| {
                 // According to Pan Docs, these should be the only invalid instructions
@@ -426,10 +508,233 @@ mod tests {
         // technically this is not guaranteed to test everything but realistically it should
         for _ in 0..10_000 {
             let result = dmg.load_instruction();
-            let prefix_result = dmg.load_prefixed();
+            let prefix_result = dmg.load_prefixed(&mut 0);
             assert!(result.is_ok(), "Should not crash for any instruction");
             assert!(prefix_result.is_ok(), "Should not crash for any prefixed instruction");
         }
     }
-}
 
+    #[test]
+    fn test_panic_free_decode_never_panics() {
+        // every opcode, followed by operand bytes that are either all 0s or all 1s
+        for operand in [0x00, 0xFF] {
+            for opcode in 0..=0xFF_u8 {
+                let mut mem = MockMemoryController::new();
+                mem.expect_load_byte()
+                    .returning(move |address| Some(if address == 0 { opcode } else { operand }));
+                let mut dmg = GameBoySystem::new(Box::new(mem));
+                dmg.set_panic_free_decode(true);
+
+                let result = dmg.load_instruction();
+
+                assert!(
+                    !matches!(result, Err(GameBoySystemError::MemoryReadError(_))),
+                    "Crafted memory is always readable, so only decoding should be able to fail"
+                );
+            }
+        }
+
+        // the invariants can't be broken through load_instruction, so call the decoders with
+        // instructions they should never be given
+        let mut dmg = init_program(vec![]);
+        dmg.set_panic_free_decode(true);
+        let mut broken = vec![
+            ("block 0", 0xC0, dmg.load_block_0(0xC0, &mut 0).map(|_| ())),
+            ("block 0 16-bit", 0x04, dmg.load_block_0_16bit(0x04, &mut 0).map(|_| ())),
+            ("block 0 ALU", 0x00, dmg.load_block_0_alu(0x00).map(|_| ())),
+            ("block 1", 0x00, dmg.load_block_1(0x00).map(|_| ())),
+            ("block 2", 0x00, dmg.load_block_2(0x00).map(|_| ())),
+            ("block 3", 0x00, dmg.load_block_3(0x00, &mut 0).map(|_| ())),
+            ("block 3 stack", 0xC0, dmg.load_block_3_stack(0xC0).map(|_| ())),
+            ("taken condition", 0xC6, dmg.load_block_3_cond(0xC6, &mut 0).map(|_| ())),
+            ("prefixed register", 0x08, dmg.load_prefixed_alu(0x08, 0, 8).map(|_| ())),
+            ("prefixed ALU", 0x40, dmg.load_prefixed_alu(0x40, 8, 0).map(|_| ())),
+        ];
+        dmg.registers.set_zero_flag(true);
+        broken.push(
+            ("skipped condition", 0xC6, dmg.load_block_3_cond(0xC6, &mut 0).map(|_| ()))
+        );
+
+        for (name, instruction, result) in broken {
+            assert!(
+                matches!(
+                    result,
+                    Err(GameBoySystemError::InvalidInstructionError(found)) if found == instruction
+                ),
+                "A broken {name} invariant should be reported as an invalid instruction"
+            );
+        }
+    }
+
+    #[test]
+    fn test_imm16_is_little_endian() {
+        // LD BC, 0x1234, with the low byte of the immediate first
+        let mut dmg = init_program(vec![0x01, 0x34, 0x12]);
+
+        let result = dmg.load_instruction();
+
+        assert_eq!(
+            result.ok(), Some(Instruction { op: Operation::Load16(0, 0x1234), cycles: 3 }),
+            "The first byte of the immediate should be the low byte"
+        );
+    }
+
+    #[test]
+    fn test_conditional_return_cycles() {
+        // RET NZ
+        let mut taken = init_program(vec![0xC0]);
+        let mut not_taken = init_program(vec![0xC0]);
+        not_taken.registers.set_zero_flag(true);
+
+        let taken_result = taken.load_instruction();
+        let not_taken_result = not_taken.load_instruction();
+
+        assert_eq!(
+            taken_result.ok(), Some(Instruction { op: Operation::Return(false), cycles: 5 }),
+            "A taken RET NZ should return in 5 cycles"
+        );
+        assert_eq!(
+            not_taken_result.ok(), Some(Instruction { op: Operation::NOP, cycles: 2 }),
+            "A RET NZ which isn't taken should do nothing for 2 cycles"
+        );
+    }
+
+    #[test]
+    fn test_conditional_jump_and_call_not_taken_cycles() {
+        // JP NZ 0x1234, CALL NZ 0x1234
+        for (opcode, name) in [(0xC2, "JP NZ"), (0xC4, "CALL NZ")] {
+            let mut dmg = init_program(vec![opcode, 0x34, 0x12]);
+            dmg.registers.set_zero_flag(true);
+
+            let result = dmg.load_instruction();
+
+            assert_eq!(
+                result.ok(), Some(Instruction { op: Operation::NOP, cycles: 3 }),
+                "A {name} which isn't taken should do nothing for 3 cycles"
+            );
+            assert_eq!(dmg.registers.pc, 3, "A {name} which isn't taken should skip its target");
+        }
+    }
+
+    #[test]
+    fn test_conditional_jump_and_call_taken_cycles() {
+        let mut jump = init_program(vec![0xC2, 0x34, 0x12]);
+        let mut call = init_program(vec![0xC4, 0x34, 0x12]);
+
+        assert_eq!(
+            jump.load_instruction().ok(),
+            Some(Instruction { op: Operation::Jump(0x1234), cycles: 4 }),
+            "A taken JP NZ should jump in 4 cycles"
+        );
+        assert_eq!(
+            call.load_instruction().ok(),
+            Some(Instruction { op: Operation::Call(0x1234), cycles: 6 }),
+            "A taken CALL NZ should call in 6 cycles"
+        );
+    }
+
+    #[test]
+    fn test_high_ram_loads_are_not_conditional() {
+        // LDH [0x42], A
+        let mut dmg = init_program(vec![0xE0, 0x42]);
+        dmg.registers.set_zero_flag(true);
+
+        let result = dmg.load_instruction();
+
+        assert_eq!(
+            result.ok(), Some(Instruction {
+                op: Operation::StoreAccumulator(MemoryAddress::Immediate(0xFF42)),
+                cycles: 3
+            }),
+            "0xE0 should be decoded as a store rather than a conditional branch"
+        );
+    }
+
+    #[test]
+    fn test_accumulator_rotates_target_a() {
+        let rotates = [
+            (0x07, Operation::RotateAccumulatorLeft(true)),
+            (0x0F, Operation::RotateAccumulatorRight(true)),
+            (0x17, Operation::RotateAccumulatorLeft(false)),
+            (0x1F, Operation::RotateAccumulatorRight(false)),
+        ];
+
+        for (opcode, op) in rotates {
+            let mut dmg = init_program(vec![opcode]);
+
+            let result = dmg.load_instruction();
+
+            assert_eq!(
+                result.ok(), Some(Instruction { op, cycles: 1 }),
+                "{opcode:#04X} should rotate A, not B"
+            );
+        }
+    }
+
+    #[test]
+    fn test_add16_keeps_source_register() {
+        // ADD HL, BC / ADD HL, DE / ADD HL, HL / ADD HL, SP
+        for (opcode, register) in [(0x09, 0), (0x19, 1), (0x29, 2), (0x39, 3)] {
+            let mut dmg = init_program(vec![opcode]);
+
+            let result = dmg.load_instruction();
+
+            assert_eq!(
+                result.ok(), Some(Instruction { op: Operation::Add16(register), cycles: 2 }),
+                "{opcode:#04X} should add register {register} to HL"
+            );
+        }
+    }
+
+    #[test]
+    fn test_peek_matches_load() {
+        let program = vec![
+            0x00, // NOP
+            0x3E, 0x42, // LD A, 0x42
+            0xC3, 0x34, 0x12, // JP 0x1234
+            0xCB, 0x7C, // BIT 7, H
+            0x22, // LD [HL+], A
+            0x18, 0xFE, // JR -2
+        ];
+        let mut dmg = init_program(program);
+
+        let mut address = 0;
+        for length in [1, 2, 3, 2, 1, 2] {
+            let peeked = dmg.peek_instruction(address);
+            assert!(peeked.is_ok(), "Should be able to peek at {address:#06X}");
+            let (peeked, peeked_length) = peeked.unwrap();
+            assert_eq!(dmg.registers.pc, address, "Peeking shouldn't move PC");
+
+            let loaded = dmg.load_instruction();
+            assert_eq!(loaded.ok(), Some(peeked), "Peeking should decode the same instruction");
+            assert_eq!(peeked_length, length, "{address:#06X} should have {length} bytes");
+            assert_eq!(dmg.registers.pc, address + length, "Loading should move past the operands");
+            address += length;
+        }
+    }
+
+    #[test]
+    fn test_peek_leaves_hl() {
+        // LD A, [HL-]
+        let mut dmg = init_program(vec![0x3A]);
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0x0000);
+
+        assert!(dmg.peek_instruction(0).is_ok(), "Should be able to peek");
+        assert_eq!(
+            dmg.registers.get_joined_registers(CpuRegister::H, CpuRegister::L), 0,
+            "Peeking shouldn't change HL"
+        );
+
+        let loaded = dmg.load_instruction();
+        assert!(loaded.is_ok(), "Should be able to load");
+        assert_eq!(
+            dmg.registers.get_joined_registers(CpuRegister::H, CpuRegister::L), 0,
+            "Loading shouldn't change HL either, since it only moves when the instruction runs"
+        );
+        assert!(dmg.execute_instruction(loaded.unwrap()).is_ok(), "Should be able to run");
+        assert_eq!(
+            dmg.registers.get_joined_registers(CpuRegister::H, CpuRegister::L), 0xFFFF,
+            "Running should decrement HL"
+        );
+    }
+}
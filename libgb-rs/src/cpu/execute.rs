@@ -0,0 +1,1592 @@
+use crate::{GameBoySystem, GameBoySystemError};
+use crate::cpu::{alu, CpuRegister, FlagRegister};
+use crate::cpu::instructions::{Instruction, Operand8, Operation};
+use crate::cycles::{m_to_t, MCycles};
+use crate::debug::StepOutcome;
+use crate::joypad::{JoypadState, BUTTONS, P1, P1_BUTTONS};
+use crate::ppu::{DOTS_PER_FRAME, LCDC, LCDC_ENABLE, LY, VBLANK_LINE};
+use crate::timer::DIV;
+use crate::utils::Split;
+
+const REG_HL: u8 = 2; // HL in the r16 operand encoding
+const REG_MEM: u8 = 6; // [HL] in the r8 operand encoding
+const HALTED_CYCLES: u32 = 1; // how long a step waits for an interrupt while the CPU is halted
+const STOPPED_CYCLES: u32 = 1; // how long a step waits for a button press while the CPU is stopped
+
+impl GameBoySystem {
+    /// Fetch, decode, and run the next instruction. This is the main entry point for running
+    /// the system, and can just be called in a loop.
+    ///
+    /// Memory is ticked one M-cycle at a time while the instruction runs, so the PPU, timer, and
+    /// DMA see each memory access on the cycle it happens on the hardware:
+    /// - the opcode and any immediate operands are read up front while decoding
+    /// - a single read or write of memory (such as `LD A, [HL]` or `ADD A, [HL]`) happens on
+    ///   the last cycle of the instruction
+    /// - read-modify-write instructions on [HL] read on the second to last cycle, and write on
+    ///   the last one
+    /// - pushes write the high byte, then the low byte, on the last 2 cycles of PUSH and CALL,
+    ///   and on the 3rd and 4th cycles of an interrupt dispatch
+    /// - pops read the low byte, then the high byte, on the 2 cycles after the opcode (or after
+    ///   the condition is checked for RET cc)
+    ///
+    /// Whatever cycles are left after the last access are ticked once the instruction is done.
+    ///
+    /// Breakpoints are checked right before an instruction is fetched, and watchpoints once it
+    /// has finished running.
+    ///
+    /// Returns what happened during the step, including the number of machine cycles it took
+    pub fn step(&mut self) -> Result<StepOutcome, GameBoySystemError> {
+        self.ticked_cycles = 0;
+        self.watch_hit = None;
+        // the clock is stopped, so nothing else gets ticked either
+        if self.stays_stopped()? {
+            return Ok(StepOutcome::Completed(MCycles(STOPPED_CYCLES)));
+        }
+        let cycles = match self.handle_interrupts()? {
+            Some(cycles) => {
+                self.resume_from = None;
+                cycles
+            },
+            None if self.stays_halted()? => MCycles(HALTED_CYCLES),
+            None => {
+                let pc = self.registers.pc;
+                if self.breakpoints.contains(&pc) && self.resume_from != Some(pc) {
+                    self.resume_from = Some(pc);
+                    return Ok(StepOutcome::Breakpoint(pc));
+                }
+                self.resume_from = None;
+                self.run_next_instruction()?
+            }
+        };
+        let remaining = (cycles.0 as u8).saturating_sub(self.ticked_cycles);
+        self.tick_components(remaining);
+
+        Ok(match self.watch_hit.take() {
+            Some((address, write)) => StepOutcome::Watchpoint { address, write, cycles },
+            None => StepOutcome::Completed(cycles)
+        })
+    }
+
+    /// Step the system until the PPU enters VBlank (line 144), which is when it has finished
+    /// drawing a frame, and is how a front-end would usually run it. Breakpoints and watchpoints
+    /// don't end the frame early.
+    ///
+    /// The PPU doesn't draw anything while the LCD is off, so the frame ends once 70224 dots
+    /// (the length of a frame) have passed since the last one ended instead. Any extra dots the
+    /// last instruction ran for are counted towards the next frame, so that frames stay the
+    /// same length on average.
+    ///
+    /// Returns the framebuffer once the frame is done, in the same format as `framebuffer`
+    pub fn run_frame(&mut self) -> Result<&[u8], GameBoySystemError> {
+        loop {
+            let line = self.current_line();
+            let cycles = self.step()?.cycles();
+            self.frame_dots += m_to_t(cycles, self.double_speed).0;
+
+            if line < VBLANK_LINE && self.current_line() >= VBLANK_LINE {
+                self.frame_dots = 0;
+                break;
+            }
+            if !self.lcd_enabled() && self.frame_dots >= DOTS_PER_FRAME {
+                self.frame_dots -= DOTS_PER_FRAME;
+                break;
+            }
+        }
+
+        Ok(self.framebuffer())
+    }
+
+    /// Get the line the PPU is on (LY)
+    fn current_line(&self) -> u8 {
+        // LY is always connected, so this can't fail on a real memory controller
+        self.memory.load_byte(LY).unwrap_or(0)
+    }
+
+    /// Check whether the LCD is turned on in LCDC
+    fn lcd_enabled(&self) -> bool {
+        self.memory.load_byte(LCDC).unwrap_or(0) & LCDC_ENABLE != 0
+    }
+
+    /// Hold down exactly the given buttons, then run until the PPU enters VBlank (see
+    /// `run_frame`). This is the usual loop for a front-end: apply the input for the frame, run
+    /// it until VBlank, then show it.
+    ///
+    /// Parameters:
+    /// - `input`: the buttons to hold down for the frame, where every other button is released
+    ///
+    /// Returns the framebuffer once the frame is done, in the same format as `framebuffer`
+    pub fn run_to_vblank(&mut self, input: JoypadState) -> Result<&[u8], GameBoySystemError> {
+        for button in BUTTONS {
+            self.set_button(button, input.is_pressed(button));
+        }
+        self.run_frame()
+    }
+
+    fn run_next_instruction(&mut self) -> Result<MCycles, GameBoySystemError> {
+        // EI from the previous step only takes effect once this instruction has run
+        let enable_ime = self.ime_pending;
+        let address = self.registers.pc;
+        let instruction = self.load_instruction()?;
+        self.run_trace_hook(address, &instruction.op);
+        let cycles = self.execute_instruction(instruction)?;
+        // DI right after EI cancels it
+        if enable_ime && self.ime_pending {
+            self.ime = true;
+            self.ime_pending = false;
+        }
+
+        Ok(cycles)
+    }
+
+    /// Run a decoded instruction against the registers and memory of this system. Memory is
+    /// ticked up to each access the instruction makes, but the cycles after the last access are
+    /// left for `step` to tick.
+    ///
+    /// Parameters:
+    /// - `instr`: the instruction to run, as returned by `load_instruction`
+    ///
+    /// Returns the number of machine cycles the instruction took, or an error if it accesses an
+    /// invalid address or its operation can't be executed yet
+    pub fn execute_instruction(
+        &mut self, instr: Instruction
+    ) -> Result<MCycles, GameBoySystemError> {
+        self.ticked_cycles = 0;
+        let last_cycle = instr.cycles;
+        match instr.op {
+            Operation::NOP => {},
+            Operation::Load8(register, value) => {
+                let value = self.read_operand8(value, last_cycle)?;
+                self.wait_for_r8(register, last_cycle);
+                self.set_r8(register, value)?;
+            },
+            Operation::Load16(register, value) => self.set_r16(register, value),
+            Operation::LoadAccumulator(address) => {
+                let address = self.resolve_address(address);
+                self.wait_for_cycle(last_cycle);
+                let value = self.read_memory(address)?;
+                self.registers.set_register(CpuRegister::A, value);
+            },
+            Operation::StoreAccumulator(address) => {
+                let address = self.resolve_address(address);
+                let value = self.registers.get_register(CpuRegister::A);
+                self.wait_for_cycle(last_cycle);
+                self.write_memory(address, value)?;
+            },
+            Operation::StoreStackPointer(address) => {
+                // the low byte is written first, on the second to last cycle
+                let (high, low) = self.registers.sp.split();
+                let high_address = address.wrapping_add(1);
+                self.wait_for_cycle(last_cycle - 1);
+                self.write_memory(address, low)?;
+                self.wait_for_cycle(last_cycle);
+                self.write_memory(high_address, high)?;
+            },
+            Operation::Add8(value, use_carry) => {
+                let value = self.read_operand8(value, last_cycle)?;
+                self.execute_add8(value, use_carry);
+            },
+            Operation::Add16(register) => self.execute_add16(self.get_r16(register)),
+            Operation::Sub8(value, use_carry) => {
+                let value = self.read_operand8(value, last_cycle)?;
+                self.execute_sub8(value, use_carry, true);
+            },
+            Operation::Compare8(value) => {
+                let value = self.read_operand8(value, last_cycle)?;
+                self.execute_sub8(value, false, false);
+            },
+            Operation::And8(value) => {
+                let value = self.read_operand8(value, last_cycle)?;
+                self.execute_logic8(|a| alu::and8(a, value));
+            },
+            Operation::Or8(value) => {
+                let value = self.read_operand8(value, last_cycle)?;
+                self.execute_logic8(|a| alu::or8(a, value));
+            },
+            Operation::Xor8(value) => {
+                let value = self.read_operand8(value, last_cycle)?;
+                self.execute_logic8(|a| alu::xor8(a, value));
+            },
+            Operation::AddStackPointer(offset) => {
+                let (result, flags) = alu::add_sp(self.registers.sp, offset);
+                self.registers.sp = result;
+                self.registers.set_register(CpuRegister::F, flags.into());
+            },
+            Operation::LoadStackPointerOffset(offset) => {
+                let (result, flags) = alu::add_sp(self.registers.sp, offset);
+                self.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, result);
+                self.registers.set_register(CpuRegister::F, flags.into());
+            },
+            Operation::DAA => self.execute_daa(),
+            Operation::Complement => {
+                let a = self.registers.get_register(CpuRegister::A);
+                self.registers.set_register(CpuRegister::A, !a);
+                self.registers.set_subtract_flag(true);
+                self.registers.set_half_carry_flag(true);
+            },
+            Operation::SetCarryFlag => self.execute_set_carry(true),
+            Operation::ComplementCarryFlag => self.execute_set_carry(!self.registers.carry_flag()),
+            Operation::Increment8(register) => {
+                let carry = self.registers.carry_flag();
+                self.execute_shift(register, last_cycle, |value| alu::increment8(value, carry))?;
+            },
+            Operation::Decrement8(register) => {
+                let carry = self.registers.carry_flag();
+                self.execute_shift(register, last_cycle, |value| alu::decrement8(value, carry))?;
+            },
+            // unlike their 8-bit versions, INC r16 and DEC r16 don't change any flags
+            Operation::Increment16(register) => {
+                self.set_r16(register, self.get_r16(register).wrapping_add(1));
+            },
+            Operation::Decrement16(register) => {
+                self.set_r16(register, self.get_r16(register).wrapping_sub(1));
+            },
+            Operation::RotateLeft(register, circular) => {
+                let carry = self.registers.carry_flag();
+                let rotate = |value| alu::rotate_left(value, circular, carry);
+                self.execute_shift(register, last_cycle, rotate)?;
+            },
+            Operation::RotateRight(register, circular) => {
+                let carry = self.registers.carry_flag();
+                let rotate = |value| alu::rotate_right(value, circular, carry);
+                self.execute_shift(register, last_cycle, rotate)?;
+            },
+            Operation::RotateAccumulatorLeft(circular) => {
+                let carry = self.registers.carry_flag();
+                self.execute_rotate_a(|value| alu::rotate_left(value, circular, carry));
+            },
+            Operation::RotateAccumulatorRight(circular) => {
+                let carry = self.registers.carry_flag();
+                self.execute_rotate_a(|value| alu::rotate_right(value, circular, carry));
+            },
+            Operation::ShiftLeftArithmetic(register) => {
+                self.execute_shift(register, last_cycle, alu::shift_left_arithmetic)?;
+            },
+            Operation::ShiftRightArithmetic(register) => {
+                self.execute_shift(register, last_cycle, alu::shift_right_arithmetic)?;
+            },
+            Operation::ShiftRightLogical(register) => {
+                self.execute_shift(register, last_cycle, alu::shift_right_logical)?;
+            },
+            Operation::SwapBits(register) => {
+                self.execute_shift(register, last_cycle, alu::swap)?;
+            },
+            Operation::TestBit(register, bit) => {
+                self.wait_for_r8(register, last_cycle);
+                self.execute_test_bit(register, bit)?;
+            },
+            Operation::ResetBit(register, bit) => {
+                self.wait_for_r8(register, last_cycle - 1);
+                let value = self.get_r8(register)?;
+                self.wait_for_r8(register, last_cycle);
+                self.set_r8(register, value & !(1 << bit))?;
+            },
+            Operation::SetBit(register, bit) => {
+                self.wait_for_r8(register, last_cycle - 1);
+                let value = self.get_r8(register)?;
+                self.wait_for_r8(register, last_cycle);
+                self.set_r8(register, value | (1 << bit))?;
+            },
+            Operation::Jump(address) => self.registers.pc = address,
+            Operation::JumpToHL => self.registers.pc = self.get_r16(REG_HL),
+            Operation::SetStackPointer => self.registers.sp = self.get_r16(REG_HL),
+            Operation::PushStack(register) => {
+                self.push_stack(self.get_r16_stk(register), last_cycle - 1)?;
+            },
+            Operation::PopStack(register) => {
+                let value = self.pop_stack(2)?;
+                self.set_r16_stk(register, value);
+            },
+            Operation::Call(address) => {
+                // the pc already points past the call, which is where it should return to
+                self.push_stack(self.registers.pc, last_cycle - 1)?;
+                self.registers.pc = address;
+            },
+            Operation::Return(enable_interrupts) => {
+                // RET cc spends a cycle checking its condition before popping, which is the
+                // only difference in length between them
+                self.registers.pc = self.pop_stack(last_cycle - 2)?;
+                // unlike EI, RETI enables interrupts immediately
+                if enable_interrupts {
+                    self.ime = true;
+                }
+            },
+            Operation::EnableInterrupts => self.ime_pending = true,
+            Operation::DisableInterrupts => {
+                self.ime = false;
+                self.ime_pending = false;
+            },
+            Operation::Halt => self.execute_halt()?,
+            Operation::Stop => self.execute_stop()?,
+        }
+
+        Ok(MCycles(instr.cycles as u32))
+    }
+
+    /// Stop the CPU until an interrupt is requested. If IME is off and an interrupt is already
+    /// pending, the CPU doesn't stop at all, and instead fails to move the pc past the next
+    /// opcode, which gets read twice (the HALT bug).
+    fn execute_halt(&mut self) -> Result<(), GameBoySystemError> {
+        if !self.ime && self.pending_interrupts()? != 0 {
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
+        Ok(())
+    }
+
+    /// Check whether a halted CPU should keep waiting, waking it up once any enabled interrupt
+    /// is requested. It wakes up even if IME is off, in which case the interrupt isn't handled
+    /// and the CPU carries on after the HALT.
+    fn stays_halted(&mut self) -> Result<bool, GameBoySystemError> {
+        if self.halted && self.pending_interrupts()? != 0 {
+            self.halted = false;
+        }
+        Ok(self.halted)
+    }
+
+    /// Check whether a stopped CPU should stay stopped, starting it again once one of the
+    /// buttons selected in P1 is pressed. This doesn't depend on IE, since pressing a button
+    /// restarts the clock whether or not the joypad interrupt is enabled.
+    fn stays_stopped(&mut self) -> Result<bool, GameBoySystemError> {
+        if self.stopped {
+            let buttons = self.memory.load_byte(P1)
+                .ok_or(GameBoySystemError::MemoryReadError(P1))? & P1_BUTTONS;
+            // the button bits are active-low, so any 0 is a selected button being pressed
+            if buttons != P1_BUTTONS {
+                self.stopped = false;
+            }
+        }
+        Ok(self.stopped)
+    }
+
+    /// Switch between normal and double speed if a speed switch has been armed in KEY1, or
+    /// otherwise stop the clock until a button is pressed. Either way, this resets DIV.
+    fn execute_stop(&mut self) -> Result<(), GameBoySystemError> {
+        if self.memory.speed_switch_armed() {
+            self.double_speed = !self.double_speed;
+            self.memory.set_double_speed(self.double_speed);
+        } else {
+            self.stopped = true;
+        }
+
+        self.memory.store_byte(DIV, 0)
+            .map_err(|err| GameBoySystemError::MemoryWriteError(err, 0))?;
+        Ok(())
+    }
+
+    /// Wait until the given M-cycle of the current instruction if the r8 register refers to the
+    /// byte at HL, so that accessing it reads or writes memory on that cycle
+    fn wait_for_r8(&mut self, register: u8, cycle: u8) {
+        if register == REG_MEM {
+            self.wait_for_cycle(cycle);
+        }
+    }
+
+    /// Get the value of an 8-bit operand, reading the byte at HL on the given M-cycle of the
+    /// current instruction if that's what the operand refers to
+    fn read_operand8(&mut self, operand: Operand8, cycle: u8) -> Result<u8, GameBoySystemError> {
+        if let Operand8::Register(register) = operand {
+            self.wait_for_r8(register, cycle);
+        }
+        self.get_operand8(operand)
+    }
+
+    /// Add a value to register A (ADD/ADC), updating the flags
+    fn execute_add8(&mut self, value: u8, use_carry: bool) {
+        let carry = use_carry && self.registers.carry_flag();
+        let a = self.registers.get_register(CpuRegister::A);
+        let (result, flags) = alu::add8(a, value, carry);
+
+        self.registers.set_register(CpuRegister::A, result);
+        self.registers.set_register(CpuRegister::F, flags.into());
+    }
+
+    /// Add a value to register HL (ADD HL, r16), updating every flag except zero
+    fn execute_add16(&mut self, value: u16) {
+        let hl = self.registers.get_joined_registers(CpuRegister::H, CpuRegister::L);
+        let (result, mut flags) = alu::add16(hl, value);
+        flags.zero = self.registers.zero_flag();
+
+        self.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, result);
+        self.registers.set_register(CpuRegister::F, flags.into());
+    }
+
+    /// Subtract a value from register A (SUB/SBC/CP), updating the flags
+    ///
+    /// Parameters:
+    /// - `value`: the number to subtract from A
+    /// - `use_carry`: whether the carry flag should also be subtracted (SBC)
+    /// - `write_back`: whether the result should be stored in A, which is false for CP since it
+    ///   only sets the flags
+    fn execute_sub8(&mut self, value: u8, use_carry: bool, write_back: bool) {
+        let carry = use_carry && self.registers.carry_flag();
+        let a = self.registers.get_register(CpuRegister::A);
+        let (result, flags) = alu::sub8(a, value, carry);
+
+        if write_back {
+            self.registers.set_register(CpuRegister::A, result);
+        }
+        self.registers.set_register(CpuRegister::F, flags.into());
+    }
+
+    /// Run a bitwise operation (AND/OR/XOR) on register A, updating the flags
+    fn execute_logic8(&mut self, logic: impl FnOnce(u8) -> (u8, FlagRegister)) {
+        let (result, flags) = logic(self.registers.get_register(CpuRegister::A));
+
+        self.registers.set_register(CpuRegister::A, result);
+        self.registers.set_register(CpuRegister::F, flags.into());
+    }
+
+    /// Rotate, shift, or swap the bits of a register, updating the flags. This is also used for
+    /// INC r8 and DEC r8, which change a register in place the same way.
+    ///
+    /// Parameters:
+    /// - `register`: the r8 index of the register to change (6 changes the byte at HL)
+    /// - `last_cycle`: the last M-cycle of the instruction, which writes the byte at HL back
+    ///   after reading it on the cycle before
+    /// - `shift`: the ALU operation, which returns the new value and flags
+    fn execute_shift(
+        &mut self, register: u8, last_cycle: u8, shift: impl FnOnce(u8) -> (u8, FlagRegister)
+    ) -> Result<(), GameBoySystemError> {
+        self.wait_for_r8(register, last_cycle - 1);
+        let (result, flags) = shift(self.get_r8(register)?);
+
+        self.wait_for_r8(register, last_cycle);
+        self.set_r8(register, result)?;
+        self.registers.set_register(CpuRegister::F, flags.into());
+        Ok(())
+    }
+
+    /// Rotate register A for RLCA, RRCA, RLA, and RRA. These work the same way as the
+    /// CB-prefixed rotates, except that the zero flag is always cleared.
+    fn execute_rotate_a(&mut self, rotate: impl FnOnce(u8) -> (u8, FlagRegister)) {
+        let (result, mut flags) = rotate(self.registers.get_register(CpuRegister::A));
+        flags.zero = false;
+
+        self.registers.set_register(CpuRegister::A, result);
+        self.registers.set_register(CpuRegister::F, flags.into());
+    }
+
+    /// Check a single bit of a register (BIT), setting the zero flag if the bit is 0. The carry
+    /// flag is left alone.
+    ///
+    /// Parameters:
+    /// - `register`: the r8 index of the register to check (6 checks the byte at HL)
+    /// - `bit`: which bit to check, from 0 (least significant) to 7
+    fn execute_test_bit(&mut self, register: u8, bit: u8) -> Result<(), GameBoySystemError> {
+        let value = self.get_r8(register)?;
+
+        self.registers.set_zero_flag(value & (1 << bit) == 0);
+        self.registers.set_subtract_flag(false);
+        self.registers.set_half_carry_flag(true);
+        Ok(())
+    }
+
+    /// Adjust register A back into binary-coded decimal after adding or subtracting two BCD
+    /// numbers. The subtract flag says which operation came before, and the half carry and carry
+    /// flags say which digits overflowed (or borrowed) past 9.
+    /// Set the carry flag for SCF or CCF, which both clear the subtract and half carry flags
+    fn execute_set_carry(&mut self, carry: bool) {
+        self.registers.set_subtract_flag(false);
+        self.registers.set_half_carry_flag(false);
+        self.registers.set_carry_flag(carry);
+    }
+
+    fn execute_daa(&mut self) {
+        let a = self.registers.get_register(CpuRegister::A);
+        let mut adjustment = 0;
+        let mut carry = self.registers.carry_flag();
+
+        let result = if self.registers.subtract_flag() {
+            // after a subtraction the digits can't be above 9, so only borrows need fixing
+            if self.registers.half_carry_flag() {
+                adjustment |= 0x06;
+            }
+            if carry {
+                adjustment |= 0x60;
+            }
+            a.wrapping_sub(adjustment)
+        } else {
+            if self.registers.half_carry_flag() || (a & 0xF) > 0x9 {
+                adjustment |= 0x06;
+            }
+            if carry || a > 0x99 {
+                adjustment |= 0x60;
+                carry = true;
+            }
+            a.wrapping_add(adjustment)
+        };
+
+        self.registers.set_register(CpuRegister::A, result);
+        self.registers.set_zero_flag(result == 0);
+        self.registers.set_half_carry_flag(false);
+        self.registers.set_carry_flag(carry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+
+    use crate::GameBoySystem;
+    use crate::cpu::instructions::{MemoryAddress, Operand8};
+    use crate::cycles::TCycles;
+    use crate::joypad::Button;
+    use crate::memory::{DmgMemoryController, MemoryWriteError, MockMemoryController, KEY1};
+    use crate::memory::cartridge::MockCartridgeMapper;
+    use crate::ppu::STAT;
+    use crate::test_utils::{init_program, init_rom_program, init_system};
+    use crate::timer::{TAC, TIMA};
+
+    use super::*;
+
+    fn execute(dmg: &mut GameBoySystem, op: Operation, cycles: u8) {
+        let result = dmg.execute_instruction(Instruction { op, cycles });
+        assert!(
+            matches!(result, Ok(MCycles(result_cycles)) if result_cycles == cycles as u32),
+            "Executing should return the instruction's cycles"
+        );
+    }
+
+    #[test]
+    fn test_execute_nop() {
+        let mut dmg = init_system();
+
+        execute(&mut dmg, Operation::NOP, 1);
+
+        assert_eq!(dmg.registers.pc, 0, "NOP shouldn't change anything");
+    }
+
+    #[test]
+    fn test_execute_load8_register() {
+        let mut dmg = init_system();
+
+        execute(&mut dmg, Operation::Load8(3, Operand8::Immediate(0x42)), 2);
+
+        assert!(matches!(dmg.get_r8(3), Ok(0x42)), "The value should be loaded into the register");
+    }
+
+    #[test]
+    fn test_load_b_from_a() {
+        // LD B, A
+        let mut dmg = init_program(vec![0x47]);
+        dmg.registers.set_register(CpuRegister::A, 0x42);
+
+        assert!(dmg.step().is_ok(), "LD B, A should run");
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::B), 0x42, "A should be copied into B");
+        assert_eq!(dmg.registers.get_register(CpuRegister::L), 0, "L shouldn't be touched");
+    }
+
+    #[test]
+    fn test_load_a_from_b() {
+        // LD A, B
+        let mut dmg = init_program(vec![0x78]);
+        dmg.registers.set_register(CpuRegister::B, 0x28);
+
+        assert!(dmg.step().is_ok(), "LD A, B should run");
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0x28, "B should be copied into A");
+    }
+
+    #[test]
+    fn test_execute_load8_memory() {
+        let mut dmg = init_system();
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0xC042);
+
+        execute(&mut dmg, Operation::Load8(6, Operand8::Immediate(0x28)), 3);
+
+        assert_eq!(
+            dmg.memory.load_byte(0xC042), Some(0x28),
+            "Loading into register 6 should write to the address in HL"
+        );
+    }
+
+    #[test]
+    fn test_execute_load16() {
+        let mut dmg = init_system();
+
+        execute(&mut dmg, Operation::Load16(1, 0xBEEF), 3);
+        execute(&mut dmg, Operation::Load16(3, 0xFFFE), 3);
+
+        assert_eq!(dmg.get_r16(1), 0xBEEF, "The value should be loaded into DE");
+        assert_eq!(dmg.registers.sp, 0xFFFE, "Register 3 should be the stack pointer");
+    }
+
+    #[test]
+    fn test_execute_store_accumulator() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, 0x42);
+
+        execute(&mut dmg, Operation::StoreAccumulator(MemoryAddress::Immediate(0xC000)), 2);
+
+        assert_eq!(dmg.memory.load_byte(0xC000), Some(0x42), "A should be stored");
+    }
+
+    #[test]
+    fn test_execute_load_accumulator_high_c() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::C, 0x80);
+        assert!(dmg.memory.store_byte(0xFF80, 0x28).is_ok(), "Should be able to write HRAM");
+
+        execute(&mut dmg, Operation::LoadAccumulator(MemoryAddress::HighC), 2);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0x28, "[0xFF00 + C] should be read");
+    }
+
+    #[test]
+    fn test_execute_hl_increment_and_decrement() {
+        let mut dmg = init_system();
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0xC000);
+        dmg.registers.set_register(CpuRegister::A, 0x42);
+
+        execute(&mut dmg, Operation::StoreAccumulator(MemoryAddress::Register(2)), 2);
+        execute(&mut dmg, Operation::LoadAccumulator(MemoryAddress::Register(3)), 2);
+
+        assert_eq!(dmg.memory.load_byte(0xC000), Some(0x42), "[HL+] should store at the old HL");
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0, "[HL-] should read 0xC001");
+        assert_eq!(
+            dmg.registers.get_joined_registers(CpuRegister::H, CpuRegister::L), 0xC000,
+            "HL should move up and then back down"
+        );
+    }
+
+    #[test]
+    fn test_execute_store_stack_pointer() {
+        let mut dmg = init_system();
+        dmg.registers.sp = 0xBEEF;
+
+        execute(&mut dmg, Operation::StoreStackPointer(0xC000), 5);
+
+        assert_eq!(dmg.memory.load_byte(0xC000), Some(0xEF), "The low byte should be first");
+        assert_eq!(dmg.memory.load_byte(0xC001), Some(0xBE), "The high byte should be second");
+    }
+
+    #[test]
+    fn test_execute_invalid_store() {
+        let mut cartridge = MockCartridgeMapper::new();
+        cartridge.expect_write_mem()
+            .return_const(Err(MemoryWriteError::RamDisabled(0)));
+        let mut dmg = GameBoySystem::new(Box::new(DmgMemoryController::new(Box::new(cartridge))));
+        dmg.registers.set_register(CpuRegister::A, 0x42);
+
+        let result = dmg.execute_instruction(Instruction {
+            op: Operation::StoreAccumulator(MemoryAddress::Immediate(0xA000)),
+            cycles: 2
+        });
+
+        assert!(
+            matches!(
+                result,
+                Err(GameBoySystemError::MemoryWriteError(err, 0x42))
+                    if err == MemoryWriteError::RamDisabled(0xA000)
+            ),
+            "A failed store should report why it failed, along with the address and value"
+        );
+    }
+
+    #[test]
+    fn test_execute_call_and_return() {
+        let mut dmg = init_system();
+        dmg.registers.pc = 0x0153;
+        dmg.registers.sp = 0xFFFE;
+
+        execute(&mut dmg, Operation::Call(0x0200), 6);
+        let call_pc = dmg.registers.pc;
+        let call_sp = dmg.registers.sp;
+        let return_address = dmg.memory.load_half_word(0xFFFC);
+        execute(&mut dmg, Operation::Return(false), 4);
+
+        assert_eq!(call_pc, 0x0200, "Calling should jump to the target");
+        assert_eq!(call_sp, 0xFFFC, "Calling should push 2 bytes onto the stack");
+        assert_eq!(return_address, Some(0x0153), "Calling should push the return address");
+        assert_eq!(dmg.registers.pc, 0x0153, "Returning should go back to the pushed address");
+        assert_eq!(dmg.registers.sp, 0xFFFE, "Returning should pop 2 bytes off of the stack");
+    }
+
+    #[test]
+    fn test_increment_and_decrement16() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::F, 0xF0);
+        dmg.registers.sp = 0xFFFF;
+
+        execute(&mut dmg, Operation::Increment16(3), 2);
+        execute(&mut dmg, Operation::Decrement16(0), 2);
+
+        assert_eq!(dmg.registers.sp, 0x0000, "Register 3 should be SP, which wraps around");
+        assert_eq!(
+            dmg.registers.get_joined_registers(CpuRegister::B, CpuRegister::C), 0xFFFF,
+            "Register 0 should be BC, which wraps around"
+        );
+        assert_eq!(dmg.registers.get_register(CpuRegister::F), 0xF0, "No flags should change");
+    }
+
+    #[test]
+    fn test_add_hl_hl_carries() {
+        // ADD HL, HL
+        let mut dmg = init_program(vec![0x29]);
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0xFFFF);
+        // set zero and subtract, to check that only subtract gets cleared
+        dmg.registers.set_register(CpuRegister::F, 0xC0);
+
+        assert!(dmg.step().is_ok(), "ADD HL, HL should run");
+
+        assert_eq!(
+            dmg.registers.get_joined_registers(CpuRegister::H, CpuRegister::L), 0xFFFE,
+            "0xFFFF + 0xFFFF should wrap around to 0xFFFE"
+        );
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0xB0,
+            "Zero should be kept, subtract cleared, and both carries set"
+        );
+    }
+
+    #[test]
+    fn test_add16_reads_register_when_executed() {
+        // ADD HL, BC
+        let mut dmg = init_program(vec![0x09]);
+        let instruction = dmg.load_instruction();
+        assert!(instruction.is_ok(), "ADD HL, BC should decode");
+        dmg.registers.set_joined_registers(CpuRegister::B, CpuRegister::C, 0x1234);
+
+        assert!(dmg.execute_instruction(instruction.unwrap()).is_ok(), "ADD HL, BC should run");
+
+        assert_eq!(
+            dmg.registers.get_joined_registers(CpuRegister::H, CpuRegister::L), 0x1234,
+            "BC should be read when the instruction runs, not when it's decoded"
+        );
+    }
+
+    #[test]
+    fn test_add_hl_keeps_clear_zero() {
+        let mut dmg = init_system();
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0x0F00);
+        dmg.registers.set_joined_registers(CpuRegister::D, CpuRegister::E, 0xF100);
+        dmg.registers.set_register(CpuRegister::F, 0x00);
+
+        execute(&mut dmg, Operation::Add16(1), 2);
+
+        assert_eq!(
+            dmg.registers.get_joined_registers(CpuRegister::H, CpuRegister::L), 0x0000,
+            "0x0F00 + 0xF100 should wrap around to 0x0000"
+        );
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0x30,
+            "A result of 0 shouldn't set the zero flag"
+        );
+    }
+
+    #[test]
+    fn test_add_sp_negative_offset() {
+        // ADD SP, -1
+        let mut dmg = init_program(vec![0xE8, 0xFF]);
+        dmg.registers.sp = 0xC001;
+        dmg.registers.set_register(CpuRegister::F, 0xC0);
+
+        assert!(dmg.step().is_ok(), "ADD SP, e8 should run");
+
+        assert_eq!(dmg.registers.sp, 0xC000, "SP should move down by 1");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0x30,
+            "0x01 + 0xFF should carry out of bits 3 and 7, and clear zero and subtract"
+        );
+    }
+
+    #[test]
+    fn test_load_hl_sp_offset() {
+        // LD HL, SP + 0x10
+        let mut dmg = init_program(vec![0xF8, 0x10]);
+        dmg.registers.sp = 0xDFF8;
+
+        assert!(dmg.step().is_ok(), "LD HL, SP + e8 should run");
+
+        assert_eq!(
+            dmg.registers.get_joined_registers(CpuRegister::H, CpuRegister::L), 0xE008,
+            "HL should be SP + 0x10, crossing into the next page"
+        );
+        assert_eq!(dmg.registers.sp, 0xDFF8, "SP shouldn't change");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0x10,
+            "0xF8 + 0x10 should only carry out of bit 7"
+        );
+    }
+
+    #[test]
+    fn test_load_hl_sp_negative_offset() {
+        // LD HL, SP - 2
+        let mut dmg = init_program(vec![0xF8, 0xFE]);
+        dmg.registers.sp = 0xFF00;
+
+        assert!(dmg.step().is_ok(), "LD HL, SP + e8 should run");
+
+        assert_eq!(
+            dmg.registers.get_joined_registers(CpuRegister::H, CpuRegister::L), 0xFEFE,
+            "HL should be SP - 2, crossing into the previous page"
+        );
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0x00,
+            "0x00 + 0xFE shouldn't carry out of bit 3 or 7"
+        );
+    }
+
+    #[test]
+    fn test_operands_read_when_executed() {
+        // LD B, [HL] then ADD A, B
+        let mut dmg = init_program(vec![0x46, 0x80]);
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0xC000);
+        let load = dmg.load_instruction();
+        assert!(load.is_ok(), "LD B, [HL] should decode");
+        let add = dmg.load_instruction();
+        assert!(add.is_ok(), "ADD A, B should decode");
+
+        assert!(dmg.memory.store_byte(0xC000, 0x28).is_ok(), "Should be able to write WRAM");
+        assert!(dmg.execute_instruction(load.unwrap()).is_ok(), "LD B, [HL] should run");
+        assert!(dmg.execute_instruction(add.unwrap()).is_ok(), "ADD A, B should run");
+
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::A), 0x28,
+            "Memory and registers should be read when the instructions run, not when decoded"
+        );
+    }
+
+    #[test]
+    fn test_jump_to_hl_and_set_stack_pointer() {
+        let mut dmg = init_system();
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0xC123);
+
+        execute(&mut dmg, Operation::JumpToHL, 1);
+        execute(&mut dmg, Operation::SetStackPointer, 2);
+
+        assert_eq!(dmg.registers.pc, 0xC123, "JP HL should jump to the address in HL");
+        assert_eq!(dmg.registers.sp, 0xC123, "LD SP, HL should copy HL into SP");
+    }
+
+    #[test]
+    fn test_push_and_pop() {
+        let mut dmg = init_system();
+        dmg.registers.sp = 0xFFFE;
+        dmg.registers.set_joined_registers(CpuRegister::B, CpuRegister::C, 0xBEEF);
+
+        execute(&mut dmg, Operation::PushStack(0), 4);
+        let push_sp = dmg.registers.sp;
+        execute(&mut dmg, Operation::PopStack(1), 3);
+
+        assert_eq!(push_sp, 0xFFFC, "Pushing should move the stack pointer down by 2");
+        assert_eq!(dmg.memory.load_byte(0xFFFD), Some(0xBE), "B should be pushed first");
+        assert_eq!(dmg.memory.load_byte(0xFFFC), Some(0xEF), "C should be pushed below B");
+        assert_eq!(
+            dmg.registers.get_joined_registers(CpuRegister::D, CpuRegister::E), 0xBEEF,
+            "Popping should load the pushed value into DE"
+        );
+        assert_eq!(dmg.registers.sp, 0xFFFE, "Popping should move the stack pointer up by 2");
+    }
+
+    #[test]
+    fn test_pop_af_masks_flags() {
+        let mut dmg = init_system();
+        dmg.registers.sp = 0xFFFE;
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0x12FF);
+
+        execute(&mut dmg, Operation::PushStack(2), 4);
+        execute(&mut dmg, Operation::PopStack(3), 3);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0x12, "A should be popped");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0xF0,
+            "The lower 4 bits of F should always be 0"
+        );
+    }
+
+    #[test]
+    fn test_conditional_call_and_return() {
+        let mut program = vec![0; 0x20];
+        // CALL NZ, 0x0010 then CALL Z, 0x0010
+        program[0x0000..0x0006].copy_from_slice(&[0xC4, 0x10, 0x00, 0xCC, 0x10, 0x00]);
+        // RET Z then RET NC
+        program[0x0010..0x0012].copy_from_slice(&[0xC8, 0xD0]);
+        let mut dmg = init_program(program);
+        dmg.registers.sp = 0xFFFE;
+
+        let mut steps = Vec::new();
+        for _ in 0..4 {
+            let result = dmg.step();
+            assert!(result.is_ok(), "Every instruction should run");
+            steps.push((result.unwrap().cycles(), dmg.registers.pc, dmg.registers.sp));
+        }
+
+        assert_eq!(
+            steps,
+            vec![
+                (MCycles(6), 0x0010, 0xFFFC), // the call is taken
+                (MCycles(2), 0x0011, 0xFFFC), // the first return isn't
+                (MCycles(5), 0x0003, 0xFFFE), // the second return is
+                (MCycles(3), 0x0006, 0xFFFE), // the second call isn't
+            ],
+            "Conditional calls and returns should only use the stack when they're taken"
+        );
+    }
+
+    #[test]
+    fn test_step() {
+        let mut program = vec![0; 0x210];
+        // JP 0x0150
+        program[0x0000..0x0003].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        // CALL 0x0200, then JR -2 back to itself
+        program[0x0150..0x0155].copy_from_slice(&[0xCD, 0x00, 0x02, 0x18, 0xFE]);
+        // LD A, 0x42 then RET
+        program[0x0200..0x0203].copy_from_slice(&[0x3E, 0x42, 0xC9]);
+        let mut dmg = init_program(program);
+
+        let mut states = Vec::new();
+        for _ in 0..5 {
+            let result = dmg.step();
+            assert!(result.is_ok(), "Every instruction in the program should run");
+            states.push((dmg.registers.pc, dmg.registers.sp, result.unwrap().cycles().0));
+        }
+
+        assert_eq!(
+            states,
+            vec![
+                (0x0150, 0xFFFE, 4), // JP
+                (0x0200, 0xFFFC, 6), // CALL
+                (0x0202, 0xFFFC, 2), // LD A, n8
+                (0x0153, 0xFFFE, 4), // RET
+                (0x0153, 0xFFFE, 3), // JR
+            ],
+            "Each step should leave pc and sp where the instruction sends them"
+        );
+    }
+
+    #[test]
+    fn test_step_ticks_timer() {
+        let mut dmg = init_program(vec![]);
+        // increment TIMA every 16 T-cycles
+        assert!(dmg.memory.store_byte(TAC, 0x05).is_ok());
+
+        for _ in 0..4 {
+            assert!(dmg.step().is_ok());
+        }
+
+        assert_eq!(
+            dmg.memory.load_byte(TIMA), Some(1),
+            "4 NOPs should run the timer for 16 T-cycles"
+        );
+    }
+
+    #[test]
+    fn test_stop_switches_speed() {
+        let mut cartridge = MockCartridgeMapper::new();
+        // STOP, then NOPs
+        cartridge.expect_read_rom()
+            .returning(|address| Some(if address == 0 { 0x10 } else { 0x00 }));
+        let memory = DmgMemoryController::new_cgb(Box::new(cartridge));
+        let mut dmg = GameBoySystem::new(Box::new(memory));
+        assert!(dmg.memory.store_byte(KEY1, 0x01).is_ok(), "Should be able to arm the switch");
+
+        assert!(dmg.step().is_ok(), "STOP should switch speeds");
+        assert!(dmg.double_speed(), "The CPU should be in double speed mode");
+        assert_eq!(dmg.memory.load_byte(KEY1), Some(0xFE), "KEY1 should report double speed");
+
+        // increment TIMA every 16 T-cycles, and start drawing from the first line
+        assert!(dmg.memory.store_byte(TAC, 0x05).is_ok());
+        assert!(dmg.memory.store_byte(DIV, 0).is_ok());
+        assert!(dmg.memory.store_byte(LCDC, 0x91).is_ok());
+        // 114 M-cycles is exactly one line at normal speed
+        for _ in 0..114 {
+            assert!(dmg.step().is_ok());
+        }
+        assert_eq!(dmg.memory.load_byte(TIMA), Some(28), "The timer should run at CPU speed");
+        assert_eq!(dmg.memory.load_byte(LY), Some(0), "The PPU should only be half way through");
+        for _ in 0..114 {
+            assert!(dmg.step().is_ok());
+        }
+        assert_eq!(dmg.memory.load_byte(LY), Some(1), "The PPU should keep its own speed");
+    }
+
+    #[test]
+    fn test_stop_without_switch() {
+        // STOP (and the byte after it), then NOPs
+        let mut dmg = init_program(vec![0x10, 0x00]);
+        dmg.memory.tick(TCycles(512));
+        let div_before = dmg.memory.load_byte(DIV);
+
+        assert!(dmg.step().is_ok(), "STOP should run without a speed switch");
+        let div_after = dmg.memory.load_byte(DIV);
+
+        assert_eq!(div_before, Some(2), "DIV should have counted up before STOP");
+        assert_eq!(div_after, Some(0), "STOP should reset DIV");
+        assert!(dmg.stopped(), "The CPU should be stopped");
+        assert!(!dmg.double_speed(), "The speed shouldn't change");
+        assert_eq!(dmg.registers.pc, 0x0002, "STOP should skip the byte after it");
+    }
+
+    #[test]
+    fn test_stopped_until_button_pressed() {
+        let mut dmg = init_program(vec![0x10, 0x00]);
+        // select the directions
+        assert!(dmg.memory.store_byte(P1, 0x20).is_ok());
+        assert!(dmg.step().is_ok());
+
+        for _ in 0..1000 {
+            assert_eq!(dmg.step().ok(), Some(StepOutcome::Completed(MCycles(1))));
+        }
+        let pc_while_stopped = dmg.registers.pc;
+        let div_while_stopped = dmg.memory.load_byte(DIV);
+        dmg.set_button(Button::A, true);
+        assert!(dmg.step().is_ok());
+        let stopped_after_unselected = dmg.stopped();
+        dmg.set_button(Button::Down, true);
+        assert!(dmg.step().is_ok());
+
+        assert_eq!(pc_while_stopped, 0x0002, "Nothing should run while stopped");
+        assert_eq!(div_while_stopped, Some(0), "The timer should be stopped too");
+        assert!(stopped_after_unselected, "A button which isn't selected shouldn't wake the CPU");
+        assert!(!dmg.stopped(), "Pressing a selected button should wake the CPU");
+        assert_eq!(dmg.registers.pc, 0x0003, "The CPU should carry on after the STOP");
+    }
+
+    #[test]
+    fn test_decoded_loads_run() {
+        let program = [
+            0x11, 0xEF, 0xBE, // LD DE, 0xBEEF
+            0x0E, 0x42, // LD C, 0x42
+        ];
+        let mut dmg = init_program(program.to_vec());
+
+        for _ in 0..2 {
+            let instruction = dmg.load_instruction().unwrap();
+            assert!(dmg.execute_instruction(instruction).is_ok());
+        }
+
+        assert_eq!(dmg.get_r16(1), 0xBEEF, "LD DE, n16 should load into DE");
+        assert!(matches!(dmg.get_r8(1), Ok(0x42)), "LD C, n8 should load into register 1");
+    }
+
+    #[test]
+    fn test_execute_add8() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, 0x3A);
+
+        execute(&mut dmg, Operation::Add8(Operand8::Immediate(0xC6), false), 2);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0x00, "0x3A + 0xC6 should wrap");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0xB0,
+            "The zero, half carry, and carry flags should be set"
+        );
+    }
+
+    #[test]
+    fn test_execute_add8_with_carry() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, 0xFE);
+        dmg.registers.set_carry_flag(true);
+
+        execute(&mut dmg, Operation::Add8(Operand8::Immediate(0x01), true), 2);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0x00, "ADC should add the carry");
+        assert!(dmg.registers.zero_flag(), "The wrapped result is zero");
+        assert!(dmg.registers.carry_flag(), "The carry-in should carry out of the byte");
+    }
+
+    #[test]
+    fn test_execute_add8_ignores_carry() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, 0xFE);
+        dmg.registers.set_carry_flag(true);
+
+        execute(&mut dmg, Operation::Add8(Operand8::Immediate(0x01), false), 1);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0xFF, "ADD shouldn't use carry");
+        assert!(!dmg.registers.carry_flag(), "Nothing should carry out of the byte");
+    }
+
+    #[test]
+    fn test_execute_sub8_with_carry() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, 0x00);
+        dmg.registers.set_carry_flag(true);
+
+        execute(&mut dmg, Operation::Sub8(Operand8::Immediate(0xFF), true), 2);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0x00, "SBC should wrap to 0");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0xF0,
+            "Every flag should be set"
+        );
+    }
+
+    #[test]
+    fn test_execute_compare8() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, 0x3C);
+
+        execute(&mut dmg, Operation::Compare8(Operand8::Immediate(0x40)), 2);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0x3C, "CP shouldn't change A");
+        assert!(dmg.registers.subtract_flag(), "CP should set the subtract flag");
+        assert!(dmg.registers.carry_flag(), "0x3C < 0x40 should borrow");
+        assert!(!dmg.registers.zero_flag(), "The values aren't equal");
+    }
+
+    /// Run an addition or subtraction on A followed by DAA, returning A and the flags
+    fn bcd_result(a: u8, op: Operation) -> (u8, u8) {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, a);
+
+        execute(&mut dmg, op, 1);
+        execute(&mut dmg, Operation::DAA, 1);
+
+        (dmg.registers.get_register(CpuRegister::A), dmg.registers.get_register(CpuRegister::F))
+    }
+
+    #[test]
+    fn test_execute_daa_after_add() {
+        assert_eq!(
+            bcd_result(0x0F, Operation::Add8(Operand8::Immediate(0x01), false)), (0x16, 0x00),
+            "0x0F + 0x01 carries out of the lower digit, so it should be adjusted to 0x16"
+        );
+        assert_eq!(
+            bcd_result(0x45, Operation::Add8(Operand8::Immediate(0x38), false)), (0x83, 0x00),
+            "45 + 38 should be adjusted to 83"
+        );
+        assert_eq!(
+            bcd_result(0x99, Operation::Add8(Operand8::Immediate(0x01), false)), (0x00, 0x90),
+            "99 + 1 should wrap to 00, setting zero and carry"
+        );
+        assert_eq!(
+            bcd_result(0x90, Operation::Add8(Operand8::Immediate(0x90), false)), (0x80, 0x10),
+            "90 + 90 should be 80 with a carry"
+        );
+    }
+
+    #[test]
+    fn test_execute_daa_after_sub() {
+        assert_eq!(
+            bcd_result(0x10, Operation::Sub8(Operand8::Immediate(0x01), false)), (0x09, 0x40),
+            "10 - 1 borrows from the upper digit, so it should be adjusted to 09"
+        );
+        assert_eq!(
+            bcd_result(0x83, Operation::Sub8(Operand8::Immediate(0x38), false)), (0x45, 0x40),
+            "83 - 38 should be adjusted to 45"
+        );
+        assert_eq!(
+            bcd_result(0x00, Operation::Sub8(Operand8::Immediate(0x01), false)), (0x99, 0x50),
+            "00 - 1 should wrap to 99, keeping the carry"
+        );
+        assert_eq!(
+            bcd_result(0x42, Operation::Sub8(Operand8::Immediate(0x42), false)), (0x00, 0xC0),
+            "42 - 42 should be 00, keeping the subtract flag"
+        );
+    }
+
+    #[test]
+    fn test_execute_complement() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, 0x3C);
+        dmg.registers.set_register(CpuRegister::F, 0x90);
+
+        execute(&mut dmg, Operation::Complement, 1);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0xC3, "CPL should flip every bit");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0xF0,
+            "CPL should set N and H, leaving Z and C alone"
+        );
+    }
+
+    #[test]
+    fn test_execute_set_carry_flag() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::F, 0xE0);
+
+        execute(&mut dmg, Operation::SetCarryFlag, 1);
+
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0x90,
+            "SCF should set C and clear N and H, leaving Z alone"
+        );
+    }
+
+    #[test]
+    fn test_execute_complement_carry_flag() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::F, 0x70);
+
+        execute(&mut dmg, Operation::ComplementCarryFlag, 1);
+        let cleared = dmg.registers.get_register(CpuRegister::F);
+        execute(&mut dmg, Operation::ComplementCarryFlag, 1);
+
+        assert_eq!(cleared, 0x00, "CCF should flip C to 0 and clear N and H");
+        assert_eq!(dmg.registers.get_register(CpuRegister::F), 0x10, "CCF should flip C to 1");
+    }
+
+    #[test]
+    fn test_execute_test_bit() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::B, 0x80);
+        dmg.registers.set_register(CpuRegister::F, 0x50);
+
+        execute(&mut dmg, Operation::TestBit(0, 6), 2);
+
+        assert!(dmg.registers.zero_flag(), "Bit 6 is 0, so the zero flag should be set");
+        assert!(!dmg.registers.subtract_flag(), "BIT should clear the subtract flag");
+        assert!(dmg.registers.half_carry_flag(), "BIT should set the half carry flag");
+        assert!(dmg.registers.carry_flag(), "BIT shouldn't change the carry flag");
+
+        execute(&mut dmg, Operation::TestBit(0, 7), 2);
+
+        assert!(!dmg.registers.zero_flag(), "Bit 7 is 1, so the zero flag should be cleared");
+        assert_eq!(dmg.registers.get_register(CpuRegister::B), 0x80, "BIT shouldn't change B");
+    }
+
+    #[test]
+    fn test_execute_test_bit_memory() {
+        let mut dmg = init_system();
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0xC042);
+        assert!(dmg.memory.store_byte(0xC042, 0x01).is_ok());
+
+        execute(&mut dmg, Operation::TestBit(6, 0), 3);
+
+        assert!(!dmg.registers.zero_flag(), "BIT should check the byte at the address in HL");
+        assert!(!dmg.registers.carry_flag(), "BIT shouldn't change the carry flag");
+    }
+
+    #[test]
+    fn test_execute_set_and_reset_bit() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, 0xF0);
+        dmg.registers.set_register(CpuRegister::F, 0xA0);
+
+        execute(&mut dmg, Operation::SetBit(7, 0), 2);
+        execute(&mut dmg, Operation::ResetBit(7, 7), 2);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0x71, "SET and RES should update A");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0xA0,
+            "SET and RES shouldn't change the flags"
+        );
+    }
+
+    #[test]
+    fn test_execute_set_and_reset_bit_memory() {
+        let mut dmg = init_system();
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0xC042);
+        assert!(dmg.memory.store_byte(0xC042, 0x0F).is_ok());
+
+        execute(&mut dmg, Operation::SetBit(6, 4), 4);
+        execute(&mut dmg, Operation::ResetBit(6, 0), 4);
+
+        assert_eq!(
+            dmg.memory.load_byte(0xC042), Some(0x1E),
+            "SET and RES should write back to the address in HL"
+        );
+    }
+
+    #[test]
+    fn test_execute_rotate_through_carry() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::C, 0x80);
+
+        // RL C
+        execute(&mut dmg, Operation::RotateLeft(1, false), 2);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::C), 0x00, "Bit 7 should go to carry");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0x90,
+            "The prefixed rotates should set zero from the result"
+        );
+
+        // RL C again, which should bring the carry back in
+        execute(&mut dmg, Operation::RotateLeft(1, false), 2);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::C), 0x01, "Carry should be rotated in");
+        assert_eq!(dmg.registers.get_register(CpuRegister::F), 0x00, "Nothing was shifted out");
+    }
+
+    #[test]
+    fn test_execute_rotate_accumulator_clears_zero() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, 0x80);
+
+        // RLA
+        execute(&mut dmg, Operation::RotateAccumulatorLeft(false), 1);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0x00, "Bit 7 should go to carry");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0x10,
+            "RLA should clear the zero flag even though the result is 0"
+        );
+    }
+
+    #[test]
+    fn test_execute_swap_memory() {
+        let mut dmg = init_system();
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0xC042);
+        dmg.registers.set_carry_flag(true);
+        assert!(dmg.memory.store_byte(0xC042, 0x12).is_ok());
+
+        execute(&mut dmg, Operation::SwapBits(6), 4);
+
+        assert_eq!(dmg.memory.load_byte(0xC042), Some(0x21), "SWAP should write back to [HL]");
+        assert!(!dmg.registers.carry_flag(), "SWAP should clear the carry flag");
+    }
+
+    #[test]
+    fn test_execute_and8_sets_half_carry() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, 0x3C);
+        dmg.registers.set_register(CpuRegister::F, 0x50);
+
+        execute(&mut dmg, Operation::And8(Operand8::Immediate(0x0F)), 2);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0x0C, "A should be ANDed");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0x20,
+            "AND should always set half carry, and clear subtract and carry"
+        );
+    }
+
+    #[test]
+    fn test_execute_or8_memory() {
+        let mut dmg = init_system();
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0xC042);
+        dmg.registers.set_register(CpuRegister::A, 0x0F);
+        dmg.registers.set_register(CpuRegister::F, 0x70);
+        assert!(dmg.memory.store_byte(0xC042, 0xF0).is_ok());
+
+        execute(&mut dmg, Operation::Or8(Operand8::Register(6)), 2);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0xFF, "A should be ORed with [HL]");
+        assert_eq!(dmg.registers.get_register(CpuRegister::F), 0x00, "OR should clear the flags");
+    }
+
+    #[test]
+    fn test_execute_xor_a_a() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, 0x42);
+        dmg.registers.set_register(CpuRegister::F, 0x70);
+
+        execute(&mut dmg, Operation::Xor8(Operand8::Register(7)), 1);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0x00, "XOR A, A should clear A");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0x80,
+            "XOR A, A should only set the zero flag"
+        );
+    }
+
+    #[test]
+    fn test_execute_increment8() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::B, 0x0F);
+        dmg.registers.set_carry_flag(true);
+
+        execute(&mut dmg, Operation::Increment8(0), 1);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::B), 0x10, "B should be incremented");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0x30,
+            "INC should set half carry at 0x0F, and leave carry set"
+        );
+    }
+
+    #[test]
+    fn test_execute_decrement8() {
+        let mut dmg = init_system();
+        dmg.registers.set_register(CpuRegister::A, 0x00);
+
+        execute(&mut dmg, Operation::Decrement8(7), 1);
+
+        assert_eq!(dmg.registers.get_register(CpuRegister::A), 0xFF, "A should wrap to 0xFF");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0x60,
+            "DEC should set subtract and half carry at 0x00, and leave carry clear"
+        );
+    }
+
+    #[test]
+    fn test_execute_increment_and_decrement_memory() {
+        let mut dmg = init_system();
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, 0xC042);
+        assert!(dmg.memory.store_byte(0xC042, 0xFF).is_ok());
+
+        execute(&mut dmg, Operation::Increment8(6), 3);
+        let incremented = dmg.memory.load_byte(0xC042);
+        let increment_flags = dmg.registers.get_register(CpuRegister::F);
+        execute(&mut dmg, Operation::Decrement8(6), 3);
+
+        assert_eq!(incremented, Some(0x00), "INC [HL] should wrap around to 0x00");
+        assert_eq!(increment_flags, 0xA0, "INC [HL] should set zero and half carry");
+        assert_eq!(dmg.memory.load_byte(0xC042), Some(0xFF), "DEC [HL] should write back to [HL]");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::F), 0x60,
+            "DEC [HL] should set subtract and half carry"
+        );
+    }
+
+    #[test]
+    fn test_memory_read_on_last_cycle() {
+        // 63 NOPs, then ADD A, [HL]
+        let mut program = vec![0x00; 63];
+        program.push(0x86);
+        let mut dmg = init_program(program);
+        dmg.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, DIV);
+        assert!(dmg.memory.store_byte(DIV, 0).is_ok(), "Should be able to reset DIV");
+
+        // DIV goes up every 64 M-cycles, and the NOPs take 63 of them
+        for _ in 0..63 {
+            assert!(dmg.step().is_ok());
+        }
+        assert_eq!(
+            dmg.step().ok(), Some(StepOutcome::Completed(MCycles(2))),
+            "ADD A, [HL] should take 2 cycles"
+        );
+
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::A), 1,
+            "[HL] should be read on the second cycle, after DIV goes up"
+        );
+    }
+
+    #[test]
+    fn test_call_pushes_on_last_cycles() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut memory = MockMemoryController::new();
+        // CALL 0x1234
+        let program = [0xCD, 0x34, 0x12];
+        memory.expect_load_byte().returning(move |address| program.get(address as usize).copied());
+        let tick_events = events.clone();
+        memory.expect_tick()
+            .returning(move |cycles| {
+                tick_events.lock().unwrap().push(format!("tick {}", cycles.0));
+            });
+        let store_events = events.clone();
+        memory.expect_store_byte()
+            .returning(move |address, data| {
+                store_events.lock().unwrap().push(format!("store {address:#06X} {data:#04X}"));
+                Ok(0)
+            });
+        let mut dmg = GameBoySystem::new(Box::new(memory));
+        dmg.registers.sp = 0xD000;
+
+        assert_eq!(
+            dmg.step().ok(), Some(StepOutcome::Completed(MCycles(6))),
+            "CALL should take 6 cycles"
+        );
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["tick 16", "store 0xCFFF 0x00", "tick 4", "store 0xCFFE 0x03", "tick 4"],
+            "The return address should be pushed on the 5th and 6th cycles, high byte first"
+        );
+        assert_eq!(dmg.registers.pc, 0x1234, "CALL should jump to its target");
+    }
+
+    /// Count the dots that pass while a program that only runs `JR -2` (12 dots) is running
+    fn count_jr_dots(dmg: &mut GameBoySystem) -> Rc<RefCell<u32>> {
+        let dots = Rc::new(RefCell::new(0));
+        let hook_dots = dots.clone();
+        dmg.set_trace_hook(Box::new(move |_| *hook_dots.borrow_mut() += 12));
+        dots
+    }
+
+    #[test]
+    fn test_run_frame_ends_at_vblank() {
+        let mut dmg = init_rom_program(&[0x18, 0xFE]); // JR -2
+        assert!(dmg.run_frame().is_ok(), "The program should run");
+        let dots = count_jr_dots(&mut dmg);
+
+        assert!(dmg.run_frame().is_ok(), "The program should run");
+
+        assert_eq!(dmg.current_line(), VBLANK_LINE, "The frame should end as VBlank starts");
+        assert!(
+            (DOTS_PER_FRAME..DOTS_PER_FRAME + 12).contains(&*dots.borrow()),
+            "A whole frame should run between VBlanks, not {}", dots.borrow()
+        );
+    }
+
+    #[test]
+    fn test_run_frame_after_lcd_toggle() {
+        let mut dmg = init_rom_program(&[0x18, 0xFE]); // JR -2
+        assert!(dmg.run_frame().is_ok(), "The program should run");
+        for _ in 0..3000 {
+            assert!(dmg.step().is_ok(), "The program should run");
+        }
+        assert!(dmg.memory.store_byte(LCDC, 0x11).is_ok(), "Should turn the LCD off");
+        assert!(dmg.memory.store_byte(LCDC, 0x91).is_ok(), "Should turn the LCD back on");
+        let dots = count_jr_dots(&mut dmg);
+
+        assert!(dmg.run_frame().is_ok(), "The program should run");
+
+        assert_eq!(dmg.current_line(), VBLANK_LINE, "The frame should end as VBlank starts");
+        let visible_dots = VBLANK_LINE as u32 * 456;
+        assert!(
+            (visible_dots..visible_dots + 12).contains(&*dots.borrow()),
+            "Every line should be drawn after the LCD restarts, not {} dots", dots.borrow()
+        );
+    }
+
+    #[test]
+    fn test_run_frame() {
+        // the LCD is off, so the frame ends after 70224 dots instead of at VBlank
+        // LD A 0x42 (8 dots), then JR -4 back to it (12 dots)
+        let mut dmg = init_program(vec![0x3E, 0x42, 0x18, 0xFC]);
+        let dots = Rc::new(RefCell::new(0));
+        let hook_dots = dots.clone();
+        dmg.set_trace_hook(Box::new(move |entry| {
+            *hook_dots.borrow_mut() += if entry.bytes[0] == 0x3E { 8 } else { 12 };
+        }));
+
+        let frame = dmg.run_frame().ok().map(|frame| frame.len());
+        let first_frame_dots = *dots.borrow();
+        for _ in 0..4 {
+            assert!(dmg.run_frame().is_ok());
+        }
+
+        assert_eq!(frame, Some(160 * 144), "The whole framebuffer should be returned");
+        assert!(
+            (DOTS_PER_FRAME..DOTS_PER_FRAME + 12).contains(&first_frame_dots),
+            "The frame should end at the first instruction past 70224 dots, not {first_frame_dots}"
+        );
+        let total_dots = *dots.borrow();
+        assert!(
+            (5 * DOTS_PER_FRAME..5 * DOTS_PER_FRAME + 12).contains(&total_dots),
+            "Running past the end of a frame should be taken out of the next one, not {total_dots}"
+        );
+    }
+    #[test]
+    fn test_run_to_vblank() {
+        let mut dmg = init_rom_program(&[
+            0x3E, 0x10, // LD A, 0x10
+            0xE0, 0x00, // LDH [P1], A (select the action buttons)
+            0xF0, 0x00, // loop: LDH A, [P1]
+            0xE0, 0x80, // LDH [0x80], A
+            0x18, 0xFA, // JR loop
+        ]);
+        // start partway through a frame
+        for _ in 0..1000 {
+            assert!(dmg.step().is_ok(), "The program should run");
+        }
+
+        let frame = dmg.run_to_vblank(JoypadState { a: true, ..Default::default() })
+            .ok()
+            .map(|frame| frame.len());
+        let pressed = dmg.memory.load_byte(0xFF80);
+        let pressed_line = dmg.current_line();
+        assert!(dmg.run_to_vblank(JoypadState::default()).is_ok(), "The program should run");
+        let released = dmg.memory.load_byte(0xFF80);
+
+        assert_eq!(frame, Some(160 * 144), "The whole framebuffer should be returned");
+        assert_eq!(pressed_line, VBLANK_LINE, "Should stop as the PPU enters VBlank");
+        assert_eq!(dmg.current_line(), VBLANK_LINE, "Every frame should stop at VBlank");
+        assert_eq!(
+            dmg.memory.load_byte(STAT).map(|stat| stat & 0x03), Some(1),
+            "The PPU should be in VBlank"
+        );
+        assert_eq!(pressed, Some(0xDE), "The program should see A held down during the frame");
+        assert_eq!(released, Some(0xDF), "A should be released for the next frame");
+    }
+}
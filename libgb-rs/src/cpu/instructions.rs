@@ -1,51 +1,75 @@
 /// #Operation
 /// Represents a CPU instruction for the Sharp SM83 (CPU used by the Game Boy & Game Boy Color)
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Operation {
     NOP,
-    Load8(u8, u8), // Load 8-bit register (register, value)
+    Load8(u8, Operand8), // Load 8-bit register (register, value)
     Load16(u8, u16), // Load 16-bit register (register, value)
-    Store8(u16, u8), // Store an 8-bit value in memory (address, value)
-    Store16(u16, u16), // Store a 16-bit value in memory (address, value)
-    Add8(u8, bool), // value to add to A, and whether the carry flag should be used in op
-    Add16(u16), // value to add to HL
-    Sub8(u8, bool), // value to subtract from A, and whether the carry flag should be used in op
-    And8(u8), // value to do bitwise and with A
-    Or8(u8), // value to do bitwise or with A
-    Xor8(u8), // value to do bitwise xor with A
-    Compare8(u8), // value to compare with A (same as Sub8 but without storing result)
+    LoadAccumulator(MemoryAddress), // Load the byte at an address into A
+    StoreAccumulator(MemoryAddress), // Store A at an address
+    StoreStackPointer(u16), // Store the stack pointer at an address
+    Add8(Operand8, bool), // value to add to A, and whether the carry flag should be used in op
+    Add16(u8), // 16-bit register to add to HL
+    Sub8(Operand8, bool), // value to subtract from A, and whether the carry flag should be used
+    And8(Operand8), // value to do bitwise and with A
+    Or8(Operand8), // value to do bitwise or with A
+    Xor8(Operand8), // value to do bitwise xor with A
+    Compare8(Operand8), // value to compare with A (same as Sub8 but without storing result)
     Increment8(u8), // register to increment
     Increment16(u8), // register to increment 
     Decrement8(u8), // register to decrement
     Decrement16(u8), // register to decrement
-    RotateLeft(u8, bool), // Rotate register left 1 bit. Bool is whether to use carry bit in op
-    RotateRight(u8, bool), // Rotate register right 1 bit. Bool is whether to use carry bit in op
+    RotateLeft(u8, bool), // Rotate register left 1 bit. Bool is whether bit 7 wraps to bit 0
+    RotateRight(u8, bool), // Rotate register right 1 bit. Bool is whether bit 0 wraps to bit 7
+    RotateAccumulatorLeft(bool), // Same as RotateLeft on A, but the zero flag is always cleared
+    RotateAccumulatorRight(bool), // Same as RotateRight on A, but the zero flag is always cleared
     ShiftLeftArithmetic(u8), // Shift register left 1 bit
     ShiftRightArithmetic(u8), // Shift register right 1 bit, keeping most significant bit (MSB)
     ShiftRightLogical(u8), // Shift the register right 1 bit, using 0 as the new MSB
     SwapBits(u8), // Swap the upper and lower 4 bits of the given register
-    DAA, // ???
+    DAA, // Adjust A into binary-coded decimal after an addition or subtraction
     Complement, // A = !A
     SetCarryFlag, // Set c = 1
     ComplementCarryFlag, // Set c = !c
     Jump(u16), // Address to jump to
+    JumpToHL, // Jump to the address in HL
     Call(u16), // Address to jump to, storing next address on the stack
     Return(bool), // Return to the previous address on the stack, and whether to enable interrupts
-    TestBit(u8, u8), // Set C to the value of the target bit in the target register (reg, bit)
+    TestBit(u8, u8), // Set Z if the target bit in the target register is 0 (reg, bit)
     ResetBit(u8, u8), // Set the target bit in the target register to 0 (reg, bit)
     SetBit(u8, u8), // Set the target bit in the target register to 1 (reg, bit)
     PopStack(u8), // Pop the last 2 bytes of the stack into the given 16-bit register
     PushStack(u8), // Push the value in the given 16-bit register onto the stack
-    AddStackPointer(i8), // Add the given signed integer to the register in the stack
-    SetStackPointer(u16), // Set the stack pointer to a specific value
+    AddStackPointer(i8), // Add the given signed integer to the stack pointer
+    LoadStackPointerOffset(i8), // Load the stack pointer plus the given signed integer into HL
+    SetStackPointer, // Copy HL into the stack pointer
     EnableInterrupts,
     DisableInterrupts,
     Stop,
     Halt,
 }
 
+/// # Operand8
+/// Where the value for an 8-bit operation comes from. Registers (and the byte at HL) are read
+/// when the instruction runs, rather than when it's decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand8 {
+    Immediate(u8), // a value which is part of the instruction
+    Register(u8), // an r8 register index, where 6 is the byte at HL
+}
+
+/// # MemoryAddress
+/// The address that a load or store of A uses, which is worked out when the instruction runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAddress {
+    Immediate(u16), // an address which is part of the instruction (including LDH's 0xFF00 + n8)
+    HighC, // 0xFF00 + C
+    Register(u8), // an r16mem register index (BC, DE, HL+ or HL-)
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub struct Instruction {
-    pub cycles: u8,
+    pub cycles: u8, // measured in M-cycles (see `cycles::MCycles`)
     pub op: Operation
 }
 
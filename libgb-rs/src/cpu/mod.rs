@@ -1,8 +1,11 @@
 use std::mem::transmute;
 
+pub mod alu;
 pub mod decode;
+pub mod execute;
 pub mod instructions;
 
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 use crate::utils::{Merge, Split};
 
 // TODO: Restructure this into a folder with submodules like "decode"
@@ -31,6 +34,33 @@ impl From<u8> for CpuRegister {
     }
 }
 
+/// Convert a register index from the r8 operand encoding used by instructions (B, C, D, E, H,
+/// L, [HL], A) into the register it refers to
+///
+/// Parameters:
+/// - `idx`: the r8 index from an instruction, between 0 and 7
+///
+/// Panics if given index 6, since that refers to the byte at the address in HL rather than a
+/// register, and has to be handled separately
+pub fn r8_to_register(idx: u8) -> CpuRegister {
+    match idx & 0x7 {
+        0 => CpuRegister::B,
+        1 => CpuRegister::C,
+        2 => CpuRegister::D,
+        3 => CpuRegister::E,
+        4 => CpuRegister::H,
+        5 => CpuRegister::L,
+        7 => CpuRegister::A,
+        _ => panic!("r8 index 6 is [HL], which isn't a register"),
+    }
+}
+
+// Bit masks for each flag in the F register
+const ZERO_FLAG: u8 = 0x80;
+const SUBTRACT_FLAG: u8 = 0x40;
+const HALF_CARRY_FLAG: u8 = 0x20;
+const CARRY_FLAG: u8 = 0x10;
+
 /// #FlagRegister
 /// A convenient struct for holding CPU flags
 #[derive(Debug, Clone, Copy)]
@@ -53,10 +83,10 @@ impl From<FlagRegister> for u8 {
 impl From<u8> for FlagRegister {
     fn from(value: u8) -> Self {
         FlagRegister {
-            zero: (value & 0x80) != 0,
-            subtract: (value & 0x40) != 0,
-            half_carry: (value & 0x20) != 0,
-            carry: (value & 0x10) != 0
+            zero: (value & ZERO_FLAG) != 0,
+            subtract: (value & SUBTRACT_FLAG) != 0,
+            half_carry: (value & HALF_CARRY_FLAG) != 0,
+            carry: (value & CARRY_FLAG) != 0
         }
     }
 }
@@ -71,6 +101,12 @@ pub struct CpuData {
     pub pc: u16
 }
 
+impl Default for CpuData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CpuData {
     pub fn new() -> Self {
         CpuData {
@@ -90,22 +126,83 @@ impl CpuData {
         self.registers[idx as usize] = value;
     }
 
-    /// Get a 16-bit by joining two bytes from the given registers in Little-Endian ordering
+    /// Get a 16-bit value by joining two registers, where the first register holds the 8 most
+    /// significant bits (so B and C join into BC)
     pub fn get_joined_registers(&self, idx1: CpuRegister, idx2: CpuRegister) -> u16 {
-        let right = self.get_register(idx1);
-        let left = self.get_register(idx2);
+        let left = self.get_register(idx1);
+        let right = self.get_register(idx2);
         left.merge(right)
     }
 
-    /// Store a 16-bit value by splitting the given data and storing it in Little-Endian ordering
-    /// into the given registers
+    /// Store a 16-bit value by splitting the given data across two registers
     pub fn set_joined_registers(&mut self, idx1: CpuRegister, idx2: CpuRegister, data: u16) {
         let (left_data, right_data) = data.split();
 
         // Register 1 gets the 8 most significant bits
-        self.set_register(idx1, right_data);
+        self.set_register(idx1, left_data);
         // Register 2 gets the 8 least significant bits
-        self.set_register(idx2, left_data);
+        self.set_register(idx2, right_data);
+    }
+
+    /// Read a single bit of the F register without converting the whole register
+    fn get_flag(&self, mask: u8) -> bool {
+        self.get_register(CpuRegister::F) & mask != 0
+    }
+
+    /// Set or clear a single bit of the F register without converting the whole register
+    fn set_flag(&mut self, mask: u8, value: bool) {
+        let flags = self.get_register(CpuRegister::F);
+        let flags = if value { flags | mask } else { flags & !mask };
+        self.set_register(CpuRegister::F, flags);
+    }
+
+    pub fn zero_flag(&self) -> bool {
+        self.get_flag(ZERO_FLAG)
+    }
+
+    pub fn set_zero_flag(&mut self, value: bool) {
+        self.set_flag(ZERO_FLAG, value);
+    }
+
+    pub fn subtract_flag(&self) -> bool {
+        self.get_flag(SUBTRACT_FLAG)
+    }
+
+    pub fn set_subtract_flag(&mut self, value: bool) {
+        self.set_flag(SUBTRACT_FLAG, value);
+    }
+
+    pub fn half_carry_flag(&self) -> bool {
+        self.get_flag(HALF_CARRY_FLAG)
+    }
+
+    pub fn set_half_carry_flag(&mut self, value: bool) {
+        self.set_flag(HALF_CARRY_FLAG, value);
+    }
+
+    pub fn carry_flag(&self) -> bool {
+        self.get_flag(CARRY_FLAG)
+    }
+
+    pub fn set_carry_flag(&mut self, value: bool) {
+        self.set_flag(CARRY_FLAG, value);
+    }
+
+    /// Write every register into a snapshot
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_bytes(&self.registers);
+        writer.write_u16(self.sp);
+        writer.write_u16(self.pc);
+    }
+
+    /// Restore the registers written by `save_state`
+    pub fn load_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        reader.read_into(&mut self.registers)?;
+        // the lower nibble of F doesn't exist, so it always reads as 0
+        self.registers[CpuRegister::F as usize] &= 0xF0;
+        self.sp = reader.read_u16()?;
+        self.pc = reader.read_u16()?;
+        Ok(())
     }
 }
 
@@ -113,18 +210,80 @@ impl CpuData {
 mod tests {
     use crate::utils::Merge;
 
-    use super::{CpuData, CpuRegister};
+    use super::{r8_to_register, CpuData, CpuRegister, FlagRegister};
 
     #[test]
     fn test_endianness() {
         let mut data = CpuData::new();
         data.set_joined_registers(CpuRegister::B, CpuRegister::C, 0xBEEF);
 
-        let right = data.get_register(CpuRegister::B);
-        let left = data.get_register(CpuRegister::C);
+        let left = data.get_register(CpuRegister::B);
+        let right = data.get_register(CpuRegister::C);
 
         let n16 = data.get_joined_registers(CpuRegister::B, CpuRegister::C);
 
-        assert_eq!(n16, left.merge(right), "Data should be assigned in Little Endian order");
+        assert_eq!(left, 0xBE, "B should hold the most significant byte of BC");
+        assert_eq!(right, 0xEF, "C should hold the least significant byte of BC");
+        assert_eq!(n16, left.merge(right), "Joining should undo splitting");
+    }
+
+    #[test]
+    fn test_r8_to_register() {
+        let registers: Vec<CpuRegister> = [0, 1, 2, 3, 4, 5, 7].into_iter()
+            .map(r8_to_register)
+            .collect();
+
+        assert!(
+            matches!(
+                registers.as_slice(),
+                [
+                    CpuRegister::B, CpuRegister::C, CpuRegister::D, CpuRegister::E,
+                    CpuRegister::H, CpuRegister::L, CpuRegister::A
+                ]
+            ),
+            "r8 indices should follow the hardware encoding"
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_r8_to_register_hl() {
+        r8_to_register(6);
+    }
+
+    #[test]
+    fn test_flag_accessors_match_flag_register() {
+        let mut data = CpuData::new();
+        for value in [0x00, 0x80, 0x40, 0x20, 0x10, 0xF0, 0xA0, 0x50, 0xFF] {
+            data.set_register(CpuRegister::F, value);
+            let flags: FlagRegister = value.into();
+
+            assert_eq!(data.zero_flag(), flags.zero, "Zero flag should match for {value:#X}");
+            assert_eq!(
+                data.subtract_flag(), flags.subtract, "Subtract flag should match for {value:#X}"
+            );
+            assert_eq!(
+                data.half_carry_flag(), flags.half_carry,
+                "Half carry flag should match for {value:#X}"
+            );
+            assert_eq!(data.carry_flag(), flags.carry, "Carry flag should match for {value:#X}");
+        }
+    }
+
+    #[test]
+    fn test_flag_setters() {
+        let mut data = CpuData::new();
+        data.set_register(CpuRegister::F, 0x50);
+
+        data.set_zero_flag(true);
+        data.set_subtract_flag(false);
+        data.set_half_carry_flag(true);
+        data.set_carry_flag(true);
+
+        let expected = FlagRegister { zero: true, subtract: false, half_carry: true, carry: true };
+        assert_eq!(
+            data.get_register(CpuRegister::F), u8::from(expected),
+            "Setting flags should only change their own bits"
+        );
     }
 }
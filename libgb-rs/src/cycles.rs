@@ -0,0 +1,60 @@
+/// The number of T-cycles (dots) in a single M-cycle at normal speed
+pub const T_CYCLES_PER_M_CYCLE: u32 = 4;
+/// The number of T-cycles (dots) in a single M-cycle when a CGB is running in double speed mode
+pub const DOUBLE_SPEED_T_CYCLES_PER_M_CYCLE: u32 = 2;
+
+/// # MCycles
+/// A number of machine cycles, which is the unit the CPU works in. Every instruction takes a
+/// whole number of M-cycles to run.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MCycles(pub u32);
+
+/// # TCycles
+/// A number of clock cycles, which is the unit the PPU, timer, and APU work in. The PPU calls
+/// these dots. At normal speed there are 4 T-cycles in an M-cycle.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TCycles(pub u32);
+
+/// Convert machine cycles into the number of T-cycles which pass during them
+///
+/// Parameters:
+/// - `cycles`: the machine cycles to convert
+/// - `double_speed`: whether the CPU is in CGB double speed mode, where an M-cycle only lasts
+///   2 T-cycles from the point of view of the PPU
+pub fn m_to_t(cycles: MCycles, double_speed: bool) -> TCycles {
+    TCycles(cycles.0 * t_cycles_per_m_cycle(double_speed))
+}
+
+/// Convert T-cycles into whole machine cycles, dropping any leftover T-cycles
+///
+/// Parameters:
+/// - `cycles`: the T-cycles to convert
+/// - `double_speed`: whether the CPU is in CGB double speed mode (see `m_to_t`)
+pub fn t_to_m(cycles: TCycles, double_speed: bool) -> MCycles {
+    MCycles(cycles.0 / t_cycles_per_m_cycle(double_speed))
+}
+
+fn t_cycles_per_m_cycle(double_speed: bool) -> u32 {
+    if double_speed { DOUBLE_SPEED_T_CYCLES_PER_M_CYCLE } else { T_CYCLES_PER_M_CYCLE }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_m_to_t() {
+        assert_eq!(m_to_t(MCycles(4), false), TCycles(16), "An M-cycle should be 4 T-cycles");
+        assert_eq!(
+            m_to_t(MCycles(4), true), TCycles(8),
+            "An M-cycle should be 2 T-cycles in double speed mode"
+        );
+    }
+
+    #[test]
+    fn test_t_to_m() {
+        assert_eq!(t_to_m(TCycles(16), false), MCycles(4));
+        assert_eq!(t_to_m(TCycles(19), false), MCycles(4), "Partial M-cycles should be dropped");
+        assert_eq!(t_to_m(TCycles(8), true), MCycles(4));
+    }
+}
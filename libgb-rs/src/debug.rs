@@ -0,0 +1,488 @@
+use crate::GameBoySystem;
+use crate::cpu::{CpuData, CpuRegister};
+use crate::cpu::instructions::Operation;
+use crate::cycles::MCycles;
+
+/// The opcode for `LD B, B`, which test ROMs use as a software breakpoint
+pub const MAGIC_BREAKPOINT_OPCODE: u8 = 0x40;
+
+/// A callback run at each instruction boundary (see `GameBoySystem::set_step_callback`)
+pub type StepCallback = Box<dyn FnMut(u16, &Operation, &GameBoySystem)>;
+
+/// A callback run with every instruction `step` executes (see `GameBoySystem::set_trace_hook`)
+pub type TraceHook = Box<dyn FnMut(&TraceEntry)>;
+
+/// # TraceEntry
+/// An instruction which is about to be executed by `GameBoySystem::step`, along with the state of
+/// the CPU before it runs. This is intended for logging every instruction to compare against a
+/// trace from a known-good emulator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub registers: TrapState, // the registers before the instruction, where pc is its address
+    pub bytes: Vec<u8>, // the opcode and operands of the instruction
+    pub operation: Operation,
+    pub memory: [u8; 4], // the 4 bytes of memory starting at the pc, which can run past the end
+}
+
+impl TraceEntry {
+    /// Format the entry as a line of a Gameboy Doctor log, which looks like
+    /// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`
+    pub fn gameboy_doctor_line(&self) -> String {
+        let registers = &self.registers;
+        let [m0, m1, m2, m3] = self.memory;
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} \
+            PC:{:04X} PCMEM:{m0:02X},{m1:02X},{m2:02X},{m3:02X}",
+            registers.a, registers.f, registers.b, registers.c, registers.d, registers.e,
+            registers.h, registers.l, registers.sp, registers.pc
+        )
+    }
+}
+
+/// # Watchpoint
+/// The kinds of memory access to an address which stop `GameBoySystem::step`. Only accesses made
+/// by instructions count, so fetching the instruction itself or the DMA copying a byte won't
+/// trigger one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub on_write: bool,
+    pub on_read: bool,
+}
+
+/// # StepOutcome
+/// What happened during a call to `GameBoySystem::step`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// An instruction ran (or an interrupt was handled), taking the given number of M-cycles
+    Completed(MCycles),
+    /// The instruction at the given address has a breakpoint, so the system stopped before
+    /// running it. The next step runs the instruction instead of stopping again.
+    Breakpoint(u16),
+    /// An instruction ran and accessed an address with a watchpoint on it. Only the first
+    /// access an instruction makes to a watched address is reported.
+    Watchpoint { address: u16, write: bool, cycles: MCycles },
+}
+
+impl StepOutcome {
+    /// The number of M-cycles which passed during the step, which is 0 for a breakpoint
+    pub fn cycles(&self) -> MCycles {
+        match self {
+            StepOutcome::Completed(cycles) => *cycles,
+            StepOutcome::Breakpoint(_) => MCycles(0),
+            StepOutcome::Watchpoint { cycles, .. } => *cycles,
+        }
+    }
+}
+
+/// # CodeCoverage
+/// A bitmap with one bit per byte of the cartridge ROM, marking the bytes which have been fetched
+/// as part of an instruction. Any byte which isn't marked was either only read as data or never
+/// touched at all, which helps with telling code apart from data in a ROM.
+///
+/// Bytes are tracked by their position in the full ROM, so the same address in two different
+/// banks is tracked separately.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeCoverage {
+    code: Vec<u8>,
+    rom_size: usize,
+}
+
+impl CodeCoverage {
+    /// Create an empty bitmap for a ROM of the given size in bytes
+    pub fn new(rom_size: usize) -> CodeCoverage {
+        CodeCoverage {
+            code: vec![0; rom_size.div_ceil(8)],
+            rom_size,
+        }
+    }
+
+    /// Mark the byte at the given ROM offset as code. Offsets outside of the ROM are ignored.
+    pub fn mark_code(&mut self, offset: usize) {
+        if offset < self.rom_size {
+            self.code[offset / 8] |= 1 << (offset % 8);
+        }
+    }
+
+    /// Whether the byte at the given ROM offset has been fetched as part of an instruction
+    pub fn is_code(&self, offset: usize) -> bool {
+        offset < self.rom_size && (self.code[offset / 8] >> (offset % 8)) & 1 == 1
+    }
+
+    /// Get the offset of every byte in the ROM which has been marked as code, in order
+    pub fn code_offsets(&self) -> Vec<usize> {
+        (0..self.rom_size)
+            .filter(|offset| self.is_code(*offset))
+            .collect()
+    }
+
+    /// The size of the ROM being tracked, in bytes
+    pub fn rom_size(&self) -> usize {
+        self.rom_size
+    }
+}
+
+// The register values (B, C, D, E, H, L) used by the Mooneye test suite to report a result
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+
+/// # TestTrap
+/// A condition which makes the system capture its registers when an instruction is fetched.
+/// This is intended for detecting when a test ROM has finished running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestTrap {
+    /// Trap whenever a `LD B, B` instruction is reached (the "magic breakpoint" convention)
+    MagicBreakpoint,
+    /// Trap whenever an instruction is fetched from the given address
+    Address(u16),
+}
+
+/// # TrapState
+/// The state of the CPU registers right before a trapped instruction was executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapState {
+    pub pc: u16,
+    pub sp: u16,
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+}
+
+impl TrapState {
+    pub fn new(registers: &CpuData, pc: u16) -> TrapState {
+        TrapState {
+            pc,
+            sp: registers.sp,
+            a: registers.get_register(CpuRegister::A),
+            f: registers.get_register(CpuRegister::F),
+            b: registers.get_register(CpuRegister::B),
+            c: registers.get_register(CpuRegister::C),
+            d: registers.get_register(CpuRegister::D),
+            e: registers.get_register(CpuRegister::E),
+            h: registers.get_register(CpuRegister::H),
+            l: registers.get_register(CpuRegister::L),
+        }
+    }
+
+    /// Whether the registers hold the Mooneye "pass" signature (B/C/D/E/H/L = 3/5/8/13/21/34)
+    pub fn is_mooneye_pass(&self) -> bool {
+        self.result_registers() == MOONEYE_PASS
+    }
+
+    /// Whether the registers hold the Mooneye "fail" signature (B/C/D/E/H/L are all 0x42)
+    pub fn is_mooneye_fail(&self) -> bool {
+        self.result_registers() == MOONEYE_FAIL
+    }
+
+    fn result_registers(&self) -> [u8; 6] {
+        [self.b, self.c, self.d, self.e, self.h, self.l]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::cpu::instructions::Operand8;
+    use crate::memory::{DmgMemoryController, MockMemoryController};
+    use crate::memory::cartridge::MockCartridgeMapper;
+    use crate::test_utils::init_program;
+
+    use super::*;
+
+    fn set_result_registers(dmg: &mut GameBoySystem, values: [u8; 6]) {
+        let registers = [
+            CpuRegister::B, CpuRegister::C, CpuRegister::D,
+            CpuRegister::E, CpuRegister::H, CpuRegister::L
+        ];
+        for (register, value) in registers.into_iter().zip(values) {
+            dmg.registers.set_register(register, value);
+        }
+    }
+
+    #[test]
+    fn test_magic_breakpoint_reports_pass() {
+        // load the pass signature the way a mooneye test ROM does, then signal the result
+        let program = vec![
+            0x06, 3, // LD B, 3
+            0x0E, 5, // LD C, 5
+            0x16, 8, // LD D, 8
+            0x1E, 13, // LD E, 13
+            0x26, 21, // LD H, 21
+            0x2E, 34, // LD L, 34
+            MAGIC_BREAKPOINT_OPCODE, // LD B, B
+        ];
+        let mut dmg = init_program(program);
+        dmg.set_test_trap(Some(TestTrap::MagicBreakpoint));
+
+        for _ in 0..6 {
+            assert!(dmg.step().is_ok(), "The loads should run");
+        }
+        let before_trap = dmg.take_trap_state();
+        let result = dmg.step();
+        let trap = dmg.take_trap_state();
+
+        assert!(before_trap.is_none(), "Only LD B, B should trigger the trap");
+        assert!(result.is_ok(), "LD B, B should still run normally");
+        assert_eq!(trap.map(|trap| trap.pc), Some(12), "The trap should capture LD B, B");
+        assert!(trap.is_some(), "LD B, B should trigger the magic breakpoint trap");
+        assert!(trap.unwrap().is_mooneye_pass(), "Registers should hold the pass signature");
+        assert!(!trap.unwrap().is_mooneye_fail(), "Registers should not hold the fail signature");
+        assert!(dmg.take_trap_state().is_none(), "Taking the trap state should clear it");
+    }
+
+    #[test]
+    fn test_magic_breakpoint_reports_fail() {
+        let mut dmg = init_program(vec![MAGIC_BREAKPOINT_OPCODE]);
+        dmg.set_test_trap(Some(TestTrap::MagicBreakpoint));
+        set_result_registers(&mut dmg, MOONEYE_FAIL);
+
+        assert!(dmg.load_instruction().is_ok());
+        let trap = dmg.take_trap_state();
+
+        assert!(trap.is_some_and(|trap| trap.is_mooneye_fail()), "Registers should report a fail");
+    }
+
+    #[test]
+    fn test_magic_breakpoint_disabled() {
+        let mut dmg = init_program(vec![MAGIC_BREAKPOINT_OPCODE]);
+        set_result_registers(&mut dmg, MOONEYE_PASS);
+
+        assert!(dmg.load_instruction().is_ok());
+        let trap = dmg.take_trap_state();
+
+        assert!(trap.is_none(), "Nothing should be trapped unless a trap is set");
+    }
+
+    #[test]
+    fn test_address_trap() {
+        let mut dmg = init_program(vec![]);
+        dmg.set_test_trap(Some(TestTrap::Address(0x0002)));
+
+        assert!(dmg.load_instruction().is_ok());
+        assert!(dmg.load_instruction().is_ok());
+        let before_trap = dmg.take_trap_state();
+        assert!(dmg.load_instruction().is_ok());
+        let trap = dmg.take_trap_state();
+
+        assert!(before_trap.is_none(), "Addresses before the trap should not be trapped");
+        assert_eq!(trap.map(|trap| trap.pc), Some(0x0002), "Trap should capture the trap address");
+    }
+
+    #[test]
+    fn test_coverage_marks_executed_bytes() {
+        let mut rom = vec![0xFF; 0x8000];
+        // NOP, LD A 0x42, JP 0x0150 in bank 0, then NOP in bank 1
+        rom[..6].copy_from_slice(&[0x00, 0x3E, 0x42, 0xC3, 0x50, 0x01]);
+        rom[0x4000] = 0x00;
+        let mut cartridge = MockCartridgeMapper::new();
+        cartridge.expect_read_rom()
+            .returning(move |address| rom.get(address as usize).copied());
+        cartridge.expect_rom_offset()
+            .returning(|address| Some(address as usize));
+        cartridge.expect_rom_size()
+            .return_const(0x8000_usize);
+        let mut dmg = GameBoySystem::new(Box::new(DmgMemoryController::new(Box::new(cartridge))));
+        dmg.enable_coverage();
+
+        for _ in 0..3 {
+            assert!(dmg.load_instruction().is_ok());
+        }
+        dmg.registers.pc = 0x4000;
+        assert!(dmg.load_instruction().is_ok());
+
+        let coverage = dmg.coverage();
+        assert!(coverage.is_some(), "Coverage should be tracked once enabled");
+        assert_eq!(
+            coverage.unwrap().code_offsets(), vec![0, 1, 2, 3, 4, 5, 0x4000],
+            "Exactly the fetched instruction bytes should be marked as code"
+        );
+    }
+
+    #[test]
+    fn test_coverage_disabled() {
+        let mut dmg = init_program(vec![]);
+
+        assert!(dmg.load_instruction().is_ok());
+
+        assert!(dmg.coverage().is_none(), "Coverage should not be tracked unless enabled");
+    }
+
+    #[test]
+    fn test_coverage_bitmap() {
+        let mut coverage = CodeCoverage::new(20);
+        coverage.mark_code(9);
+        coverage.mark_code(19);
+        coverage.mark_code(20);
+
+        assert!(coverage.is_code(9), "Marked bytes should be code");
+        assert!(!coverage.is_code(8), "Unmarked bytes should not be code");
+        assert!(!coverage.is_code(20), "Bytes outside of the ROM should never be code");
+        assert_eq!(coverage.code_offsets(), vec![9, 19]);
+    }
+
+    #[test]
+    fn test_step_callback_records_addresses() {
+        // NOP, LD A 0x42, NOP, JP 0x0150
+        let program = [0x00, 0x3E, 0x42, 0x00, 0xC3, 0x50, 0x01];
+        let mut mem = MockMemoryController::new();
+        mem.expect_load_byte()
+            .returning(move |address| program.get(address as usize).copied());
+        let mut dmg = GameBoySystem::new(Box::new(mem));
+        let steps = Rc::new(RefCell::new(Vec::new()));
+        let callback_steps = steps.clone();
+        dmg.set_step_callback(move |address, operation, system| {
+            assert!(system.registers().pc > address, "Callback should run after decoding");
+            callback_steps.borrow_mut().push((address, format!("{operation:?}")));
+        });
+
+        for _ in 0..4 {
+            assert!(dmg.load_instruction().is_ok());
+        }
+
+        let addresses: Vec<u16> = steps.borrow().iter().map(|step| step.0).collect();
+        assert_eq!(addresses, vec![0, 1, 3, 4], "Callback should see every instruction address");
+        assert_eq!(steps.borrow()[3].1, "Jump(336)", "Callback should see the decoded operation");
+    }
+
+    #[test]
+    fn test_cleared_step_callback() {
+        let mut dmg = init_program(vec![]);
+        let steps = Rc::new(RefCell::new(0));
+        let callback_steps = steps.clone();
+        dmg.set_step_callback(move |_, _, _| *callback_steps.borrow_mut() += 1);
+
+        assert!(dmg.load_instruction().is_ok());
+        dmg.clear_step_callback();
+        assert!(dmg.load_instruction().is_ok());
+
+        assert_eq!(*steps.borrow(), 1, "Callback should not run after being cleared");
+    }
+
+    #[test]
+    fn test_breakpoint_stops_before_instruction() {
+        // NOP, LD A 0x42
+        let mut dmg = init_program(vec![0x00, 0x3E, 0x42]);
+        dmg.add_breakpoint(0x0001);
+
+        let first = dmg.step();
+        let second = dmg.step();
+        let a_at_breakpoint = dmg.registers().get_register(CpuRegister::A);
+        let third = dmg.step();
+
+        assert_eq!(first.ok(), Some(StepOutcome::Completed(MCycles(1))));
+        assert_eq!(
+            second.ok(), Some(StepOutcome::Breakpoint(0x0001)),
+            "The step should report the breakpoint's address"
+        );
+        assert_eq!(a_at_breakpoint, 0, "The instruction at the breakpoint shouldn't run yet");
+        assert_eq!(
+            third.ok(), Some(StepOutcome::Completed(MCycles(2))),
+            "The next step should run the instruction instead of stopping again"
+        );
+        assert_eq!(dmg.registers().get_register(CpuRegister::A), 0x42);
+    }
+
+    #[test]
+    fn test_breakpoint_hit_every_time() {
+        // JR -2 back to itself
+        let mut dmg = init_program(vec![0x18, 0xFE]);
+        dmg.add_breakpoint(0x0000);
+
+        let outcomes: Vec<_> = (0..4).map(|_| dmg.step().ok()).collect();
+
+        assert_eq!(
+            outcomes,
+            vec![
+                Some(StepOutcome::Breakpoint(0x0000)),
+                Some(StepOutcome::Completed(MCycles(3))),
+                Some(StepOutcome::Breakpoint(0x0000)),
+                Some(StepOutcome::Completed(MCycles(3))),
+            ],
+            "A loop should stop at the breakpoint on every pass"
+        );
+
+        dmg.remove_breakpoint(0x0000);
+        assert_eq!(dmg.step().ok(), Some(StepOutcome::Completed(MCycles(3))));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        // LD HL 0xC000, LD [HL] 0x12, LD A [HL], LD [HL] 0x34, LD A [HL]
+        let mut dmg = init_program(vec![0x21, 0x00, 0xC0, 0x36, 0x12, 0x7E, 0x36, 0x34, 0x7E]);
+        dmg.add_watchpoint(0xC000, true, false);
+
+        let outcomes: Vec<_> = (0..3).map(|_| dmg.step().ok()).collect();
+        let a_after_read = dmg.registers().get_register(CpuRegister::A);
+        dmg.add_watchpoint(0xC000, false, true);
+        let replaced = dmg.step();
+        let read = dmg.step();
+
+        assert_eq!(
+            outcomes,
+            vec![
+                Some(StepOutcome::Completed(MCycles(3))),
+                Some(StepOutcome::Watchpoint { address: 0xC000, write: true, cycles: MCycles(3) }),
+                Some(StepOutcome::Completed(MCycles(2))),
+            ],
+            "Only writes should stop at a write watchpoint, once the instruction has run"
+        );
+        assert_eq!(a_after_read, 0x12, "The watched instructions should still run");
+        assert_eq!(
+            replaced.ok(), Some(StepOutcome::Completed(MCycles(3))),
+            "A read watchpoint should replace the write watchpoint"
+        );
+        assert_eq!(
+            read.ok(),
+            Some(StepOutcome::Watchpoint { address: 0xC000, write: false, cycles: MCycles(2) }),
+            "Reads should stop at a read watchpoint"
+        );
+    }
+
+    #[test]
+    fn test_trace_hook() {
+        // NOP, LD A 0x42, JP 0x0150
+        let mut dmg = init_program(vec![0x00, 0x3E, 0x42, 0xC3, 0x50, 0x01]);
+        dmg.registers.sp = 0xFFFE;
+        let entries = Rc::new(RefCell::new(Vec::new()));
+        let hook_entries = entries.clone();
+        dmg.set_trace_hook(Box::new(move |entry| hook_entries.borrow_mut().push(entry.clone())));
+
+        for _ in 0..3 {
+            assert!(dmg.step().is_ok());
+        }
+
+        let entries = entries.borrow();
+        assert_eq!(entries.len(), 3, "Every executed instruction should be traced");
+        assert_eq!(entries[1].bytes, vec![0x3E, 0x42], "The entry should hold the raw bytes");
+        assert_eq!(entries[1].operation, Operation::Load8(7, Operand8::Immediate(0x42)));
+        assert_eq!(entries[2].registers.a, 0x42, "Registers should come from before execution");
+        assert_eq!(
+            entries[1].gameboy_doctor_line(),
+            "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0001 PCMEM:3E,42,C3,50",
+            "The entry should match the Gameboy Doctor format"
+        );
+    }
+
+    #[test]
+    fn test_trace_hook_skips_breakpoint() {
+        let mut dmg = init_program(vec![0x00]);
+        dmg.add_breakpoint(0x0000);
+        let traced = Rc::new(RefCell::new(0));
+        let hook_traced = traced.clone();
+        dmg.set_trace_hook(Box::new(move |_| *hook_traced.borrow_mut() += 1));
+
+        assert_eq!(dmg.step().ok(), Some(StepOutcome::Breakpoint(0x0000)));
+        let traced_at_breakpoint = *traced.borrow();
+        dmg.clear_trace_hook();
+        assert!(dmg.step().is_ok());
+
+        assert_eq!(traced_at_breakpoint, 0, "Nothing runs when stopping at a breakpoint");
+        assert_eq!(*traced.borrow(), 0, "The hook shouldn't run after being cleared");
+    }
+}
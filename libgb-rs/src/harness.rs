@@ -0,0 +1,130 @@
+use crate::{GameBoySystem, GameBoySystemError};
+use crate::load::GameBoySystemBuilder;
+use crate::memory::cartridge::LoadCartridgeError;
+use crate::serial::VecSerialSink;
+
+// What test ROMs (like Blargg's) print over serial once they have a result
+const PASSED: &str = "Passed";
+const FAILED: &str = "Failed";
+
+/// # TestRomError
+/// The reasons a test ROM couldn't be run to a result
+#[derive(Debug)]
+pub enum TestRomError {
+    InvalidRom(LoadCartridgeError), // the ROM couldn't be loaded
+    SystemError(GameBoySystemError), // the system stopped running the ROM
+    Timeout(String), // the ROM ran out of cycles without a result, holding its serial output
+}
+
+/// Run a test ROM headlessly until it prints "Passed" or "Failed" over serial. The ROM starts at
+/// its entry point without a boot ROM.
+///
+/// Parameters:
+/// - `rom`: the full contents of the ROM, including its header
+/// - `max_cycles`: the number of M-cycles the ROM can run for before giving up
+///
+/// Returns everything the ROM printed over serial (which still has to be checked for "Passed"),
+/// or an error if the ROM couldn't run or didn't finish in time
+pub fn run_test_rom(rom: Vec<u8>, max_cycles: u64) -> Result<String, TestRomError> {
+    let sink = VecSerialSink::new();
+    let mut system = GameBoySystemBuilder::new()
+        .with_cartridge(rom)
+        .with_serial_sink(sink.clone())
+        .build()
+        .map_err(TestRomError::InvalidRom)?;
+
+    let mut cycles = 0;
+    while cycles < max_cycles {
+        cycles += system_step(&mut system)?;
+
+        let output = serial_text(&sink);
+        if output.ends_with(PASSED) || output.ends_with(FAILED) {
+            return Ok(output);
+        }
+    }
+
+    Err(TestRomError::Timeout(serial_text(&sink)))
+}
+
+/// Run a single step, returning the number of M-cycles it took
+fn system_step(system: &mut GameBoySystem) -> Result<u64, TestRomError> {
+    let outcome = system.step()
+        .map_err(TestRomError::SystemError)?;
+    // a step always takes at least 1 cycle, so a ROM can't keep the harness running forever
+    Ok(outcome.cycles().0.max(1) as u64)
+}
+
+fn serial_text(sink: &VecSerialSink) -> String {
+    String::from_utf8_lossy(&sink.bytes()).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM_SIZE: usize = 0x8000;
+    const MESSAGE_START: usize = 0x200;
+    const ENTRY_POINT: [u8; 4] = [
+        0x00, // 0x100: NOP
+        0xC3, 0x50, 0x01, // 0x101: JP 0x0150
+    ];
+    // Send every byte from the message until a 0, then loop forever
+    const PRINT_PROGRAM: [u8; 15] = [
+        0x21, 0x00, 0x02, // 0x150: LD HL, 0x0200
+        0x2A, // 0x153: LD A, [HL+]
+        0xA7, // 0x154: AND A
+        0x28, 0xFE, // 0x155: JR Z, 0x0155 (stop at the end of the message)
+        0xE0, 0x01, // 0x157: LDH [SB], A
+        0x3E, 0x81, // 0x159: LD A, 0x81
+        0xE0, 0x02, // 0x15B: LDH [SC], A (start an internally clocked transfer)
+        0x18, 0xF4, // 0x15D: JR 0x0153
+    ];
+
+    /// Create a ROM-only cartridge which prints the given message over serial
+    fn init_rom(message: &str) -> Vec<u8> {
+        let mut rom = vec![0; ROM_SIZE];
+        rom[0x100..0x100 + ENTRY_POINT.len()].copy_from_slice(&ENTRY_POINT);
+        rom[0x150..0x150 + PRINT_PROGRAM.len()].copy_from_slice(&PRINT_PROGRAM);
+        rom[MESSAGE_START..MESSAGE_START + message.len()].copy_from_slice(message.as_bytes());
+        rom
+    }
+
+    #[test]
+    fn test_passed() {
+        let result = run_test_rom(init_rom("OK\nPassed\n"), 10_000);
+
+        assert!(result.is_ok(), "The ROM should finish running");
+        // the harness stops as soon as the result is printed, so the newline is never sent
+        assert_eq!(result.unwrap(), "OK\nPassed", "Should return everything that was printed");
+    }
+
+    #[test]
+    fn test_failed() {
+        let result = run_test_rom(init_rom("OK\nFailed"), 10_000);
+
+        assert!(
+            result.is_ok_and(|output| output.ends_with("Failed")),
+            "A failed test ROM should still finish running"
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        let result = run_test_rom(init_rom("OK"), 10_000);
+
+        assert!(
+            matches!(result, Err(TestRomError::Timeout(ref output)) if output == "OK"),
+            "A ROM which never reports a result should time out with its output"
+        );
+    }
+
+    #[test]
+    fn test_invalid_rom() {
+        let result = run_test_rom(vec![0; 0x100], 10_000);
+
+        assert!(
+            matches!(result, Err(TestRomError::InvalidRom(LoadCartridgeError::RomTooShort))),
+            "A ROM without a header can't be run"
+        );
+    }
+}
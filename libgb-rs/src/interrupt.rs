@@ -0,0 +1,316 @@
+/// The address of the IF register, which holds a bit for each requested interrupt
+pub const INTERRUPT_FLAG: u16 = 0xFF0F;
+/// The address of the IE register, which holds a bit for each enabled interrupt
+pub const INTERRUPT_ENABLE: u16 = 0xFFFF;
+
+// The address each interrupt jumps to when it's handled
+pub const VBLANK_VECTOR: u16 = 0x40;
+pub const STAT_VECTOR: u16 = 0x48;
+pub const TIMER_VECTOR: u16 = 0x50;
+pub const SERIAL_VECTOR: u16 = 0x58;
+pub const JOYPAD_VECTOR: u16 = 0x60;
+
+// The bit for each interrupt in the IF and IE registers
+pub const VBLANK_MASK: u8 = 0x01;
+pub const STAT_MASK: u8 = 0x02;
+pub const TIMER_MASK: u8 = 0x04;
+pub const SERIAL_MASK: u8 = 0x08;
+pub const JOYPAD_MASK: u8 = 0x10;
+
+/// The number of machine cycles it takes to jump to an interrupt's vector
+pub const INTERRUPT_DISPATCH_CYCLES: u32 = 5;
+
+/// Every interrupt, ordered from the highest priority to the lowest
+pub const INTERRUPTS: [Interrupt; 5] = [
+    Interrupt::VBlank, Interrupt::Stat, Interrupt::Timer, Interrupt::Serial, Interrupt::Joypad
+];
+
+/// # Interrupt
+/// The sources which can interrupt the CPU, ordered from the highest priority to the lowest.
+/// Each one is represented by the bit with the same index in the IF and IE registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank = 0,
+    Stat = 1,
+    Timer = 2,
+    Serial = 3,
+    Joypad = 4,
+}
+
+impl Interrupt {
+    /// Get the bit which represents this interrupt in the IF and IE registers
+    pub const fn mask(self) -> u8 {
+        match self {
+            Interrupt::VBlank => VBLANK_MASK,
+            Interrupt::Stat => STAT_MASK,
+            Interrupt::Timer => TIMER_MASK,
+            Interrupt::Serial => SERIAL_MASK,
+            Interrupt::Joypad => JOYPAD_MASK,
+        }
+    }
+
+    /// Get the address which the CPU jumps to when handling this interrupt
+    pub const fn vector(self) -> u16 {
+        match self {
+            Interrupt::VBlank => VBLANK_VECTOR,
+            Interrupt::Stat => STAT_VECTOR,
+            Interrupt::Timer => TIMER_VECTOR,
+            Interrupt::Serial => SERIAL_VECTOR,
+            Interrupt::Joypad => JOYPAD_VECTOR,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CpuRegister;
+    use crate::cycles::MCycles;
+    use crate::debug::StepOutcome;
+    use crate::test_utils::{init_program, init_system};
+
+    use super::*;
+
+    #[test]
+    fn test_interrupt_masks() {
+        assert_eq!(Interrupt::VBlank.mask(), 0x01);
+        assert_eq!(Interrupt::Stat.mask(), 0x02);
+        assert_eq!(Interrupt::Timer.mask(), 0x04);
+        assert_eq!(Interrupt::Serial.mask(), 0x08);
+        assert_eq!(Interrupt::Joypad.mask(), 0x10);
+    }
+
+    #[test]
+    fn test_interrupt_vectors() {
+        assert_eq!(Interrupt::VBlank.vector(), 0x40);
+        assert_eq!(Interrupt::VBlank.mask(), 0x01);
+        assert_eq!(Interrupt::Joypad.vector(), 0x60);
+        assert_eq!(Interrupt::Joypad.mask(), 0x10);
+    }
+
+    #[test]
+    fn test_interrupt_table_matches_bits() {
+        for (bit, interrupt) in INTERRUPTS.iter().enumerate() {
+            assert_eq!(interrupt.mask(), 1 << bit, "Interrupts should be ordered by their bits");
+            assert_eq!(
+                interrupt.vector(), 0x40 + 8 * bit as u16,
+                "Each interrupt's vector should be 8 bytes after the previous one"
+            );
+        }
+    }
+
+    #[test]
+    fn test_request_interrupt() {
+        let mut dmg = init_system();
+
+        assert!(dmg.request_interrupt(Interrupt::Serial).is_ok());
+        assert!(dmg.request_interrupt(Interrupt::Timer).is_ok());
+
+        assert_eq!(
+            dmg.memory().load_byte(INTERRUPT_FLAG), Some(0x0C),
+            "Requesting interrupts should set their IF bits without clearing others"
+        );
+    }
+
+    #[test]
+    fn test_set_interrupt_enable() {
+        let mut dmg = init_system();
+
+        assert!(dmg.set_interrupt_enable(Interrupt::Serial.mask()).is_ok());
+
+        assert_eq!(
+            dmg.memory().load_byte(INTERRUPT_ENABLE), Some(0x08),
+            "The mask should be written into IE"
+        );
+    }
+
+    #[test]
+    fn test_interrupt_dispatch() {
+        // EI, NOP, NOP
+        let mut dmg = init_program(vec![0xFB, 0x00, 0x00]);
+        assert!(dmg.set_interrupt_enable(Interrupt::VBlank.mask()).is_ok());
+        assert!(dmg.request_interrupt(Interrupt::VBlank).is_ok());
+
+        assert!(dmg.step().is_ok());
+        let ime_after_ei = dmg.interrupts_enabled();
+        assert!(dmg.step().is_ok());
+        let pc_after_delay = dmg.registers.pc;
+        let result = dmg.step();
+
+        assert!(!ime_after_ei, "EI shouldn't set IME until after the next instruction");
+        assert_eq!(pc_after_delay, 2, "The instruction after EI should run before the interrupt");
+        assert!(
+            matches!(result, Ok(StepOutcome::Completed(MCycles(5)))),
+            "Handling an interrupt should take 5 cycles"
+        );
+        assert_eq!(dmg.registers.pc, VBLANK_VECTOR, "Should jump to the VBlank vector");
+        assert_eq!(dmg.memory().load_half_word(0xFFFC), Some(2), "Should push the old pc");
+        assert_eq!(dmg.memory().load_byte(INTERRUPT_FLAG), Some(0), "Should clear the IF bit");
+        assert!(!dmg.interrupts_enabled(), "Handling an interrupt should clear IME");
+    }
+
+    #[test]
+    fn test_serial_interrupt_dispatch() {
+        let mut dmg = init_program(vec![]);
+        dmg.ime = true;
+        assert!(dmg.set_interrupt_enable(Interrupt::Serial.mask()).is_ok());
+        assert!(dmg.request_interrupt(Interrupt::Serial).is_ok());
+
+        let result = dmg.step();
+
+        assert!(
+            matches!(result, Ok(StepOutcome::Completed(MCycles(5)))),
+            "Handling an interrupt should take 5 cycles"
+        );
+        assert_eq!(dmg.registers.pc, SERIAL_VECTOR, "Should jump to the serial vector");
+        assert_eq!(dmg.memory().load_byte(INTERRUPT_FLAG), Some(0), "Should clear the IF bit");
+    }
+
+    #[test]
+    fn test_interrupt_priority() {
+        let mut dmg = init_program(vec![]);
+        dmg.ime = true;
+        assert!(dmg.set_interrupt_enable(0x1F).is_ok());
+        assert!(dmg.request_interrupt(Interrupt::Joypad).is_ok());
+        assert!(dmg.request_interrupt(Interrupt::Stat).is_ok());
+
+        assert!(matches!(dmg.handle_interrupts(), Ok(Some(_))));
+
+        assert_eq!(dmg.registers.pc, STAT_VECTOR, "STAT should be handled before Joypad");
+        assert_eq!(
+            dmg.memory().load_byte(INTERRUPT_FLAG), Some(JOYPAD_MASK),
+            "Joypad should still be requested"
+        );
+    }
+
+    #[test]
+    fn test_interrupt_not_enabled() {
+        let mut dmg = init_program(vec![]);
+        dmg.ime = true;
+        assert!(dmg.set_interrupt_enable(Interrupt::Timer.mask()).is_ok());
+        assert!(dmg.request_interrupt(Interrupt::Serial).is_ok());
+
+        let result = dmg.handle_interrupts();
+
+        assert!(matches!(result, Ok(None)), "Interrupts which aren't in IE should be ignored");
+        assert_eq!(dmg.registers.pc, 0, "Nothing should be dispatched");
+    }
+
+    #[test]
+    fn test_interrupt_without_ime() {
+        let mut dmg = init_program(vec![]);
+        assert!(dmg.set_interrupt_enable(Interrupt::Timer.mask()).is_ok());
+        assert!(dmg.request_interrupt(Interrupt::Timer).is_ok());
+
+        let result = dmg.handle_interrupts();
+
+        assert!(matches!(result, Ok(None)), "Nothing should be handled while IME is clear");
+    }
+
+    #[test]
+    fn test_di_cancels_ei() {
+        // EI, DI, NOP
+        let mut dmg = init_program(vec![0xFB, 0xF3, 0x00]);
+
+        for _ in 0..3 {
+            assert!(dmg.step().is_ok());
+        }
+
+        assert!(!dmg.interrupts_enabled(), "DI right after EI should keep interrupts disabled");
+    }
+
+    #[test]
+    fn test_reti_enables_interrupts() {
+        // RETI
+        let mut dmg = init_program(vec![0xD9]);
+        dmg.registers.sp = 0xFFFC;
+        assert!(dmg.memory.store_half_word(0xFFFC, 0x0150).is_ok());
+
+        assert!(dmg.step().is_ok());
+
+        assert_eq!(dmg.registers.pc, 0x0150, "RETI should return");
+        assert!(dmg.interrupts_enabled(), "RETI should set IME right away");
+    }
+
+    #[test]
+    fn test_halt_waits_for_interrupt() {
+        // HALT, LD A 0x42
+        let mut dmg = init_program(vec![0x76, 0x3E, 0x42]);
+        assert!(dmg.set_interrupt_enable(Interrupt::Timer.mask()).is_ok());
+
+        assert!(dmg.step().is_ok());
+        let waiting = dmg.step();
+        let halted_before_request = dmg.halted();
+        assert!(dmg.request_interrupt(Interrupt::Timer).is_ok());
+        assert!(dmg.step().is_ok());
+
+        assert_eq!(
+            waiting.ok(), Some(StepOutcome::Completed(MCycles(1))),
+            "A halted CPU should wait a cycle at a time"
+        );
+        assert!(halted_before_request, "HALT should wait until an interrupt is requested");
+        assert!(!dmg.halted(), "Requesting an interrupt should wake the CPU up");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::A), 0x42,
+            "With IME off, the CPU should carry on after the HALT"
+        );
+        assert_eq!(
+            dmg.memory().load_byte(INTERRUPT_FLAG), Some(Interrupt::Timer.mask()),
+            "The interrupt shouldn't be handled with IME off"
+        );
+    }
+
+    #[test]
+    fn test_halt_with_ime_handles_interrupt() {
+        // EI, HALT, NOP
+        let mut dmg = init_program(vec![0xFB, 0x76, 0x00]);
+        assert!(dmg.set_interrupt_enable(Interrupt::VBlank.mask()).is_ok());
+
+        assert!(dmg.step().is_ok());
+        assert!(dmg.step().is_ok());
+        assert!(dmg.step().is_ok());
+        assert!(dmg.request_interrupt(Interrupt::VBlank).is_ok());
+        let result = dmg.step();
+
+        assert!(matches!(result, Ok(StepOutcome::Completed(MCycles(5)))));
+        assert!(!dmg.halted(), "Handling the interrupt should wake the CPU up");
+        assert_eq!(dmg.registers.pc, VBLANK_VECTOR, "Should jump to the VBlank vector");
+        assert_eq!(dmg.memory().load_half_word(0xFFFC), Some(2), "Should return after the HALT");
+    }
+
+    #[test]
+    fn test_halt_bug() {
+        // HALT, LD B 0x42
+        let mut dmg = init_program(vec![0x76, 0x06, 0x42]);
+        assert!(dmg.set_interrupt_enable(Interrupt::Timer.mask()).is_ok());
+        assert!(dmg.request_interrupt(Interrupt::Timer).is_ok());
+
+        assert!(dmg.step().is_ok());
+        let halted = dmg.halted();
+        assert!(dmg.step().is_ok());
+
+        assert!(!halted, "HALT shouldn't stop with IME off and an interrupt pending");
+        assert_eq!(
+            dmg.registers.get_register(CpuRegister::B), 0x06,
+            "The opcode after HALT should be read again as its own operand"
+        );
+        assert_eq!(dmg.registers.pc, 0x0002, "The pc should only move past one of the reads");
+    }
+
+    #[test]
+    fn test_halt_bug_after_ei() {
+        // EI, HALT
+        let mut dmg = init_program(vec![0xFB, 0x76]);
+        assert!(dmg.set_interrupt_enable(Interrupt::Timer.mask()).is_ok());
+        assert!(dmg.request_interrupt(Interrupt::Timer).is_ok());
+
+        assert!(dmg.step().is_ok());
+        assert!(dmg.step().is_ok());
+        assert!(dmg.step().is_ok());
+
+        assert_eq!(dmg.registers.pc, TIMER_VECTOR, "The interrupt should be handled after HALT");
+        assert_eq!(
+            dmg.memory().load_half_word(0xFFFC), Some(1),
+            "The interrupt should return to the HALT, which runs again"
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use crate::cycles::TCycles;
+use crate::interrupt::JOYPAD_MASK;
+use crate::memory::MemoryWriteError;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+// Joypad register address
+pub const P1: u16 = 0xFF00;
+
+// P1 bit masks. The select bits are active-low, so writing 0x10 selects the action buttons.
+const P1_SELECT_DIRECTIONS: u8 = 0x10;
+const P1_SELECT_ACTIONS: u8 = 0x20;
+const P1_SELECT: u8 = P1_SELECT_DIRECTIONS | P1_SELECT_ACTIONS;
+pub const P1_BUTTONS: u8 = 0x0F;
+// Bits 6 and 7 of P1 don't exist, so they always read as 1
+const P1_UNUSED: u8 = 0xC0;
+
+/// # Button
+/// One of the 8 buttons on a Game Boy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+// Every button, in the same order as their bits
+pub const BUTTONS: [Button; 8] = [
+    Button::Right, Button::Left, Button::Up, Button::Down,
+    Button::A, Button::B, Button::Select, Button::Start,
+];
+
+impl Button {
+    /// Get the bit which holds this button's state. The directions are in the low nibble and
+    /// the action buttons are in the high nibble, each in the same order as they appear in P1.
+    const fn mask(self) -> u8 {
+        match self {
+            Button::Right => 0x01,
+            Button::Left => 0x02,
+            Button::Up => 0x04,
+            Button::Down => 0x08,
+            Button::A => 0x10,
+            Button::B => 0x20,
+            Button::Select => 0x40,
+            Button::Start => 0x80,
+        }
+    }
+}
+
+/// # JoypadState
+/// Which buttons are held down, for setting all of them at once (like once per frame from a
+/// front-end's input)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct JoypadState {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+impl JoypadState {
+    /// Check whether the given button is held down
+    pub fn is_pressed(&self, button: Button) -> bool {
+        match button {
+            Button::Right => self.right,
+            Button::Left => self.left,
+            Button::Up => self.up,
+            Button::Down => self.down,
+            Button::A => self.a,
+            Button::B => self.b,
+            Button::Select => self.select,
+            Button::Start => self.start,
+        }
+    }
+}
+
+/// # Joypad
+/// The buttons of a Game Boy system, which are read through the P1 register. The program picks
+/// which group of buttons (directions or actions) to read with bits 4 and 5, and the state of
+/// those buttons shows up in the low nibble, where 0 means the button is pressed.
+///
+/// A joypad interrupt is requested whenever one of the visible button bits goes from 1 to 0.
+pub struct Joypad {
+    pressed: u8, // a mask of Button bits for every button being held down
+    select: u8, // the select bits (4 and 5) of P1, exactly as they were written
+    pending_interrupts: u8, // interrupts requested by a button press, reported on the next tick
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            pressed: 0,
+            // neither group is selected at power-up
+            select: P1_SELECT,
+            pending_interrupts: 0,
+        }
+    }
+
+    /// Press or release one of the buttons
+    ///
+    /// Parameters:
+    /// - `button`: the button to change
+    /// - `pressed`: whether the button is being held down
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let old_value = self.read_p1();
+        if pressed {
+            self.pressed |= button.mask();
+        } else {
+            self.pressed &= !button.mask();
+        }
+        self.check_interrupt(old_value);
+    }
+
+    /// Advance the joypad by the given number of T-cycles
+    ///
+    /// Returns the interrupts requested since the last tick, as a mask of bits in the IF register
+    pub fn tick(&mut self, _cycles: TCycles) -> u8 {
+        std::mem::take(&mut self.pending_interrupts)
+    }
+
+    /// Read one of the joypad registers
+    ///
+    /// Parameters:
+    /// - `address`: the address of the register in memory (0xFF00 for P1)
+    ///
+    /// Returns the value of the register, or None if the address isn't a joypad register
+    pub fn read_register(&self, address: u16) -> Option<u8> {
+        match address {
+            P1 => Some(self.read_p1()),
+            _ => None
+        }
+    }
+
+    /// Write to one of the joypad registers. Only the select bits of P1 can be written, and they
+    /// keep selecting the same group of buttons until they are written again.
+    ///
+    /// Parameters:
+    /// - `address`: the address of the register in memory (0xFF00 for P1)
+    /// - `data`: the value to write into the register
+    ///
+    /// Returns the previous value of the register, or a MemoryWriteError if the address isn't a
+    /// joypad register
+    pub fn write_register(&mut self, address: u16, data: u8) -> Result<u8, MemoryWriteError> {
+        if address != P1 {
+            return Err(MemoryWriteError::OutOfBounds(address));
+        }
+
+        let old_value = self.read_p1();
+        self.select = data & P1_SELECT;
+        // selecting a group with a button already held down also counts as a press
+        self.check_interrupt(old_value);
+
+        Ok(old_value)
+    }
+
+    /// Write the state of the joypad into a snapshot, including the buttons being held down
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_u8(self.pressed);
+        writer.write_u8(self.select);
+        writer.write_u8(self.pending_interrupts);
+    }
+
+    /// Restore the state written by `save_state`
+    pub fn load_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.pressed = reader.read_u8()?;
+        self.select = reader.read_u8()? & P1_SELECT;
+        self.pending_interrupts = reader.read_u8()?;
+        Ok(())
+    }
+
+    fn read_p1(&self) -> u8 {
+        let mut pressed = 0;
+        if self.select & P1_SELECT_DIRECTIONS == 0 {
+            pressed |= self.pressed & P1_BUTTONS;
+        }
+        if self.select & P1_SELECT_ACTIONS == 0 {
+            pressed |= self.pressed >> 4;
+        }
+
+        P1_UNUSED | self.select | (!pressed & P1_BUTTONS)
+    }
+
+    /// Request a joypad interrupt if any of the button bits in P1 went from 1 to 0
+    fn check_interrupt(&mut self, old_value: u8) {
+        let falling_edges = old_value & !self.read_p1() & P1_BUTTONS;
+        if falling_edges != 0 {
+            self.pending_interrupts |= JOYPAD_MASK;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nothing_selected() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::A, true);
+        joypad.set_button(Button::Up, true);
+
+        assert_eq!(joypad.read_register(P1), Some(0xFF), "No buttons should be visible");
+        assert_eq!(joypad.tick(TCycles(4)), 0, "Hidden buttons shouldn't request an interrupt");
+    }
+
+    #[test]
+    fn test_direction_buttons() {
+        let mut joypad = Joypad::new();
+        assert!(joypad.write_register(P1, 0x20).is_ok(), "Should be able to select directions");
+
+        joypad.set_button(Button::Left, true);
+        joypad.set_button(Button::Down, true);
+        joypad.set_button(Button::Start, true);
+
+        assert_eq!(
+            joypad.read_register(P1), Some(0xE5),
+            "Pressed directions should read as 0, and action buttons should be hidden"
+        );
+    }
+
+    #[test]
+    fn test_action_buttons() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::B, true);
+        joypad.set_button(Button::Right, true);
+
+        assert!(joypad.write_register(P1, 0x10).is_ok(), "Should be able to select actions");
+
+        assert_eq!(
+            joypad.read_register(P1), Some(0xDD),
+            "The action row should stay selected, with pressed buttons reading as 0"
+        );
+        assert_eq!(joypad.read_register(P1), Some(0xDD), "The selection should persist");
+    }
+
+    #[test]
+    fn test_both_groups_selected() {
+        let mut joypad = Joypad::new();
+        assert!(joypad.write_register(P1, 0x00).is_ok(), "Should be able to select both");
+
+        joypad.set_button(Button::A, true);
+        joypad.set_button(Button::Down, true);
+
+        assert_eq!(joypad.read_register(P1), Some(0xC6), "Both groups should be combined");
+    }
+
+    #[test]
+    fn test_release_button() {
+        let mut joypad = Joypad::new();
+        assert!(joypad.write_register(P1, 0x10).is_ok(), "Should be able to select actions");
+
+        joypad.set_button(Button::Select, true);
+        joypad.set_button(Button::Select, false);
+
+        assert_eq!(joypad.read_register(P1), Some(0xDF), "Released buttons should read as 1");
+    }
+
+    #[test]
+    fn test_press_requests_interrupt() {
+        let mut joypad = Joypad::new();
+        assert!(joypad.write_register(P1, 0x10).is_ok(), "Should be able to select actions");
+
+        joypad.set_button(Button::Start, true);
+
+        assert_eq!(joypad.tick(TCycles(4)), JOYPAD_MASK, "Pressing should request an interrupt");
+        assert_eq!(joypad.tick(TCycles(4)), 0, "The interrupt should only be requested once");
+
+        joypad.set_button(Button::Start, false);
+        assert_eq!(joypad.tick(TCycles(4)), 0, "Releasing shouldn't request an interrupt");
+    }
+
+    #[test]
+    fn test_selecting_held_button_requests_interrupt() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::Up, true);
+
+        assert!(joypad.write_register(P1, 0x20).is_ok(), "Should be able to select directions");
+
+        assert_eq!(
+            joypad.tick(TCycles(4)), JOYPAD_MASK,
+            "Showing a held button should request an interrupt"
+        );
+    }
+}
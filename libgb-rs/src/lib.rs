@@ -1,96 +1,802 @@
+use std::collections::{HashMap, HashSet};
+
 pub mod cpu;
+pub mod cycles;
+pub mod debug;
+#[cfg(any(test, feature = "test-harness"))]
+pub mod harness;
+pub mod interrupt;
+pub mod joypad;
+pub mod load;
 pub mod memory;
+pub mod ppu;
+pub mod rewind;
+pub mod serial;
+pub mod snapshot;
+pub mod timer;
+#[cfg(test)]
+mod test_utils;
 mod utils;
 
-use cpu::{CpuData, CpuRegister};
-use memory::MemoryController;
+use cpu::{r8_to_register, CpuData, CpuRegister, FlagRegister};
+use cpu::instructions::{MemoryAddress, Operand8, Operation};
+use debug::{
+    CodeCoverage, StepCallback, TestTrap, TraceEntry, TraceHook, TrapState, Watchpoint,
+    MAGIC_BREAKPOINT_OPCODE
+};
+use cycles::{m_to_t, MCycles};
+use interrupt::{Interrupt, INTERRUPTS, INTERRUPT_DISPATCH_CYCLES, INTERRUPT_ENABLE, INTERRUPT_FLAG};
+use joypad::Button;
+use memory::{MemoryController, MemoryWriteError};
+use memory::cartridge::RomInfo;
+use ppu::{DOTS_PER_FRAME, OAM_SIZE};
+use serial::SerialSink;
+use snapshot::{SnapshotError, SnapshotReader, SnapshotWriter, SNAPSHOT_MAGIC, SNAPSHOT_VERSION};
+use utils::{Merge, Split};
 
 #[derive(Debug)]
 pub enum GameBoySystemError {
     MemoryReadError(u16), // the address at which a read was attempted
-    MemoryWriteError(u16, u16), // The address at which a write was attempted, and the write value
-    InvalidInstructionError(u8) // The invalid binary instruction
+    MemoryWriteError(MemoryWriteError, u8), // Why a write failed (with its address), and the value
+    InvalidInstructionError(u8), // The invalid binary instruction
+    UnimplementedOperation(Operation) // An operation which can be decoded but not executed yet
 }
 
 pub struct GameBoySystem {
     registers: CpuData,
     memory: Box<dyn MemoryController>,
-    // PPU will also need to go here eventually
+    ime: bool, // the interrupt master enable flag, which allows interrupts to be handled
+    ime_pending: bool, // whether EI was just run, since it only sets IME after the next instruction
+    double_speed: bool, // whether a CGB has switched the CPU to double speed mode with STOP
+    halted: bool, // whether HALT is waiting for an interrupt to be requested
+    halt_bug: bool, // whether the next opcode fetch fails to move the pc past the opcode
+    stopped: bool, // whether STOP has stopped the clock until a button is pressed
+    frame_dots: u32, // dots since `run_frame` last ended a frame, for when the LCD is off
+    ticked_cycles: u8, // M-cycles of the running instruction that memory has been ticked for
+    panic_free_decode: bool,
+    test_trap: Option<TestTrap>,
+    trap_state: Option<TrapState>,
+    step_callback: Option<StepCallback>,
+    trace_hook: Option<TraceHook>,
+    coverage: Option<CodeCoverage>,
+    rom_info: Option<RomInfo>, // the header of the loaded ROM, when created with `load`
+    breakpoints: HashSet<u16>,
+    watchpoints: HashMap<u16, Watchpoint>,
+    resume_from: Option<u16>, // the breakpoint the last step stopped at, which the next one runs
+    watch_hit: Option<(u16, bool)>, // the first watched address (and whether it was a write)
 }
 
 impl GameBoySystem {
     pub fn new(memory: Box<dyn MemoryController>) -> Self {
         Self {
             registers: CpuData::new(),
-            memory
+            memory,
+            ime: false,
+            ime_pending: false,
+            double_speed: false,
+            halted: false,
+            halt_bug: false,
+            stopped: false,
+            frame_dots: 0,
+            ticked_cycles: 0,
+            panic_free_decode: false,
+            test_trap: None,
+            trap_state: None,
+            step_callback: None,
+            trace_hook: None,
+            coverage: None,
+            rom_info: None,
+            breakpoints: HashSet::new(),
+            watchpoints: HashMap::new(),
+            resume_from: None,
+            watch_hit: None
+        }
+    }
+
+    /// Get the current state of the CPU registers
+    pub fn registers(&self) -> &CpuData {
+        &self.registers
+    }
+
+    /// Get the value of one of the 8-bit CPU registers
+    pub fn register(&self, register: CpuRegister) -> u8 {
+        self.registers.get_register(register)
+    }
+
+    /// Change the value of one of the 8-bit CPU registers. The lower 4 bits of F don't exist, so
+    /// they always stay 0.
+    pub fn set_register(&mut self, register: CpuRegister, value: u8) {
+        let value = match register {
+            CpuRegister::F => value & 0xF0,
+            _ => value
+        };
+        self.registers.set_register(register, value);
+    }
+
+    /// Get the 16-bit value of two 8-bit registers joined together (like BC or HL)
+    ///
+    /// Parameters:
+    /// - `high`: the register holding the upper 8 bits
+    /// - `low`: the register holding the lower 8 bits
+    pub fn register_pair(&self, high: CpuRegister, low: CpuRegister) -> u16 {
+        self.registers.get_joined_registers(high, low)
+    }
+
+    /// Change the value of two 8-bit registers joined together (like BC or HL), where `high`
+    /// holds the upper 8 bits and `low` holds the lower 8 bits
+    pub fn set_register_pair(&mut self, high: CpuRegister, low: CpuRegister, value: u16) {
+        let (high_value, low_value) = value.split();
+        self.set_register(high, high_value);
+        self.set_register(low, low_value);
+    }
+
+    /// Get the program counter, which is the address of the next instruction
+    pub fn pc(&self) -> u16 {
+        self.registers.pc
+    }
+
+    /// Move the program counter, so the next step runs the instruction at the given address
+    pub fn set_pc(&mut self, address: u16) {
+        self.registers.pc = address;
+    }
+
+    /// Get the stack pointer
+    pub fn sp(&self) -> u16 {
+        self.registers.sp
+    }
+
+    /// Change the stack pointer
+    pub fn set_sp(&mut self, address: u16) {
+        self.registers.sp = address;
+    }
+
+    /// Get the flags stored in the upper 4 bits of the F register
+    pub fn flags(&self) -> FlagRegister {
+        self.registers.get_register(CpuRegister::F).into()
+    }
+
+    /// Change the flags stored in the upper 4 bits of the F register
+    pub fn set_flags(&mut self, flags: FlagRegister) {
+        self.registers.set_register(CpuRegister::F, flags.into());
+    }
+
+    /// Whether the CPU has been stopped by HALT until an interrupt is requested
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Whether STOP has stopped the CPU (along with the timer and PPU) until a button is pressed
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Whether the CPU is running in CGB double speed mode, where it runs twice as many
+    /// M-cycles in the time the PPU draws a frame
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    /// Get the memory attached to this system
+    pub fn memory(&self) -> &dyn MemoryController {
+        self.memory.as_ref()
+    }
+
+    /// Save the full state of the system (the CPU, memory, and all of the hardware attached to
+    /// it) so that it can be restored later with `restore`. Debugging features like traps,
+    /// callbacks and coverage aren't part of the state.
+    ///
+    /// Returns the state as bytes, starting with a header which holds the format version
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new();
+        SNAPSHOT_MAGIC.into_iter()
+            .for_each(|byte| writer.write_u8(byte));
+        writer.write_u16(SNAPSHOT_VERSION);
+        self.registers.save_state(&mut writer);
+        writer.write_bool(self.ime);
+        writer.write_bool(self.ime_pending);
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.halted);
+        writer.write_bool(self.halt_bug);
+        writer.write_bool(self.stopped);
+        writer.write_u32(self.frame_dots);
+        writer.write_bytes(&self.memory.save_state());
+        writer.finish()
+    }
+
+    /// Restore a state saved by `snapshot`, which has to come from a system running the same
+    /// cartridge. If an error is returned, the system may have been partly restored and
+    /// should be restored from another snapshot or reset before it's run.
+    ///
+    /// Parameters:
+    /// - `data`: the bytes returned by `snapshot`
+    ///
+    /// Returns a SnapshotError if the data isn't a snapshot from this version of the format, or
+    /// doesn't fit this system
+    pub fn restore(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = SnapshotReader::new(data);
+        for byte in SNAPSHOT_MAGIC {
+            if reader.read_u8().ok() != Some(byte) {
+                return Err(SnapshotError::InvalidHeader);
+            }
+        }
+        let version = reader.read_u16()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        self.registers.load_state(&mut reader)?;
+        self.ime = reader.read_bool()?;
+        self.ime_pending = reader.read_bool()?;
+        self.double_speed = reader.read_bool()?;
+        self.halted = reader.read_bool()?;
+        self.halt_bug = reader.read_bool()?;
+        self.stopped = reader.read_bool()?;
+        self.frame_dots = reader.read_u32()?;
+        if self.frame_dots >= DOTS_PER_FRAME {
+            return Err(SnapshotError::InvalidValue);
+        }
+        self.memory.load_state(reader.read_bytes()?)?;
+        reader.finish()
+    }
+
+    /// Save the full state of the system, the same as `snapshot`. The ROM itself isn't included,
+    /// so the state can only be loaded into a system running the same cartridge.
+    pub fn save_state(&self) -> Vec<u8> {
+        self.snapshot()
+    }
+
+    /// Load a state saved by `save_state`, the same as `restore`
+    ///
+    /// Parameters:
+    /// - `data`: the bytes returned by `save_state`
+    ///
+    /// Returns a SnapshotError if the state can't be loaded (see `restore`)
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SnapshotError> {
+        self.restore(data)
+    }
+
+    /// Set a callback which runs at every instruction boundary, before the instruction is
+    /// executed. It receives the address of the instruction, the decoded operation, and the
+    /// system itself for reading registers or memory.
+    ///
+    /// When no callback is set, nothing extra happens per instruction.
+    pub fn set_step_callback(
+        &mut self, callback: impl FnMut(u16, &Operation, &GameBoySystem) + 'static
+    ) {
+        self.step_callback = Some(Box::new(callback));
+    }
+
+    /// Remove the callback set by `set_step_callback`
+    pub fn clear_step_callback(&mut self) {
+        self.step_callback = None;
+    }
+
+    fn run_step_callback(&mut self, address: u16, operation: &Operation) {
+        // the callback has to be taken out while running so it can borrow the whole system
+        if let Some(mut callback) = self.step_callback.take() {
+            callback(address, operation, self);
+            self.step_callback = Some(callback);
+        }
+    }
+
+    /// Set a hook which `step` runs with every instruction right before executing it, for logging
+    /// a trace of the program. Interrupt dispatches aren't instructions, so they aren't traced.
+    pub fn set_trace_hook(&mut self, hook: TraceHook) {
+        self.trace_hook = Some(hook);
+    }
+
+    /// Remove the hook set by `set_trace_hook`
+    pub fn clear_trace_hook(&mut self) {
+        self.trace_hook = None;
+    }
+
+    /// Run the trace hook for an instruction which has been decoded but not executed yet
+    ///
+    /// Parameters:
+    /// - `address`: the address of the instruction, since the pc has already moved past it
+    /// - `operation`: the decoded operation
+    fn run_trace_hook(&mut self, address: u16, operation: &Operation) {
+        if self.trace_hook.is_none() {
+            return;
+        }
+        let length = self.registers.pc.wrapping_sub(address);
+        let mut memory = [0; 4];
+        memory.copy_from_slice(&self.memory.load_slice(address, 4));
+        let entry = TraceEntry {
+            registers: TrapState::new(&self.registers, address),
+            bytes: self.memory.load_slice(address, length),
+            operation: operation.clone(),
+            memory,
+        };
+
+        if let Some(hook) = self.trace_hook.as_mut() {
+            hook(&entry);
+        }
+    }
+
+    /// Start tracking which bytes of the cartridge ROM are fetched as instructions, clearing
+    /// anything which was already tracked
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(CodeCoverage::new(self.memory.rom_size()));
+    }
+
+    /// Get the ROM coverage tracked since `enable_coverage` was called, if it is enabled
+    pub fn coverage(&self) -> Option<&CodeCoverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Mark the given address as code if coverage is being tracked and it maps to the ROM
+    fn mark_code(&mut self, address: u16) {
+        if let Some(coverage) = self.coverage.as_mut() {
+            if let Some(offset) = self.memory.rom_offset(address) {
+                coverage.mark_code(offset);
+            }
+        }
+    }
+
+    /// Request an interrupt by setting its bit in the IF register, the same way the hardware
+    /// that raises it would. This allows interrupts to be tested without driving the component
+    /// which would normally request them.
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) -> Result<(), GameBoySystemError> {
+        let flags = self.memory.load_byte(INTERRUPT_FLAG)
+            .ok_or(GameBoySystemError::MemoryReadError(INTERRUPT_FLAG))?;
+        let flags = flags | interrupt.mask();
+        self.memory.store_byte(INTERRUPT_FLAG, flags)
+            .map_err(|err| GameBoySystemError::MemoryWriteError(err, flags))?;
+
+        Ok(())
+    }
+
+    /// Whether the CPU will currently handle interrupts (the IME flag)
+    pub fn interrupts_enabled(&self) -> bool {
+        self.ime
+    }
+
+    /// Handle the highest priority interrupt which is both requested (in IF) and enabled (in IE),
+    /// if IME is set. Handling an interrupt clears IME and the interrupt's IF bit, pushes the
+    /// program counter onto the stack, and jumps to the interrupt's vector.
+    ///
+    /// Returns the number of machine cycles spent handling the interrupt, or None if nothing was
+    /// handled
+    pub fn handle_interrupts(&mut self) -> Result<Option<MCycles>, GameBoySystemError> {
+        if !self.ime {
+            return Ok(None);
+        }
+
+        let flags = self.memory.load_byte(INTERRUPT_FLAG)
+            .ok_or(GameBoySystemError::MemoryReadError(INTERRUPT_FLAG))?;
+        let pending = self.pending_interrupts()?;
+        let Some(interrupt) = INTERRUPTS.into_iter()
+            .find(|interrupt| pending & interrupt.mask() != 0)
+        else {
+            return Ok(None);
+        };
+
+        self.ime = false;
+        self.halted = false;
+        let flags = flags & !interrupt.mask();
+        self.memory.store_byte(INTERRUPT_FLAG, flags)
+            .map_err(|err| GameBoySystemError::MemoryWriteError(err, flags))?;
+        // with the HALT bug (after EI, HALT), the byte after HALT hasn't been read yet, but the
+        // pc still fails to move past it, so the interrupt returns to the HALT instead
+        let return_address = if self.halt_bug {
+            self.halt_bug = false;
+            self.registers.pc.wrapping_sub(1)
+        } else {
+            self.registers.pc
+        };
+        // the dispatch waits for 2 cycles, then writes the pc on the 3rd and 4th
+        self.push_stack(return_address, 3)?;
+        self.registers.pc = interrupt.vector();
+
+        Ok(Some(MCycles(INTERRUPT_DISPATCH_CYCLES)))
+    }
+
+    /// Get the interrupts which are both requested (in IF) and enabled (in IE), whether or not
+    /// IME is set
+    fn pending_interrupts(&self) -> Result<u8, GameBoySystemError> {
+        let flags = self.memory.load_byte(INTERRUPT_FLAG)
+            .ok_or(GameBoySystemError::MemoryReadError(INTERRUPT_FLAG))?;
+        let enabled = self.memory.load_byte(INTERRUPT_ENABLE)
+            .ok_or(GameBoySystemError::MemoryReadError(INTERRUPT_ENABLE))?;
+
+        Ok(flags & enabled)
+    }
+
+    /// Set which interrupts are enabled by writing the given mask into the IE register
+    pub fn set_interrupt_enable(&mut self, mask: u8) -> Result<(), GameBoySystemError> {
+        self.memory.store_byte(INTERRUPT_ENABLE, mask)
+            .map_err(|err| GameBoySystemError::MemoryWriteError(err, mask))?;
+
+        Ok(())
+    }
+
+    /// Connect something to the link port, which receives every byte the program sends over it
+    pub fn set_serial_sink(&mut self, sink: impl SerialSink + 'static) {
+        self.memory.set_serial_sink(Box::new(sink));
+    }
+
+    /// Press or release one of the buttons on the joypad. Pressing a button which the program is
+    /// currently reading requests a joypad interrupt.
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.memory.set_button(button, pressed);
+    }
+
+    /// Get the last frame drawn by the PPU, as one shade (0-3, from lightest to darkest) per pixel
+    /// in rows of 160 pixels from top to bottom
+    pub fn framebuffer(&self) -> &[u8] {
+        self.memory.framebuffer()
+    }
+
+    /// Copy bytes straight into VRAM, so that tiles and tile maps can be set up without running
+    /// any code (see `MemoryController::load_vram`)
+    pub fn load_vram(&mut self, offset: u16, data: &[u8]) -> Result<(), MemoryWriteError> {
+        self.memory.load_vram(offset, data)
+    }
+
+    /// Replace the whole of OAM, so that sprites can be set up without running any code
+    pub fn load_oam(&mut self, data: &[u8; OAM_SIZE]) {
+        self.memory.load_oam(data);
+    }
+
+    /// Get the last frame drawn by the PPU as RGBA8888 pixels, with each shade mapped through the
+    /// given palette of 0xRRGGBBAA colors (like `ppu::DMG_GREEN_PALETTE`)
+    pub fn framebuffer_rgba(&self, palette: &[u32; 4]) -> Vec<u8> {
+        ppu::shades_to_rgba(self.memory.framebuffer(), palette)
+    }
+
+    /// Set a condition which captures the CPU registers when it is met, or None to stop trapping.
+    /// This can be used by a test harness to detect when a test ROM has finished.
+    pub fn set_test_trap(&mut self, trap: Option<TestTrap>) {
+        self.test_trap = trap;
+    }
+
+    /// Take the registers captured by the most recent trap, if one has been hit since the last
+    /// time this was called
+    pub fn take_trap_state(&mut self) -> Option<TrapState> {
+        self.trap_state.take()
+    }
+
+    /// Stop `step` before it runs the instruction at the given address
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    /// Remove the breakpoint at the given address, if there is one
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.breakpoints.remove(&address);
+    }
+
+    /// Stop `step` after an instruction accesses the given address. Adding a watchpoint to an
+    /// address which already has one replaces it.
+    ///
+    /// Parameters:
+    /// - `address`: the address to watch
+    /// - `on_write`: whether writing to the address should stop
+    /// - `on_read`: whether reading from the address should stop
+    pub fn add_watchpoint(&mut self, address: u16, on_write: bool, on_read: bool) {
+        self.watchpoints.insert(address, Watchpoint { on_write, on_read });
+    }
+
+    /// Stop watching the given address
+    pub fn remove_watchpoint(&mut self, address: u16) {
+        self.watchpoints.remove(&address);
+    }
+
+    /// Note an access made by an instruction if there's a watchpoint for it on the address, and
+    /// no other watched access has been made during this step
+    fn check_watchpoint(&mut self, address: u16, write: bool) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        let Some(watchpoint) = self.watchpoints.get(&address) else {
+            return;
+        };
+        if (write && watchpoint.on_write) || (!write && watchpoint.on_read) {
+            self.watch_hit = Some((address, write));
         }
     }
 
-    fn fetch_byte(&mut self) -> Result<u8, GameBoySystemError> {
-        let byte = self.memory.load_byte(self.registers.pc)
-            .ok_or(GameBoySystemError::MemoryReadError(self.registers.pc))?;
-        self.registers.pc += 1;
+    /// Read a byte of memory for the instruction which is running
+    fn read_memory(&mut self, address: u16) -> Result<u8, GameBoySystemError> {
+        self.check_watchpoint(address, false);
+        self.memory.load_byte(address)
+            .ok_or(GameBoySystemError::MemoryReadError(address))
+    }
+
+    /// Write a byte of memory for the instruction which is running
+    fn write_memory(&mut self, address: u16, data: u8) -> Result<(), GameBoySystemError> {
+        self.check_watchpoint(address, true);
+        self.memory.store_byte(address, data)
+            .map_err(|err| GameBoySystemError::MemoryWriteError(err, data))?;
+        Ok(())
+    }
+
+    /// Capture the registers if the instruction at the given address meets the test trap
+    fn check_test_trap(&mut self, address: u16, instruction: u8) {
+        let trapped = match self.test_trap {
+            Some(TestTrap::MagicBreakpoint) => instruction == MAGIC_BREAKPOINT_OPCODE,
+            Some(TestTrap::Address(trap_address)) => address == trap_address,
+            None => false
+        };
+
+        if trapped {
+            self.trap_state = Some(TrapState::new(&self.registers, address));
+        }
+    }
+
+    /// Change whether broken decoder invariants should panic (the default) or be reported as an
+    /// InvalidInstructionError. Enabling this is intended for hosts where a panic is never
+    /// acceptable, even for a bug inside of this library.
+    pub fn set_panic_free_decode(&mut self, enabled: bool) {
+        self.panic_free_decode = enabled;
+    }
+
+    /// Read the byte at the cursor while decoding, and move the cursor past it
+    fn fetch_byte(&self, cursor: &mut u16) -> Result<u8, GameBoySystemError> {
+        let byte = self.memory.load_byte(*cursor)
+            .ok_or(GameBoySystemError::MemoryReadError(*cursor))?;
+        *cursor = cursor.wrapping_add(1);
 
         Ok(byte)
     }
 
-    fn fetch_imm16(&mut self) -> Result<u16, GameBoySystemError> {
-        let half_word = self.memory.load_half_word(self.registers.pc)
-            .ok_or(GameBoySystemError::MemoryReadError(self.registers.pc))?;
-        self.registers.pc += 2;
-        Ok(half_word)
+    /// Read the 16-bit number at the cursor while decoding, and move the cursor past it. The
+    /// number is stored in little endian order (low byte first), the same as `load_half_word`.
+    fn fetch_imm16(&self, cursor: &mut u16) -> Result<u16, GameBoySystemError> {
+        let low = self.fetch_byte(cursor)?;
+        let high = self.fetch_byte(cursor)?;
+        Ok(high.merge(low))
+    }
+
+    /// Advance everything attached to memory (the PPU, timer, DMA, and so on) by a number of
+    /// M-cycles, and count them towards the instruction which is currently running
+    fn tick_components(&mut self, m_cycles: u8) {
+        self.memory.tick(m_to_t(MCycles(m_cycles as u32), self.double_speed));
+        self.ticked_cycles += m_cycles;
+    }
+
+    /// Tick memory up to the start of one of the M-cycles of the current instruction, so that
+    /// the next memory access happens on that cycle. Cycles count from 1, which is the cycle
+    /// the opcode is fetched on. Nothing is ticked if the cycle has already started.
+    fn wait_for_cycle(&mut self, cycle: u8) {
+        let elapsed = cycle.saturating_sub(1);
+        if elapsed > self.ticked_cycles {
+            self.tick_components(elapsed - self.ticked_cycles);
+        }
+    }
+
+    /// Push a 16-bit value onto the stack, moving the stack pointer down by 2
+    ///
+    /// Parameters:
+    /// - `value`: the value to push
+    /// - `cycle`: the M-cycle of the current instruction which writes the high byte. The low
+    ///   byte is written on the cycle after it, below the high byte.
+    fn push_stack(&mut self, value: u16, cycle: u8) -> Result<(), GameBoySystemError> {
+        let (high, low) = value.split();
+        let high_address = self.registers.sp.wrapping_sub(1);
+        let low_address = self.registers.sp.wrapping_sub(2);
+
+        self.wait_for_cycle(cycle);
+        self.write_memory(high_address, high)?;
+        self.wait_for_cycle(cycle + 1);
+        self.write_memory(low_address, low)?;
+        self.registers.sp = low_address;
+
+        Ok(())
+    }
+
+    /// Pop a 16-bit value off of the stack, moving the stack pointer up by 2
+    ///
+    /// Parameters:
+    /// - `cycle`: the M-cycle of the current instruction which reads the low byte. The high
+    ///   byte is read on the cycle after it.
+    fn pop_stack(&mut self, cycle: u8) -> Result<u16, GameBoySystemError> {
+        let low_address = self.registers.sp;
+        let high_address = low_address.wrapping_add(1);
+
+        self.wait_for_cycle(cycle);
+        let low = self.read_memory(low_address)?;
+        self.wait_for_cycle(cycle + 1);
+        let high = self.read_memory(high_address)?;
+        self.registers.sp = low_address.wrapping_add(2);
+
+        Ok(high.merge(low))
     }
 
-    fn get_r8(&self, reg: u8) -> Result<u8, GameBoySystemError> {
+    /// Get the value of an 8-bit operand, reading the register (or the byte at HL) it refers to
+    fn get_operand8(&mut self, operand: Operand8) -> Result<u8, GameBoySystemError> {
+        match operand {
+            Operand8::Immediate(value) => Ok(value),
+            Operand8::Register(register) => self.get_r8(register),
+        }
+    }
+
+    /// Work out the address a load or store of A uses. This moves HL when it's used as [HL+] or
+    /// [HL-], so it should only be called once per instruction.
+    fn resolve_address(&mut self, address: MemoryAddress) -> u16 {
+        match address {
+            MemoryAddress::Immediate(address) => address,
+            MemoryAddress::HighC => 0xFF00 + self.registers.get_register(CpuRegister::C) as u16,
+            MemoryAddress::Register(register) => {
+                let address = self.get_r16_mem(register);
+                self.step_r16_mem(register);
+                address
+            }
+        }
+    }
+
+    fn get_r8(&mut self, reg: u8) -> Result<u8, GameBoySystemError> {
         if reg == 6 {
             let addr = self.registers.get_joined_registers(CpuRegister::H, CpuRegister::L);
-            return self.memory.load_byte(addr)
-                .ok_or(GameBoySystemError::MemoryReadError(addr));
+            return self.read_memory(addr);
         }
 
-        Ok(self.registers.get_register(reg.into()))
+        Ok(self.registers.get_register(r8_to_register(reg)))
     }
 
     fn set_r8(&mut self, reg: u8, value: u8) -> Result<(), GameBoySystemError> {
         if reg == 6 {
             let address = self.registers.get_joined_registers(CpuRegister::H, CpuRegister::L);
-            self.memory.store_byte(address, value)
-                .map_err(|_err| GameBoySystemError::MemoryWriteError(address, value as u16))?;
-            return Ok(());
+            return self.write_memory(address, value);
+        }
+
+        self.registers.set_register(r8_to_register(reg), value);
+        Ok(())
+    }
+
+    fn get_r16(&self, register: u8) -> u16 {
+        // only the lower 2 bits are used, so every register value is valid
+        match register & 3 {
+            0 => self.registers.get_joined_registers(CpuRegister::B, CpuRegister::C),
+            1 => self.registers.get_joined_registers(CpuRegister::D, CpuRegister::E),
+            2 => self.registers.get_joined_registers(CpuRegister::H, CpuRegister::L),
+            _ => self.registers.sp,
         }
+    }
 
-        Ok(self.registers.set_register(reg.into(), value))
+    fn set_r16(&mut self, register: u8, value: u16) {
+        // only the lower 2 bits are used, so every register value is valid
+        match register & 3 {
+            0 => self.registers.set_joined_registers(CpuRegister::B, CpuRegister::C, value),
+            1 => self.registers.set_joined_registers(CpuRegister::D, CpuRegister::E, value),
+            2 => self.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, value),
+            _ => self.registers.sp = value,
+        }
     }
 
-    fn get_r16(&mut self, register: u8) -> u16 {
-        match register {
+    fn get_r16_stk(&self, register: u8) -> u16 {
+        // only the lower 2 bits are used, so every register value is valid
+        match register & 3 {
             0 => self.registers.get_joined_registers(CpuRegister::B, CpuRegister::C),
             1 => self.registers.get_joined_registers(CpuRegister::D, CpuRegister::E),
             2 => self.registers.get_joined_registers(CpuRegister::H, CpuRegister::L),
-            3 => self.registers.sp,
-            _ => panic!("Invalid r16 address - value {register} greater than 4 passed to get_r16")
+            // the stack instructions use AF in place of SP
+            _ => self.registers.get_joined_registers(CpuRegister::A, CpuRegister::F),
         }
     }
 
-    fn get_r16_mem(&mut self, register: u8) -> u16 {
-        match register {
+    fn set_r16_stk(&mut self, register: u8, value: u16) {
+        // only the lower 2 bits are used, so every register value is valid
+        match register & 3 {
+            0 => self.registers.set_joined_registers(CpuRegister::B, CpuRegister::C, value),
+            1 => self.registers.set_joined_registers(CpuRegister::D, CpuRegister::E, value),
+            2 => self.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, value),
+            // the lower 4 bits of F don't exist, so they always stay 0
+            _ => self.registers.set_joined_registers(
+                CpuRegister::A, CpuRegister::F, value & 0xFFF0
+            ),
+        }
+    }
+
+    fn get_r16_mem(&self, register: u8) -> u16 {
+        // only the lower 2 bits are used, so every register value is valid
+        match register & 3 {
             0 => self.registers.get_joined_registers(CpuRegister::B, CpuRegister::C),
             1 => self.registers.get_joined_registers(CpuRegister::D, CpuRegister::E),
-            2 => {
-                let value = self.registers.get_joined_registers(CpuRegister::H, CpuRegister::L);
-                self.registers.set_joined_registers(
-                    CpuRegister::H, CpuRegister::L, value.overflowing_add(1).0
-                );
-                value
-            },
-            3 => {
-                let value = self.registers.get_joined_registers(CpuRegister::H, CpuRegister::L);
-                self.registers.set_joined_registers(
-                    CpuRegister::H, CpuRegister::L, value.overflowing_sub(1).0
-                );
-                value
-            },
-            _ => panic!("Invalid r16mem address - value greater than 4 passed in")
+            // [HL+] and [HL-] both use HL, and then move it with `step_r16_mem`
+            _ => self.registers.get_joined_registers(CpuRegister::H, CpuRegister::L),
+        }
+    }
+
+    /// Increment HL after it was used as [HL+], or decrement it after it was used as [HL-]
+    fn step_r16_mem(&mut self, register: u8) {
+        let value = self.registers.get_joined_registers(CpuRegister::H, CpuRegister::L);
+        let value = match register & 3 {
+            2 => value.wrapping_add(1),
+            3 => value.wrapping_sub(1),
+            _ => return,
+        };
+        self.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cycles::TCycles;
+    use crate::ppu::{BGP, DOTS_PER_FRAME, LCDC, OBP0, SCREEN_WIDTH};
+    use crate::test_utils::{init_program, init_system};
+
+    use super::*;
+
+    #[test]
+    fn test_render_loaded_tiles() {
+        let mut dmg = init_system();
+        // tile 1 is solid color 1, and tile 2 only has color 3 in its leftmost column
+        let tiles: Vec<u8> = [[0xFF, 0x00]; 8].concat().into_iter()
+            .chain([[0x80, 0x80]; 8].concat())
+            .collect();
+        let mut oam = [0; OAM_SIZE];
+        // a sprite using tile 2 at (16, 0) on the screen
+        oam[0..4].copy_from_slice(&[16, 24, 2, 0x00]);
+
+        assert!(dmg.load_vram(0x0010, &tiles).is_ok(), "Should be able to load the tiles");
+        assert!(dmg.load_vram(0x1800, &[1]).is_ok(), "Should be able to load the tile map");
+        dmg.load_oam(&oam);
+        for (register, value) in [(LCDC, 0x93), (BGP, 0xE4), (OBP0, 0xE4)] {
+            assert!(dmg.memory.store_byte(register, value).is_ok(), "Should set up the PPU");
         }
+        dmg.memory.tick(TCycles(DOTS_PER_FRAME));
+
+        let line = &dmg.framebuffer()[0..SCREEN_WIDTH];
+        assert_eq!(line[0..8], [1; 8], "The first tile in the map should be tile 1");
+        assert_eq!(line[8..16], [0; 8], "The rest of the map should be tile 0");
+        assert_eq!(line[16..18], [3, 0], "The sprite should be drawn from OAM");
+        assert_eq!(dmg.framebuffer()[7 * SCREEN_WIDTH], 1, "The whole tile should be drawn");
+    }
+
+    #[test]
+    fn test_register_round_trip() {
+        let mut dmg = init_system();
+
+        dmg.set_register(CpuRegister::B, 0x12);
+        dmg.set_register_pair(CpuRegister::H, CpuRegister::L, 0xBEEF);
+        dmg.set_pc(0x0150);
+        dmg.set_sp(0xFFFE);
+
+        assert_eq!(dmg.register(CpuRegister::B), 0x12);
+        assert_eq!(dmg.register_pair(CpuRegister::B, CpuRegister::C), 0x1200);
+        assert_eq!(dmg.register(CpuRegister::H), 0xBE, "H should hold the upper byte of HL");
+        assert_eq!(dmg.register(CpuRegister::L), 0xEF, "L should hold the lower byte of HL");
+        assert_eq!(dmg.pc(), 0x0150);
+        assert_eq!(dmg.sp(), 0xFFFE);
+    }
+
+    #[test]
+    fn test_f_register_lower_bits() {
+        let mut dmg = init_system();
+
+        dmg.set_register(CpuRegister::F, 0xFF);
+        let f = dmg.register(CpuRegister::F);
+        dmg.set_register_pair(CpuRegister::A, CpuRegister::F, 0x12FF);
+
+        assert_eq!(f, 0xF0, "The lower 4 bits of F don't exist");
+        assert_eq!(dmg.register_pair(CpuRegister::A, CpuRegister::F), 0x12F0);
+    }
+
+    #[test]
+    fn test_flags_round_trip() {
+        let mut dmg = init_system();
+
+        dmg.set_flags(FlagRegister { zero: true, subtract: false, half_carry: true, carry: false });
+        let flags = dmg.flags();
+
+        assert_eq!(dmg.register(CpuRegister::F), 0xA0, "Flags should be stored in F");
+        assert!(flags.zero && flags.half_carry, "Set flags should be read back");
+        assert!(!flags.subtract && !flags.carry, "Cleared flags should be read back");
+    }
+
+    #[test]
+    fn test_set_pc_moves_execution() {
+        // NOP at 0x0000, LD A 0x42 at 0x0150
+        let mut program = vec![0x00; 0x0150];
+        program.extend([0x3E, 0x42]);
+        let mut dmg = init_program(program);
+
+        dmg.set_pc(0x0150);
+
+        assert!(dmg.step().is_ok());
+        assert_eq!(dmg.register(CpuRegister::A), 0x42, "The instruction at the new pc should run");
+        assert_eq!(dmg.pc(), 0x0152);
     }
 }
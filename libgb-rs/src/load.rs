@@ -0,0 +1,525 @@
+use crate::GameBoySystem;
+use crate::cpu::CpuRegister;
+use crate::debug::TraceHook;
+use crate::interrupt::INTERRUPT_ENABLE;
+use crate::memory::{DmgMemoryController, MemoryController};
+use crate::memory::cartridge::{CartridgeMapper, LoadCartridgeError, RomInfo};
+use crate::memory::rtc::Clock;
+use crate::serial::SerialSink;
+
+// Where execution starts when the boot ROM is skipped, which is where the boot ROM would jump
+const CARTRIDGE_ENTRY_POINT: u16 = 0x0100;
+const INITIAL_STACK_POINTER: u16 = 0xFFFE;
+
+// The register pairs the DMG boot ROM leaves behind when it jumps to the cartridge
+const POST_BOOT_AF: u16 = 0x01B0;
+const POST_BOOT_BC: u16 = 0x0013;
+const POST_BOOT_DE: u16 = 0x00D8;
+const POST_BOOT_HL: u16 = 0x014D;
+
+// The register pairs the CGB boot ROM leaves behind for CGB games, where A = 0x11 is how games
+// tell that they're running on a CGB
+// https://gbdev.io/pandocs/Power_Up_Sequence.html#cpu-registers
+const CGB_POST_BOOT_AF: u16 = 0x1180;
+const CGB_POST_BOOT_BC: u16 = 0x0000;
+const CGB_POST_BOOT_DE: u16 = 0xFF56;
+const CGB_POST_BOOT_HL: u16 = 0x000D;
+
+// The IO registers the DMG boot ROM leaves behind, as (address, value). DIV isn't included since
+// writing to it always resets it to 0.
+// https://gbdev.io/pandocs/Power_Up_Sequence.html#hardware-registers
+const POST_BOOT_IO: [(u16, u8); 38] = [
+    (0xFF00, 0xCF), // P1
+    (0xFF01, 0x00), // SB
+    (0xFF02, 0x7E), // SC
+    (0xFF05, 0x00), // TIMA
+    (0xFF06, 0x00), // TMA
+    (0xFF07, 0xF8), // TAC
+    (0xFF0F, 0xE1), // IF
+    (0xFF10, 0x80), // NR10
+    (0xFF11, 0xBF), // NR11
+    (0xFF12, 0xF3), // NR12
+    (0xFF13, 0xFF), // NR13
+    (0xFF14, 0xBF), // NR14
+    (0xFF16, 0x3F), // NR21
+    (0xFF17, 0x00), // NR22
+    (0xFF18, 0xFF), // NR23
+    (0xFF19, 0xBF), // NR24
+    (0xFF1A, 0x7F), // NR30
+    (0xFF1B, 0xFF), // NR31
+    (0xFF1C, 0x9F), // NR32
+    (0xFF1D, 0xFF), // NR33
+    (0xFF1E, 0xBF), // NR34
+    (0xFF20, 0xFF), // NR41
+    (0xFF21, 0x00), // NR42
+    (0xFF22, 0x00), // NR43
+    (0xFF23, 0xBF), // NR44
+    (0xFF24, 0x77), // NR50
+    (0xFF25, 0xF3), // NR51
+    (0xFF26, 0xF1), // NR52
+    (0xFF40, 0x91), // LCDC
+    (0xFF42, 0x00), // SCY
+    (0xFF43, 0x00), // SCX
+    (0xFF45, 0x00), // LYC
+    (0xFF47, 0xFC), // BGP
+    (0xFF48, 0x00), // OBP0
+    (0xFF49, 0x00), // OBP1
+    (0xFF4A, 0x00), // WY
+    (0xFF4B, 0x00), // WX
+    (0xFF50, 0x01), // boot ROM disable
+];
+const POST_BOOT_IE: u8 = 0x00;
+
+/// # Model
+/// The Game Boy hardware to emulate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Cgb,
+}
+
+/// # LoadOptions
+/// Settings for `GameBoySystem::load`. The defaults run the ROM on a DMG, starting right at the
+/// cartridge entry point with no save data.
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    pub model: Model,
+    pub boot_rom: Option<Vec<u8>>, // runs before the cartridge, instead of skipping to 0x0100
+    pub strict_checksum: bool, // whether a ROM with the wrong header checksum should be rejected
+    pub save_data: Option<Vec<u8>>, // loaded into the cartridge RAM if it has a battery
+}
+
+/// # GameBoySystemBuilder
+/// Puts together a system from a ROM and any optional pieces, as an alternative to setting up
+/// `LoadOptions` and attaching everything else after `GameBoySystem::load`.
+#[derive(Default)]
+pub struct GameBoySystemBuilder {
+    rom: Option<Vec<u8>>,
+    options: LoadOptions,
+    serial_sink: Option<Box<dyn SerialSink>>,
+    trace_hook: Option<TraceHook>,
+    rtc_clock: Option<Box<dyn Clock>>,
+}
+
+impl GameBoySystemBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the ROM to run, including its header
+    pub fn with_cartridge(mut self, rom: Vec<u8>) -> Self {
+        self.rom = Some(rom);
+        self
+    }
+
+    /// Run a boot ROM before the cartridge
+    pub fn with_boot_rom(mut self, boot_rom: Vec<u8>) -> Self {
+        self.options.boot_rom = Some(boot_rom);
+        self
+    }
+
+    /// Start at the cartridge entry point in the state the boot ROM leaves behind, dropping any
+    /// boot ROM which was given. This is the default.
+    pub fn skip_boot(mut self) -> Self {
+        self.options.boot_rom = None;
+        self
+    }
+
+    /// Load save data into the cartridge RAM
+    pub fn with_save_data(mut self, save_data: Vec<u8>) -> Self {
+        self.options.save_data = Some(save_data);
+        self
+    }
+
+    /// Choose the hardware to emulate
+    pub fn with_model(mut self, model: Model) -> Self {
+        self.options.model = model;
+        self
+    }
+
+    /// Reject ROMs with the wrong header checksum
+    pub fn strict_checksum(mut self) -> Self {
+        self.options.strict_checksum = true;
+        self
+    }
+
+    /// Connect something to the link port (see `GameBoySystem::set_serial_sink`)
+    pub fn with_serial_sink(mut self, sink: impl SerialSink + 'static) -> Self {
+        self.serial_sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Run a hook before every instruction (see `GameBoySystem::set_trace_hook`)
+    pub fn with_trace_hook(mut self, hook: TraceHook) -> Self {
+        self.trace_hook = Some(hook);
+        self
+    }
+
+    /// Count time for the cartridge's real time clock (if it has one) with the given clock
+    /// instead of `std::time`. The clock is attached before the save data is loaded, so it also
+    /// decides how much time passed since the save was made.
+    pub fn with_rtc_clock(mut self, clock: Box<dyn Clock>) -> Self {
+        self.rtc_clock = Some(clock);
+        self
+    }
+
+    /// Create the system, which is loaded the same way as `GameBoySystem::load`
+    ///
+    /// Returns the system, a MissingRom error if no ROM was given, or any error that
+    /// `GameBoySystem::load` would return
+    pub fn build(self) -> Result<GameBoySystem, LoadCartridgeError> {
+        let rom = self.rom.ok_or(LoadCartridgeError::MissingRom)?;
+        let mut system = GameBoySystem::load_with_rtc_clock(rom, self.options, self.rtc_clock)?;
+        if let Some(sink) = self.serial_sink {
+            system.memory.set_serial_sink(sink);
+        }
+        if let Some(hook) = self.trace_hook {
+            system.set_trace_hook(hook);
+        }
+
+        Ok(system)
+    }
+}
+
+impl GameBoySystem {
+    /// Create a system which is ready to run the given ROM. This reads the cartridge header,
+    /// builds the matching cartridge, loads any save data, and connects it all to a new system.
+    ///
+    /// Parameters:
+    /// - `rom`: the full contents of the ROM, including the header
+    /// - `options`: how the system should be set up (see `LoadOptions`)
+    ///
+    /// Returns the system, or a LoadCartridgeError if the ROM or save data can't be loaded
+    pub fn load(rom: Vec<u8>, options: LoadOptions) -> Result<Self, LoadCartridgeError> {
+        Self::load_with_rtc_clock(rom, options, None)
+    }
+
+    /// Load a ROM the same way as `load`, giving the cartridge's real time clock a different
+    /// clock (see `GameBoySystemBuilder::with_rtc_clock`)
+    fn load_with_rtc_clock(
+        rom: Vec<u8>, options: LoadOptions, rtc_clock: Option<Box<dyn Clock>>
+    ) -> Result<Self, LoadCartridgeError> {
+        let info = RomInfo::parse(&rom)?;
+        if options.strict_checksum && !info.verify_header_checksum(&rom) {
+            return Err(LoadCartridgeError::ChecksumMismatch);
+        }
+
+        let mut cartridge: Box<dyn CartridgeMapper> = rom.try_into()?;
+        if let Some(clock) = rtc_clock {
+            cartridge.set_rtc_clock(clock);
+        }
+        if let Some(save_data) = options.save_data {
+            cartridge.load_save(save_data)
+                .map_err(LoadCartridgeError::InvalidSave)?;
+        }
+
+        let mut memory = match options.model {
+            Model::Dmg => DmgMemoryController::new(cartridge),
+            Model::Cgb => DmgMemoryController::new_cgb(cartridge),
+        };
+        let skip_boot_rom = options.boot_rom.is_none();
+        if let Some(boot_rom) = options.boot_rom {
+            memory.set_boot_rom(boot_rom);
+        }
+
+        let mut system = GameBoySystem::new(Box::new(memory));
+        if skip_boot_rom {
+            system.reset_model(options.model);
+        }
+        system.rom_info = Some(info);
+
+        Ok(system)
+    }
+
+    /// Create a system which is ready to run the given ROM from its entry point, without a boot
+    /// ROM. Games which support CGB features are run on a CGB, and everything else is run on a
+    /// DMG (see `Model`). Use `load` or `GameBoySystemBuilder` for more control.
+    ///
+    /// Parameters:
+    /// - `rom`: the full contents of the ROM, including its header
+    ///
+    /// Returns the system, or an error if the ROM couldn't be loaded into a cartridge
+    pub fn from_rom(rom: Vec<u8>) -> Result<Self, LoadCartridgeError> {
+        let model = if RomInfo::parse(&rom)?.supports_cgb() { Model::Cgb } else { Model::Dmg };
+
+        GameBoySystem::load(rom, LoadOptions { model, ..Default::default() })
+    }
+
+    /// Create a system which starts in the state the DMG boot ROM leaves it in, so that a
+    /// cartridge can be run without a boot ROM (see `reset`)
+    ///
+    /// Parameters:
+    /// - `memory`: the memory to connect to the system
+    pub fn new_post_boot(memory: Box<dyn MemoryController>) -> Self {
+        let mut system = GameBoySystem::new(memory);
+        system.reset();
+        system
+    }
+
+    /// Put the CPU registers and IO registers into the state the DMG boot ROM leaves them in
+    /// right before it jumps to the cartridge at 0x0100. This unmaps the boot ROM if there is
+    /// one. The rest of memory (like RAM and VRAM) is left alone.
+    pub fn reset(&mut self) {
+        self.reset_model(Model::Dmg);
+    }
+
+    /// Put the system into the state the given model's boot ROM leaves it in, the same way as
+    /// `reset`. The CPU registers are the ones that model's boot ROM leaves behind for the
+    /// cartridge, and the IO registers are the same as on a DMG.
+    ///
+    /// Parameters:
+    /// - `model`: the hardware whose boot ROM should have run
+    pub fn reset_model(&mut self, model: Model) {
+        let (af, bc, de, hl) = match model {
+            Model::Dmg => (POST_BOOT_AF, POST_BOOT_BC, POST_BOOT_DE, POST_BOOT_HL),
+            Model::Cgb => (CGB_POST_BOOT_AF, CGB_POST_BOOT_BC, CGB_POST_BOOT_DE, CGB_POST_BOOT_HL),
+        };
+        self.registers.set_joined_registers(CpuRegister::A, CpuRegister::F, af);
+        self.registers.set_joined_registers(CpuRegister::B, CpuRegister::C, bc);
+        self.registers.set_joined_registers(CpuRegister::D, CpuRegister::E, de);
+        self.registers.set_joined_registers(CpuRegister::H, CpuRegister::L, hl);
+        self.registers.sp = INITIAL_STACK_POINTER;
+        self.registers.pc = CARTRIDGE_ENTRY_POINT;
+        self.ime = false;
+        self.ime_pending = false;
+        self.halted = false;
+        self.halt_bug = false;
+        self.stopped = false;
+        self.frame_dots = 0;
+        self.double_speed = false;
+        self.memory.set_double_speed(false);
+
+        // every one of these is a register on a DMG, so the writes can't fail
+        for (address, value) in POST_BOOT_IO {
+            let _ = self.memory.store_byte(address, value);
+        }
+        let _ = self.memory.store_byte(INTERRUPT_ENABLE, POST_BOOT_IE);
+    }
+
+    /// Get the header of the ROM which was loaded with `load`, if the system was created that way
+    pub fn rom_info(&self) -> Option<&RomInfo> {
+        self.rom_info.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::memory::KEY1;
+    use crate::memory::cartridge::{MockCartridgeMapper, SaveError};
+    use crate::memory::rtc::RTC_SAVE_SIZE;
+    use crate::serial::VecSerialSink;
+    use crate::test_utils::StoppedClock;
+
+    use super::*;
+
+    // The size of a ROM with 2 banks
+    const ROM_SIZE: usize = 0x8000;
+
+    /// Create a ROM with 2 banks for an MBC1 cartridge with 8 KiB of battery-backed RAM
+    fn init_rom() -> Vec<u8> {
+        let mut rom = vec![0; ROM_SIZE];
+        rom[0x147] = 0x03; // MBC1 + RAM + battery
+        rom[0x149] = 0x02; // 1 RAM bank
+        rom
+    }
+
+    #[test]
+    fn test_load_with_save() {
+        let mut save = vec![0; 0x2000];
+        save[0x42] = 0x28;
+        let options = LoadOptions { save_data: Some(save), ..Default::default() };
+
+        let result = GameBoySystem::load(init_rom(), options);
+        assert!(result.is_ok(), "Should be able to load the ROM");
+        let mut dmg = result.unwrap();
+        assert!(dmg.memory.store_byte(0x0000, 0x0A).is_ok(), "Should be able to enable RAM");
+
+        assert_eq!(dmg.rom_info().map(|info| info.cartridge_type), Some(0x03));
+        assert_eq!(dmg.memory().load_byte(0xA042), Some(0x28), "The save should be restored");
+        assert_eq!(dmg.registers().pc, 0x0100, "Should skip straight to the cartridge");
+    }
+
+    #[test]
+    fn test_load_save_too_big() {
+        let options = LoadOptions { save_data: Some(vec![0; 0x2001]), ..Default::default() };
+
+        let result = GameBoySystem::load(init_rom(), options);
+
+        assert!(matches!(result, Err(LoadCartridgeError::InvalidSave(SaveError::SaveFileTooBig))));
+    }
+
+    #[test]
+    fn test_load_strict_checksum() {
+        let options = LoadOptions { strict_checksum: true, ..Default::default() };
+
+        let result = GameBoySystem::load(init_rom(), options);
+
+        assert!(
+            matches!(result, Err(LoadCartridgeError::ChecksumMismatch)),
+            "The test ROM doesn't have a valid checksum"
+        );
+    }
+
+    #[test]
+    fn test_load_with_boot_rom() {
+        let options = LoadOptions { boot_rom: Some(vec![0x31; 0x100]), ..Default::default() };
+
+        let result = GameBoySystem::load(init_rom(), options);
+        assert!(result.is_ok(), "Should be able to load the ROM");
+        let dmg = result.unwrap();
+
+        assert_eq!(dmg.registers().pc, 0, "The boot ROM should run first");
+        assert_eq!(dmg.memory().load_byte(0), Some(0x31), "The boot ROM should be mapped");
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut rom = init_rom();
+        // LD A, 0x42; LDH [0x01], A; LD A, 0x81; LDH [0x02], A (send A over serial)
+        let program = [0x3E, 0x42, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        let sink = VecSerialSink::new();
+        let traced = Rc::new(RefCell::new(Vec::new()));
+        let hook_traced = traced.clone();
+
+        let result = GameBoySystemBuilder::new()
+            .with_cartridge(rom)
+            .with_boot_rom(vec![0; 0x100])
+            .skip_boot()
+            .with_serial_sink(sink.clone())
+            .with_trace_hook(Box::new(move |entry| {
+                hook_traced.borrow_mut().push(entry.registers.pc);
+            }))
+            .build();
+        assert!(result.is_ok(), "Should be able to build the system");
+        let mut dmg = result.unwrap();
+        for _ in 0..4 {
+            assert!(dmg.step().is_ok(), "Should be able to run the program");
+        }
+
+        assert_eq!(traced.borrow()[..], [0x100, 0x102, 0x104, 0x106], "Every step is traced");
+        assert_eq!(dmg.register(CpuRegister::A), 0x81, "The program should have run");
+        assert_eq!(sink.bytes(), vec![0x42], "The byte should be sent to the serial sink");
+    }
+
+    #[test]
+    fn test_builder_with_rtc_clock() {
+        let save_time: u64 = 1_700_000_000;
+        let mut rom = vec![0; ROM_SIZE];
+        rom[0x147] = 0x0F; // MBC3 + timer + battery
+        let mut save = vec![0; RTC_SAVE_SIZE - 8];
+        save.extend(save_time.to_le_bytes());
+
+        let result = GameBoySystemBuilder::new()
+            .with_cartridge(rom)
+            .with_save_data(save)
+            .with_rtc_clock(Box::new(StoppedClock(save_time + 3600)))
+            .build();
+        assert!(result.is_ok(), "Should be able to build the system");
+        let mut dmg = result.unwrap();
+        assert!(dmg.memory.store_byte(0x0000, 0x0A).is_ok(), "Should be able to enable the RTC");
+        for latch in [0, 1] {
+            assert!(dmg.memory.store_byte(0x6000, latch).is_ok(), "Should be able to latch");
+        }
+        assert!(dmg.memory.store_byte(0x4000, 0x0A).is_ok(), "Should be able to select hours");
+
+        assert_eq!(dmg.memory().load_byte(0xA000), Some(1), "The clock should count the hour");
+    }
+
+    #[test]
+    fn test_builder_without_rom() {
+        let result = GameBoySystemBuilder::new()
+            .skip_boot()
+            .build();
+
+        assert!(matches!(result, Err(LoadCartridgeError::MissingRom)), "A ROM is required");
+    }
+
+    #[test]
+    fn test_from_rom() {
+        let result = GameBoySystem::from_rom(init_rom());
+        assert!(result.is_ok(), "Should be able to load the ROM");
+        let mut dmg = result.unwrap();
+
+        assert_eq!(dmg.registers().pc, 0x0100, "Should start at the entry point");
+        assert_eq!(dmg.registers().sp, 0xFFFE, "Should start with the boot ROM's SP");
+        assert!(dmg.rom_info().is_some(), "The header should be kept");
+        assert_eq!(dmg.memory.load_byte(KEY1), Some(0xFF), "A DMG game shouldn't get CGB memory");
+        assert!(dmg.step().is_ok(), "Should be able to run the ROM");
+    }
+
+    #[test]
+    fn test_from_rom_cgb() {
+        let mut rom = init_rom();
+        rom[0x143] = 0x80;
+
+        let result = GameBoySystem::from_rom(rom);
+        assert!(result.is_ok(), "Should be able to load the ROM");
+        let dmg = result.unwrap();
+
+        assert_eq!(dmg.registers().pc, 0x0100, "Should start at the entry point");
+        assert_eq!(dmg.memory.load_byte(KEY1), Some(0x7E), "A CGB game should get CGB memory");
+        assert_eq!(dmg.register(CpuRegister::A), 0x11, "A CGB game should see the CGB's A");
+    }
+
+    #[test]
+    fn test_load_cgb() {
+        let options = LoadOptions { model: Model::Cgb, ..Default::default() };
+
+        let result = GameBoySystem::load(init_rom(), options);
+        assert!(result.is_ok(), "Should be able to load a DMG game on a CGB");
+        let cgb = result.unwrap();
+
+        assert_eq!(cgb.memory.load_byte(KEY1), Some(0x7E), "Should get CGB memory");
+        assert_eq!(cgb.register(CpuRegister::A), 0x11, "Should start with the CGB's A");
+        assert_eq!(
+            cgb.register_pair(CpuRegister::D, CpuRegister::E), 0xFF56,
+            "Should start with the CGB's DE"
+        );
+        assert_eq!(cgb.registers().pc, 0x0100, "Should skip straight to the cartridge");
+    }
+
+    #[test]
+    fn test_from_rom_too_short() {
+        let result = GameBoySystem::from_rom(vec![0; 0x100]);
+
+        assert!(matches!(result, Err(LoadCartridgeError::RomTooShort)), "There's no header");
+    }
+
+    #[test]
+    fn test_reset_registers() {
+        let memory = DmgMemoryController::new(Box::new(MockCartridgeMapper::new()));
+        let mut dmg = GameBoySystem::new(Box::new(memory));
+        dmg.registers.set_register(CpuRegister::A, 0x42);
+        dmg.registers.pc = 0x4242;
+
+        dmg.reset();
+
+        let registers = dmg.registers();
+        assert_eq!(registers.get_joined_registers(CpuRegister::A, CpuRegister::F), 0x01B0);
+        assert_eq!(registers.get_joined_registers(CpuRegister::B, CpuRegister::C), 0x0013);
+        assert_eq!(registers.get_joined_registers(CpuRegister::D, CpuRegister::E), 0x00D8);
+        assert_eq!(registers.get_joined_registers(CpuRegister::H, CpuRegister::L), 0x014D);
+        assert_eq!(registers.sp, 0xFFFE, "The stack should start at the top of HRAM");
+        assert_eq!(registers.pc, 0x0100, "Should start at the cartridge entry point");
+        assert!(!dmg.interrupts_enabled(), "Interrupts should start disabled");
+    }
+
+    #[test]
+    fn test_reset_io() {
+        let memory = DmgMemoryController::new(Box::new(MockCartridgeMapper::new()));
+        let dmg = GameBoySystem::new_post_boot(Box::new(memory));
+
+        assert_eq!(dmg.memory().load_byte(0xFF00), Some(0xCF), "No joypad row is selected");
+        assert_eq!(dmg.memory().load_byte(0xFF07), Some(0xF8), "The timer should be stopped");
+        assert_eq!(dmg.memory().load_byte(0xFF0F), Some(0xE1), "VBlank should be requested");
+        assert_eq!(dmg.memory().load_byte(0xFF26), Some(0xF1), "Sound should be on");
+        assert_eq!(dmg.memory().load_byte(0xFF40), Some(0x91), "The LCD should be on");
+        assert_eq!(dmg.memory().load_byte(0xFF47), Some(0xFC), "BGP should be set up");
+        assert_eq!(dmg.memory().load_byte(0xFFFF), Some(0x00), "No interrupts are enabled");
+    }
+}
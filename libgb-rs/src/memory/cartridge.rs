@@ -1,19 +1,26 @@
 use mockall::automock;
 use crate::memory::MemoryWriteError;
+use crate::memory::rtc::Clock;
+use crate::snapshot::SnapshotError;
 
 mod basicrom;
+mod huc1;
 mod mbc1;
 mod mbc2;
 mod mbc3;
 mod bankedrom;
 mod builder;
+mod header;
 
 pub use basicrom::RomOnlyCartridge;
+pub use builder::{detect_mapper, from_rom_checked, MapperKind};
+pub use header::RomInfo;
+pub use huc1::HuC1;
 pub use mbc1::MBC1;
 pub use mbc2::MBC2;
 pub use mbc3::MBC3;
 
-const ROM_BANK_SIZE: usize = 16384;
+pub const ROM_BANK_SIZE: usize = 16384;
 const RAM_BANK_SIZE: usize = 8192;
 
 pub type RomBank = [u8; ROM_BANK_SIZE];
@@ -22,7 +29,47 @@ pub type MemBank = [u8; RAM_BANK_SIZE];
 #[derive(Debug)]
 pub enum LoadCartridgeError {
     UnsupportedType,
-    InvalidRomFile
+    InvalidRomFile,
+    RomTooShort, // the ROM is too small to contain a full cartridge header
+    RomSizeMismatch, // the ROM isn't the size which its header says it is
+    ChecksumMismatch, // the header checksum is wrong, and checksums are being enforced
+    UnsupportedModel, // the cartridge can't be run on the requested system yet
+    InvalidSave(SaveError), // the save data couldn't be loaded into the cartridge
+    MissingRom, // a system was built without a ROM to load
+}
+
+/// # RamFill
+/// How to initialize cartridge RAM which isn't backed by a battery. RAM on real hardware powers
+/// up holding garbage rather than zeroes, and some games (usually by accident) rely on that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamFill {
+    /// Leave every byte as 0, which is what cartridges start with when they're created
+    Zero,
+    /// Set every byte to the same value (0xFF is the most common power-up value)
+    Value(u8),
+    /// Set every byte to pseudo-random noise generated from the given seed, so that the same
+    /// seed always produces the same RAM
+    Random(u64),
+}
+
+impl RamFill {
+    /// Overwrite every byte in the given RAM with this pattern
+    pub fn fill(self, ram: &mut [u8]) {
+        match self {
+            RamFill::Zero => ram.fill(0),
+            RamFill::Value(value) => ram.fill(value),
+            RamFill::Random(seed) => {
+                // xorshift64 gets stuck on 0, so make sure at least one bit is set
+                let mut state = seed | 1;
+                for byte in ram.iter_mut() {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    *byte = (state >> 56) as u8;
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -55,9 +102,28 @@ pub trait CartridgeMapper {
     /// - `address`: the ROM address to write to, indexed between 0 and 32,767
     /// - `data`: the value to store in RAM
     ///
-    /// Returns a MemoryWriteError if the address is not in the valid range
+    /// Returns a MemoryWriteError if the address is not in the valid range, or if the cartridge
+    /// doesn't handle writes to the address
     fn write_rom(&mut self, address: u16, data: u8) -> Result<(), MemoryWriteError>;
 
+    /// Get the position in the full ROM which the given address currently maps to, taking the
+    /// selected ROM bank into account
+    ///
+    /// Parameters:
+    /// - `address`: the ROM address to look up, indexed between 0 and 32,767
+    ///
+    /// Returns the index of the byte in the ROM, or None if the address is not valid
+    fn rom_offset(&self, address: u16) -> Option<usize>;
+
+    /// Get the size of the full ROM (every bank) in bytes
+    fn rom_size(&self) -> usize;
+
+    /// Get the RAM bank which is currently mapped into the cartridge RAM region
+    ///
+    /// Returns the bank number, or None if no RAM is mapped (because the cartridge has no RAM,
+    /// RAM is disabled, or something else like a clock register is mapped instead)
+    fn mem_bank(&self) -> Option<usize>;
+
     /// Get the 8-bit number at the given address on the cartridge RAM
     ///
     /// Parameters:
@@ -74,12 +140,23 @@ pub trait CartridgeMapper {
     /// - `data`: the value to store in RAM
     ///
     /// Returns the value of the byte that was previously in the given location in RAM,
-    /// or a MemoryWriteError if the address is not in the valid range
+    /// or a MemoryWriteError if the address is not in the valid range or no RAM is mapped there
     fn write_mem(&mut self, address: u16, data: u8) -> Result<u8, MemoryWriteError>;
 
+    /// Fill this cartridge's RAM with the given pattern. Battery-backed RAM is left alone, since
+    /// its contents are supposed to come from a save file instead.
+    ///
+    /// Parameters:
+    /// - `fill`: the pattern to write into every byte of RAM
+    fn fill_ram(&mut self, fill: RamFill);
+
     /// Returns whether or not this cartridge supports saving
     fn can_save(&self) -> bool;
 
+    /// Change where the cartridge's real time clock gets the passage of time from (see
+    /// `RealTimeClock::set_clock`). Cartridges without a clock ignore this.
+    fn set_rtc_clock(&mut self, _clock: Box<dyn Clock>) {}
+
     /// Load a save file into the cartridge's memory
     ///
     /// Parameters:
@@ -93,4 +170,18 @@ pub trait CartridgeMapper {
 
     /// Dump a cartridge's memory as a vector of bytes.
     fn save(&self) -> Vec<u8>;
+
+    /// Get the full state of the cartridge for a snapshot, which includes the selected banks,
+    /// whether RAM is enabled, and the contents of RAM (even without a battery). The ROM isn't
+    /// included, since it never changes.
+    fn save_state(&self) -> Vec<u8>;
+
+    /// Restore the state returned by `save_state`
+    ///
+    /// Parameters:
+    /// - `state`: the saved state, which should come from the same kind of cartridge
+    ///
+    /// Returns a SnapshotError if the state is invalid or doesn't fit this cartridge (like when
+    /// it has a different amount of RAM)
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError>;
 }
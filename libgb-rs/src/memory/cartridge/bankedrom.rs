@@ -1,6 +1,17 @@
 use crate::memory::MemoryWriteError;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 
-use super::{LoadCartridgeError, SaveError, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use super::{LoadCartridgeError, RamFill, SaveError, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+/// Get the bank which is actually selected when a cartridge's ROM bank register is set. Bank 0
+/// is always mapped at 0x0000 - 0x3FFF, so selecting it for 0x4000 - 0x7FFF selects bank 1 instead.
+///
+/// Parameters:
+/// - `bank`: the value of the ROM bank register, only including the bits which the cartridge
+///   compares against 0
+pub fn nonzero_bank(bank: usize) -> usize {
+    bank.max(1)
+}
 
 /// # BankedRom
 /// This is an abstraction (not intended to be exposed publicly) for Game Boy cartridges.
@@ -10,6 +21,7 @@ use super::{LoadCartridgeError, SaveError, RAM_BANK_SIZE, ROM_BANK_SIZE};
 /// It will handle loading and dumping, but without any extra logic for determining rom banks
 pub struct BankedRom {
     rom: Vec<u8>,
+    loaded_size: usize, // the size of the ROM file, the rest of the banks are padding
     rom_bank: usize,
     ram: Vec<u8>,
     ram_bank: usize,
@@ -40,6 +52,7 @@ impl BankedRom {
         Ok(
             BankedRom {
                 rom,
+                loaded_size: rom_bytes.len(),
                 rom_bank: 1,
                 ram: vec![0; ram_size],
                 ram_bank: 0,
@@ -54,7 +67,24 @@ impl BankedRom {
         self.rom_bank = bank % bank_count;
     }
 
+    /// Set the ROM bank the same as `set_rom_bank`, except that bank 0 selects bank 1
+    pub fn set_rom_bank_nonzero(&mut self, bank: usize) {
+        self.set_rom_bank(nonzero_bank(bank));
+    }
+
+    /// Read from the ROM in the currently selected bank. Addresses inside of the banks but past
+    /// the end of the loaded file (e.g. a ROM given to a mapper's constructor with fewer banks
+    /// than it was told to have) aren't connected to anything, so they read as open bus (0xFF).
     pub fn read_rom(&self, address: u16) -> Option<u8> {
+        let offset = self.rom_offset(address)?;
+        if offset >= self.loaded_size {
+            return Some(0xFF);
+        }
+        self.rom.get(offset)
+            .copied()
+    }
+
+    pub fn rom_offset(&self, address: u16) -> Option<usize> {
         if address >= 0x8000 {
             return None;
         }
@@ -66,13 +96,29 @@ impl BankedRom {
 
         let rom_address = (tag << 14) | offset;
 
-        self.rom.get(rom_address)
-            .copied()
+        if rom_address >= self.rom.len() {
+            return None;
+        }
+        Some(rom_address)
+    }
+
+    pub fn rom_size(&self) -> usize {
+        self.rom.len()
     }
 
     pub fn set_mem_bank(&mut self, bank: usize) {
         let bank_count = self.ram.len() / RAM_BANK_SIZE;
-        self.ram_bank = bank % bank_count;
+        // there is nothing to switch between when the cartridge doesn't have any RAM
+        if bank_count > 0 {
+            self.ram_bank = bank % bank_count;
+        }
+    }
+
+    pub fn mem_bank(&self) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        Some(self.ram_bank)
     }
 
     pub fn read_mem(&self, address: u16) -> Option<u8> {
@@ -88,23 +134,35 @@ impl BankedRom {
 
     pub fn write_mem(&mut self, address: u16, value: u8) -> Result<u8, MemoryWriteError> {
         if address >= 0x2000 {
-            return Err(MemoryWriteError);
+            return Err(MemoryWriteError::OutOfBounds(address));
         }
 
-        let address = address as usize & 0x1FFF; // address inside of the bank (up to 8KB)
-        let ram_address = (self.ram_bank << 13) | address;
+        let offset = address as usize & 0x1FFF; // address inside of the bank (up to 8KB)
+        let ram_address = (self.ram_bank << 13) | offset;
+        // the selected bank doesn't exist, which includes cartridges without any RAM
         let byte = self.ram.get_mut(ram_address)
-            .ok_or(MemoryWriteError)?;
-        let old_value = byte.clone();
+            .ok_or(MemoryWriteError::RamDisabled(address))?;
+        let old_value = *byte;
         *byte = value;
 
         Ok(old_value)
     }
+
+    pub fn fill_ram(&mut self, fill: RamFill) {
+        if !self.has_battery {
+            fill.fill(&mut self.ram);
+        }
+    }
+
     pub fn can_save(&self) -> bool {
-        self.has_battery && self.ram.len() > 0
+        self.has_battery && !self.ram.is_empty()
+    }
+
+    /// Get the size of the cartridge RAM in bytes
+    pub fn ram_size(&self) -> usize {
+        self.ram.len()
     }
 
-    // TODO - think about how this would interact with RTC functionality
     pub fn load_save(&mut self, save_data: Vec<u8>) -> Result<(), SaveError> {
         if !self.has_battery {
             return Err(SaveError::SavesNotSupported);
@@ -120,9 +178,68 @@ impl BankedRom {
         Ok(())
     }
 
-    // TODO - think about how this would interact with RTC functionality
     pub fn save(&self) -> Vec<u8> {
         self.ram.clone()
     }
+
+    /// Write the selected banks and the contents of RAM into a snapshot
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_u32(self.rom_bank as u32);
+        writer.write_u32(self.ram_bank as u32);
+        writer.write_bytes(&self.ram);
+    }
+
+    /// Restore the state written by `save_state`
+    pub fn load_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let rom_bank = reader.read_u32()? as usize;
+        let ram_bank = reader.read_u32()? as usize;
+        if rom_bank >= self.rom.len() / ROM_BANK_SIZE
+            || (ram_bank > 0 && ram_bank >= self.ram.len() / RAM_BANK_SIZE) {
+            return Err(SnapshotError::InvalidValue);
+        }
+        reader.read_into(&mut self.ram)?;
+        self.rom_bank = rom_bank;
+        self.ram_bank = ram_bank;
+
+        Ok(())
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_rom_bank_nonzero() {
+        let mut rom = vec![0; ROM_BANK_SIZE * 4];
+        rom[ROM_BANK_SIZE] = 0x11;
+        rom[ROM_BANK_SIZE * 2] = 0x22;
+        let mut banked_rom = BankedRom::new(rom, 4, 0, false, false).unwrap();
+
+        banked_rom.set_rom_bank_nonzero(2);
+        let bank2 = banked_rom.read_rom(0x4000);
+        banked_rom.set_rom_bank_nonzero(0);
+        let bank0 = banked_rom.read_rom(0x4000);
+
+        assert_eq!(bank2, Some(0x22), "Other banks should be selected as-is");
+        assert_eq!(bank0, Some(0x11), "Bank 0 should select bank 1 instead");
+    }
+
+    #[test]
+    fn test_read_past_loaded_rom() {
+        let mut rom = vec![0; ROM_BANK_SIZE * 2];
+        rom[ROM_BANK_SIZE + 0x42] = 0x42;
+        let mut banked_rom = BankedRom::new(rom, 4, 0, false, false).unwrap();
+
+        let bank1 = banked_rom.read_rom(0x4042);
+        banked_rom.set_rom_bank(2);
+        let bank2 = banked_rom.read_rom(0x4042);
+        banked_rom.set_rom_bank(3);
+        let bank3 = banked_rom.read_rom(0x7FFF);
+
+        assert_eq!(bank1, Some(0x42), "Banks in the file should be read normally");
+        assert_eq!(bank2, Some(0xFF), "Banks past the end of the file should be open bus");
+        assert_eq!(bank3, Some(0xFF), "The last allocated bank should be open bus");
+        assert_eq!(banked_rom.rom_size(), ROM_BANK_SIZE * 4, "All 4 banks should be allocated");
+    }
+}
@@ -1,7 +1,8 @@
 use crate::memory::cartridge::CartridgeMapper;
 use crate::memory::MemoryWriteError;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 
-use super::{LoadCartridgeError, SaveError};
+use super::{LoadCartridgeError, RamFill, SaveError};
 
 const ROM_SIZE: usize = 32768;
 const RAM_SIZE: usize = 8192;
@@ -22,7 +23,7 @@ impl RomOnlyCartridge {
         let ram = if has_ram { Some([0; RAM_SIZE]) } else { None };
         let mut rom = [0; ROM_SIZE];
 
-        if rom.len() > ROM_SIZE {
+        if rom_data.len() > ROM_SIZE {
             return Err(LoadCartridgeError::InvalidRomFile);
         }
 
@@ -37,6 +38,15 @@ impl RomOnlyCartridge {
             }
         )
     }
+
+    /// Map a headerless ROM (such as a small homebrew or test program) directly into memory
+    /// starting at address 0, without any RAM. Anything past the end of the given bytes reads
+    /// as 0.
+    ///
+    /// Returns an InvalidRomFile error if the ROM is larger than 32 KiB
+    pub fn from_raw(rom_data: Vec<u8>) -> Result<Self, LoadCartridgeError> {
+        RomOnlyCartridge::new(rom_data, false, false)
+    }
 }
 
 impl CartridgeMapper for RomOnlyCartridge {
@@ -46,8 +56,24 @@ impl CartridgeMapper for RomOnlyCartridge {
             .copied()
     }
 
-    fn write_rom(&mut self, _address: u16, _data: u8) -> Result<(), MemoryWriteError> {
-        Err(MemoryWriteError)
+    fn write_rom(&mut self, address: u16, _data: u8) -> Result<(), MemoryWriteError> {
+        Err(MemoryWriteError::ReadOnly(address))
+    }
+
+    fn rom_offset(&self, address: u16) -> Option<usize> {
+        let address = address as usize;
+        if address >= ROM_SIZE {
+            return None;
+        }
+        Some(address)
+    }
+
+    fn rom_size(&self) -> usize {
+        ROM_SIZE
+    }
+
+    fn mem_bank(&self) -> Option<usize> {
+        self.ram.map(|_| 0)
     }
 
     fn read_mem(&self, address: u16) -> Option<u8> {
@@ -60,15 +86,25 @@ impl CartridgeMapper for RomOnlyCartridge {
     fn write_mem(&mut self, address: u16, data: u8) -> Result<u8, MemoryWriteError> {
         match self.ram.as_mut() {
             Some(ram) => {
-                let address = address as usize;
-                let prev = ram.get(address)
-                    .ok_or(MemoryWriteError)?.clone();
-                let byte = ram.get_mut(address)
-                    .ok_or(MemoryWriteError)?;
+                let index = address as usize;
+                let prev = ram.get(index)
+                    .copied()
+                    .ok_or(MemoryWriteError::OutOfBounds(address))?;
+                let byte = ram.get_mut(index)
+                    .ok_or(MemoryWriteError::OutOfBounds(address))?;
                 *byte = data;
                 Ok(prev)
             },
-            None => Err(MemoryWriteError)
+            None => Err(MemoryWriteError::RamDisabled(address))
+        }
+    }
+
+    fn fill_ram(&mut self, fill: RamFill) {
+        if self.has_battery {
+            return;
+        }
+        if let Some(ram) = self.ram.as_mut() {
+            fill.fill(ram);
         }
     }
 
@@ -101,6 +137,21 @@ impl CartridgeMapper for RomOnlyCartridge {
             None => Vec::new()
         }
     }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new();
+        writer.write_bytes(&self.save());
+        writer.finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = SnapshotReader::new(state);
+        match self.ram.as_mut() {
+            Some(ram) => reader.read_into(ram)?,
+            None => reader.read_into(&mut [])?
+        }
+        reader.finish()
+    }
 }
 
 #[cfg(test)]
@@ -159,7 +210,27 @@ mod tests {
 
         let result = controller.write_rom(0, 12);
 
-        assert_eq!(result, Err(MemoryWriteError), "Writing to ROM is not supported");
+        assert_eq!(result, Err(MemoryWriteError::ReadOnly(0)), "Writing to ROM is not supported");
+    }
+
+    #[test]
+    fn test_raw_rom() {
+        let rom: Vec<u8> = (1..=16).collect();
+        let result = RomOnlyCartridge::from_raw(rom);
+        assert!(result.is_ok(), "Should be able to map a headerless ROM");
+        let controller = result.unwrap();
+
+        assert_eq!(controller.read_rom(0), Some(1), "ROM should be mapped starting at 0");
+        assert_eq!(controller.read_rom(15), Some(16), "ROM should be mapped directly");
+        assert_eq!(controller.read_rom(16), Some(0), "Bytes past the ROM should read as 0");
+        assert_eq!(controller.read_mem(0), None, "A raw ROM should not have any RAM");
+    }
+
+    #[test]
+    fn test_raw_rom_too_big() {
+        let result = RomOnlyCartridge::from_raw(vec![0; ROM_SIZE + 1]);
+
+        assert!(result.is_err(), "A raw ROM can't be bigger than 32 KiB");
     }
 
     #[test]
@@ -215,7 +286,10 @@ mod tests {
 
         let result = controller.write_mem(1024, 200);
 
-        assert!(result.is_err(), "Test writing when there is no RAM");
+        assert_eq!(
+            result, Err(MemoryWriteError::RamDisabled(1024)),
+            "Test writing when there is no RAM"
+        );
     }
 
     #[test]
@@ -226,6 +300,50 @@ mod tests {
 
         let result = controller.write_mem(0x2000, 200);
 
-        assert!(result.is_err(), "Test writing to invalid address")
+        assert_eq!(
+            result, Err(MemoryWriteError::OutOfBounds(0x2000)),
+            "Test writing to invalid address"
+        );
+    }
+
+    #[test]
+    fn test_fill_ram_without_battery() {
+        let rom = [0; ROM_SIZE];
+        let result = RomOnlyCartridge::new(rom.into(), true, false);
+        assert!(result.is_ok(), "Should be able to create ROM");
+        let mut controller = result.unwrap();
+
+        controller.fill_ram(RamFill::Value(0xFF));
+
+        assert_eq!(controller.read_mem(0), Some(0xFF), "Fresh RAM should hold the fill pattern");
+        assert_eq!(controller.read_mem(0x1FFF), Some(0xFF), "All of RAM should be filled");
+    }
+
+    #[test]
+    fn test_fill_ram_with_battery() {
+        let rom = [0; ROM_SIZE];
+        let mut ram = [0; RAM_SIZE];
+        ram[0x42] = 0x28;
+        let mut controller = init_rom(rom, Some(ram), true);
+
+        controller.fill_ram(RamFill::Value(0xFF));
+
+        assert_eq!(controller.read_mem(0x42), Some(0x28), "Battery RAM should keep the save");
+        assert_eq!(controller.read_mem(0), Some(0), "Battery RAM should not be filled");
+    }
+
+    #[test]
+    fn test_fill_ram_random() {
+        let rom = [0; ROM_SIZE];
+        let first = RomOnlyCartridge::new(rom.into(), true, false);
+        let second = RomOnlyCartridge::new(rom.into(), true, false);
+        assert!(first.is_ok() && second.is_ok(), "Should be able to create ROM");
+        let (mut first, mut second) = (first.unwrap(), second.unwrap());
+
+        first.fill_ram(RamFill::Random(42));
+        second.fill_ram(RamFill::Random(42));
+
+        assert_eq!(first.save(), second.save(), "The same seed should produce the same RAM");
+        assert!(first.save().iter().any(|byte| *byte != 0), "Random RAM shouldn't be zeroed");
     }
 }
@@ -1,31 +1,48 @@
-use crate::memory::{cartridge::{CartridgeMapper, LoadCartridgeError, RomOnlyCartridge, MBC1, MBC2, MBC3}, rtc::RealTimeClock};
+use crate::memory::cartridge::{
+    CartridgeMapper, HuC1, LoadCartridgeError, RomOnlyCartridge, MBC1, MBC2, MBC3
+};
+use crate::memory::rtc::RealTimeClock;
+
+use super::header::RomInfo;
+
+/// # MapperKind
+/// The family of memory bank controller a cartridge uses, without the extra hardware (RAM,
+/// battery, or RTC) it has alongside it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapperKind {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+    HuC1,
+    Unsupported(u8), // any other cartridge type (like MMM01 or HuC3), holding the raw type byte
+}
 
 impl TryFrom<Vec<u8>> for Box<dyn CartridgeMapper> {
     type Error = LoadCartridgeError;
 
     fn try_from(rom: Vec<u8>) -> Result<Self, Self::Error> {
-        let cartridge_type = rom.get(0x147)
-            .ok_or(LoadCartridgeError::InvalidRomFile)?;
-        let rom_size = rom.get(0x148)
-            .ok_or(LoadCartridgeError::InvalidRomFile)?;
-        let ram_size = rom.get(0x148)
-            .ok_or(LoadCartridgeError::InvalidRomFile)?;
-        let rom_banks = 2 << rom_size;
-        let mem_banks = match ram_size {
-            0 => 0,
-            1 ..= 2 => 1,
-            3 => 4,
-            4 => 16,
-            5 => 8,
-            _ => return Err(LoadCartridgeError::InvalidRomFile)
-        };
-        match cartridge_type {
+        let info = RomInfo::parse(&rom)?;
+        info.check_rom_size(&rom)?;
+        // the mappers count banks with a u8, and none of them can switch between 256 ROM banks
+        let rom_banks = u8::try_from(info.rom_banks()?)
+            .map_err(|_| LoadCartridgeError::UnsupportedType)?;
+        let mem_banks = info.ram_banks()? as u8;
+
+        match info.cartridge_type {
             0x00 => Ok(Box::new(RomOnlyCartridge::new(rom, false, false)?)),
             0x08 => Ok(Box::new(RomOnlyCartridge::new(rom, true, false)?)),
             0x09 => Ok(Box::new(RomOnlyCartridge::new(rom, true, true)?)),
+            // multi-game collections use the same cartridge types as a plain MBC1
+            0x01 if MBC1::is_multicart(&rom) => {
+                Ok(Box::new(MBC1::new_multicart(rom, rom_banks, 0, false)?))
+            },
             0x01 => Ok(Box::new(MBC1::new(rom, rom_banks, 0, false)?)),
             0x02 => Ok(Box::new(MBC1::new(rom, rom_banks, mem_banks, false)?)),
             0x03 => Ok(Box::new(MBC1::new(rom, rom_banks, mem_banks, true)?)),
+            // the MBC2 has its own RAM, so the header shouldn't declare any
+            0x05 | 0x06 if mem_banks != 0 => Err(LoadCartridgeError::InvalidRomFile),
             0x05 => Ok(Box::new(MBC2::new(rom, rom_banks, false)?)),
             0x06 => Ok(Box::new(MBC2::new(rom, rom_banks, true)?)),
             0x0F => Ok(
@@ -39,8 +56,245 @@ impl TryFrom<Vec<u8>> for Box<dyn CartridgeMapper> {
             0x11 => Ok(Box::new(MBC3::new(rom, rom_banks, 0, false, None)?)),
             0x12 => Ok(Box::new(MBC3::new(rom, rom_banks, mem_banks, false, None)?)),
             0x13 => Ok(Box::new(MBC3::new(rom, rom_banks, mem_banks, true, None)?)),
+            0xFF => Ok(Box::new(HuC1::new(rom, rom_banks, mem_banks, true)?)),
 
             _ => Err(LoadCartridgeError::UnsupportedType)
         }
     }
 }
+
+/// Find which mapper a ROM uses from the cartridge type in its header (0x147), without building
+/// the cartridge. This recognizes mappers that can't be built yet (like the MBC5).
+///
+/// Parameters:
+/// - `rom`: the full contents of the ROM, including the header
+///
+/// Returns the kind of mapper, or a RomTooShort error if the ROM is too small to contain a header
+pub fn detect_mapper(rom: &[u8]) -> Result<MapperKind, LoadCartridgeError> {
+    let info = RomInfo::parse(rom)?;
+
+    Ok(
+        match info.cartridge_type {
+            0x00 | 0x08 | 0x09 => MapperKind::RomOnly,
+            0x01..=0x03 => MapperKind::Mbc1,
+            0x05 | 0x06 => MapperKind::Mbc2,
+            0x0F..=0x13 => MapperKind::Mbc3,
+            0x19..=0x1E => MapperKind::Mbc5,
+            0xFF => MapperKind::HuC1,
+            cartridge_type => MapperKind::Unsupported(cartridge_type),
+        }
+    )
+}
+
+/// Build the cartridge for a ROM the same way as `try_from`, but only if the header checksum
+/// (0x14D) matches the rest of the header. Plenty of homebrew never sets the checksum, so
+/// `try_from` should be used to load those ROMs instead.
+///
+/// Parameters:
+/// - `rom`: the full contents of the ROM, including the header
+///
+/// Returns the cartridge, a ChecksumMismatch error if the checksum is wrong, or any error that
+/// `try_from` would return
+pub fn from_rom_checked(rom: Vec<u8>) -> Result<Box<dyn CartridgeMapper>, LoadCartridgeError> {
+    let info = RomInfo::parse(&rom)?;
+    if !info.verify_header_checksum(&rom) {
+        return Err(LoadCartridgeError::ChecksumMismatch);
+    }
+
+    rom.try_into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::cartridge::ROM_BANK_SIZE;
+    use crate::memory::cartridge::header::{HEADER_END, NINTENDO_LOGO};
+
+    use super::*;
+
+    // The size of a ROM with a ROM size of 0 in its header
+    const ROM_SIZE: usize = 2 * ROM_BANK_SIZE;
+
+    #[test]
+    fn test_rom_too_short() {
+        let result: Result<Box<dyn CartridgeMapper>, _> = vec![0; 16].try_into();
+
+        assert!(
+            matches!(result, Err(LoadCartridgeError::RomTooShort)),
+            "A ROM without room for a header should be rejected"
+        );
+    }
+
+    #[test]
+    fn test_header_only_rom() {
+        let result: Result<Box<dyn CartridgeMapper>, _> = vec![0; HEADER_END].try_into();
+
+        assert!(
+            matches!(result, Err(LoadCartridgeError::RomSizeMismatch)),
+            "A ROM containing just a header is smaller than its 2 banks"
+        );
+    }
+
+    #[test]
+    fn test_full_size_rom() {
+        let result: Result<Box<dyn CartridgeMapper>, _> = vec![0; ROM_SIZE].try_into();
+
+        assert!(result.is_ok(), "A ROM with as many banks as its header says should be loaded");
+    }
+
+    #[test]
+    fn test_truncated_rom() {
+        let mut rom = vec![0; ROM_SIZE];
+        rom[0x147] = 0x01; // MBC1
+        rom[0x148] = 0x05; // 64 ROM banks
+        let result: Result<Box<dyn CartridgeMapper>, _> = rom.try_into();
+
+        assert!(
+            matches!(result, Err(LoadCartridgeError::RomSizeMismatch)),
+            "A ROM with fewer banks than its header says shouldn't be padded out"
+        );
+    }
+
+    #[test]
+    fn test_mbc2_with_ram_size() {
+        let mut rom = vec![0; ROM_SIZE];
+        rom[0x147] = 0x06; // MBC2 + battery
+        rom[0x149] = 0x02; // 1 RAM bank
+        let result: Result<Box<dyn CartridgeMapper>, _> = rom.try_into();
+
+        assert!(
+            matches!(result, Err(LoadCartridgeError::InvalidRomFile)),
+            "An MBC2 has its own RAM, so the header shouldn't declare any"
+        );
+    }
+
+    #[test]
+    fn test_ram_size_from_header() {
+        let mut rom = vec![0; ROM_SIZE];
+        rom[0x147] = 0x03; // MBC1 + RAM + battery
+        rom[0x149] = 0x03; // 4 RAM banks
+        let result: Result<Box<dyn CartridgeMapper>, _> = rom.try_into();
+        assert!(result.is_ok(), "Should be able to load an MBC1 cartridge");
+        let mut cartridge = result.unwrap();
+
+        assert!(cartridge.write_rom(0x0000, 0x0A).is_ok());
+        assert!(cartridge.write_rom(0x6000, 0x01).is_ok());
+        assert!(cartridge.write_rom(0x4000, 0x03).is_ok());
+
+        assert_eq!(cartridge.mem_bank(), Some(3), "The RAM size should come from 0x149");
+    }
+
+    #[test]
+    fn test_checked_valid_header() {
+        let mut rom = vec![0; ROM_SIZE];
+        // 0 - (sum of the 25 bytes from 0x134 to 0x14C) - 25, where every byte is 0
+        rom[0x14D] = 0xE7;
+
+        let result = from_rom_checked(rom);
+
+        assert!(result.is_ok(), "A ROM with the right checksum should be loaded");
+    }
+
+    #[test]
+    fn test_checked_corrupted_header() {
+        let mut rom = vec![0; ROM_SIZE];
+        rom[0x14D] = 0xE7;
+        rom[0x134] = b'X'; // the title changed after the checksum was calculated
+
+        let checked_result = from_rom_checked(rom.clone());
+        let unchecked_result: Result<Box<dyn CartridgeMapper>, _> = rom.try_into();
+
+        assert!(
+            matches!(checked_result, Err(LoadCartridgeError::ChecksumMismatch)),
+            "A corrupted header should be rejected"
+        );
+        assert!(unchecked_result.is_ok(), "The unchecked path should still load the ROM");
+    }
+
+    #[test]
+    fn test_huc1_from_header() {
+        let mut rom = vec![0; ROM_SIZE];
+        rom[0x147] = 0xFF; // HuC1 + RAM + battery
+        rom[0x149] = 0x03; // 4 RAM banks
+        let result: Result<Box<dyn CartridgeMapper>, _> = rom.try_into();
+        assert!(result.is_ok(), "Should be able to load a HuC1 cartridge");
+        let mut cartridge = result.unwrap();
+
+        assert!(cartridge.write_rom(0x4000, 0x02).is_ok());
+
+        assert_eq!(cartridge.mem_bank(), Some(2), "Should switch RAM banks without enabling RAM");
+        assert!(cartridge.can_save(), "HuC1 cartridges have a battery");
+    }
+
+    #[test]
+    fn test_mbc1_multicart_from_header() {
+        let mut rom = vec![0; 64 * ROM_BANK_SIZE];
+        rom[0x147] = 0x01; // MBC1
+        rom[0x148] = 0x05; // 64 ROM banks
+        rom[0x13 * ROM_BANK_SIZE] = 0x13;
+        rom[0x23 * ROM_BANK_SIZE] = 0x23;
+        let plain_result: Result<Box<dyn CartridgeMapper>, _> = rom.clone().try_into();
+        // the second game in the collection has a header of its own
+        let logo_start = 0x10 * ROM_BANK_SIZE + 0x104;
+        rom[logo_start..logo_start + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        let multicart_result: Result<Box<dyn CartridgeMapper>, _> = rom.try_into();
+        assert!(plain_result.is_ok() && multicart_result.is_ok(), "Should load both cartridges");
+        let (mut plain, mut multicart) = (plain_result.unwrap(), multicart_result.unwrap());
+
+        for cartridge in [&mut plain, &mut multicart] {
+            assert!(cartridge.write_rom(0x4000, 0x01).is_ok());
+            assert!(cartridge.write_rom(0x2000, 0x03).is_ok());
+        }
+
+        assert_eq!(plain.read_rom(0x4000), Some(0x23), "A plain MBC1 should map bank 0x23");
+        assert_eq!(multicart.read_rom(0x4000), Some(0x13), "An MBC1M should map bank 0x13");
+    }
+
+    #[test]
+    fn test_detect_mapper() {
+        let cases = [
+            (0x00, MapperKind::RomOnly),
+            (0x01, MapperKind::Mbc1),
+            (0x02, MapperKind::Mbc1),
+            (0x03, MapperKind::Mbc1),
+            (0x05, MapperKind::Mbc2),
+            (0x06, MapperKind::Mbc2),
+            (0x08, MapperKind::RomOnly),
+            (0x09, MapperKind::RomOnly),
+            (0x0B, MapperKind::Unsupported(0x0B)), // MMM01
+            (0x0D, MapperKind::Unsupported(0x0D)), // MMM01 + RAM + battery
+            (0x0F, MapperKind::Mbc3),
+            (0x10, MapperKind::Mbc3),
+            (0x11, MapperKind::Mbc3),
+            (0x12, MapperKind::Mbc3),
+            (0x13, MapperKind::Mbc3),
+            (0x19, MapperKind::Mbc5),
+            (0x1B, MapperKind::Mbc5),
+            (0x1E, MapperKind::Mbc5),
+            (0x20, MapperKind::Unsupported(0x20)), // MBC6
+            (0x22, MapperKind::Unsupported(0x22)), // MBC7
+            (0xFC, MapperKind::Unsupported(0xFC)), // Pocket Camera
+            (0xFD, MapperKind::Unsupported(0xFD)), // Bandai TAMA5
+            (0xFE, MapperKind::Unsupported(0xFE)), // HuC3
+            (0xFF, MapperKind::HuC1),
+        ];
+
+        for (cartridge_type, kind) in cases {
+            let mut rom = vec![0; HEADER_END];
+            rom[0x147] = cartridge_type;
+
+            let result = detect_mapper(&rom);
+
+            assert_eq!(result.ok(), Some(kind), "Cartridge type {:#04X}", cartridge_type);
+        }
+    }
+
+    #[test]
+    fn test_detect_mapper_too_short() {
+        let result = detect_mapper(&[0; 16]);
+
+        assert!(
+            matches!(result, Err(LoadCartridgeError::RomTooShort)),
+            "A ROM without room for a header has no cartridge type"
+        );
+    }
+}
@@ -0,0 +1,290 @@
+use super::{LoadCartridgeError, ROM_BANK_SIZE};
+
+// The cartridge header ends at 0x014F, so any ROM with a header has to be at least this big
+pub(super) const HEADER_END: usize = 0x150;
+
+const LOGO_START: usize = 0x104;
+const TITLE_START: usize = 0x134;
+const TITLE_END: usize = 0x144;
+const CGB_FLAG: usize = 0x143;
+// Set in the CGB flag by games which support CGB features (with or without requiring them)
+const CGB_SUPPORTED: u8 = 0x80;
+const NEW_LICENSEE_START: usize = 0x144;
+const NEW_LICENSEE_END: usize = 0x146;
+const SGB_FLAG: usize = 0x146;
+const CARTRIDGE_TYPE: usize = 0x147;
+const ROM_SIZE: usize = 0x148;
+const RAM_SIZE: usize = 0x149;
+const DESTINATION_CODE: usize = 0x14A;
+const OLD_LICENSEE: usize = 0x14B;
+const HEADER_CHECKSUM: usize = 0x14D;
+const GLOBAL_CHECKSUM: usize = 0x14E;
+
+/// The bitmap which the boot ROM scrolls down the screen, and which it refuses to continue past
+/// unless the cartridge has an identical copy of it at 0x104-0x133
+pub(super) const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+    0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+    0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+    0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+// The CGB boot ROM only checks the top half of the logo
+const CGB_LOGO_CHECK_SIZE: usize = NINTENDO_LOGO.len() / 2;
+
+/// # RomInfo
+/// The information stored in a cartridge's header (0x100-0x14F), which describes the game and
+/// the hardware on the cartridge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RomInfo {
+    pub title: String,
+    pub cgb_flag: u8, // 0x80 if the game supports CGB features, 0xC0 if it requires a CGB
+    pub new_licensee: String, // the publisher, as 2 ASCII characters (only used with old code 0x33)
+    pub old_licensee: u8, // the publisher, or 0x33 to use the new licensee code instead
+    pub sgb_flag: u8, // 0x03 if the game supports SGB features
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    pub destination_code: u8, // 0x00 for Japan, 0x01 for everywhere else
+    pub header_checksum: u8,
+    pub global_checksum: u16, // the sum of every byte in the ROM other than the checksum itself
+}
+
+impl RomInfo {
+    /// Read the header of a ROM
+    ///
+    /// Parameters:
+    /// - `rom`: the full contents of the ROM
+    ///
+    /// Returns the header information, or a RomTooShort error if the ROM is too small to contain
+    /// a header
+    pub fn parse(rom: &[u8]) -> Result<RomInfo, LoadCartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(LoadCartridgeError::RomTooShort);
+        }
+
+        // the title is padded with zeroes when it doesn't fill the whole space
+        let title = rom[TITLE_START..TITLE_END].iter()
+            .take_while(|byte| **byte != 0)
+            .map(|byte| *byte as char)
+            .collect();
+
+        let new_licensee = rom[NEW_LICENSEE_START..NEW_LICENSEE_END].iter()
+            .map(|byte| *byte as char)
+            .collect();
+
+        Ok(
+            RomInfo {
+                title,
+                cgb_flag: rom[CGB_FLAG],
+                new_licensee,
+                old_licensee: rom[OLD_LICENSEE],
+                sgb_flag: rom[SGB_FLAG],
+                cartridge_type: rom[CARTRIDGE_TYPE],
+                rom_size: rom[ROM_SIZE],
+                ram_size: rom[RAM_SIZE],
+                destination_code: rom[DESTINATION_CODE],
+                header_checksum: rom[HEADER_CHECKSUM],
+                // unlike everything else, the global checksum is stored in big endian order
+                global_checksum: u16::from_be_bytes(
+                    [rom[GLOBAL_CHECKSUM], rom[GLOBAL_CHECKSUM + 1]]
+                ),
+            }
+        )
+    }
+
+    /// Get the number of 16 KiB ROM banks declared by the ROM size (0x148)
+    ///
+    /// Returns the number of banks, or an InvalidRomFile error if the size isn't a known value
+    pub fn rom_banks(&self) -> Result<usize, LoadCartridgeError> {
+        match self.rom_size {
+            // starting at 32 KiB, and doubling with each step up to 8 MiB
+            0..=8 => Ok(2 << self.rom_size),
+            _ => Err(LoadCartridgeError::InvalidRomFile)
+        }
+    }
+
+    /// Get the number of 8 KiB RAM banks declared by the RAM size (0x149). This doesn't count
+    /// RAM which is built into the mapper (like on an MBC2), since the header declares no RAM
+    /// for those cartridges.
+    ///
+    /// Returns the number of banks, or an InvalidRomFile error if the size isn't a known value
+    pub fn ram_banks(&self) -> Result<usize, LoadCartridgeError> {
+        match self.ram_size {
+            0 => Ok(0),
+            // 1 was only ever used for 2 KiB of RAM, which still takes up a bank
+            1..=2 => Ok(1),
+            3 => Ok(4),
+            4 => Ok(16),
+            5 => Ok(8),
+            _ => Err(LoadCartridgeError::InvalidRomFile)
+        }
+    }
+
+    /// Check that a ROM is exactly as big as the ROM size in its header says it is
+    ///
+    /// Parameters:
+    /// - `rom`: the full contents of the ROM
+    ///
+    /// Returns a RomSizeMismatch error if the ROM is bigger or smaller than its banks, or an
+    /// InvalidRomFile error if the ROM size isn't a known value
+    pub fn check_rom_size(&self, rom: &[u8]) -> Result<(), LoadCartridgeError> {
+        if rom.len() != self.rom_banks()? * ROM_BANK_SIZE {
+            return Err(LoadCartridgeError::RomSizeMismatch);
+        }
+        Ok(())
+    }
+
+    /// Check whether the header checksum which was parsed from the header matches the header
+    /// of the given ROM, by subtracting each byte from 0x134 to 0x14C (and 1 more for every
+    /// byte) from 0. The boot ROM locks up if it doesn't match, so a mismatch means the ROM is
+    /// corrupt (or never ran on hardware).
+    ///
+    /// Parameters:
+    /// - `rom`: the full contents of the ROM
+    ///
+    /// Returns false if the checksum doesn't match, or the ROM is too small to contain a header
+    pub fn verify_header_checksum(&self, rom: &[u8]) -> bool {
+        compute_header_checksum(rom) == Some(self.header_checksum)
+    }
+
+    /// Check whether a ROM contains the exact Nintendo logo which the DMG boot ROM requires
+    ///
+    /// Returns false if the logo doesn't match, or the ROM is too small to contain one
+    pub fn logo_matches(rom: &[u8]) -> bool {
+        rom.get(LOGO_START..LOGO_START + NINTENDO_LOGO.len())
+            .is_some_and(|logo| logo == NINTENDO_LOGO)
+    }
+
+    /// Whether the game supports CGB features, which is true for CGB-only games too
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & CGB_SUPPORTED != 0
+    }
+
+    /// Check whether a ROM would pass the CGB boot ROM's logo check, which only compares the
+    /// top half of the logo. Any ROM which passes `logo_matches` also passes this check.
+    pub fn cgb_logo_matches(rom: &[u8]) -> bool {
+        rom.get(LOGO_START..LOGO_START + CGB_LOGO_CHECK_SIZE)
+            .is_some_and(|logo| logo == &NINTENDO_LOGO[..CGB_LOGO_CHECK_SIZE])
+    }
+}
+
+/// Calculate the header checksum the same way the boot ROM does
+///
+/// Returns None if the ROM is too small to contain a header
+fn compute_header_checksum(rom: &[u8]) -> Option<u8> {
+    if rom.len() < HEADER_END {
+        return None;
+    }
+
+    let checksum = rom[TITLE_START..HEADER_CHECKSUM].iter()
+        .fold(0_u8, |checksum, byte| checksum.wrapping_sub(*byte).wrapping_sub(1));
+    Some(checksum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_rom() -> Vec<u8> {
+        let mut rom = vec![0; HEADER_END];
+        rom[LOGO_START..LOGO_START + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        rom
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let mut rom = init_rom();
+        rom[TITLE_START..TITLE_START + 6].copy_from_slice(b"TETRIS");
+        rom[CARTRIDGE_TYPE] = 0x13;
+        rom[ROM_SIZE] = 0x05;
+        rom[RAM_SIZE] = 0x03;
+
+        let result = RomInfo::parse(&rom);
+
+        assert!(result.is_ok(), "Should be able to parse a full header");
+        let info = result.unwrap();
+        assert_eq!(info.title, "TETRIS", "The title should stop at the padding");
+        assert_eq!(info.cartridge_type, 0x13);
+        assert_eq!(info.rom_size, 0x05);
+        assert_eq!(info.ram_size, 0x03);
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let mut rom = init_rom();
+        rom[CGB_FLAG] = 0x80;
+        rom[NEW_LICENSEE_START..NEW_LICENSEE_END].copy_from_slice(b"01");
+        rom[SGB_FLAG] = 0x03;
+        rom[DESTINATION_CODE] = 0x01;
+        rom[OLD_LICENSEE] = 0x33;
+        rom[HEADER_CHECKSUM] = 0xE7;
+        rom[GLOBAL_CHECKSUM] = 0x12;
+        rom[GLOBAL_CHECKSUM + 1] = 0x34;
+
+        let result = RomInfo::parse(&rom);
+
+        assert!(result.is_ok(), "Should be able to parse a full header");
+        let info = result.unwrap();
+        assert_eq!(info.cgb_flag, 0x80, "The game should support CGB features");
+        assert_eq!(info.new_licensee, "01", "The new licensee should be read as text");
+        assert_eq!(info.old_licensee, 0x33, "The old licensee should point to the new one");
+        assert_eq!(info.sgb_flag, 0x03, "The game should support SGB features");
+        assert_eq!(info.destination_code, 0x01, "The game should be sold outside of Japan");
+        assert_eq!(info.header_checksum, 0xE7);
+        assert_eq!(info.global_checksum, 0x1234, "The global checksum is big endian");
+    }
+
+    #[test]
+    fn test_parse_too_short() {
+        let result = RomInfo::parse(&[0; 16]);
+
+        assert!(matches!(result, Err(LoadCartridgeError::RomTooShort)));
+    }
+
+    #[test]
+    fn test_verify_header_checksum() {
+        let mut rom = init_rom();
+        rom[TITLE_START..TITLE_START + 6].copy_from_slice(b"TETRIS");
+        let sum: u32 = b"TETRIS".iter().map(|byte| *byte as u32).sum();
+        rom[HEADER_CHECKSUM] = (0_u32.wrapping_sub(sum + 25) & 0xFF) as u8;
+        let result = RomInfo::parse(&rom);
+        assert!(result.is_ok(), "Should be able to parse a full header");
+        let info = result.unwrap();
+
+        assert!(info.verify_header_checksum(&rom), "The parsed checksum should match");
+        rom[TITLE_START] = b'X';
+        assert!(!info.verify_header_checksum(&rom), "A changed title should break the checksum");
+        assert!(!info.verify_header_checksum(&rom[..0x14D]), "A cut off header can't match");
+    }
+
+    #[test]
+    fn test_logo_matches() {
+        let rom = init_rom();
+
+        assert!(RomInfo::logo_matches(&rom), "The exact logo should match");
+        assert!(RomInfo::cgb_logo_matches(&rom), "The exact logo should pass the CGB check");
+    }
+
+    #[test]
+    fn test_corrupted_logo() {
+        let mut rom = init_rom();
+        rom[LOGO_START + 4] ^= 0x01;
+
+        assert!(!RomInfo::logo_matches(&rom), "A single wrong byte should fail the check");
+        assert!(!RomInfo::cgb_logo_matches(&rom), "The top half should fail the CGB check");
+    }
+
+    #[test]
+    fn test_corrupted_logo_bottom_half() {
+        let mut rom = init_rom();
+        rom[LOGO_START + 40] ^= 0x01;
+
+        assert!(!RomInfo::logo_matches(&rom), "A single wrong byte should fail the check");
+        assert!(RomInfo::cgb_logo_matches(&rom), "The CGB shouldn't check the bottom half");
+    }
+
+    #[test]
+    fn test_logo_too_short() {
+        assert!(!RomInfo::logo_matches(&[0; 0x110]), "A cut off logo should never match");
+    }
+}
@@ -0,0 +1,255 @@
+use crate::memory::cartridge::CartridgeMapper;
+use crate::memory::MemoryWriteError;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+use super::bankedrom::BankedRom;
+use super::{LoadCartridgeError, RamFill, SaveError};
+
+// Writing this to 0x0000 - 0x1FFF maps the IR register over RAM, anything else maps RAM back
+const IR_SELECT: u8 = 0x0E;
+// The IR register reads as 0xC0 when no light is being received (and 0xC1 when it is)
+const IR_NO_LIGHT: u8 = 0xC0;
+
+/// # HuC1
+/// This struct represents a HuC1 (Hudson Soft) cartridge mapper. It switches ROM and RAM banks
+/// much like an MBC1, but RAM is always enabled, and the RAM region can be swapped out for an
+/// infrared port which lets two cartridges communicate.
+///
+/// There's no way for another cartridge to send light to this one yet, so the IR register
+/// always reports that no light is being received.
+pub struct HuC1 {
+    rom: BankedRom,
+    ir_mode: bool, // whether the IR register is mapped instead of RAM
+    ir_led: u8, // the last value written to the IR register (bit 0 turns the LED on)
+}
+
+impl HuC1 {
+    pub fn new(
+        rom: Vec<u8>, rom_banks: u8,
+        ram_banks: u8, has_battery: bool
+    ) -> Result<Self, LoadCartridgeError> where Self:Sized {
+        let rom = BankedRom::new(rom, rom_banks as usize, ram_banks as usize, has_battery, false)?;
+
+        Ok(
+            HuC1 {
+                rom,
+                ir_mode: false,
+                ir_led: 0,
+            }
+        )
+    }
+}
+
+impl CartridgeMapper for HuC1 {
+    fn read_rom(&self, address: u16) -> Option<u8> {
+        self.rom.read_rom(address)
+    }
+
+    fn rom_offset(&self, address: u16) -> Option<usize> {
+        self.rom.rom_offset(address)
+    }
+
+    fn rom_size(&self) -> usize {
+        self.rom.rom_size()
+    }
+
+    fn mem_bank(&self) -> Option<usize> {
+        if self.ir_mode {
+            return None;
+        }
+        self.rom.mem_bank()
+    }
+
+    fn write_rom(&mut self, address: u16, data: u8) -> Result<(), MemoryWriteError> {
+        match address {
+            // IR select region
+            0..=0x1FFF => {
+                self.ir_mode = data == IR_SELECT;
+                Ok(())
+            }
+            // ROM bank region
+            0x2000..=0x3FFF => {
+                self.rom.set_rom_bank_nonzero((data & 0x3F) as usize);
+                Ok(())
+            }
+            // RAM bank region
+            0x4000..=0x5FFF => {
+                self.rom.set_mem_bank((data & 0x03) as usize);
+                Ok(())
+            }
+            // nothing is connected here
+            0x6000..=0x7FFF => Ok(()),
+            _ => Err(MemoryWriteError::OutOfBounds(address))
+        }
+    }
+
+    fn read_mem(&self, address: u16) -> Option<u8> {
+        if self.ir_mode {
+            return Some(IR_NO_LIGHT);
+        }
+        self.rom.read_mem(address)
+    }
+
+    fn write_mem(&mut self, address: u16, data: u8) -> Result<u8, MemoryWriteError> {
+        if self.ir_mode {
+            let old_value = self.ir_led;
+            self.ir_led = data;
+            return Ok(old_value);
+        }
+        self.rom.write_mem(address, data)
+    }
+
+    fn fill_ram(&mut self, fill: RamFill) {
+        self.rom.fill_ram(fill);
+    }
+
+    fn can_save(&self) -> bool {
+        self.rom.can_save()
+    }
+
+    fn load_save(&mut self, save_data: Vec<u8>) -> Result<(), SaveError> {
+        self.rom.load_save(save_data)
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.rom.save()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new();
+        writer.write_bool(self.ir_mode);
+        writer.write_u8(self.ir_led);
+        self.rom.save_state(&mut writer);
+        writer.finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = SnapshotReader::new(state);
+        self.ir_mode = reader.read_bool()?;
+        self.ir_led = reader.read_u8()?;
+        self.rom.load_state(&mut reader)?;
+        reader.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::cartridge::{MemBank, RomBank, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+    use super::*;
+
+    fn init_mapper(rom: Vec<RomBank>, ram: Vec<MemBank>) -> HuC1 {
+        let sequential_rom = rom.concat();
+
+        let result = HuC1::new(sequential_rom, rom.len() as u8, ram.len() as u8, true);
+        assert!(result.is_ok(), "should be able to create ROM");
+        let mut cartridge = result.unwrap();
+
+        let save_result = cartridge.load_save(ram.concat());
+        assert!(save_result.is_ok(), "should be able to load memory for ROM");
+
+        cartridge
+    }
+
+    #[test]
+    fn test_read_rom_bank_0_after_switch() {
+        let mut rom = vec![[0; ROM_BANK_SIZE]; 64];
+        rom[0][0x42] = 28;
+        let mut mapper = init_mapper(rom, Vec::new());
+
+        let switch_result = mapper.write_rom(0x3000, 0x20);
+        let read_result = mapper.read_rom(0x42);
+
+        assert!(switch_result.is_ok(), "Should successfully switch banks");
+        assert_eq!(read_result, Some(28), "Should read correctly from bank 0");
+    }
+
+    #[test]
+    fn test_read_rom_after_bank_switch() {
+        let mut rom = vec![[0; ROM_BANK_SIZE]; 64];
+        rom[5][0x280] = 28;
+        let mut mapper = init_mapper(rom, Vec::new());
+
+        let switch_result = mapper.write_rom(0x3000, 5);
+        let read_result = mapper.read_rom(0x4280);
+
+        assert!(switch_result.is_ok(), "Should successfully switch banks");
+        assert_eq!(read_result, Some(28), "Should read correctly from switched bank");
+    }
+
+    #[test]
+    fn test_rom_bank_0_selects_bank_1() {
+        let mut rom = vec![[0; ROM_BANK_SIZE]; 4];
+        rom[1][0x280] = 28;
+        let mut mapper = init_mapper(rom, Vec::new());
+
+        assert!(mapper.write_rom(0x2000, 3).is_ok(), "Should successfully switch banks");
+        assert!(mapper.write_rom(0x2000, 0).is_ok(), "Should successfully switch banks");
+
+        assert_eq!(mapper.read_rom(0x4280), Some(28), "Bank 0 should be mapped as bank 1");
+    }
+
+    #[test]
+    fn test_rom_write_invalid_address() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 16];
+        let mut mapper = init_mapper(rom, Vec::new());
+
+        let result = mapper.write_rom(0x8000, 0xFF);
+
+        assert!(result.is_err(), "Should not write to invalid address");
+    }
+
+    #[test]
+    fn test_read_ram_banks() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 2];
+        let mut ram = vec![[0; RAM_BANK_SIZE]; 4];
+        let changed_values: Vec<(u16, u8)> = vec![(0x789, 42), (0x456, 43), (0x123, 44)];
+        ram[1][0x789] = 42;
+        ram[2][0x456] = 43;
+        ram[3][0x123] = 44;
+        let mut mapper = init_mapper(rom, ram);
+
+        for i in 1..4 {
+            let switch_result = mapper.write_rom(0x5000, i);
+            let read_result = mapper.read_mem(changed_values[(i - 1) as usize].0);
+
+            assert!(switch_result.is_ok(), "Should successfully switch to bank {i}");
+            assert_eq!(
+                read_result, Some(changed_values[(i - 1) as usize].1),
+                "Should successfully switch to bank {i}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_write_ram_banks() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 2];
+        let ram = vec![[0; RAM_BANK_SIZE]; 4];
+        let mut mapper = init_mapper(rom, ram);
+
+        assert!(mapper.write_rom(0x4000, 2).is_ok(), "Should switch to bank 2");
+        assert_eq!(mapper.write_mem(0x0123, 62), Ok(0), "RAM should be enabled without a write");
+        assert!(mapper.write_rom(0x4000, 0).is_ok(), "Should switch to bank 0");
+        assert_eq!(mapper.read_mem(0x0123), Some(0), "Bank 0 should be unchanged");
+        assert!(mapper.write_rom(0x4000, 2).is_ok(), "Should switch back to bank 2");
+
+        assert_eq!(mapper.read_mem(0x0123), Some(62), "Should read back the write to bank 2");
+        assert_eq!(mapper.save()[2 * RAM_BANK_SIZE + 0x0123], 62, "The write should be saved");
+    }
+
+    #[test]
+    fn test_ir_mode() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 2];
+        let mut ram = vec![[0; RAM_BANK_SIZE]; 1];
+        ram[0][0x42] = 28;
+        let mut mapper = init_mapper(rom, ram);
+
+        assert!(mapper.write_rom(0x0000, 0x0E).is_ok(), "Should be able to select IR mode");
+        assert_eq!(mapper.read_mem(0x42), Some(0xC0), "No light should be received");
+        assert_eq!(mapper.mem_bank(), None, "RAM shouldn't be mapped in IR mode");
+        assert!(mapper.write_mem(0x42, 0x01).is_ok(), "Should be able to turn the LED on");
+
+        assert!(mapper.write_rom(0x0000, 0x0A).is_ok(), "Should be able to select RAM mode");
+        assert_eq!(mapper.read_mem(0x42), Some(28), "RAM should be mapped again");
+    }
+}
@@ -1,6 +1,14 @@
 use std::cell::RefCell;
 use crate::memory::MemoryWriteError;
-use super::{bankedrom::BankedRom, CartridgeMapper, LoadCartridgeError, SaveError, ROM_BANK_SIZE};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use super::bankedrom::{nonzero_bank, BankedRom};
+use super::header::RomInfo;
+use super::{CartridgeMapper, LoadCartridgeError, RamFill, SaveError};
+use super::ROM_BANK_SIZE;
+
+// MBC1M collections are always 1 MiB, split into 4 games of 16 banks each
+const MULTICART_ROM_BANKS: usize = 64;
+const MULTICART_GAME_BANKS: usize = 16;
 
 /// # StorageMode
 /// An Enum representing the banking mode of an MBC1 Cartridge. 
@@ -13,7 +21,8 @@ use super::{bankedrom::BankedRom, CartridgeMapper, LoadCartridgeError, SaveError
 ///   with more than 512 KiB, there can only be 8 KiB of RAM, but using this mode will allow
 ///   the first half of the address space to be switched, allowing banks 0x20, 0x40, and 0x60
 ///   to be accessed where bank 0 would have been.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
 enum StorageMode {
     ROM = 0,
     RAM = 1,
@@ -36,7 +45,9 @@ pub struct MBC1 {
     rom_bank: u8,
     ram_bank: u8,
     ram_enabled: bool,
-    extra_storage: bool
+    extra_storage: bool, // whether the secondary register is wired to the ROM (>512 KiB)
+    secondary_rom_only: bool, // whether the ROM needs both secondary register bits (2 MiB)
+    multicart: bool, // whether this is an MBC1M, which leaves bit 4 of the ROM bank unwired
 }
 
 impl MBC1 {
@@ -64,19 +75,55 @@ impl MBC1 {
                 ram_bank: 0,
                 rom_bank: 1,
                 ram_enabled: false,
-                extra_storage: rom_banks > 32
+                extra_storage: rom_banks > 32,
+                secondary_rom_only: rom_banks > 64,
+                multicart: false,
             }
         )
     }
 
+    /// Constructor for an MBC1M, the MBC1 wiring used by multi-game collections. Only the lower
+    /// 4 bits of the ROM bank register are connected, and the secondary register becomes bits
+    /// 4-5 of the bank number instead of bits 5-6, so that it selects one of the games.
+    ///
+    /// Parameters: the same as `new`
+    ///
+    /// Returns a new cartridge, or an error if the ROM doesn't fit in the given banks
+    pub fn new_multicart(
+        rom: Vec<u8>, rom_banks: u8,
+        ram_banks: u8, has_battery: bool
+    ) -> Result<Self, LoadCartridgeError> {
+        Ok(
+            MBC1 {
+                multicart: true,
+                ..MBC1::new(rom, rom_banks, ram_banks, has_battery)?
+            }
+        )
+    }
+
+    /// Guess whether a ROM is an MBC1M collection, since the header doesn't say so. Every game
+    /// in a collection has its own header, so a 1 MiB ROM which has the Nintendo logo at the
+    /// start of the second game (bank 0x10) is treated as one.
+    ///
+    /// Parameters:
+    /// - `rom`: the full contents of the ROM
+    pub fn is_multicart(rom: &[u8]) -> bool {
+        let second_game = MULTICART_GAME_BANKS * ROM_BANK_SIZE;
+        rom.len() == MULTICART_ROM_BANKS * ROM_BANK_SIZE
+            && RomInfo::logo_matches(&rom[second_game..])
+    }
+
+    /// The number of bits the secondary register is shifted by to become the top of the ROM
+    /// bank number, and the mask of the ROM bank register bits below it
+    fn bank_wiring(&self) -> (u8, usize) {
+        if self.multicart { (4, 0x0F) } else { (5, 0x1F) }
+    }
+
     /// Set the lower 5 bits of the rom bank value
     fn set_lower_rom_bank(&mut self, data: u8) {
-        self.rom_bank = data & 0x1F;
         // hardware bug present in MBC1 cartridges, because the 0-comparison
         // only looks at the first 5 bits
-        if self.rom_bank == 0 {
-            self.rom_bank += 1;
-        }
+        self.rom_bank = nonzero_bank((data & 0x1F) as usize) as u8;
     }
 
     /// Set the upper 2 bits of the rom bank value, or the ram bank value
@@ -86,25 +133,24 @@ impl MBC1 {
     }
 
     fn get_mem_bank(&self) -> usize {
-        if self.storage_mode == StorageMode::ROM {
+        // 1 MiB cartridges only use the first secondary register bit for ROM banking, but 2 MiB
+        // cartridges need both of them, so there is nothing left over for switching RAM banks
+        if self.storage_mode == StorageMode::ROM || self.secondary_rom_only {
             return 0;
         }
         self.ram_bank as usize
     }
-}
 
-// TODO - worth noting that the logic for accessing ROM might still be off, I don't know if there
-// is a reliable knowing how the hardware on an individual cartridge is wired up for using the
-// extra 2 bit register for RAM vs. ROM
-impl CartridgeMapper for MBC1 {
-    fn read_rom(&self, address: u16) -> Option<u8> {
+    /// Switch the underlying ROM to whichever bank the given address currently maps to
+    fn select_rom_bank(&self, address: u16) {
         let mut bank = self.rom_bank as usize;
         let first_half = address < (ROM_BANK_SIZE as u16);
+        let (shift, mask) = self.bank_wiring();
 
-        // The first half is mapped to 0x00, 0x20, 0x40, or 0x60 when there are enough banks
-        // and the advanced banking mode is 0
+        // The first half is mapped to 0x00, 0x20, 0x40, or 0x60 (or 0x00, 0x10, 0x20, or 0x30
+        // on an MBC1M) when there are enough banks and the advanced banking mode is 1
         if first_half && self.storage_mode == StorageMode::RAM && self.extra_storage {
-            bank = (self.ram_bank << 5) as usize;
+            bank = (self.ram_bank << shift) as usize;
         }
         // the first half is always bank 0 when the advanced banking mode is disabled
         else if first_half {
@@ -112,14 +158,40 @@ impl CartridgeMapper for MBC1 {
         }
         else if self.extra_storage {
             // account for the offset in the internal index
-            bank = (self.ram_bank << 5) as usize | (bank & 0x1F);
+            bank = (self.ram_bank << shift) as usize | (bank & mask);
         }
 
         // TODO - should I be handling the case where a bank is out of bounds or is returning
         // "None" here fine?
+        self.rom.borrow_mut().set_rom_bank(bank);
+    }
+}
+
+// TODO - worth noting that the logic for accessing ROM might still be off, I don't know if there
+// is a reliable knowing how the hardware on an individual cartridge is wired up for using the
+// extra 2 bit register for RAM vs. ROM
+impl CartridgeMapper for MBC1 {
+    fn read_rom(&self, address: u16) -> Option<u8> {
+        self.select_rom_bank(address);
+        self.rom.borrow().read_rom(address)
+    }
+
+    fn rom_offset(&self, address: u16) -> Option<usize> {
+        self.select_rom_bank(address);
+        self.rom.borrow().rom_offset(address)
+    }
+
+    fn rom_size(&self) -> usize {
+        self.rom.borrow().rom_size()
+    }
+
+    fn mem_bank(&self) -> Option<usize> {
+        if !self.ram_enabled {
+            return None;
+        }
         let mut rom = self.rom.borrow_mut();
-        rom.set_rom_bank(bank);
-        rom.read_rom(address)
+        rom.set_mem_bank(self.get_mem_bank());
+        rom.mem_bank()
     }
 
     fn write_rom(&mut self, address: u16, data: u8) -> Result<(),MemoryWriteError> {
@@ -140,7 +212,7 @@ impl CartridgeMapper for MBC1 {
                 self.storage_mode = data.into();
                 Ok(())
             }
-            _ => Err(MemoryWriteError)
+            _ => Err(MemoryWriteError::OutOfBounds(address))
         }
     }
 
@@ -167,6 +239,11 @@ impl CartridgeMapper for MBC1 {
         rom.write_mem(address, data)
     }
 
+    fn fill_ram(&mut self, fill: RamFill) {
+        self.rom.get_mut()
+            .fill_ram(fill);
+    }
+
     fn can_save(&self) -> bool {
         self.rom.borrow()
             .can_save()
@@ -181,11 +258,34 @@ impl CartridgeMapper for MBC1 {
         self.rom.borrow()
             .save()
     }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new();
+        writer.write_u8(self.storage_mode as u8);
+        writer.write_u8(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_bool(self.ram_enabled);
+        self.rom.borrow()
+            .save_state(&mut writer);
+        writer.finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = SnapshotReader::new(state);
+        self.storage_mode = reader.read_u8()?.into();
+        self.rom_bank = reader.read_u8()?;
+        self.ram_bank = reader.read_u8()?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom.get_mut()
+            .load_state(&mut reader)?;
+        reader.finish()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::memory::cartridge::{MemBank, RomBank, RAM_BANK_SIZE};
+    use crate::memory::cartridge::header::NINTENDO_LOGO;
 
     use super::*;
 
@@ -202,6 +302,73 @@ mod tests {
         cartridge
     }
 
+    // A 1 MiB ROM where the first byte of every bank holds the bank's number
+    fn numbered_banks() -> Vec<RomBank> {
+        (0..64u8).map(|index| {
+            let mut bank = [0; ROM_BANK_SIZE];
+            bank[0] = index;
+            bank
+        }).collect()
+    }
+
+    #[test]
+    fn test_multicart_bank_wiring() {
+        let mut mbc1 = init_bank(numbered_banks(), vec!());
+        let result = MBC1::new_multicart(numbered_banks().concat(), 64, 0, false);
+        assert!(result.is_ok(), "Should create an MBC1M successfully");
+        let mut mbc1m = result.unwrap();
+
+        for bank in [&mut mbc1, &mut mbc1m] {
+            assert!(bank.write_rom(0x4000, 0x01).is_ok(), "Set the secondary register to 1");
+            assert!(bank.write_rom(0x2000, 0x13).is_ok(), "Set the ROM bank register to 0x13");
+        }
+
+        assert_eq!(mbc1.read_rom(0x4000), Some(0x33), "An MBC1 uses 5 bits of the ROM bank");
+        assert_eq!(mbc1m.read_rom(0x4000), Some(0x13), "An MBC1M only uses 4 bits of it");
+    }
+
+    #[test]
+    fn test_multicart_advanced_storage_mode() {
+        let mut mbc1 = init_bank(numbered_banks(), vec!());
+        let result = MBC1::new_multicart(numbered_banks().concat(), 64, 0, false);
+        assert!(result.is_ok(), "Should create an MBC1M successfully");
+        let mut mbc1m = result.unwrap();
+
+        for bank in [&mut mbc1, &mut mbc1m] {
+            assert!(bank.write_rom(0x6000, 0x01).is_ok(), "Change into advanced banking mode");
+            assert!(bank.write_rom(0x4000, 0x01).is_ok(), "Set the secondary register to 1");
+        }
+
+        assert_eq!(mbc1.read_rom(0x0000), Some(0x20), "An MBC1 maps bank 0x20 to the first half");
+        assert_eq!(mbc1m.read_rom(0x0000), Some(0x10), "An MBC1M maps the second game's bank 0");
+    }
+
+    #[test]
+    fn test_multicart_bank_0_remap() {
+        let result = MBC1::new_multicart(numbered_banks().concat(), 64, 0, false);
+        assert!(result.is_ok(), "Should create an MBC1M successfully");
+        let mut mbc1m = result.unwrap();
+
+        assert!(mbc1m.write_rom(0x4000, 0x02).is_ok(), "Set the secondary register to 2");
+        assert!(mbc1m.write_rom(0x2000, 0x10).is_ok(), "Set the ROM bank register to 0x10");
+
+        // the 0 check still looks at all 5 bits, but bit 4 isn't wired to the ROM
+        assert_eq!(mbc1m.read_rom(0x4000), Some(0x20), "Bank 0x10 should map to bank 0 of a game");
+    }
+
+    #[test]
+    fn test_is_multicart() {
+        let mut rom = vec!([0; ROM_BANK_SIZE]; 64).concat();
+        assert!(!MBC1::is_multicart(&rom), "A ROM with one header isn't a collection");
+
+        let logo_start = 0x10 * ROM_BANK_SIZE + 0x104;
+        rom[logo_start..logo_start + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        assert!(MBC1::is_multicart(&rom), "The second game should have a logo");
+
+        let half = rom[..32 * ROM_BANK_SIZE].to_vec();
+        assert!(!MBC1::is_multicart(&half), "Collections are always 1 MiB");
+    }
+
     #[test]
     fn test_storage_mode_ram_access() {
         let rom = vec!([0; ROM_BANK_SIZE]; 2);
@@ -293,6 +460,22 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_read_past_loaded_rom() {
+        let mut rom = vec![0; ROM_BANK_SIZE * 2];
+        rom[ROM_BANK_SIZE + 0x15] = 0x62;
+        let result = MBC1::new(rom, 4, 0, false);
+        assert!(result.is_ok(), "A ROM smaller than its banks should be padded out");
+        let mut mbc1 = result.unwrap();
+
+        let bank_1_result = mbc1.read_rom(0x4015);
+        assert!(mbc1.write_rom(0x2000, 0x3).is_ok(), "Change to ROM bank 3");
+        let bank_3_result = mbc1.read_rom(0x4015);
+
+        assert_eq!(bank_1_result, Some(0x62), "Banks in the file should be read normally");
+        assert_eq!(bank_3_result, Some(0xFF), "Banks past the end of the file should be open bus");
+    }
+
     #[test]
     fn test_64_rom_banks_basic_storage_mode() {
         let mut rom = vec!([0; ROM_BANK_SIZE]; 64);
@@ -337,6 +520,71 @@ mod tests {
         assert_eq!(result, Some(0x19), "Check that bank 0 switches in advanced storage mode");
     }
 
+    #[test]
+    fn test_2mb_advanced_storage_mode_reaches_bank_0x60() {
+        let mut rom = vec!([0; ROM_BANK_SIZE]; 128);
+        rom[0x60][0x10] = 0x60;
+        rom[0x61][0x10] = 0x61;
+        let ram = vec!();
+        let mut bank = init_bank(rom, ram);
+
+        assert!(bank.write_rom(0x6000, 1).is_ok(), "Change into advanced banking mode");
+        assert!(bank.write_rom(0x4000, 3).is_ok(), "Set the secondary register to 3");
+        let first_half_result = bank.read_rom(0x10);
+        let second_half_result = bank.read_rom(0x4010);
+
+        assert_eq!(first_half_result, Some(0x60), "First half should map to bank 0x60");
+        assert_eq!(second_half_result, Some(0x61), "Second half should map to bank 0x61");
+    }
+
+    #[test]
+    fn test_1mb_secondary_register_wraps() {
+        let mut rom = vec!([0; ROM_BANK_SIZE]; 64);
+        rom[0x20][0x10] = 0x20;
+        let ram = vec!();
+        let mut bank = init_bank(rom, ram);
+
+        assert!(bank.write_rom(0x6000, 1).is_ok(), "Change into advanced banking mode");
+        assert!(bank.write_rom(0x4000, 3).is_ok(), "Set the secondary register to 3");
+        let result = bank.read_rom(0x10);
+
+        assert_eq!(result, Some(0x20), "Bank 0x60 should wrap around to 0x20 in a 1 MiB ROM");
+    }
+
+    #[test]
+    fn test_1mb_advanced_storage_mode_switches_ram() {
+        let rom = vec!([0; ROM_BANK_SIZE]; 64);
+        let mut ram = vec!([0; RAM_BANK_SIZE]; 4);
+        ram[2][0x42] = 0x22;
+        let mut bank = init_bank(rom, ram);
+
+        assert!(bank.write_rom(0x0000, 0xA).is_ok(), "Enable RAM");
+        assert!(bank.write_rom(0x6000, 1).is_ok(), "Change into advanced banking mode");
+        assert!(bank.write_rom(0x4000, 2).is_ok(), "Set the secondary register to 2");
+        let result = bank.read_mem(0x42);
+
+        assert_eq!(result, Some(0x22), "1 MiB cartridges should still be able to bank RAM");
+    }
+
+    #[test]
+    fn test_2mb_advanced_storage_mode_keeps_ram_bank() {
+        let rom = vec!([0; ROM_BANK_SIZE]; 128);
+        let mut ram = vec!([0; RAM_BANK_SIZE]; 4);
+        ram[0][0x42] = 0x11;
+        ram[2][0x42] = 0x22;
+        let mut bank = init_bank(rom, ram);
+
+        assert!(bank.write_rom(0x0000, 0xA).is_ok(), "Enable RAM");
+        assert!(bank.write_rom(0x6000, 1).is_ok(), "Change into advanced banking mode");
+        assert!(bank.write_rom(0x4000, 2).is_ok(), "Set the secondary register to 2");
+        let result = bank.read_mem(0x42);
+
+        assert_eq!(
+            result, Some(0x11),
+            "The secondary register should only switch ROM banks in a 2 MiB cartridge"
+        );
+    }
+
     #[test]
     fn test_4_rom_banks_advanced_storage_mode() {
         let mut rom = vec!([0; ROM_BANK_SIZE]; 4);
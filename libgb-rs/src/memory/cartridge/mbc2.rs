@@ -1,8 +1,12 @@
 use crate::memory::MemoryWriteError;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 
-use super::{bankedrom::BankedRom, CartridgeMapper, LoadCartridgeError, SaveError, ROM_BANK_SIZE};
+use super::{bankedrom::BankedRom, CartridgeMapper, LoadCartridgeError, RamFill, SaveError};
+use super::ROM_BANK_SIZE;
 
 pub const MBC2_MEM_SIZE: usize = 512;
+/// The size of a save file with two 4-bit cells packed into each byte
+pub const MBC2_PACKED_SAVE_SIZE: usize = MBC2_MEM_SIZE / 2;
 
 pub struct MBC2 {
     rom: BankedRom,
@@ -35,9 +39,22 @@ impl CartridgeMapper for MBC2 {
         self.rom.read_rom(address)
     }
 
+    fn rom_offset(&self, address: u16) -> Option<usize> {
+        self.rom.rom_offset(address)
+    }
+
+    fn rom_size(&self) -> usize {
+        self.rom.rom_size()
+    }
+
+    fn mem_bank(&self) -> Option<usize> {
+        // the built-in RAM only has a single bank
+        self.ram_enabled.then_some(0)
+    }
+
     fn write_rom(&mut self, address: u16, data: u8) -> Result<(), MemoryWriteError> {
         if address > 0x7FFF {
-            return Err(MemoryWriteError);
+            return Err(MemoryWriteError::OutOfBounds(address));
         }
         if address >= (ROM_BANK_SIZE as u16) {
             return Ok(());
@@ -47,9 +64,7 @@ impl CartridgeMapper for MBC2 {
         if address & 0x0100 == 0 {
             self.ram_enabled = data == 0x0A; 
         } else {
-            let mut bank = data & 0x1F;
-            bank = if bank != 0 { bank } else { 1 };
-            self.rom.set_rom_bank(bank as usize);
+            self.rom.set_rom_bank_nonzero((data & 0x1F) as usize);
         }
         Ok(())
     }
@@ -69,9 +84,9 @@ impl CartridgeMapper for MBC2 {
             return Ok(0xFF)
         }
         // only use the first 9 bits since there are only 512 entries in memory
-        let address = (address & 0x1FF) as usize;
-        let half_byte = self.ram.get_mut(address)
-            .ok_or(MemoryWriteError)?;
+        let index = (address & 0x1FF) as usize;
+        let half_byte = self.ram.get_mut(index)
+            .ok_or(MemoryWriteError::OutOfBounds(address))?;
         let old_value = *half_byte;
 
         // only use the lower 4 bits of the address, leaving the rest as 0
@@ -81,8 +96,20 @@ impl CartridgeMapper for MBC2 {
         Ok(old_value)
     }
 
+    fn fill_ram(&mut self, fill: RamFill) {
+        if self.has_battery {
+            return;
+        }
+        fill.fill(&mut self.ram);
+        // each cell only holds 4 bits, the same as when it's written to
+        for cell in self.ram.iter_mut() {
+            *cell &= 0xF;
+        }
+    }
+
     fn can_save(&self) -> bool {
-        self.rom.can_save()
+        // the RAM is built into the MBC2 chip, so the banked ROM never has any RAM to save
+        self.has_battery
     }
 
     fn load_save(&mut self, save_data: Vec<u8>) -> Result<(), SaveError> {
@@ -94,16 +121,52 @@ impl CartridgeMapper for MBC2 {
             return Err(SaveError::SaveFileTooBig);
         }
 
-        // Can't just do a copy because the data needs to be only 4 bits
-        for idx in 0..save_data.len() {
-            self.ram[idx] = save_data[idx] & 0xF;
+        if save_data.len() == MBC2_PACKED_SAVE_SIZE {
+            // the first cell of each pair is in the lower half of the byte
+            for (cells, byte) in self.ram.chunks_exact_mut(2).zip(save_data) {
+                cells[0] = byte & 0xF;
+                cells[1] = byte >> 4;
+            }
+            return Ok(());
+        }
+
+        // Older saves have one cell per byte. Can't just do a copy because the data needs to be
+        // only 4 bits
+        for (cell, byte) in self.ram.iter_mut().zip(save_data) {
+            *cell = byte & 0xF;
         }
 
         Ok(())
     }
 
     fn save(&self) -> Vec<u8> {
-        self.ram.into()
+        if !self.has_battery {
+            return Vec::new();
+        }
+        // pack two cells into each byte, with the first cell in the lower half
+        self.ram.chunks_exact(2)
+            .map(|cells| (cells[1] << 4) | (cells[0] & 0xF))
+            .collect()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new();
+        writer.write_bool(self.ram_enabled);
+        writer.write_bytes(&self.ram);
+        self.rom.save_state(&mut writer);
+        writer.finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = SnapshotReader::new(state);
+        self.ram_enabled = reader.read_bool()?;
+        reader.read_into(&mut self.ram)?;
+        // each cell only holds 4 bits
+        for cell in self.ram.iter_mut() {
+            *cell &= 0xF;
+        }
+        self.rom.load_state(&mut reader)?;
+        reader.finish()
     }
 }
 
@@ -265,4 +328,68 @@ mod tests {
 
         assert_eq!(result, Ok(0xFF), "Should ignore writes when memory is disabled");
     }
+
+    #[test]
+    fn test_can_save_with_battery() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 2];
+        let mut ram = [0; MBC2_MEM_SIZE];
+        ram[0x43] = 0x7;
+        let mbc2 = init_mapper(rom, ram);
+
+        let save = mbc2.save();
+
+        assert!(mbc2.can_save(), "The built-in RAM should be saved with a battery");
+        assert_eq!(save.len(), MBC2_PACKED_SAVE_SIZE, "Two cells should be packed into a byte");
+        assert_eq!(save[0x21], 0x70, "Odd cells should be in the upper half of a byte");
+    }
+
+    #[test]
+    fn test_packed_save_round_trip() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 2];
+        let mut ram = [0; MBC2_MEM_SIZE];
+        ram[0] = 0x3;
+        ram[1] = 0xC;
+        ram[0x1FF] = 0xF;
+        let mbc2 = init_mapper(rom.clone(), ram);
+        let mut loaded = init_mapper(rom, [0; MBC2_MEM_SIZE]);
+
+        let save = mbc2.save();
+        let result = loaded.load_save(save.clone());
+
+        assert_eq!(save[0], 0xC3, "The first cell should be in the lower half");
+        assert!(result.is_ok(), "Should load a packed save");
+        assert_eq!(loaded.save(), save, "Loading a packed save should give back the same RAM");
+        assert_eq!(loaded.ram, ram, "Each cell should be unpacked");
+    }
+
+    #[test]
+    fn test_unpacked_save_round_trip() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 2];
+        let mut ram = [0; MBC2_MEM_SIZE];
+        ram[0] = 0xF3;
+        ram[0x1FF] = 0x0A;
+        let mbc2 = init_mapper(rom, ram);
+
+        let save = mbc2.save();
+
+        assert_eq!(mbc2.ram[0], 0x3, "Only the lower 4 bits of an older save should be loaded");
+        assert_eq!(mbc2.ram[0x1FF], 0xA, "The last cell of an older save should be loaded");
+        assert_eq!(save[0], 0x03, "The upper bits shouldn't be packed into the next cell");
+        assert_eq!(save[0xFF], 0xA0, "The last cell should be packed into the last byte");
+    }
+
+    #[test]
+    fn test_no_battery() {
+        let rom = vec![0; ROM_BANK_SIZE * 2];
+        let mut mbc2 = MBC2::new(rom, 2, false).unwrap();
+
+        let result = mbc2.load_save(vec![0; MBC2_MEM_SIZE]);
+
+        assert!(
+            matches!(result, Err(SaveError::SavesNotSupported)),
+            "Can't load a save without a battery"
+        );
+        assert!(!mbc2.can_save(), "Can't save without a battery");
+        assert!(mbc2.save().is_empty(), "Nothing should be saved without a battery");
+    }
 }
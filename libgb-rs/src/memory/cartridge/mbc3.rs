@@ -1,9 +1,10 @@
 use crate::memory::cartridge::CartridgeMapper;
-use crate::memory::rtc::RealTimeClock;
+use crate::memory::rtc::{Clock, RealTimeClock};
 use crate::memory::MemoryWriteError;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
 
 use super::bankedrom::BankedRom;
-use super::LoadCartridgeError;
+use super::{LoadCartridgeError, RamFill, SaveError};
 
 /// # MBC3
 /// This struct represents an MBC3 (Memory Bank Controller 3) cartridge mapper for a DMG or CGB 
@@ -42,12 +43,27 @@ impl CartridgeMapper for MBC3 {
         self.rom.read_rom(address)
     }
 
+    fn rom_offset(&self, address: u16) -> Option<usize> {
+        self.rom.rom_offset(address)
+    }
+
+    fn rom_size(&self) -> usize {
+        self.rom.rom_size()
+    }
+
+    fn mem_bank(&self) -> Option<usize> {
+        // banks past 3 map RTC registers instead of RAM
+        if !self.ram_enabled || self.ram_bank > 3 {
+            return None;
+        }
+        self.rom.mem_bank()
+    }
+
     fn write_rom(&mut self, address: u16, data: u8) -> Result<(), MemoryWriteError> {
-        let address = address as usize;
         match address {
             // RAM enable region
             0..=0x1FFF => {
-                self.ram_enabled = data == 0xA0;
+                self.ram_enabled = (data & 0x0F) == 0x0A;
                 Ok(())
             }
             // ROM bank region
@@ -66,16 +82,17 @@ impl CartridgeMapper for MBC3 {
                 if data == 0 {
                     self.latching = true;
                 } else if data == 1 && self.latching {
-                    self.rtc.as_mut()
-                        .ok_or(MemoryWriteError)?
-                        .latch();
+                    // a cartridge without an RTC has nothing to latch, so it ignores the write
+                    if let Some(rtc) = self.rtc.as_mut() {
+                        rtc.latch();
+                    }
                     self.latching = false;
                 } else {
                     self.latching = false;
                 }
                 Ok(())
             }
-            _ => Err(MemoryWriteError)
+            _ => Err(MemoryWriteError::OutOfBounds(address))
         }
     }
 
@@ -104,33 +121,89 @@ impl CartridgeMapper for MBC3 {
         // First 4 banks correspond to RAM, 0x8 -> 0xC correspond to RTC registers
         match self.ram_bank {
             0..=3 => self.rom.write_mem(address, data),
-            8 => Ok(self.rtc.as_mut().ok_or(MemoryWriteError)?.set_seconds(data)),
-            9 => Ok(self.rtc.as_mut().ok_or(MemoryWriteError)?.set_minutes(data)),
-            0xA => Ok(self.rtc.as_mut().ok_or(MemoryWriteError)?.set_hours(data)),
-            0xB => Ok(self.rtc.as_mut().ok_or(MemoryWriteError)?.set_days_lower(data)),
-            0xC => Ok(self.rtc.as_mut().ok_or(MemoryWriteError)?.set_days_upper(data)),
-            _ => Err(MemoryWriteError)
+            bank @ 8..=0xC => {
+                let rtc = self.rtc.as_mut()
+                    .ok_or(MemoryWriteError::RamDisabled(address))?;
+                Ok(match bank {
+                    8 => rtc.set_seconds(data),
+                    9 => rtc.set_minutes(data),
+                    0xA => rtc.set_hours(data),
+                    0xB => rtc.set_days_lower(data),
+                    _ => rtc.set_days_upper(data),
+                })
+            }
+            _ => Err(MemoryWriteError::RamDisabled(address))
         }
     }
 
+    fn fill_ram(&mut self, fill: RamFill) {
+        self.rom.fill_ram(fill);
+    }
+
     fn can_save(&self) -> bool {
-        self.rom.can_save()
+        // the clock is only there on cartridges with a battery to keep it running
+        self.rom.can_save() || self.rtc.is_some()
     }
 
+    fn set_rtc_clock(&mut self, clock: Box<dyn Clock>) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.set_clock(clock);
+        }
+    }
+
+    /// Save the RAM, followed by the clock (see `RealTimeClock::save`) if there is one
     fn save(&self) -> Vec<u8> {
-        // TODO - figure out RTC stuff
-        self.rom.save()
+        let mut save = self.rom.save();
+        if let Some(rtc) = self.rtc.as_ref() {
+            save.extend(rtc.save());
+        }
+        save
     }
 
-    fn load_save(&mut self,save_data:Vec<u8>) -> Result<(),super::SaveError> {
-        // TODO - figure out RTC stuff
+    /// Load the RAM, along with the clock if the save is long enough to have one after the RAM
+    fn load_save(&mut self, mut save_data: Vec<u8>) -> Result<(), SaveError> {
+        let ram_size = self.rom.ram_size();
+        if let Some(rtc) = self.rtc.as_mut().filter(|_| save_data.len() > ram_size) {
+            rtc.restore(&save_data[ram_size..])
+                .ok_or(SaveError::SaveFileTooBig)?;
+            save_data.truncate(ram_size);
+        }
+
         self.rom.load_save(save_data)
     }
+
+    /// Save the banking registers and RAM, followed by the clock if there is one
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new();
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.ram_bank);
+        writer.write_bool(self.latching);
+        self.rom.save_state(&mut writer);
+        if let Some(rtc) = self.rtc.as_ref() {
+            writer.write_bytes(&rtc.save());
+        }
+        writer.finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = SnapshotReader::new(state);
+        self.ram_enabled = reader.read_bool()?;
+        self.ram_bank = reader.read_u8()?;
+        self.latching = reader.read_bool()?;
+        self.rom.load_state(&mut reader)?;
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.restore(reader.read_bytes()?)
+                .ok_or(SnapshotError::InvalidValue)?;
+        }
+        reader.finish()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::memory::cartridge::{MemBank, RomBank, RAM_BANK_SIZE, ROM_BANK_SIZE};
+    use crate::memory::rtc::RTC_SAVE_SIZE;
+    use crate::test_utils::StoppedClock;
 
     use super::*;
 
@@ -204,6 +277,18 @@ mod tests {
         assert!(result.is_err(), "Should not write to invalid address");
     }
 
+    #[test]
+    fn test_latch_without_rtc() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 16];
+        let mut mapper = init_mapper(rom, Vec::new(), None);
+
+        let prepare_result = mapper.write_rom(0x6000, 0);
+        let latch_result = mapper.write_rom(0x6000, 1);
+
+        assert!(prepare_result.is_ok(), "Should be able to prepare a latch");
+        assert!(latch_result.is_ok(), "Latching without an RTC should be ignored");
+    }
+
     #[test]
     fn test_read_ram_bank_0() {
         let rom = vec![[0; ROM_BANK_SIZE]; 16];
@@ -211,7 +296,7 @@ mod tests {
         ram[0][0x0315] = 62;
         let mut mapper = init_mapper(rom, ram, None);
 
-        let enable_result = mapper.write_rom(0x1000, 0xA0);
+        let enable_result = mapper.write_rom(0x1000, 0x0A);
         let read_result = mapper.read_mem(0x0315);
 
         assert!(enable_result.is_ok(), "Should enable RAM successfully");
@@ -228,7 +313,7 @@ mod tests {
         ram[3][0x123] = 44;
         let mut mapper = init_mapper(rom, ram, None);
 
-        let _ = mapper.write_rom(0x1000, 0xA0);
+        let _ = mapper.write_rom(0x1000, 0x0A);
 
         for i in 1..4 {
             let switch_result = mapper.write_rom(0x5000, i);
@@ -249,7 +334,7 @@ mod tests {
         let rtc = RealTimeClock::new(Some(1), Some(2), Some(3), Some(4), Some(5));
         let mut mapper = init_mapper(rom, ram, Some(rtc));
 
-        assert!(mapper.write_rom(0x1000, 0xA0).is_ok());
+        assert!(mapper.write_rom(0x1000, 0x0A).is_ok());
 
         assert!(mapper.write_rom(0x5000, 8).is_ok());
         assert_eq!(mapper.read_mem(0x0), Some(1), "Check seconds register");
@@ -269,13 +354,32 @@ mod tests {
         let ram = vec![[0; RAM_BANK_SIZE]; 1];
         let mut mapper = init_mapper(rom, ram, None);
 
-        let enable_result = mapper.write_rom(0x1000, 0xA0);
+        let enable_result = mapper.write_rom(0x1000, 0x0A);
         let result = mapper.read_mem(0x2000);
 
         assert!(enable_result.is_ok(), "Should be able to enable RAM");
         assert!(result.is_none(), "Should not read invalid address");
     }
 
+    #[test]
+    fn test_ram_enable_uses_low_nibble() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 2];
+        let mut ram = vec![[0; RAM_BANK_SIZE]; 1];
+        ram[0][0x42] = 0x28;
+        let mut mapper = init_mapper(rom, ram, None);
+
+        assert!(mapper.write_rom(0x0000, 0x0A).is_ok());
+        let enabled_result = mapper.read_mem(0x42);
+        assert!(mapper.write_rom(0x1FFF, 0xA0).is_ok());
+        let disabled_result = mapper.read_mem(0x42);
+        assert!(mapper.write_rom(0x1FFF, 0x1A).is_ok());
+        let upper_nibble_result = mapper.read_mem(0x42);
+
+        assert_eq!(enabled_result, Some(0x28), "Writing 0x0A should enable RAM");
+        assert_eq!(disabled_result, Some(0xFF), "Writing 0xA0 should disable RAM");
+        assert_eq!(upper_nibble_result, Some(0x28), "The upper nibble should be ignored");
+    }
+
     #[test]
     fn test_read_ram_disabled() {
         let rom = vec![[0; ROM_BANK_SIZE]; 2];
@@ -294,7 +398,7 @@ mod tests {
         ram[0][0x123] = 6;
         let mut mapper = init_mapper(rom, ram, None);
 
-        let enable_result = mapper.write_rom(0x1234, 0xA0);
+        let enable_result = mapper.write_rom(0x1234, 0x0A);
         let write_result = mapper.write_mem(0x0123, 5);
         let value_written = mapper.read_mem(0x123);
 
@@ -309,7 +413,7 @@ mod tests {
         let ram = vec![[0; RAM_BANK_SIZE]; 4];
         let mut mapper = init_mapper(rom, ram, None);
 
-        assert!(mapper.write_rom(0x0, 0xA0).is_ok());
+        assert!(mapper.write_rom(0x0, 0x0A).is_ok());
 
         for i in 1..4 {
             assert!(mapper.write_rom(0x4040, i).is_ok(), "Should switch to bank {i}");
@@ -331,7 +435,7 @@ mod tests {
         let rtc = RealTimeClock::new(None, None, None, None, Some(0x40));
         let mut mapper = init_mapper(rom, ram, Some(rtc));
 
-        assert!(mapper.write_rom(0x0500, 0xA0).is_ok());
+        assert!(mapper.write_rom(0x0500, 0x0A).is_ok());
 
         assert!(mapper.write_rom(0x5FFF, 8).is_ok());
         assert_eq!(mapper.write_mem(0, 5), Ok(0), "Write to seconds register");
@@ -350,6 +454,78 @@ mod tests {
         assert_eq!(mapper.read_mem(0), Some(0x41), "Check upper day value");
     }
 
+    #[test]
+    fn test_write_ram_rtc_returns_previous_value() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 2];
+        let ram = vec![[0; RAM_BANK_SIZE]; 1];
+        let rtc = RealTimeClock::new(Some(12), Some(34), Some(5), Some(0x67), Some(0x41));
+        let mut mapper = init_mapper(rom, ram, Some(rtc));
+        assert!(mapper.write_rom(0x0000, 0x0A).is_ok(), "Enable RAM");
+
+        for bank in 0x8..=0xC {
+            assert!(mapper.write_rom(0x4000, bank).is_ok(), "Select a clock register");
+            let before = mapper.read_mem(0);
+
+            let result = mapper.write_mem(0, 0x01);
+
+            assert_eq!(
+                result.ok(), before,
+                "Writing to register {bank:#X} should return what it held before"
+            );
+        }
+        assert_eq!(
+            read_rtc_registers(&mut mapper), vec![Some(1); 5],
+            "Every register should hold the new value"
+        );
+    }
+
+    #[test]
+    fn test_write_ram_rtc_returns_masked_value() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 2];
+        let ram = vec![[0; RAM_BANK_SIZE]; 1];
+        let mut mapper = init_mapper(rom, ram, Some(RealTimeClock::manual()));
+        assert!(mapper.write_rom(0x0000, 0x0A).is_ok(), "Enable RAM");
+        assert!(mapper.write_rom(0x4000, 0x8).is_ok(), "Select the seconds register");
+
+        assert_eq!(mapper.write_mem(0, 0xFF), Ok(0), "The seconds should start at 0");
+        let read = mapper.read_mem(0);
+        let result = mapper.write_mem(0, 0);
+
+        assert_eq!(read, Some(0x3F), "The seconds register is only 6 bits");
+        assert_eq!(result.ok(), read, "A write should return the register, not the last write");
+    }
+
+    #[test]
+    fn test_rtc_halt_through_days_upper_register() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 2];
+        let ram = vec![[0; RAM_BANK_SIZE]; 1];
+        let mut mapper = init_mapper(rom, ram, Some(RealTimeClock::default()));
+        assert!(mapper.write_rom(0x0000, 0x0A).is_ok(), "Enable RAM");
+
+        // halt the clock the same way a game would, then let time pass
+        assert!(mapper.write_rom(0x4000, 0xC).is_ok(), "Select the upper day register");
+        assert!(mapper.write_mem(0, 0x40).is_ok(), "Set the halt bit");
+        mapper.rtc.as_mut().unwrap().fake_elapsed(30);
+        assert!(mapper.write_rom(0x6000, 0).is_ok());
+        assert!(mapper.write_rom(0x6000, 1).is_ok(), "Latch the clock");
+        assert!(mapper.write_rom(0x4000, 0x8).is_ok(), "Select the seconds register");
+        let halted_seconds = mapper.read_mem(0);
+
+        // resume the clock and let more time pass
+        assert!(mapper.write_rom(0x4000, 0xC).is_ok(), "Select the upper day register");
+        assert!(mapper.write_mem(0, 0x00).is_ok(), "Clear the halt bit");
+        let upper_days = mapper.read_mem(0);
+        mapper.rtc.as_mut().unwrap().fake_elapsed(5);
+        assert!(mapper.write_rom(0x6000, 0).is_ok());
+        assert!(mapper.write_rom(0x6000, 1).is_ok(), "Latch the clock");
+        assert!(mapper.write_rom(0x4000, 0x8).is_ok(), "Select the seconds register");
+        let resumed_seconds = mapper.read_mem(0);
+
+        assert_eq!(halted_seconds, Some(0), "Seconds should not advance while halted");
+        assert_eq!(upper_days, Some(0), "Upper day register should not be halted anymore");
+        assert_eq!(resumed_seconds, Some(5), "Seconds should advance again after resuming");
+    }
+
     #[test]
     fn test_write_ram_disabled() {
         let rom = vec![[0; ROM_BANK_SIZE]; 2];
@@ -357,7 +533,7 @@ mod tests {
         let mut mapper = init_mapper(rom, ram, None);
 
         let result = mapper.write_mem(0x420, 42);
-        assert!(mapper.write_rom(0, 0xA0).is_ok());
+        assert!(mapper.write_rom(0, 0x0A).is_ok());
         let check_result = mapper.read_mem(0x420);
 
         assert_eq!(result, Ok(0xFF), "Writing when disabled should do nothing");
@@ -370,9 +546,66 @@ mod tests {
         let ram = vec![[0; RAM_BANK_SIZE]; 1];
         let mut mapper = init_mapper(rom, ram, None);
 
-        assert!(mapper.write_rom(0x0001, 0xA0).is_ok());
+        assert!(mapper.write_rom(0x0001, 0x0A).is_ok());
         let result = mapper.write_mem(0x2000, 42);
 
         assert!(result.is_err(), "Should not be able to write to an invalid address");
     }
+
+    /// Read every clock register from a mapper, in the order they are banked (seconds first)
+    fn read_rtc_registers(mapper: &mut MBC3) -> Vec<Option<u8>> {
+        (0x8..=0xC).map(|bank| {
+            assert!(mapper.write_rom(0x4000, bank).is_ok(), "Should be able to select the clock");
+            mapper.read_mem(0)
+        }).collect()
+    }
+
+    #[test]
+    fn test_save_and_load_rtc() {
+        let rom = vec![[0; ROM_BANK_SIZE]; 2];
+        let ram = vec![[0; RAM_BANK_SIZE]; 1];
+        // halted, so that no time passes between saving and loading
+        let rtc = RealTimeClock::new(None, None, None, None, Some(0x40));
+        let mut mapper = init_mapper(rom.clone(), ram.clone(), Some(rtc));
+        assert!(mapper.write_rom(0x0000, 0x0A).is_ok());
+        assert!(mapper.write_mem(0x42, 0x28).is_ok());
+        for (bank, value) in [(0x8, 30), (0x9, 6), (0xA, 5), (0xB, 2), (0xC, 0x41)] {
+            assert!(mapper.write_rom(0x4000, bank).is_ok());
+            assert!(mapper.write_mem(0, value).is_ok(), "Should be able to set the clock");
+        }
+
+        let save = mapper.save();
+        let mut loaded = init_mapper(rom, ram, Some(RealTimeClock::default()));
+        let result = loaded.load_save(save.clone());
+        assert!(loaded.write_rom(0x0000, 0x0A).is_ok());
+
+        assert_eq!(save.len(), RAM_BANK_SIZE + RTC_SAVE_SIZE, "The clock goes after the RAM");
+        assert!(result.is_ok(), "Should be able to load a save with a clock");
+        assert!(loaded.write_rom(0x4000, 0).is_ok());
+        assert_eq!(loaded.read_mem(0x42), Some(0x28), "RAM should be restored");
+        assert_eq!(
+            read_rtc_registers(&mut loaded),
+            vec![Some(30), Some(6), Some(5), Some(2), Some(0x41)],
+            "Every clock register should be restored"
+        );
+    }
+
+    #[test]
+    fn test_load_rtc_advances_clock() {
+        let save_time: u64 = 1_700_000_000;
+        let rom = vec![[0; ROM_BANK_SIZE]; 2];
+        let rtc = RealTimeClock::default().with_clock(Box::new(StoppedClock(save_time + 3600)));
+        let mut mapper = init_mapper(rom, Vec::new(), Some(rtc));
+        let mut save = vec![0; RTC_SAVE_SIZE - 8];
+        save.extend(save_time.to_le_bytes());
+
+        let result = mapper.load_save(save);
+        assert!(mapper.write_rom(0x0000, 0x0A).is_ok());
+        assert!(mapper.write_rom(0x6000, 0).is_ok() && mapper.write_rom(0x6000, 1).is_ok());
+
+        assert!(result.is_ok(), "Should be able to load a clock without any RAM");
+        let registers = read_rtc_registers(&mut mapper);
+        assert_eq!(registers[2], Some(1), "An hour should have passed since the save");
+        assert_eq!(registers[1], Some(0), "Only an hour should have passed since the save");
+    }
 }
@@ -1,13 +1,50 @@
-use cartridge::CartridgeMapper;
+use std::ops::Range;
+
+use cartridge::{CartridgeMapper, ROM_BANK_SIZE};
 use mockall::automock;
 
+use crate::cycles::{TCycles, T_CYCLES_PER_M_CYCLE};
+use crate::interrupt::INTERRUPT_FLAG;
+use crate::joypad::{Button, Joypad, P1};
+use crate::ppu::{Ppu, BCPS, LCDC, OAM_SIZE, OCPD, VBK, WX};
+use crate::serial::{Serial, SerialSink, SB, SC};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::timer::{Timer, DIV, TAC};
 use crate::utils::{Merge, Split};
 
 pub mod cartridge;
 pub mod rtc;
 
+/// # MemoryWriteError
+/// Why a write to memory failed, along with the address it was made to
 #[derive(Eq, PartialEq, Debug, Copy, Clone)]
-pub struct MemoryWriteError;
+pub enum MemoryWriteError {
+    OutOfBounds(u16), // nothing can be written at the address
+    ReadOnly(u16), // the address can be read, but nothing is connected to handle writes
+    RamDisabled(u16), // cartridge RAM was written without any RAM (or RTC register) mapped
+}
+
+impl MemoryWriteError {
+    /// Get the address the failed write was made to
+    pub fn address(&self) -> u16 {
+        match *self {
+            MemoryWriteError::OutOfBounds(address)
+                | MemoryWriteError::ReadOnly(address)
+                | MemoryWriteError::RamDisabled(address) => address
+        }
+    }
+
+    /// Get the same error for a different address. Components like the PPU and cartridge are
+    /// given addresses relative to the start of their region, so this turns them back into the
+    /// address the CPU used.
+    pub fn with_address(self, address: u16) -> Self {
+        match self {
+            MemoryWriteError::OutOfBounds(_) => MemoryWriteError::OutOfBounds(address),
+            MemoryWriteError::ReadOnly(_) => MemoryWriteError::ReadOnly(address),
+            MemoryWriteError::RamDisabled(_) => MemoryWriteError::RamDisabled(address),
+        }
+    }
+}
 
 /// A Trait representing the functionality needed for interacting with a Game Boy system's
 /// memory
@@ -45,9 +82,136 @@ pub trait MemoryController {
     /// If either byte in the 16-bit number occurs at an invalid location in memory,
     /// a MemoryWriteError with be returned.
     fn store_half_word(&mut self, address: u16, data: u16) -> Result<(), MemoryWriteError>;
+
+    /// Retrieve a run of bytes from memory, wrapping around from 0xFFFF to 0x0000
+    ///
+    /// `start`: the location of the first byte
+    /// `len`: the number of bytes to retrieve
+    ///
+    /// Returns the bytes, with 0xFF in place of any address which does not exist
+    fn load_slice(&self, start: u16, len: u16) -> Vec<u8> {
+        (0..len)
+            .map(|offset| self.load_byte(start.wrapping_add(offset)).unwrap_or(0xFF))
+            .collect()
+    }
+
+    /// Save a run of bytes into memory, wrapping around from 0xFFFF to 0x0000
+    ///
+    /// `start`: the location to save the first byte to
+    /// `data`: the bytes being saved into memory
+    ///
+    /// Returns a MemoryWriteError for the first byte at an invalid location. Every byte before
+    /// it has already been saved.
+    fn store_slice(&mut self, start: u16, data: &[u8]) -> Result<(), MemoryWriteError> {
+        for (offset, byte) in data.iter().enumerate() {
+            self.store_byte(start.wrapping_add(offset as u16), *byte)?;
+        }
+        Ok(())
+    }
+
+    /// Get the position in the cartridge ROM which the given address currently maps to
+    ///
+    /// `address`: the location in memory to look up
+    ///
+    /// Returns the index of the byte in the full ROM (including the selected bank), or `None`
+    /// if the address is not mapped to the ROM
+    fn rom_offset(&self, address: u16) -> Option<usize>;
+
+    /// Get the size of the cartridge ROM in bytes
+    fn rom_size(&self) -> usize;
+
+    /// Advance the hardware attached to memory (like the timer) by the given number of T-cycles,
+    /// requesting any interrupts it raises in the IF register. In double speed mode, the
+    /// hardware which runs off the CPU clock (everything but the PPU) sees twice as many.
+    fn tick(&mut self, cycles: TCycles);
+
+    /// Whether a CGB speed switch has been armed in KEY1, so that the next STOP switches speeds
+    fn speed_switch_armed(&self) -> bool;
+
+    /// Switch between normal speed and CGB double speed mode, which disarms the speed switch
+    /// in KEY1. This is done by the CPU when it runs STOP with the switch armed.
+    ///
+    /// `double_speed`: whether the CPU is now running in double speed mode
+    fn set_double_speed(&mut self, double_speed: bool);
+
+    /// Connect something to the serial port to receive the bytes sent over it
+    fn set_serial_sink(&mut self, sink: Box<dyn SerialSink>);
+
+    /// Press or release one of the buttons on the joypad
+    fn set_button(&mut self, button: Button, pressed: bool);
+
+    /// Get the last frame drawn by the PPU, as one shade (0-3) per pixel
+    fn framebuffer(&self) -> &[u8];
+
+    /// Copy bytes straight into VRAM, ignoring anything that would stop the CPU from writing
+    /// there. This is meant for setting up tiles and tile maps in tests.
+    ///
+    /// `offset`: where to start copying to, relative to the start of VRAM (0x8000)
+    /// `data`: the bytes to copy
+    ///
+    /// Returns a MemoryWriteError if the bytes don't fit in VRAM, in which case nothing is copied
+    ///
+    /// By default this goes through `store_slice`, so it can only write where the CPU could
+    fn load_vram(&mut self, offset: u16, data: &[u8]) -> Result<(), MemoryWriteError> {
+        let vram_size = (DMG_VRAM_END - DMG_VRAM_START) as usize + 1;
+        if offset as usize + data.len() > vram_size {
+            return Err(MemoryWriteError::OutOfBounds(vram_size as u16));
+        }
+        self.store_slice(DMG_VRAM_START + offset, data)
+    }
+
+    /// Replace the whole of OAM, ignoring anything that would stop the CPU (or OAM DMA) from
+    /// writing there. This is meant for setting up sprites in tests.
+    ///
+    /// By default this goes through `store_slice`, so it can only write where the CPU could
+    fn load_oam(&mut self, data: &[u8; OAM_SIZE]) {
+        // OAM always fits, so this can only stop early at a byte the CPU isn't able to write
+        let _ = self.store_slice(DMG_OAM_START, data);
+    }
+
+    /// Copy the entire address space, along with the banks which are currently mapped into the
+    /// switchable regions of memory
+    fn snapshot(&self) -> MemorySnapshot;
+
+    /// Get the full state of memory and all of the hardware attached to it (including the
+    /// cartridge), for a snapshot of the whole system
+    fn save_state(&self) -> Vec<u8>;
+
+    /// Restore the state returned by `save_state`
+    ///
+    /// `state`: the saved state, which should come from a system running the same cartridge
+    ///
+    /// Returns a SnapshotError if the state is invalid or was taken with a different cartridge
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError>;
+}
+
+/// # MemorySnapshot
+/// A copy of every byte in the 64 KiB address space, which also records which banks were mapped
+/// into the switchable regions (0x4000 - 0x7FFF for ROM, 0xA000 - 0xBFFF for cartridge RAM)
+/// when it was taken. This is intended for debuggers, since a flat dump loses that context.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemorySnapshot {
+    pub bytes: Vec<u8>, // every address from 0x0000 to 0xFFFF, with unreadable addresses as 0xFF
+    pub rom_bank: Option<usize>, // the ROM bank mapped into 0x4000 - 0x7FFF
+    pub mem_bank: Option<usize>, // the RAM bank mapped into 0xA000 - 0xBFFF, if RAM is mapped
+}
+
+impl MemorySnapshot {
+    /// Get the bank which the given address was read from
+    ///
+    /// Returns the bank number for addresses in one of the switchable regions, or None for
+    /// any other address
+    pub fn bank_at(&self, address: u16) -> Option<usize> {
+        match address {
+            DMG_ROM_BANK_START..=DMG_ROM_END => self.rom_bank,
+            DMG_EXT_START..=DMG_EXT_END => self.mem_bank,
+            _ => None
+        }
+    }
 }
 
 // Some memory map constants
+const DMG_ROM_BANK_START: u16 = 0x4000;
 const DMG_ROM_END: u16 = 0x7FFF;
 const DMG_VRAM_START: u16 = 0x8000;
 const DMG_VRAM_END: u16 = 0x9FFF;
@@ -55,121 +219,629 @@ const DMG_EXT_START: u16 = 0xA000;
 const DMG_EXT_END: u16 = 0xBFFF;
 const DMG_RAM_START: u16 = 0xC000;
 const DMG_RAM_END: u16 = 0xDFFF;
-const DMG_RES_START: u16 = 0xFE00;
-const DMG_RES_END: u16 = 0xFFFF;
+const DMG_ECHO_START: u16 = 0xE000;
+const DMG_ECHO_END: u16 = 0xFDFF;
+const DMG_OAM_START: u16 = 0xFE00;
+const DMG_OAM_END: u16 = 0xFE9F;
+const DMG_RES_START: u16 = 0xFEA0;
+const DMG_RES_END: u16 = 0xFEFF;
+const DMG_IO_START: u16 = 0xFF00;
+const DMG_IO_END: u16 = 0xFF7F;
+const DMG_HRAM_START: u16 = 0xFF80;
+const DMG_HRAM_END: u16 = 0xFFFE;
+const DMG_IE: u16 = 0xFFFF;
+
+// WRAM is split into 4 KiB banks, where 0xC000 - 0xCFFF is always bank 0. A DMG only has bank 1
+// to map at 0xD000 - 0xDFFF, but a CGB can switch between banks 1 - 7.
+const WRAM_BANK_SIZE: usize = 0x1000;
+const CGB_WRAM_BANKS: usize = 8;
+const CGB_RAM_SIZE: usize = WRAM_BANK_SIZE * CGB_WRAM_BANKS;
+const DMG_IO_SIZE: usize = (DMG_IO_END - DMG_IO_START + 1) as usize;
+const DMG_HRAM_SIZE: usize = (DMG_HRAM_END - DMG_HRAM_START + 1) as usize;
+
+// Writing anything other than 0 here unmaps the boot ROM until the next reset
+const BOOT_ROM_DISABLE: u16 = 0xFF50;
+
+// Writing XX here copies 0xXX00 - 0xXX9F into OAM, one byte per M-cycle
+const DMA: u16 = 0xFF46;
+// The highest page OAM DMA can copy from, since higher pages are mirrored down into echo RAM
+const DMA_LAST_SOURCE: u16 = 0xDF00;
+
+// On a CGB, bit 0 of this arms a speed switch for the next STOP, and bit 7 is set while the CPU
+// is in double speed mode
+pub const KEY1: u16 = 0xFF4D;
+const KEY1_SWITCH_ARMED: u8 = 0x01;
+const KEY1_DOUBLE_SPEED: u8 = 0x80;
+const KEY1_UNUSED: u8 = 0x7E;
+
+// On a CGB, the bottom 3 bits of this select the WRAM bank mapped at 0xD000 - 0xDFFF
+pub const SVBK: u16 = 0xFF70;
+const SVBK_UNUSED: u8 = 0xF8;
 
-const DMG_RAM_SIZE: usize = 8192;
-const DMG_VRAM_SIZE: usize = 8192;
-const DMG_RES_SIZE: usize = (DMG_RES_END - DMG_RES_START + 1) as usize;
+// The value read from an IO address which isn't connected to anything
+const UNMAPPED_IO_VALUE: u8 = 0xFF;
 
-/// A Struct Storing the memory of an original Game Boy (DMG) system
+/// # OpenBus
+/// What DmgMemoryController returns for reads from addresses that nothing is connected to, like
+/// the unusable area between OAM and IO, or cartridge RAM that doesn't exist
+#[derive(Eq, PartialEq, Debug, Copy, Clone, Default)]
+pub enum OpenBus {
+    #[default]
+    PullUp, // read 0xFF, the same as the pulled-up data bus on hardware
+    Fail, // read nothing, so the instruction doing the read fails
+}
+
+impl OpenBus {
+    /// Get the value of a read from an address that nothing is connected to
+    fn value(self) -> Option<u8> {
+        match self {
+            OpenBus::PullUp => Some(0xFF),
+            OpenBus::Fail => None,
+        }
+    }
+}
+
+/// Whether there is a register at the given IO address on a DMG. Reads from any other IO
+/// address return 0xFF, and writes to them are ignored.
+fn is_mapped_io(address: u16) -> bool {
+    matches!(
+        address,
+        0xFF00..=0xFF02 // joypad and serial
+            | 0xFF04..=0xFF07 // timer
+            | 0xFF0F // IF
+            | 0xFF10..=0xFF14 | 0xFF16..=0xFF1E | 0xFF20..=0xFF26 // sound
+            | 0xFF30..=0xFF3F // wave RAM
+            | 0xFF40..=0xFF4B // LCD
+            | 0xFF50 // boot ROM disable
+    )
+}
+
+/// The arrays in DmgMemoryController which a run of addresses can be copied straight out of
+enum BackingArray {
+    Ram,
+    Hram,
+}
+
+/// The progress of an OAM DMA transfer, which is saved in snapshots so that a transfer resumes
+/// where it left off. The CGB's VRAM DMA (HDMA) isn't emulated, so this is the only transfer
+/// that can be in progress.
+struct OamDma {
+    source: u16,
+    index: u16, // the next byte to copy
+    cycles: u32, // T-cycles which haven't been used to copy a byte yet
+}
+
+impl OamDma {
+    fn new(page: u8) -> Self {
+        // the DMA can't read above 0xDFFF, and sees echo RAM (a mirror of 0xC000 - 0xDDFF)
+        // there instead
+        let source = (page as u16) << 8;
+        let source = if source >= DMG_ECHO_START { source - 0x2000 } else { source };
+
+        OamDma { source, index: 0, cycles: 0 }
+    }
+
+    fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_u16(self.source);
+        writer.write_u16(self.index);
+        writer.write_u32(self.cycles);
+    }
+
+    fn load_state(reader: &mut SnapshotReader) -> Result<Self, SnapshotError> {
+        let source = reader.read_u16()?;
+        let index = reader.read_u16()?;
+        let cycles = reader.read_u32()?;
+        // `new` only starts transfers from the start of a page, and a tick leaves less than an
+        // M-cycle unused
+        if source > DMA_LAST_SOURCE
+            || source & 0xFF != 0
+            || index >= OAM_SIZE as u16
+            || cycles >= T_CYCLES_PER_M_CYCLE
+        {
+            return Err(SnapshotError::InvalidValue);
+        }
+
+        Ok(OamDma { source, index, cycles })
+    }
+}
+
+/// A Struct Storing the memory of an original Game Boy (DMG) system. When it's created with
+/// `new_cgb`, it also has the registers the CGB adds (like SVBK and VBK for banked WRAM and VRAM).
 pub struct DmgMemoryController {
     cartridge: Box<dyn CartridgeMapper>,
-    ram: [u8; DMG_RAM_SIZE],
-    vram: [u8; DMG_VRAM_SIZE],
-    system: [u8; DMG_RES_SIZE],
+    cgb: bool, // whether the CGB registers are mapped
+    ram: [u8; CGB_RAM_SIZE], // a DMG only uses the first 2 banks
+    wram_bank: u8, // the bank selected by SVBK, where 0 maps bank 1
+    io: [u8; DMG_IO_SIZE],
+    hram: [u8; DMG_HRAM_SIZE],
+    interrupt_enable: u8,
+    boot_rom: Option<Vec<u8>>, // mapped over the start of the cartridge ROM until it's disabled
+    joypad: Joypad,
+    timer: Timer,
+    serial: Serial,
+    ppu: Ppu, // owns VRAM and OAM
+    dma: Option<OamDma>, // the OAM DMA transfer in progress
+    speed_switch_armed: bool, // bit 0 of KEY1
+    double_speed: bool, // whether the CPU clock is running twice as fast as the PPU
+    open_bus: OpenBus,
 }
 
 impl DmgMemoryController {
     pub fn new(cartridge: Box<dyn CartridgeMapper>) -> DmgMemoryController {
         DmgMemoryController {
             cartridge,
-            ram: [0; DMG_VRAM_SIZE],
-            vram: [0; DMG_VRAM_SIZE],
-            system: [0; DMG_RES_SIZE],
+            cgb: false,
+            ram: [0; CGB_RAM_SIZE],
+            wram_bank: 0,
+            io: [0; DMG_IO_SIZE],
+            hram: [0; DMG_HRAM_SIZE],
+            interrupt_enable: 0,
+            boot_rom: None,
+            joypad: Joypad::new(),
+            timer: Timer::new(),
+            serial: Serial::new(),
+            ppu: Ppu::new(),
+            dma: None,
+            speed_switch_armed: false,
+            double_speed: false,
+            open_bus: OpenBus::default(),
         }
     }
-}
 
-impl MemoryController for DmgMemoryController {
-    fn load_byte(&self, address: u16) -> Option<u8> {
+    /// Create memory for a CGB, which maps the CGB registers (KEY1, SVBK, VBK and the palette
+    /// registers) on top of everything a DMG has. The rest of the CGB hardware isn't supported yet.
+    pub fn new_cgb(cartridge: Box<dyn CartridgeMapper>) -> DmgMemoryController {
+        DmgMemoryController {
+            cgb: true,
+            ..DmgMemoryController::new(cartridge)
+        }
+    }
+
+    /// Get the index into `ram` of an address in WRAM, based on the bank selected by SVBK
+    ///
+    /// Parameters:
+    /// - `offset`: the distance of the address from the start of WRAM (or echo RAM)
+    fn ram_index(&self, offset: u16) -> usize {
+        let offset = offset as usize;
+        if offset < WRAM_BANK_SIZE {
+            return offset;
+        }
+        // bank 0 is always mapped at 0xC000 - 0xCFFF, so selecting it maps bank 1 instead
+        let bank = (self.wram_bank as usize).max(1);
+        bank * WRAM_BANK_SIZE + offset - WRAM_BANK_SIZE
+    }
+
+    /// Find where a run of addresses is stored, if all of them are next to each other in one of
+    /// the plain arrays (a single bank of WRAM, or HRAM), so they can be copied all at once
+    ///
+    /// Parameters:
+    /// - `start`: the first address of the run
+    /// - `len`: the number of addresses in the run
+    ///
+    /// Returns the array and the range of indexes the run covers in it, or None if the run
+    /// needs to be accessed a byte at a time
+    fn backing_range(&self, start: u16, len: usize) -> Option<(BackingArray, Range<usize>)> {
+        if len == 0 || self.blocked_by_dma(start) {
+            return None;
+        }
+        let end = u16::try_from(start as usize + len - 1).ok()?;
+
+        match start {
+            DMG_RAM_START..=DMG_ECHO_END => {
+                let base = if start >= DMG_ECHO_START { DMG_ECHO_START } else { DMG_RAM_START };
+                let offset = start - base;
+                // the two banks which are mapped aren't always next to each other in `ram`
+                let bank_end = base + (offset | (WRAM_BANK_SIZE as u16 - 1));
+                if end > bank_end.min(DMG_ECHO_END) {
+                    return None;
+                }
+                let index = self.ram_index(offset);
+                Some((BackingArray::Ram, index..index + len))
+            }
+            DMG_HRAM_START..=DMG_HRAM_END if end <= DMG_HRAM_END => {
+                let index = (start - DMG_HRAM_START) as usize;
+                Some((BackingArray::Hram, index..index + len))
+            }
+            _ => None
+        }
+    }
+
+    /// Map a boot ROM over the start of the cartridge ROM (0x0000 - 0x00FF for a DMG). It stays
+    /// mapped until the program writes a non-zero value to 0xFF50, which the boot ROM does right
+    /// before jumping to the cartridge at 0x0100.
+    pub fn set_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+    }
+
+    /// Choose what reads from addresses that nothing is connected to return. By default they
+    /// return 0xFF, like hardware does.
+    pub fn set_open_bus(&mut self, open_bus: OpenBus) {
+        self.open_bus = open_bus;
+    }
+
+    fn read_boot_rom(&self, address: u16) -> Option<u8> {
+        self.boot_rom.as_ref()?
+            .get(address as usize)
+            .copied()
+    }
+
+    /// Whether the CPU is cut off from the given address by an OAM DMA transfer. The transfer
+    /// takes over the bus to everything outside of the CPU, so only IO registers, HRAM and IE
+    /// can still be used (which is why games run their DMA routine from HRAM).
+    fn blocked_by_dma(&self, address: u16) -> bool {
+        self.dma.is_some() && address < DMG_IO_START
+    }
+
+    /// Copy the bytes for however many M-cycles of an OAM DMA transfer have passed
+    fn tick_dma(&mut self, cycles: TCycles) {
+        let Some(mut dma) = self.dma.take() else {
+            return;
+        };
+
+        dma.cycles += cycles.0;
+        while dma.cycles >= T_CYCLES_PER_M_CYCLE && dma.index < OAM_SIZE as u16 {
+            dma.cycles -= T_CYCLES_PER_M_CYCLE;
+            let byte = self.read_byte(dma.source + dma.index)
+                .unwrap_or(UNMAPPED_IO_VALUE);
+            // the index is always inside of OAM, so this can't fail
+            let _ = self.ppu.write_oam(dma.index, byte);
+            dma.index += 1;
+        }
+
+        if dma.index < OAM_SIZE as u16 {
+            self.dma = Some(dma);
+        }
+    }
+
+    fn read_byte(&self, address: u16) -> Option<u8> {
         match address {
             0..=DMG_ROM_END => {
-                self.cartridge.read_rom(address)
+                self.read_boot_rom(address)
+                    .or_else(|| self.cartridge.read_rom(address))
             }
+            // cartridge RAM which is disabled or doesn't exist isn't connected to the bus
             DMG_EXT_START..=DMG_EXT_END => {
                 self.cartridge.read_mem(address - DMG_EXT_START)
+                    .or_else(|| self.open_bus.value())
             }
             DMG_VRAM_START..=DMG_VRAM_END => {
-                Some(self.vram[(address - DMG_VRAM_START) as usize])
+                self.ppu.read_vram(address - DMG_VRAM_START)
             }
             DMG_RAM_START..=DMG_RAM_END => {
-                Some(self.ram[(address - DMG_RAM_START) as usize])
+                Some(self.ram[self.ram_index(address - DMG_RAM_START)])
             }
-            DMG_RES_START..=DMG_RES_END => {
-                Some(self.system[(address - DMG_RES_START) as usize])
+            // echo RAM mirrors 0xC000 - 0xDDFF
+            DMG_ECHO_START..=DMG_ECHO_END => {
+                Some(self.ram[self.ram_index(address - DMG_ECHO_START)])
             }
-            _ => None
+            DMG_OAM_START..=DMG_OAM_END => {
+                self.ppu.read_oam(address - DMG_OAM_START)
+            }
+            DMG_RES_START..=DMG_RES_END => self.open_bus.value(),
+            DMG_IO_START..=DMG_IO_END => {
+                match address {
+                    P1 => self.joypad.read_register(address),
+                    SB..=SC => self.serial.read_register(address),
+                    DIV..=TAC => self.timer.read_register(address),
+                    LCDC..=WX => self.ppu.read_register(address)
+                        .or(Some(self.io[(address - DMG_IO_START) as usize])),
+                    KEY1 if self.cgb => {
+                        let speed = if self.double_speed { KEY1_DOUBLE_SPEED } else { 0 };
+                        let armed = if self.speed_switch_armed { KEY1_SWITCH_ARMED } else { 0 };
+                        Some(KEY1_UNUSED | speed | armed)
+                    }
+                    SVBK if self.cgb => Some(SVBK_UNUSED | self.wram_bank),
+                    VBK if self.cgb => self.ppu.read_register(address),
+                    BCPS..=OCPD if self.cgb => self.ppu.read_register(address),
+                    _ if !is_mapped_io(address) => Some(UNMAPPED_IO_VALUE),
+                    _ => Some(self.io[(address - DMG_IO_START) as usize])
+                }
+            }
+            DMG_HRAM_START..=DMG_HRAM_END => {
+                Some(self.hram[(address - DMG_HRAM_START) as usize])
+            }
+            DMG_IE => Some(self.interrupt_enable),
         }
     }
 
-    fn load_half_word(&self, address: u16) -> Option<u16> {
-        let right = self.load_byte(address)?;
-        let left = self.load_byte(address + 1)?;
-
-        Some(left.merge(right))
-    }
-
-    fn store_byte(&mut self, address: u16, data: u8) -> Result<u8, MemoryWriteError> {
+    fn write_byte(&mut self, address: u16, data: u8) -> Result<u8, MemoryWriteError> {
         match address {
             0..=DMG_ROM_END => {
                 self.cartridge.write_rom(address, data)
                     .map(|_| data)
             }
             DMG_VRAM_START..=DMG_VRAM_END => {
-                let address = (address - DMG_VRAM_START) as usize;
-                let prev = self.vram[address];
-                self.vram[address] = data;
-                Ok(prev)
+                self.ppu.write_vram(address - DMG_VRAM_START, data)
+                    .map_err(|err| err.with_address(address))
             }
             DMG_EXT_START..=DMG_EXT_END => {
                 self.cartridge.write_mem(address - DMG_EXT_START, data)
+                    .map_err(|err| err.with_address(address))
             }
             DMG_RAM_START..=DMG_RAM_END => {
-                let address = (address - DMG_RAM_START) as usize;
-                let prev = self.vram[address];
+                let address = self.ram_index(address - DMG_RAM_START);
+                let prev = self.ram[address];
                 self.ram[address] = data;
                 Ok(prev)
             }
-            DMG_RES_START..=DMG_RES_END => {
-                let address = (address - DMG_RES_START) as usize;
-                let prev = self.vram[address];
-                self.system[address] = data;
+            DMG_ECHO_START..=DMG_ECHO_END => {
+                let address = self.ram_index(address - DMG_ECHO_START);
+                let prev = self.ram[address];
+                self.ram[address] = data;
+                Ok(prev)
+            }
+            DMG_OAM_START..=DMG_OAM_END => {
+                self.ppu.write_oam(address - DMG_OAM_START, data)
+                    .map_err(|err| err.with_address(address))
+            }
+            DMG_RES_START..=DMG_RES_END => Ok(UNMAPPED_IO_VALUE),
+            DMG_IO_START..=DMG_IO_END => {
+                // only the switch can be written, since the current speed is read-only
+                if self.cgb && address == KEY1 {
+                    let prev = self.read_byte(KEY1).unwrap_or(UNMAPPED_IO_VALUE);
+                    self.speed_switch_armed = data & KEY1_SWITCH_ARMED != 0;
+                    return Ok(prev);
+                }
+                if self.cgb && (address == VBK || (BCPS..=OCPD).contains(&address)) {
+                    return self.ppu.write_register(address, data);
+                }
+                if self.cgb && address == SVBK {
+                    let prev = SVBK_UNUSED | self.wram_bank;
+                    self.wram_bank = data & !SVBK_UNUSED;
+                    return Ok(prev);
+                }
+                if !is_mapped_io(address) {
+                    return Ok(UNMAPPED_IO_VALUE);
+                }
+                if address == P1 {
+                    return self.joypad.write_register(address, data);
+                }
+                if (SB..=SC).contains(&address) {
+                    return self.serial.write_register(address, data);
+                }
+                if (DIV..=TAC).contains(&address) {
+                    return self.timer.write_register(address, data);
+                }
+                // registers the PPU doesn't handle yet (like DMA) are stored with the rest of IO
+                if (LCDC..=WX).contains(&address) {
+                    if let Ok(prev) = self.ppu.write_register(address, data) {
+                        return Ok(prev);
+                    }
+                }
+                if address == BOOT_ROM_DISABLE && data != 0 {
+                    self.boot_rom = None;
+                }
+                if address == DMA {
+                    self.dma = Some(OamDma::new(data));
+                }
+                let address = (address - DMG_IO_START) as usize;
+                let prev = self.io[address];
+                self.io[address] = data;
                 Ok(prev)
             }
-            _ => Err(MemoryWriteError)
+            DMG_HRAM_START..=DMG_HRAM_END => {
+                let address = (address - DMG_HRAM_START) as usize;
+                let prev = self.hram[address];
+                self.hram[address] = data;
+                Ok(prev)
+            }
+            DMG_IE => {
+                let prev = self.interrupt_enable;
+                self.interrupt_enable = data;
+                Ok(prev)
+            }
+        }
+    }
+}
+
+impl MemoryController for DmgMemoryController {
+    fn load_byte(&self, address: u16) -> Option<u8> {
+        if self.blocked_by_dma(address) {
+            return Some(UNMAPPED_IO_VALUE);
+        }
+        self.read_byte(address)
+    }
+
+    fn load_half_word(&self, address: u16) -> Option<u16> {
+        let right = self.load_byte(address)?;
+        let left = self.load_byte(address.checked_add(1)?)?;
+
+        Some(left.merge(right))
+    }
+
+    fn rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            // the boot ROM isn't part of the cartridge
+            0..=DMG_ROM_END if self.read_boot_rom(address).is_some() => None,
+            0..=DMG_ROM_END => self.cartridge.rom_offset(address),
+            _ => None
+        }
+    }
+
+    fn rom_size(&self) -> usize {
+        self.cartridge.rom_size()
+    }
+
+    fn tick(&mut self, cycles: TCycles) {
+        let cpu_cycles = if self.double_speed { TCycles(cycles.0 * 2) } else { cycles };
+        self.tick_dma(cpu_cycles);
+        let interrupts = self.joypad.tick(cpu_cycles)
+            | self.timer.tick(cpu_cycles)
+            | self.serial.tick(cpu_cycles)
+            | self.ppu.tick(cycles);
+        self.io[(INTERRUPT_FLAG - DMG_IO_START) as usize] |= interrupts;
+    }
+
+    fn speed_switch_armed(&self) -> bool {
+        self.speed_switch_armed
+    }
+
+    fn set_double_speed(&mut self, double_speed: bool) {
+        self.double_speed = double_speed;
+        self.speed_switch_armed = false;
+    }
+
+    fn set_serial_sink(&mut self, sink: Box<dyn SerialSink>) {
+        self.serial.set_sink(sink);
+    }
+
+    fn set_button(&mut self, button: Button, pressed: bool) {
+        self.joypad.set_button(button, pressed);
+    }
+
+    fn framebuffer(&self) -> &[u8] {
+        self.ppu.framebuffer()
+    }
+
+    fn load_vram(&mut self, offset: u16, data: &[u8]) -> Result<(), MemoryWriteError> {
+        self.ppu.load_vram(offset, data)
+    }
+
+    fn load_oam(&mut self, data: &[u8; OAM_SIZE]) {
+        self.ppu.load_oam(data);
+    }
+
+    fn snapshot(&self) -> MemorySnapshot {
+        MemorySnapshot {
+            bytes: (0..=u16::MAX)
+                .map(|address| self.read_byte(address).unwrap_or(0xFF))
+                .collect(),
+            rom_bank: self.cartridge.rom_offset(DMG_ROM_BANK_START)
+                .map(|offset| offset / ROM_BANK_SIZE),
+            mem_bank: self.cartridge.mem_bank(),
+        }
+    }
+
+    fn store_byte(&mut self, address: u16, data: u8) -> Result<u8, MemoryWriteError> {
+        if self.blocked_by_dma(address) {
+            return Ok(UNMAPPED_IO_VALUE);
         }
+        self.write_byte(address, data)
     }
 
     fn store_half_word(&mut self, address: u16, data: u16) -> Result<(), MemoryWriteError> {
         let (left_data, right_data) = data.split();
+        // the second byte would wrap around to 0x0000, so the write is rejected before the first
+        // byte is changed
+        let next_address = address.checked_add(1)
+            .ok_or(MemoryWriteError::OutOfBounds(address))?;
 
         let prev_right = self.store_byte(address, right_data)?;
-        let left = self.store_byte(address + 1, left_data);
-        if left.is_err() {
+        if let Err(err) = self.store_byte(next_address, left_data) {
             self.store_byte(address, prev_right).unwrap();
-            return Err(MemoryWriteError);
+            return Err(err);
         }
         Ok(())
     }
+
+    fn load_slice(&self, start: u16, len: u16) -> Vec<u8> {
+        match self.backing_range(start, len as usize) {
+            Some((BackingArray::Ram, range)) => self.ram[range].to_vec(),
+            Some((BackingArray::Hram, range)) => self.hram[range].to_vec(),
+            None => (0..len)
+                .map(|offset| self.load_byte(start.wrapping_add(offset)).unwrap_or(0xFF))
+                .collect()
+        }
+    }
+
+    fn store_slice(&mut self, start: u16, data: &[u8]) -> Result<(), MemoryWriteError> {
+        match self.backing_range(start, data.len()) {
+            Some((BackingArray::Ram, range)) => self.ram[range].copy_from_slice(data),
+            Some((BackingArray::Hram, range)) => self.hram[range].copy_from_slice(data),
+            None => {
+                for (offset, byte) in data.iter().enumerate() {
+                    self.store_byte(start.wrapping_add(offset as u16), *byte)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new();
+        writer.write_u32(self.cartridge.rom_size() as u32);
+        writer.write_bytes(&self.cartridge.save_state());
+        writer.write_bool(self.cgb);
+        writer.write_bytes(&self.ram);
+        writer.write_u8(self.wram_bank);
+        writer.write_bytes(&self.io);
+        writer.write_bytes(&self.hram);
+        writer.write_u8(self.interrupt_enable);
+        writer.write_bool(self.boot_rom.is_some());
+        if let Some(boot_rom) = self.boot_rom.as_ref() {
+            writer.write_bytes(boot_rom);
+        }
+        self.joypad.save_state(&mut writer);
+        self.timer.save_state(&mut writer);
+        self.serial.save_state(&mut writer);
+        self.ppu.save_state(&mut writer);
+        writer.write_bool(self.dma.is_some());
+        if let Some(dma) = self.dma.as_ref() {
+            dma.save_state(&mut writer);
+        }
+        writer.write_bool(self.speed_switch_armed);
+        writer.write_bool(self.double_speed);
+        writer.finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = SnapshotReader::new(state);
+        if reader.read_u32()? as usize != self.cartridge.rom_size() {
+            return Err(SnapshotError::Incompatible);
+        }
+        self.cartridge.load_state(reader.read_bytes()?)?;
+        if reader.read_bool()? != self.cgb {
+            return Err(SnapshotError::Incompatible);
+        }
+        reader.read_into(&mut self.ram)?;
+        self.wram_bank = reader.read_u8()?;
+        if self.wram_bank as usize >= CGB_WRAM_BANKS {
+            return Err(SnapshotError::InvalidValue);
+        }
+        reader.read_into(&mut self.io)?;
+        reader.read_into(&mut self.hram)?;
+        self.interrupt_enable = reader.read_u8()?;
+        self.boot_rom = if reader.read_bool()? {
+            Some(reader.read_bytes()?.to_vec())
+        } else {
+            None
+        };
+        self.joypad.load_state(&mut reader)?;
+        self.timer.load_state(&mut reader)?;
+        self.serial.load_state(&mut reader)?;
+        self.ppu.load_state(&mut reader)?;
+        self.dma = if reader.read_bool()? {
+            Some(OamDma::load_state(&mut reader)?)
+        } else {
+            None
+        };
+        self.speed_switch_armed = reader.read_bool()?;
+        self.double_speed = reader.read_bool()?;
+        reader.finish()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use mockall::predicate::eq;
-    use crate::memory::cartridge::MockCartridgeMapper;
+    use crate::memory::cartridge::{MockCartridgeMapper, RomOnlyCartridge, MBC3, ROM_BANK_SIZE};
+    use crate::ppu::{BCPD, OCPS};
+    use crate::timer::TIMA;
     use super::*;
 
     #[test]
     fn test_rom_write_fails() {
         let mut mock = MockCartridgeMapper::new();
         mock.expect_write_rom()
-            .return_const(Err(MemoryWriteError));
+            .return_const(Err(MemoryWriteError::ReadOnly(42)));
         let mut controller = DmgMemoryController::new(Box::new(mock));
 
         let result = controller.store_byte(42, 42);
 
-        assert!(result.is_err(), "Test that writing to a rom does not work");
+        assert_eq!(
+            result, Err(MemoryWriteError::ReadOnly(42)),
+            "Test that writing to a rom does not work"
+        );
     }
 
     #[test]
@@ -189,6 +861,19 @@ mod tests {
         assert_eq!(result, Some(210), "Test reading from a ROM address");
     }
 
+    #[test]
+    fn test_rom_read_fail() {
+        let mut mock = MockCartridgeMapper::new();
+        mock.expect_read_rom()
+            .with(eq(42))
+            .return_const(None);
+        let controller = DmgMemoryController::new(Box::new(mock));
+
+        let result = controller.load_byte(42);
+
+        assert!(result.is_none(), "A failed ROM read shouldn't be hidden by the open bus");
+    }
+
     #[test]
     fn test_vram_io() {
         let mock = MockCartridgeMapper::new();
@@ -221,24 +906,41 @@ mod tests {
         mock.expect_read_mem()
             .with(eq(42))
             .return_const(None);
-        let controller = DmgMemoryController::new(Box::new(mock));
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+        controller.set_open_bus(OpenBus::Fail);
 
         let result = controller.load_byte(DMG_EXT_START + 42);
 
         assert!(result.is_none(), "Test reading cartridge RAM when it doesn't exist");
     }
 
+    #[test]
+    fn test_cart_ram_read_open_bus() {
+        let mut mock = MockCartridgeMapper::new();
+        mock.expect_read_mem()
+            .with(eq(42))
+            .return_const(None);
+        let controller = DmgMemoryController::new(Box::new(mock));
+
+        let result = controller.load_byte(DMG_EXT_START + 42);
+
+        assert_eq!(result, Some(0xFF), "Cartridge RAM that doesn't exist should read as 0xFF");
+    }
+
     #[test]
     fn test_cart_ram_write_fail() {
         let mut mock = MockCartridgeMapper::new();
         mock.expect_write_mem()
             .with(eq(42), eq(42))
-            .return_const(Err(MemoryWriteError));
+            .return_const(Err(MemoryWriteError::RamDisabled(42)));
         let mut controller = DmgMemoryController::new(Box::new(mock));
 
         let result = controller.store_byte(DMG_EXT_START + 42, 42);
 
-        assert!(result.is_err(), "Test that accessing non-existent cartridge RAM errors");
+        assert_eq!(
+            result, Err(MemoryWriteError::RamDisabled(DMG_EXT_START + 42)),
+            "The error should hold the CPU address, not the cartridge's"
+        );
     }
 
     #[test]
@@ -267,6 +969,276 @@ mod tests {
         assert_eq!(controller.load_byte(0xFE42), Some(7), "Test changed RAM value");
     }
 
+    #[test]
+    fn test_ram_overwrite_returns_prev() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+        // VRAM sits at the same offset, so a mix-up between the buffers would return this
+        assert!(controller.store_byte(0x8042, 0x99).is_ok());
+
+        assert!(controller.store_byte(0xC042, 0x28).is_ok());
+        let result = controller.store_byte(0xC042, 0x42);
+
+        assert_eq!(result, Ok(0x28), "Overwriting RAM should return the previous RAM value");
+    }
+
+    #[test]
+    fn test_reserved_overwrite_returns_prev() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+        assert!(controller.store_byte(0x8042, 0x99).is_ok());
+
+        assert!(controller.store_byte(0xFE42, 0x07).is_ok());
+        let result = controller.store_byte(0xFE42, 0x42);
+
+        assert_eq!(result, Ok(0x07), "Overwriting reserved memory should return its old value");
+    }
+
+    #[test]
+    fn test_boot_rom_mapping() {
+        let mut mock = MockCartridgeMapper::new();
+        mock.expect_read_rom()
+            .return_const(0x42);
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+        controller.set_boot_rom(vec![0x31; 0x100]);
+
+        let boot_result = controller.load_byte(0x00FF);
+        let cartridge_result = controller.load_byte(0x0100);
+        assert!(controller.store_byte(BOOT_ROM_DISABLE, 0x01).is_ok());
+        let disabled_result = controller.load_byte(0x00FF);
+
+        assert_eq!(boot_result, Some(0x31), "The boot ROM should be mapped over the cartridge");
+        assert_eq!(cartridge_result, Some(0x42), "The cartridge should be mapped past 0x00FF");
+        assert_eq!(disabled_result, Some(0x42), "Writing to 0xFF50 should unmap the boot ROM");
+    }
+
+    #[test]
+    fn test_unmapped_io() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+
+        let result = controller.store_byte(0xFF08, 0x42);
+
+        assert_eq!(result, Ok(0xFF), "Writing to unmapped IO should be ignored");
+        assert_eq!(controller.load_byte(0xFF08), Some(0xFF), "Unmapped IO should read as 0xFF");
+    }
+
+    #[test]
+    fn test_mapped_io() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+
+        let result = controller.store_byte(0xFF0F, 0x04);
+
+        assert_eq!(result, Ok(0), "Test writing to an IO register");
+        assert_eq!(controller.load_byte(0xFF0F), Some(0x04), "Test changed IO register");
+    }
+
+    #[test]
+    fn test_timer_io() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+        assert!(controller.store_byte(TAC, 0x05).is_ok(), "Should enable the fastest clock");
+        assert!(controller.store_byte(0xFF05, 0xFF).is_ok(), "Should be able to write TIMA");
+
+        controller.tick(TCycles(16));
+
+        assert_eq!(controller.load_byte(0xFF05), Some(0), "TIMA should overflow to TMA");
+        assert_eq!(controller.load_byte(DIV), Some(0), "DIV should count every 256 cycles");
+        assert_eq!(controller.load_byte(0xFF0F), Some(0x04), "A timer interrupt is requested");
+    }
+
+    #[test]
+    fn test_unusable_memory() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+
+        let result = controller.store_byte(DMG_RES_START, 0x42);
+
+        assert_eq!(result, Ok(0xFF), "Writing to the unusable area should be ignored");
+        assert_eq!(controller.load_byte(DMG_RES_START), Some(0xFF), "The write shouldn't stick");
+        assert_eq!(controller.load_byte(DMG_RES_END), Some(0xFF), "The unusable area is open bus");
+    }
+
+    #[test]
+    fn test_unusable_memory_fail() {
+        let mut controller = DmgMemoryController::new(Box::new(MockCartridgeMapper::new()));
+        controller.set_open_bus(OpenBus::Fail);
+
+        let result = controller.store_byte(DMG_RES_END, 0x42);
+
+        assert_eq!(result, Ok(0xFF), "Writes should still be ignored");
+        assert!(controller.load_byte(DMG_RES_START).is_none(), "Reads should fail");
+        assert!(controller.load_byte(DMG_RES_END).is_none(), "The whole area should fail");
+    }
+
+    #[test]
+    fn test_hram_and_div() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+        controller.tick(TCycles(0x300));
+
+        assert!(controller.store_byte(DMG_HRAM_START, 0x42).is_ok(), "Should write to HRAM");
+        assert_eq!(controller.store_byte(DIV, 0x42), Ok(0x03), "DIV should have counted up");
+
+        assert_eq!(controller.load_byte(DMG_HRAM_START), Some(0x42), "HRAM should act as RAM");
+        assert_eq!(controller.load_byte(DIV), Some(0), "Writing to DIV should reset it");
+    }
+
+    #[test]
+    fn test_ppu_io() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+        assert!(controller.store_byte(0xFF45, 0x42).is_ok(), "Should be able to write LYC");
+        assert!(controller.store_byte(0xFF46, 0xC0).is_ok(), "Should be able to write DMA");
+
+        assert_eq!(controller.load_byte(0xFF45), Some(0x42), "LYC should be held by the PPU");
+        assert_eq!(controller.load_byte(0xFF46), Some(0xC0), "DMA should still be stored");
+        assert_eq!(controller.load_byte(0xFF44), Some(0), "LY should start at line 0");
+        assert!(controller.store_byte(0xFF44, 0x42).is_ok(), "Writes to LY are ignored");
+        assert_eq!(controller.load_byte(0xFF44), Some(0), "LY should be read-only");
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+        for offset in 0..0xA0 {
+            assert!(controller.store_byte(0xC100 + offset, offset as u8 + 1).is_ok());
+        }
+
+        assert!(controller.store_byte(DMA, 0xC1).is_ok(), "Should be able to start a DMA");
+        controller.tick(TCycles(4 * 0xA0));
+
+        assert_eq!(controller.load_byte(DMA), Some(0xC1), "DMA should read back the last page");
+        for offset in 0..0xA0 {
+            assert_eq!(
+                controller.load_byte(DMG_OAM_START + offset), Some(offset as u8 + 1),
+                "OAM should hold a copy of the source page"
+            );
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_blocks_memory() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+        assert!(controller.store_byte(0xC000, 0x42).is_ok());
+        assert!(controller.store_byte(DMA, 0xC0).is_ok(), "Should be able to start a DMA");
+
+        controller.tick(TCycles(4 * 0x9F));
+        assert_eq!(controller.load_byte(0xC000), Some(0xFF), "RAM can't be read during DMA");
+        assert_eq!(controller.store_byte(0xC000, 0x28), Ok(0xFF), "RAM writes are ignored");
+        assert_eq!(controller.load_byte(0xFE00), Some(0xFF), "OAM can't be read during DMA");
+        assert!(controller.store_byte(0xFF80, 0x28).is_ok(), "HRAM can be used during DMA");
+        assert_eq!(controller.load_byte(0xFF80), Some(0x28), "HRAM can be used during DMA");
+
+        controller.tick(TCycles(4));
+        assert_eq!(controller.load_byte(0xC000), Some(0x42), "RAM is accessible after the DMA");
+        assert_eq!(controller.load_byte(0xFE00), Some(0x42), "The DMA should have copied RAM");
+    }
+
+    #[test]
+    fn test_oam_dma_restore_mid_transfer() {
+        let init_controller = || {
+            let cartridge = RomOnlyCartridge::new(vec![0; 2 * ROM_BANK_SIZE], false, false);
+            assert!(cartridge.is_ok(), "Should be able to create the cartridge");
+            DmgMemoryController::new(Box::new(cartridge.unwrap()))
+        };
+        let mut controller = init_controller();
+        for offset in 0..0xA0 {
+            assert!(controller.store_byte(0xC100 + offset, offset as u8 + 1).is_ok());
+        }
+        assert!(controller.store_byte(DMA, 0xC1).is_ok(), "Should be able to start a DMA");
+        controller.tick(TCycles(4 * 0x50));
+
+        let state = controller.save_state();
+        let mut restored = init_controller();
+        assert_eq!(restored.load_state(&state), Ok(()), "Should restore partway through a DMA");
+        assert_eq!(restored.load_byte(0xC100), Some(0xFF), "The DMA should still be running");
+        restored.tick(TCycles(4 * 0x50));
+
+        for offset in 0..0xA0 {
+            assert_eq!(
+                restored.load_byte(DMG_OAM_START + offset), Some(offset as u8 + 1),
+                "Both halves of the source page should end up in OAM"
+            );
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_load_state_out_of_range() {
+        let states = [
+            ("source past 0xDF00", 0xE000, 0, 0),
+            ("source in the middle of a page", 0xC080, 0, 0),
+            ("index past OAM", 0xC000, OAM_SIZE as u16, 0),
+            ("unused cycles", 0xC000, 0, u32::MAX),
+        ];
+
+        for (name, source, index, cycles) in states {
+            let mut writer = SnapshotWriter::new();
+            OamDma { source, index, cycles }.save_state(&mut writer);
+            let state = writer.finish();
+
+            let result = OamDma::load_state(&mut SnapshotReader::new(&state));
+
+            assert!(
+                matches!(result, Err(SnapshotError::InvalidValue)),
+                "A DMA with {name} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_joypad_io() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+        assert!(controller.store_byte(P1, 0x10).is_ok(), "Should be able to select actions");
+
+        controller.set_button(Button::A, true);
+        controller.tick(TCycles(4));
+
+        assert_eq!(controller.load_byte(P1), Some(0xDE), "A should read as pressed");
+        assert_eq!(controller.load_byte(0xFF0F), Some(0x10), "A joypad interrupt is requested");
+    }
+
+    #[test]
+    fn test_hram_io() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+
+        let result = controller.store_byte(0xFF80, 0x42);
+        let overwrite_result = controller.store_byte(0xFF80, 0x28);
+
+        assert_eq!(result, Ok(0), "Test writing to HRAM");
+        assert_eq!(overwrite_result, Ok(0x42), "Test overwriting HRAM");
+        assert_eq!(controller.load_byte(0xFF80), Some(0x28), "Test changed HRAM value");
+    }
+
+    #[test]
+    fn test_interrupt_enable_io() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+
+        let result = controller.store_byte(0xFFFF, 0x1F);
+
+        assert_eq!(result, Ok(0), "Test writing to IE");
+        assert_eq!(controller.load_byte(0xFFFF), Some(0x1F), "Test changed IE value");
+        assert_eq!(controller.load_byte(0xFFFE), Some(0), "IE should be separate from HRAM");
+    }
+
+    #[test]
+    fn test_echo_ram() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+
+        assert!(controller.store_byte(0xC042, 0x28).is_ok(), "Should be able to write to WRAM");
+        assert_eq!(controller.load_byte(0xE042), Some(0x28), "WRAM should show up in echo RAM");
+
+        assert_eq!(controller.store_byte(0xFDFF, 0x12), Ok(0), "Should write to echo RAM");
+        assert_eq!(controller.load_byte(0xDDFF), Some(0x12), "Echo RAM should write to WRAM");
+    }
+
     #[test]
     fn test_load_half_word_valid_address() {
         let mock = MockCartridgeMapper::new();
@@ -282,12 +1254,12 @@ mod tests {
     #[test]
     fn test_load_half_word_invalid_first_byte() {
         let mut mock = MockCartridgeMapper::new();
-        mock.expect_read_mem()
-            .with(eq(0x1FFF))
+        mock.expect_read_rom()
+            .with(eq(0x7FFF))
             .return_const(None);
         let controller = DmgMemoryController::new(Box::new(mock));
 
-        let result = controller.load_half_word(0xBFFF);
+        let result = controller.load_half_word(0x7FFF);
 
         assert!(result.is_none(), "Test loading address where 1st byte is an invalid address")
     }
@@ -295,16 +1267,39 @@ mod tests {
     #[test]
     fn test_load_half_word_invalid_second_byte() {
         let mut mock = MockCartridgeMapper::new();
-        mock.expect_read_mem()
-            .with(eq(0))
+        mock.expect_read_rom()
+            .with(eq(0x41))
+            .return_const(Some(0x28));
+        mock.expect_read_rom()
+            .with(eq(0x42))
             .return_const(None);
         let controller = DmgMemoryController::new(Box::new(mock));
 
-        let result = controller.load_half_word(0x9FFF);
+        let result = controller.load_half_word(0x41);
 
         assert!(result.is_none(), "Test loading address where 2nd byte is an invalid address");
     }
 
+    #[test]
+    fn test_load_half_word_is_little_endian() {
+        let mut controller = DmgMemoryController::new(Box::new(MockCartridgeMapper::new()));
+        assert!(controller.store_byte(DMG_RAM_START, 0x34).is_ok());
+        assert!(controller.store_byte(DMG_RAM_START + 1, 0x12).is_ok());
+
+        let result = controller.load_half_word(DMG_RAM_START);
+
+        assert_eq!(result, Some(0x1234), "The first byte should be the low byte");
+    }
+
+    #[test]
+    fn test_load_half_word_end_of_memory() {
+        let controller = DmgMemoryController::new(Box::new(MockCartridgeMapper::new()));
+
+        let result = controller.load_half_word(DMG_IE);
+
+        assert!(result.is_none(), "The second byte is past the end of memory");
+    }
+
     #[test]
     fn test_store_half_word_valid_address() {
         let mock = MockCartridgeMapper::new();
@@ -322,7 +1317,7 @@ mod tests {
         let mut mock = MockCartridgeMapper::new();
         mock.expect_write_rom()
             .with(eq(DMG_ROM_END), eq(0x12))
-            .return_const(Err(MemoryWriteError));
+            .return_const(Err(MemoryWriteError::ReadOnly(DMG_ROM_END)));
         let mut controller = DmgMemoryController::new(Box::new(mock));
 
         let result = controller.store_half_word(DMG_ROM_END, 0x0812);
@@ -339,15 +1334,242 @@ mod tests {
         let mut mock = MockCartridgeMapper::new();
         mock.expect_write_mem()
             .with(eq(0), eq(0x01))
-            .return_const(Err(MemoryWriteError));
+            .return_const(Err(MemoryWriteError::RamDisabled(0)));
         let mut controller = DmgMemoryController::new(Box::new(mock));
 
         let result = controller.store_half_word(DMG_VRAM_END, 0x0106);
 
-        assert_eq!(result, Err(MemoryWriteError), "Test that the invalid write failed");
+        assert_eq!(
+            result, Err(MemoryWriteError::RamDisabled(DMG_EXT_START)),
+            "Test that the invalid write failed"
+        );
         assert_eq!(
             controller.load_byte(DMG_VRAM_END), Some(0),
             "Test that the valid address is unchanged"
         );
     }
+
+    #[test]
+    fn test_store_half_word_end_of_memory() {
+        let mock = MockCartridgeMapper::new();
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+
+        let result = controller.store_half_word(DMG_IE, 0x1F1F);
+
+        assert_eq!(
+            result, Err(MemoryWriteError::OutOfBounds(DMG_IE)),
+            "The second byte is past the end of memory"
+        );
+        assert_eq!(controller.load_byte(DMG_IE), Some(0), "IE shouldn't be written");
+    }
+
+    #[test]
+    fn test_key1() {
+        let mut controller = DmgMemoryController::new_cgb(Box::new(MockCartridgeMapper::new()));
+        assert_eq!(controller.load_byte(KEY1), Some(0x7E), "Should start at normal speed");
+
+        assert!(controller.store_byte(KEY1, 0xFF).is_ok(), "Should be able to arm the switch");
+        assert_eq!(controller.load_byte(KEY1), Some(0x7F), "The current speed is read-only");
+
+        controller.set_double_speed(true);
+        assert_eq!(controller.load_byte(KEY1), Some(0xFE), "Switching should disarm KEY1");
+    }
+
+    #[test]
+    fn test_wram_banks() {
+        let mut controller = DmgMemoryController::new_cgb(Box::new(MockCartridgeMapper::new()));
+        assert_eq!(controller.load_byte(SVBK), Some(0xF8), "Should start with bank 0 selected");
+
+        assert!(controller.store_byte(SVBK, 2).is_ok(), "Should switch to bank 2");
+        assert!(controller.store_byte(0xD042, 0x28).is_ok(), "Should write to bank 2");
+        assert!(controller.store_byte(SVBK, 5).is_ok(), "Should switch to bank 5");
+        assert!(controller.store_byte(0xD042, 0x04).is_ok(), "Should write to bank 5");
+        assert!(controller.store_byte(0xC042, 0x12).is_ok(), "Should write to bank 0");
+
+        assert_eq!(controller.load_byte(0xD042), Some(0x04), "Should read from bank 5");
+        assert_eq!(controller.load_byte(SVBK), Some(0xFD), "SVBK should hold the bank");
+        assert!(controller.store_byte(SVBK, 2).is_ok(), "Should switch back to bank 2");
+        assert_eq!(controller.load_byte(0xD042), Some(0x28), "Should read from bank 2");
+        assert_eq!(controller.load_byte(0xF042), Some(0x28), "Echo RAM should follow SVBK");
+        assert_eq!(controller.load_byte(0xC042), Some(0x12), "Bank 0 should stay mapped");
+    }
+
+    #[test]
+    fn test_wram_bank_0_selects_bank_1() {
+        let mut controller = DmgMemoryController::new_cgb(Box::new(MockCartridgeMapper::new()));
+        assert!(controller.store_byte(SVBK, 1).is_ok(), "Should switch to bank 1");
+        assert!(controller.store_byte(0xD042, 0x28).is_ok(), "Should write to bank 1");
+
+        assert!(controller.store_byte(SVBK, 0).is_ok(), "Should switch to bank 0");
+
+        assert_eq!(controller.load_byte(0xD042), Some(0x28), "Bank 0 should map bank 1");
+        assert_eq!(controller.load_byte(0xC042), Some(0x00), "Bank 0 should be left alone");
+    }
+
+    #[test]
+    fn test_dmg_has_no_cgb_registers() {
+        let mut controller = DmgMemoryController::new(Box::new(MockCartridgeMapper::new()));
+        assert!(controller.store_byte(0xD042, 0x28).is_ok(), "Should write to WRAM");
+
+        assert!(controller.store_byte(SVBK, 2).is_ok(), "Writes to SVBK are ignored");
+        assert!(controller.store_byte(KEY1, 1).is_ok(), "Writes to KEY1 are ignored");
+        assert!(controller.store_byte(VBK, 1).is_ok(), "Writes to VBK are ignored");
+        assert!(controller.store_byte(0x8042, 0x04).is_ok(), "Should write to VRAM");
+
+        assert_eq!(controller.load_byte(SVBK), Some(0xFF), "SVBK isn't mapped on a DMG");
+        assert_eq!(controller.load_byte(KEY1), Some(0xFF), "KEY1 isn't mapped on a DMG");
+        assert_eq!(controller.load_byte(VBK), Some(0xFF), "VBK isn't mapped on a DMG");
+        assert!(controller.store_byte(VBK, 0).is_ok(), "Writes to VBK are ignored");
+        assert_eq!(controller.load_byte(0x8042), Some(0x04), "VRAM shouldn't switch banks");
+        assert_eq!(controller.load_byte(0xD042), Some(0x28), "WRAM shouldn't switch banks");
+        assert!(!controller.speed_switch_armed(), "There's no speed switch on a DMG");
+    }
+
+    #[test]
+    fn test_cgb_palette_registers() {
+        let mut cgb = DmgMemoryController::new_cgb(Box::new(MockCartridgeMapper::new()));
+        let mut dmg = DmgMemoryController::new(Box::new(MockCartridgeMapper::new()));
+
+        for controller in [&mut cgb, &mut dmg] {
+            // auto increment from object palette 0, color 1
+            assert!(controller.store_byte(OCPS, 0x82).is_ok(), "Should be able to write OCPS");
+            assert!(controller.store_byte(OCPD, 0xFF).is_ok(), "Should be able to write OCPD");
+            assert!(controller.store_byte(OCPD, 0x7F).is_ok(), "Should be able to write OCPD");
+        }
+
+        assert_eq!(cgb.ppu.object_palette_color(0, 1), Some(0x7FFF), "Should store white");
+        assert_eq!(cgb.load_byte(OCPS), Some(0xC4), "OCPS should move past both bytes");
+        assert_eq!(dmg.ppu.object_palette_color(0, 1), Some(0), "Palette RAM isn't on a DMG");
+        assert_eq!(dmg.load_byte(OCPS), Some(0xFF), "OCPS isn't mapped on a DMG");
+        assert_eq!(dmg.load_byte(BCPD), Some(0xFF), "BCPD isn't mapped on a DMG");
+    }
+
+    #[test]
+    fn test_double_speed_tick() {
+        let mut controller = DmgMemoryController::new(Box::new(MockCartridgeMapper::new()));
+        assert!(controller.store_byte(TAC, 0x05).is_ok(), "Should be able to start the timer");
+        controller.set_double_speed(true);
+
+        controller.tick(TCycles(32));
+
+        assert_eq!(
+            controller.load_byte(TIMA), Some(4),
+            "The timer should see twice as many T-cycles as the PPU"
+        );
+    }
+
+    #[test]
+    fn test_snapshot_reports_rom_bank() {
+        // every byte in a bank holds the number of that bank
+        let rom: Vec<u8> = (0..4_u8)
+            .flat_map(|bank| vec![bank; ROM_BANK_SIZE])
+            .collect();
+        let cartridge = MBC3::new(rom, 4, 1, false, None).unwrap();
+        let mut controller = DmgMemoryController::new(Box::new(cartridge));
+        assert!(controller.store_byte(0x2000, 3).is_ok(), "Should be able to switch ROM banks");
+
+        let snapshot = controller.snapshot();
+
+        assert_eq!(snapshot.bytes.len(), 0x10000, "The snapshot should cover all of memory");
+        assert_eq!(snapshot.bytes[0x4000], 3, "The snapshot should hold the mapped bank's bytes");
+        assert_eq!(snapshot.bank_at(0x4000), Some(3), "ROM bank 3 should be mapped");
+        assert_eq!(snapshot.bank_at(0x0000), None, "The first ROM bank isn't switchable");
+        assert_eq!(snapshot.bank_at(0xA000), None, "RAM hasn't been enabled");
+    }
+
+    #[test]
+    fn test_snapshot_reports_mem_bank() {
+        let cartridge = MBC3::new(vec![0; 2 * ROM_BANK_SIZE], 2, 4, false, None).unwrap();
+        let mut controller = DmgMemoryController::new(Box::new(cartridge));
+        assert!(controller.store_byte(0x0000, 0x0A).is_ok(), "Should be able to enable RAM");
+        assert!(controller.store_byte(0x4000, 2).is_ok(), "Should be able to switch RAM banks");
+
+        let snapshot = controller.snapshot();
+
+        assert_eq!(snapshot.bank_at(0xA000), Some(2), "RAM bank 2 should be mapped");
+    }
+
+    #[test]
+    fn test_slice_within_wram_bank() {
+        let mut controller = DmgMemoryController::new_cgb(Box::new(MockCartridgeMapper::new()));
+        assert!(controller.store_byte(SVBK, 3).is_ok(), "Should switch to bank 3");
+
+        assert!(controller.store_slice(0xD100, &[1, 2, 3]).is_ok(), "Should write to bank 3");
+
+        assert_eq!(controller.load_slice(0xD100, 3), vec![1, 2, 3], "Should read back the run");
+        assert_eq!(controller.load_slice(0xF100, 3), vec![1, 2, 3], "Echo RAM should match");
+        assert!(controller.store_byte(SVBK, 4).is_ok(), "Should switch to bank 4");
+        assert_eq!(controller.load_slice(0xD100, 3), vec![0, 0, 0], "Bank 4 should be empty");
+    }
+
+    #[test]
+    fn test_slice_across_wram_banks() {
+        let mut controller = DmgMemoryController::new_cgb(Box::new(MockCartridgeMapper::new()));
+        assert!(controller.store_byte(SVBK, 2).is_ok(), "Should switch to bank 2");
+
+        assert!(controller.store_slice(0xCFFE, &[1, 2, 3, 4]).is_ok(), "Should write both banks");
+
+        assert_eq!(controller.load_byte(0xCFFF), Some(2), "The start should be in bank 0");
+        assert_eq!(controller.load_byte(0xD000), Some(3), "The end should be in bank 2");
+        assert!(controller.store_byte(SVBK, 3).is_ok(), "Should switch to bank 3");
+        assert_eq!(
+            controller.load_slice(0xCFFE, 4), vec![1, 2, 0, 0],
+            "Only the part of the run in bank 0 should still be mapped"
+        );
+    }
+
+    #[test]
+    fn test_slice_across_hram_and_ie() {
+        let mut controller = DmgMemoryController::new(Box::new(MockCartridgeMapper::new()));
+
+        assert!(controller.store_slice(0xFFFD, &[0x12, 0x34, 0x1F]).is_ok());
+
+        assert_eq!(controller.load_byte(DMG_HRAM_END), Some(0x34), "Should write to HRAM");
+        assert_eq!(controller.load_byte(DMG_IE), Some(0x1F), "Should write to IE");
+        assert_eq!(controller.load_slice(0xFFFD, 3), vec![0x12, 0x34, 0x1F]);
+    }
+
+    #[test]
+    fn test_load_slice_wraps_around() {
+        let mut mock = MockCartridgeMapper::new();
+        mock.expect_read_rom()
+            .with(eq(0))
+            .return_const(Some(0x42));
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+        assert!(controller.store_byte(DMG_IE, 0x01).is_ok());
+
+        let result = controller.load_slice(DMG_IE, 2);
+
+        assert_eq!(result, vec![0x01, 0x42], "The run should wrap around to the start of ROM");
+    }
+
+    #[test]
+    fn test_load_slice_invalid_address() {
+        let mut mock = MockCartridgeMapper::new();
+        mock.expect_read_mem()
+            .return_const(None);
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+        assert!(controller.store_byte(DMG_VRAM_END, 0x06).is_ok());
+
+        let result = controller.load_slice(DMG_VRAM_END, 2);
+
+        assert_eq!(result, vec![0x06, 0xFF], "Cartridge RAM isn't readable without a cartridge");
+    }
+
+    #[test]
+    fn test_store_slice_stops_at_invalid_address() {
+        let mut mock = MockCartridgeMapper::new();
+        mock.expect_write_mem()
+            .with(eq(0), eq(0x02))
+            .return_const(Err(MemoryWriteError::RamDisabled(0)));
+        let mut controller = DmgMemoryController::new(Box::new(mock));
+
+        let result = controller.store_slice(DMG_VRAM_END, &[0x01, 0x02, 0x03]);
+
+        assert_eq!(
+            result, Err(MemoryWriteError::RamDisabled(DMG_EXT_START)),
+            "Cartridge RAM can't be written"
+        );
+        assert_eq!(controller.load_byte(DMG_VRAM_END), Some(0x01), "VRAM should be written");
+    }
 }
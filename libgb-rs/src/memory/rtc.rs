@@ -1,4 +1,66 @@
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// The size of a saved clock in the format used by BGB and VBA-M, with either a 64-bit or a 32-bit
+// timestamp at the end
+pub const RTC_SAVE_SIZE: usize = 48;
+const RTC_SAVE_SIZE_32: usize = 44;
+const REGISTER_COUNT: usize = 5;
+const SAVED_REGISTER_SIZE: usize = 4;
+const TIMESTAMP_OFFSET: usize = 2 * REGISTER_COUNT * SAVED_REGISTER_SIZE;
+
+/// # Clock
+/// Where a RealTimeClock gets the passage of time from, so that it isn't tied to `std::time`
+pub trait Clock {
+    /// Get the current time in seconds. Only the difference between two calls is used, so this
+    /// can count from any point (like when the program started), but it can't go backwards.
+    fn now_secs(&self) -> u64;
+
+    /// Get the number of seconds since the UNIX epoch. This is stored in save files, so that the
+    /// time the game was closed for can be added back when it's loaded.
+    ///
+    /// Returns None if the clock doesn't know the date, in which case no time passes between
+    /// saving and loading
+    fn unix_secs(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// # SystemClock
+/// The default clock, which counts real time using `std::time`
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_secs(&self) -> u64 {
+        self.start.elapsed().as_secs()
+    }
+
+    fn unix_secs(&self) -> Option<u64> {
+        // 0 if the system clock is set before the epoch
+        let unix_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        Some(unix_time)
+    }
+}
+
+/// # ManualClock
+/// A clock which never moves, so only the time passed to `RealTimeClock::advance` is counted
+pub struct ManualClock;
+
+impl Clock for ManualClock {
+    fn now_secs(&self) -> u64 {
+        0
+    }
+}
 
 /// # RealTimeClock (RTC)
 /// This RTC struct represents the set of clock registers present in an MBC3/MBC30 cartridge.
@@ -9,8 +71,14 @@ use std::time::Instant;
 /// 0xFF. The second register only uses 3 out of the 8 bits, holding an overflow bit for the day
 /// counter (in the leftmost bit of the register, bit 7), a "halting" bit which pauses the clock
 /// (in bit 6), and the 9th bit for the day counter (in bit 0).
+///
+/// Each `set_*` method returns the value the register held before, as the matching `get_*`
+/// would have read it.
 pub struct RealTimeClock {
-    last_modified: Instant,
+    clock: Box<dyn Clock>,
+    last_modified: u64, // the time on `clock` when the elapsed time started being counted
+    // seconds passed to `advance` since `last_modified`
+    advanced_seconds: u64,
     // keeps track of the seconds elapsed in between a previous latch and a halt, since
     // `last_modified` would be updated then
     seconds_since_latch: u64,
@@ -33,8 +101,11 @@ impl RealTimeClock {
         secs: Option<u8>, mins: Option<u8>, hrs: Option<u8>,
         days_lower: Option<u8>, days_upper: Option<u8>,
     ) -> RealTimeClock {
+        let clock = SystemClock::default();
         RealTimeClock {
-            last_modified: Instant::now(),
+            last_modified: clock.now_secs(),
+            clock: Box::new(clock),
+            advanced_seconds: 0,
             seconds_since_latch: 0,
             seconds: secs.unwrap_or(0) & 0x3F,
             minutes: mins.unwrap_or(0) & 0x3F,
@@ -45,23 +116,50 @@ impl RealTimeClock {
         }
     }
 
+    /// Create a clock starting at 0 which only counts the time passed to `advance`, instead of
+    /// real time
+    pub fn manual() -> RealTimeClock {
+        RealTimeClock::default().with_clock(Box::new(ManualClock))
+    }
+
+    /// Get the passage of time from the given clock instead of `std::time`
+    pub fn with_clock(mut self, clock: Box<dyn Clock>) -> RealTimeClock {
+        self.set_clock(clock);
+        self
+    }
+
+    /// Replace the clock this gets the passage of time from. Any time the previous clock counted
+    /// since the registers were last changed is dropped, so this should be done before the
+    /// clock starts running (like before a save is loaded).
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+        self.restart_elapsed();
+    }
+
+    /// Move the clock forward by the given number of seconds, on top of any real time which has
+    /// passed. Nothing happens while the clock is halted.
+    pub fn advance(&mut self, seconds: u64) {
+        if !self.halted {
+            self.advanced_seconds += seconds;
+        }
+    }
+
+    /// Get the number of seconds which have passed since `last_modified`
+    fn elapsed_seconds(&self) -> u64 {
+        self.clock.now_secs().saturating_sub(self.last_modified) + self.advanced_seconds
+    }
+
+    /// Start counting the elapsed time from now
+    fn restart_elapsed(&mut self) {
+        self.last_modified = self.clock.now_secs();
+        self.advanced_seconds = 0;
+    }
+
     // NOTE - I'm not completely sure if the way this would handle carry overs in edge cases is the
     // same, so there might be some slight differences in emulation here. For now I don't think
     // this is a big problem though.
     pub fn latch(&mut self) {
-        let current_seconds = (((self.days_upper as u64 & 1) << 8) + self.days_lower as u64) * 86400
-            + self.hours as u64 * 3500 + self.minutes as u64 * 60 + self.seconds as u64;
-
-        // When the clock is halted (i.e. not counting up), the last_modified field should be
-        // ignored, but `seconds_since_latch` shouldn't because that holds the amount of time
-        // between the previous latch and the point in time when the clock halted.
-        let total_seconds = if self.halted {
-            self.seconds_since_latch + current_seconds
-        } else {
-            let elapsed_seconds = self.last_modified.elapsed()
-                .as_secs();
-            self.seconds_since_latch + current_seconds + elapsed_seconds 
-        };
+        let total_seconds = self.current_seconds();
         self.seconds_since_latch = 0; // this value needs to be reset each time it is used
 
         self.seconds = (total_seconds % 60) as u8;
@@ -71,7 +169,108 @@ impl RealTimeClock {
         self.days_lower = total_days as u8;
         self.days_upper = self.create_days_upper(total_days);
 
-        self.last_modified = Instant::now();
+        self.restart_elapsed();
+    }
+
+    /// Get the time held in the registers, in seconds
+    fn register_seconds(&self) -> u64 {
+        to_seconds(self.seconds, self.minutes, self.hours, self.days_lower)
+            + ((self.days_upper as u64 & 1) << 8) * 86400
+    }
+
+    /// Get the time the clock has actually counted up to, in seconds, which the registers will
+    /// hold after the next latch
+    fn current_seconds(&self) -> u64 {
+        // When the clock is halted (i.e. not counting up), the last_modified field should be
+        // ignored, but `seconds_since_latch` shouldn't because that holds the amount of time
+        // between the previous latch and the point in time when the clock halted.
+        let elapsed_seconds = if self.halted {
+            0
+        } else {
+            self.elapsed_seconds()
+        };
+
+        self.seconds_since_latch + self.register_seconds() + elapsed_seconds
+    }
+
+    /// Serialize the clock in the 48-byte format used by BGB and VBA-M, which emulators append
+    /// to the end of the cartridge RAM in save files:
+    ///
+    /// - The current time (seconds, minutes, hours, lower days, upper days), with each register
+    ///   stored as a little-endian u32
+    /// - The latched time in the registers, in the same layout
+    /// - The UNIX timestamp of when the save was made, as a little-endian u64
+    pub fn save(&self) -> Vec<u8> {
+        let current_seconds = self.current_seconds();
+        let current_days = current_seconds / 86400;
+        let current = [
+            (current_seconds % 60) as u8,
+            ((current_seconds / 60) % 60) as u8,
+            ((current_seconds / 3600) % 24) as u8,
+            current_days as u8,
+            self.create_days_upper(current_days),
+        ];
+        let latched = [self.seconds, self.minutes, self.hours, self.days_lower, self.days_upper];
+
+        current.into_iter()
+            .chain(latched)
+            .flat_map(|register| (register as u32).to_le_bytes())
+            .chain(self.clock.unix_secs().unwrap_or(0).to_le_bytes())
+            .collect()
+    }
+
+    /// Restore a clock which was saved using `save`. Unless the clock was halted, it is advanced
+    /// by however much real time has passed since the save was made.
+    ///
+    /// Parameters:
+    /// - `data`: the saved clock, which can also end in a 32-bit timestamp (44 bytes total)
+    ///
+    /// Returns the restored clock, or None if the data isn't the size of a saved clock
+    pub fn load(data: &[u8]) -> Option<RealTimeClock> {
+        let mut rtc = RealTimeClock::default();
+        rtc.restore(data)?;
+        Some(rtc)
+    }
+
+    /// Overwrite the time with a clock which was saved using `save`, keeping the same source of
+    /// time. Unless the saved clock was halted, it is advanced by however much time has passed
+    /// since the save was made (if the source knows the date).
+    ///
+    /// Parameters:
+    /// - `data`: the saved clock, which can also end in a 32-bit timestamp (44 bytes total)
+    ///
+    /// Returns None without changing anything if the data isn't the size of a saved clock
+    pub fn restore(&mut self, data: &[u8]) -> Option<()> {
+        let timestamp = &data[TIMESTAMP_OFFSET.min(data.len())..];
+        let timestamp = match data.len() {
+            RTC_SAVE_SIZE => u64::from_le_bytes(timestamp.try_into().ok()?),
+            RTC_SAVE_SIZE_32 => u32::from_le_bytes(timestamp.try_into().ok()?) as u64,
+            _ => return None,
+        };
+        // every register fits in the lowest byte of its u32
+        let register = |index: usize| data[index * SAVED_REGISTER_SIZE];
+        let current_seconds = to_seconds(register(0), register(1), register(2), register(3))
+            + ((register(4) as u64 & 1) << 8) * 86400;
+        let halted = register(4) & 0x40 != 0;
+
+        self.seconds = register(5) & 0x3F;
+        self.minutes = register(6) & 0x3F;
+        self.hours = register(7) & 0x1F;
+        self.days_lower = register(8);
+        self.days_upper = register(9) & 0xC1;
+
+        let elapsed_seconds = match self.clock.unix_secs() {
+            Some(now) if !halted => now.saturating_sub(timestamp),
+            _ => 0,
+        };
+        // the current time can only be behind the latched time if the program wrote to the
+        // registers after latching them, which this clock can't represent
+        self.seconds_since_latch = (current_seconds + elapsed_seconds)
+            .saturating_sub(self.register_seconds());
+        self.halted = halted;
+        self.restart_elapsed();
+
+        Some(())
     }
 
     fn create_days_upper(&self, total_days: u64) -> u8 {
@@ -145,9 +344,9 @@ impl RealTimeClock {
     pub fn set_days_upper(&mut self, value: u8) -> u8 {
         let halted = (value & 0x40) != 0;
         if self.halted & !halted {
-            self.last_modified = Instant::now();
+            self.restart_elapsed();
         } else if !self.halted && halted {
-            self.seconds_since_latch += self.last_modified.elapsed().as_secs();
+            self.seconds_since_latch += self.elapsed_seconds();
         }
         self.halted = halted;
 
@@ -157,12 +356,26 @@ impl RealTimeClock {
     }
 }
 
+/// Get the number of seconds in a time, where the days only include the lower 8 bits
+fn to_seconds(seconds: u8, minutes: u8, hours: u8, days_lower: u8) -> u64 {
+    days_lower as u64 * 86400 + hours as u64 * 3600 + minutes as u64 * 60 + seconds as u64
+}
+
+#[cfg(test)]
+impl RealTimeClock {
+    /// Pretend that the given number of seconds have passed since the clock was last modified
+    pub(crate) fn fake_elapsed(&mut self, seconds: u64) {
+        self.advanced_seconds += seconds;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // NOTE - I explicitly did not add a test for a write followed by a latch because
     // I am not sure how this behavior should be handled.
 
-    use std::time::Duration;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
     use super::*;
 
     const CHANGE_ALL_REGISTERS: u64 = 86400 * 511 + 11190;
@@ -191,7 +404,7 @@ mod tests {
     fn test_latch_updates_all_registers() {
         let mut rtc = init_rtc();
         // subtract 10 seconds from the access time to fake as if 10 seconds went by
-        rtc.last_modified -= Duration::new(CHANGE_ALL_REGISTERS, 0);
+        rtc.fake_elapsed(CHANGE_ALL_REGISTERS);
 
         rtc.latch();
 
@@ -202,7 +415,7 @@ mod tests {
     fn test_latch_updates_overflow_bit() {
         let mut rtc = init_rtc();
         let dur_seconds = 512 * 86400;
-        rtc.last_modified -= Duration::new(dur_seconds, 0);
+        rtc.fake_elapsed(dur_seconds);
 
         rtc.latch();
 
@@ -212,11 +425,11 @@ mod tests {
     #[test]
     fn test_latch_with_halt() {
         let mut rtc = init_rtc();
-        rtc.last_modified -= Duration::new(5, 0);
+        rtc.fake_elapsed(5);
 
         rtc.set_days_upper(0x40); // halt the clock
         rtc.set_days_upper(0x0); // un-halt the clock
-        rtc.last_modified -= Duration::new(5, 0);
+        rtc.fake_elapsed(5);
         rtc.latch();
         let result = rtc.get_seconds();
 
@@ -226,14 +439,14 @@ mod tests {
     #[test]
     fn test_latch_inside_halt() {
         let mut rtc = init_rtc();
-        rtc.last_modified -= Duration::new(5, 0);
+        rtc.fake_elapsed(5);
 
         rtc.set_days_upper(0x40);
         rtc.latch();
         let halt_result = rtc.get_seconds();
 
         rtc.set_days_upper(0x0);
-        rtc.last_modified -= Duration::new(5, 0);
+        rtc.fake_elapsed(5);
         rtc.latch();
         let resume_result = rtc.get_seconds();
 
@@ -241,6 +454,82 @@ mod tests {
         assert_eq!(resume_result, 10);
     }
     
+    #[test]
+    fn test_latch_round_trips_hours() {
+        // halted, so that only the register values go into the latched time
+        let mut rtc = RealTimeClock::new(Some(30), Some(6), Some(5), Some(2), Some(0x40));
+
+        rtc.latch();
+        rtc.latch();
+
+        rtc.test_registers(0x40, 2, 5, 6, 30);
+    }
+
+    #[test]
+    fn test_manual_advance() {
+        let mut rtc = RealTimeClock::manual();
+
+        rtc.advance(CHANGE_ALL_REGISTERS);
+        rtc.latch();
+
+        rtc.test_registers(1, 255, 3, 6, 30);
+    }
+
+    #[test]
+    fn test_manual_advance_while_halted() {
+        let mut rtc = RealTimeClock::manual();
+
+        rtc.advance(5);
+        rtc.set_days_upper(0x40); // halt the clock
+        rtc.advance(20);
+        rtc.set_days_upper(0x0); // un-halt the clock
+        rtc.advance(5);
+        rtc.latch();
+
+        assert_eq!(rtc.get_seconds(), 10, "Time shouldn't pass while the clock is halted");
+    }
+
+    #[test]
+    fn test_manual_restore_ignores_real_time() {
+        let mut rtc = init_rtc();
+        rtc.fake_elapsed(30);
+        let mut save = rtc.save();
+        // pretend the save was made an hour ago
+        let timestamp = SystemClock::default().unix_secs().unwrap() - 3600;
+        save[TIMESTAMP_OFFSET..].copy_from_slice(&timestamp.to_le_bytes());
+        let mut restored = RealTimeClock::manual();
+
+        let result = restored.restore(&save);
+        restored.latch();
+
+        assert!(result.is_some(), "Should restore a saved clock");
+        restored.test_registers(0, 0, 0, 0, 30);
+    }
+
+    /// A clock which returns a list of times, one for each call
+    struct ScriptedClock(RefCell<VecDeque<u64>>);
+
+    impl Clock for ScriptedClock {
+        fn now_secs(&self) -> u64 {
+            self.0.borrow_mut()
+                .pop_front()
+                .expect("The clock was read more times than expected")
+        }
+    }
+
+    #[test]
+    fn test_custom_clock() {
+        // one read when the clock is set, and then 2 for each latch (the current time, and when
+        // to count from next)
+        let times = VecDeque::from([100, 190, 190, 250, 250]);
+        let mut rtc = init_rtc().with_clock(Box::new(ScriptedClock(RefCell::new(times))));
+
+        rtc.latch();
+        rtc.test_registers(0, 0, 0, 1, 30);
+        rtc.latch();
+        rtc.test_registers(0, 0, 0, 2, 30);
+    }
+
     #[test]
     fn test_seconds_uses_6_bits() {
         let mut rtc = init_rtc();
@@ -0,0 +1,1861 @@
+use crate::cycles::TCycles;
+use crate::interrupt::{STAT_MASK, VBLANK_MASK};
+use crate::memory::MemoryWriteError;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+mod palette;
+mod sprite;
+
+pub use palette::{PaletteRam, CGB_PALETTES, COLORS_PER_PALETTE, PALETTE_RAM_SIZE};
+pub use sprite::{SpriteAttr, SpriteFlags};
+
+/// The bit in the IF register which the PPU sets when it enters VBlank
+pub const VBLANK_INTERRUPT: u8 = VBLANK_MASK;
+
+// Scanline timing constants (measured in dots, which are the same length as T-cycles)
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+pub const VBLANK_LINE: u8 = 144;
+const LINES_PER_FRAME: u8 = 154;
+/// The number of dots it takes the PPU to draw a frame, including VBlank
+pub const DOTS_PER_FRAME: u32 = DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32;
+
+// Penalties which can lengthen mode 3 (see `Ppu::mode_3_length` for details)
+const WINDOW_PENALTY_DOTS: u16 = 6;
+const SPRITE_FETCH_DOTS: u16 = 6;
+const MAX_SPRITE_ALIGN_DOTS: u16 = 5;
+// The longest mode 3 can be, with the most fine scrolling, the window, and 10 sprites
+const MAX_DRAWING_DOTS: u16 = DRAWING_DOTS + 7 + WINDOW_PENALTY_DOTS
+    + MAX_LINE_SPRITES as u16 * (SPRITE_FETCH_DOTS + MAX_SPRITE_ALIGN_DOTS);
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+pub const FRAMEBUFFER_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
+// The number of bytes in one RGBA8888 pixel
+pub const RGBA_PIXEL_SIZE: usize = 4;
+
+/// The green tint of the original DMG screen, as 0xRRGGBBAA colors from the lightest shade to the
+/// darkest
+pub const DMG_GREEN_PALETTE: [u32; 4] = [0xE0F8D0FF, 0x88C070FF, 0x346856FF, 0x081820FF];
+
+pub const VRAM_SIZE: usize = 0x2000;
+// A CGB has a second bank of VRAM, which is selected with VBK
+const VRAM_BANKS: usize = 2;
+// Where each background tile map starts in VRAM (0x9800 and 0x9C00 in memory)
+const TILE_MAP_0: usize = 0x1800;
+const TILE_MAP_1: usize = 0x1C00;
+const TILE_MAP_WIDTH: usize = 32;
+const TILE_BYTES: usize = 16;
+// Tile numbers are signed relative to this address (0x9000) when LCDC bit 4 is clear
+const SIGNED_TILE_BASE: i32 = 0x1000;
+// WX holds the window's position plus 7, so WX = 7 puts it against the left edge of the screen
+const WINDOW_X_OFFSET: u8 = 7;
+// The window isn't visible anywhere on a line when WX is past this
+const MAX_WINDOW_X: u8 = 166;
+
+pub const OAM_SIZE: usize = 160;
+pub const OAM_ENTRIES: usize = OAM_SIZE / 4;
+// The most sprites which the OAM scan can select for a single line
+const MAX_LINE_SPRITES: usize = 10;
+
+// PPU register addresses
+pub const LCDC: u16 = 0xFF40;
+pub const STAT: u16 = 0xFF41;
+pub const SCY: u16 = 0xFF42;
+pub const SCX: u16 = 0xFF43;
+pub const LY: u16 = 0xFF44;
+pub const LYC: u16 = 0xFF45;
+pub const BGP: u16 = 0xFF47;
+pub const OBP0: u16 = 0xFF48;
+pub const OBP1: u16 = 0xFF49;
+pub const WY: u16 = 0xFF4A;
+pub const WX: u16 = 0xFF4B;
+pub const VBK: u16 = 0xFF4F;
+pub const BCPS: u16 = 0xFF68;
+pub const BCPD: u16 = 0xFF69;
+pub const OCPS: u16 = 0xFF6A;
+pub const OCPD: u16 = 0xFF6B;
+pub const OPRI: u16 = 0xFF6C;
+
+// LCDC bit masks
+const LCDC_BG_WINDOW_ENABLE: u8 = 0x01;
+const LCDC_SPRITE_ENABLE: u8 = 0x02;
+const LCDC_SPRITE_SIZE: u8 = 0x04;
+const LCDC_BG_TILE_MAP: u8 = 0x08;
+const LCDC_TILE_DATA: u8 = 0x10;
+const LCDC_WINDOW_ENABLE: u8 = 0x20;
+const LCDC_WINDOW_TILE_MAP: u8 = 0x40;
+pub const LCDC_ENABLE: u8 = 0x80;
+
+// STAT bit masks
+const STAT_COINCIDENCE: u8 = 0x04;
+const STAT_HBLANK_INTERRUPT: u8 = 0x08;
+const STAT_VBLANK_INTERRUPT: u8 = 0x10;
+const STAT_OAM_INTERRUPT: u8 = 0x20;
+const STAT_LYC_INTERRUPT: u8 = 0x40;
+const STAT_WRITABLE: u8 = 0x78;
+// Bit 7 of STAT doesn't exist, so it always reads as 1
+const STAT_UNUSED: u8 = 0x80;
+
+// Only bit 0 of VBK is used to select the VRAM bank, the rest always read as 1
+const VBK_BANK: u8 = 0x01;
+
+// OPRI bit which selects DMG-style sprite priority (by X coordinate) over CGB-style (by OAM index)
+const OPRI_BY_X: u8 = 0x01;
+
+/// # PpuMode
+/// The current state of the PPU, using the same numbering as the lower 2 bits of the STAT
+/// register.
+///
+/// Each visible line (0-143) goes through OAM Scan -> Drawing -> HBlank, and lines 144-153 are
+/// spent entirely in VBlank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PpuMode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+/// # Ppu
+/// The Picture Processing Unit of a Game Boy system. It steps through the modes of each line as
+/// it is ticked, requesting VBlank and STAT interrupts, and draws each visible line into a
+/// framebuffer once that line's mode 3 (drawing) has finished. It also holds VRAM and OAM.
+///
+/// The framebuffer holds one shade (0-3, from lightest to darkest) per pixel, after the palette
+/// registers have been applied.
+pub struct Ppu {
+    mode: PpuMode,
+    ly: u8,
+    dot: u16, // the dot being drawn inside of the current line
+    drawing_dots: u16, // the length of mode 3 in the current line
+    accurate_mode_3: bool,
+    stat_line: bool, // whether any enabled STAT interrupt condition is currently met
+    vram: [u8; VRAM_SIZE * VRAM_BANKS],
+    vram_bank: u8, // the bank the CPU reads and writes, which is always 0 on a DMG
+    oam: [u8; OAM_SIZE],
+    framebuffer: [u8; FRAMEBUFFER_SIZE],
+    background_palettes: PaletteRam, // CGB only
+    object_palettes: PaletteRam, // CGB only
+    lcdc: u8,
+    stat: u8, // only the interrupt enable bits, since the rest are derived from the PPU state
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    scy: u8,
+    scx: u8,
+    wy: u8,
+    wx: u8,
+    opri: u8,
+    window_line: u8, // the line of the window to draw next, which only moves when it's drawn
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Ppu {
+            mode: PpuMode::HBlank, // the LCD is off until LCDC turns it on
+            ly: 0,
+            dot: 0,
+            drawing_dots: DRAWING_DOTS,
+            accurate_mode_3: false,
+            stat_line: false,
+            vram: [0; VRAM_SIZE * VRAM_BANKS],
+            vram_bank: 0,
+            oam: [0; OAM_SIZE],
+            framebuffer: [0; FRAMEBUFFER_SIZE],
+            background_palettes: PaletteRam::new(),
+            object_palettes: PaletteRam::new(),
+            lcdc: 0,
+            stat: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            scy: 0,
+            scx: 0,
+            wy: 0,
+            wx: 0,
+            // the CGB boot ROM sets this for DMG games, and a DMG always uses X priority
+            opri: OPRI_BY_X,
+            window_line: 0,
+        }
+    }
+
+    /// Change whether the length of mode 3 (drawing) should vary like it does on hardware.
+    ///
+    /// When disabled (the default), mode 3 always takes 172 dots. When enabled, mode 3 is
+    /// lengthened by fine scrolling, the window, and sprites on the current line, and mode 0
+    /// (HBlank) is shortened by the same amount. This matters for timing-sensitive raster effects.
+    pub fn set_accurate_mode_3(&mut self, enabled: bool) {
+        self.accurate_mode_3 = enabled;
+    }
+
+    /// Get the line currently being processed by the PPU (the LY register)
+    pub fn get_ly(&self) -> u8 {
+        self.ly
+    }
+
+    /// Get the mode the PPU is currently in
+    pub fn get_mode(&self) -> PpuMode {
+        self.mode
+    }
+
+    /// Get the finished frame, as one shade (0-3) per pixel in rows from top to bottom. Lines
+    /// which are still being drawn hold what was drawn there in the previous frame.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    /// Get the framebuffer as RGBA8888 pixels (see `shades_to_rgba`)
+    pub fn framebuffer_rgba(&self, palette: &[u32; 4]) -> Vec<u8> {
+        shades_to_rgba(&self.framebuffer, palette)
+    }
+
+    /// Get the index into `vram` of an address in the bank selected by VBK
+    fn vram_index(&self, address: u16) -> Option<usize> {
+        let address = address as usize;
+        (address < VRAM_SIZE).then(|| self.vram_bank as usize * VRAM_SIZE + address)
+    }
+
+    /// Get the byte at the given address in the selected bank of VRAM
+    ///
+    /// Parameters:
+    /// - `address`: the VRAM address to read from, indexed between 0 and 8,191
+    ///
+    /// Returns the byte at the given address, or None if the address is not valid
+    pub fn read_vram(&self, address: u16) -> Option<u8> {
+        self.vram.get(self.vram_index(address)?)
+            .copied()
+    }
+
+    /// Write a byte into the given address in the selected bank of VRAM
+    ///
+    /// Parameters:
+    /// - `address`: the VRAM address to write to, indexed between 0 and 8,191
+    /// - `data`: the value to store in VRAM
+    ///
+    /// Returns the value previously at the given address, or a MemoryWriteError if the address
+    /// is not in the valid range
+    pub fn write_vram(&mut self, address: u16, data: u8) -> Result<u8, MemoryWriteError> {
+        let index = self.vram_index(address)
+            .ok_or(MemoryWriteError::OutOfBounds(address))?;
+        let byte = &mut self.vram[index];
+        let old_value = *byte;
+        *byte = data;
+
+        Ok(old_value)
+    }
+
+    /// Copy bytes straight into the selected bank of VRAM, without going through the CPU
+    ///
+    /// Parameters:
+    /// - `offset`: where to start copying to, indexed between 0 and 8,191
+    /// - `data`: the bytes to copy
+    ///
+    /// Returns an OutOfBounds error holding the offset of the first byte which doesn't fit, in
+    /// which case nothing is copied
+    pub fn load_vram(&mut self, offset: u16, data: &[u8]) -> Result<(), MemoryWriteError> {
+        let start = self.vram_index(offset)
+            .ok_or(MemoryWriteError::OutOfBounds(offset))?;
+        if offset as usize + data.len() > VRAM_SIZE {
+            return Err(MemoryWriteError::OutOfBounds(VRAM_SIZE as u16));
+        }
+        self.vram[start..start + data.len()].copy_from_slice(data);
+
+        Ok(())
+    }
+
+    /// Replace the whole of OAM, without going through the CPU or OAM DMA
+    pub fn load_oam(&mut self, data: &[u8; OAM_SIZE]) {
+        self.oam = *data;
+    }
+
+    /// Get the byte at the given address in OAM
+    ///
+    /// Parameters:
+    /// - `address`: the OAM address to read from, indexed between 0 and 159
+    ///
+    /// Returns the byte at the given address, or None if the address is not valid
+    pub fn read_oam(&self, address: u16) -> Option<u8> {
+        self.oam.get(address as usize)
+            .copied()
+    }
+
+    /// Write a byte into the given address in OAM
+    ///
+    /// Parameters:
+    /// - `address`: the OAM address to write to, indexed between 0 and 159
+    /// - `data`: the value to store in OAM
+    ///
+    /// Returns the value previously at the given address, or a MemoryWriteError if the address
+    /// is not in the valid range
+    pub fn write_oam(&mut self, address: u16, data: u8) -> Result<u8, MemoryWriteError> {
+        let byte = self.oam.get_mut(address as usize)
+            .ok_or(MemoryWriteError::OutOfBounds(address))?;
+        let old_value = *byte;
+        *byte = data;
+
+        Ok(old_value)
+    }
+
+    /// Read one of the PPU's registers
+    ///
+    /// Parameters:
+    /// - `address`: the address of the register in memory (e.g. 0xFF40 for LCDC)
+    ///
+    /// Returns the value of the register, or None if the address isn't a PPU register
+    pub fn read_register(&self, address: u16) -> Option<u8> {
+        match address {
+            LCDC => Some(self.lcdc),
+            STAT => Some(self.read_stat()),
+            SCY => Some(self.scy),
+            SCX => Some(self.scx),
+            LY => Some(self.ly),
+            LYC => Some(self.lyc),
+            BGP => Some(self.bgp),
+            OBP0 => Some(self.obp0),
+            OBP1 => Some(self.obp1),
+            WY => Some(self.wy),
+            WX => Some(self.wx),
+            VBK => Some(!VBK_BANK | self.vram_bank),
+            BCPS => Some(self.background_palettes.read_index()),
+            BCPD => Some(self.background_palettes.read_data(self.palettes_accessible())),
+            OCPS => Some(self.object_palettes.read_index()),
+            OCPD => Some(self.object_palettes.read_data(self.palettes_accessible())),
+            // only the lowest bit is used, the rest always read as 1
+            OPRI => Some(0xFE | self.opri),
+            _ => None
+        }
+    }
+
+    /// Write to one of the PPU's registers
+    ///
+    /// Parameters:
+    /// - `address`: the address of the register in memory (e.g. 0xFF40 for LCDC)
+    /// - `data`: the value to write into the register
+    ///
+    /// Returns the previous value of the register, or a MemoryWriteError if the address isn't a
+    /// PPU register. Writes to read-only registers (like LY) are ignored.
+    pub fn write_register(&mut self, address: u16, data: u8) -> Result<u8, MemoryWriteError> {
+        let register = match address {
+            LCDC => {
+                let old_value = self.lcdc;
+                self.lcdc = data;
+                self.switch_lcd(old_value & LCDC_ENABLE != 0, data & LCDC_ENABLE != 0);
+                return Ok(old_value);
+            }
+            STAT => {
+                let old_value = self.read_stat();
+                self.stat = data & STAT_WRITABLE;
+                return Ok(old_value);
+            }
+            SCY => &mut self.scy,
+            SCX => &mut self.scx,
+            LY => return Ok(self.ly),
+            LYC => &mut self.lyc,
+            BGP => &mut self.bgp,
+            OBP0 => &mut self.obp0,
+            OBP1 => &mut self.obp1,
+            WY => &mut self.wy,
+            WX => &mut self.wx,
+            VBK => {
+                let old_value = !VBK_BANK | self.vram_bank;
+                self.vram_bank = data & VBK_BANK;
+                return Ok(old_value);
+            }
+            OPRI => {
+                let old_value = 0xFE | self.opri;
+                self.opri = data & OPRI_BY_X;
+                return Ok(old_value);
+            }
+            BCPS => return Ok(self.background_palettes.write_index(data)),
+            BCPD => {
+                let accessible = self.palettes_accessible();
+                return Ok(self.background_palettes.write_data(data, accessible));
+            }
+            OCPS => return Ok(self.object_palettes.write_index(data)),
+            OCPD => {
+                let accessible = self.palettes_accessible();
+                return Ok(self.object_palettes.write_data(data, accessible));
+            }
+            _ => return Err(MemoryWriteError::OutOfBounds(address))
+        };
+        let old_value = *register;
+        *register = data;
+
+        Ok(old_value)
+    }
+
+    /// Write the state of the PPU into a snapshot, including VRAM, OAM and the framebuffer.
+    /// Whether mode 3 has an accurate length is a setting rather than state, so it isn't saved.
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_u8(self.mode as u8);
+        writer.write_u8(self.ly);
+        writer.write_u16(self.dot);
+        writer.write_u16(self.drawing_dots);
+        writer.write_bool(self.stat_line);
+        writer.write_bytes(&self.vram);
+        writer.write_u8(self.vram_bank);
+        writer.write_bytes(&self.oam);
+        writer.write_bytes(&self.framebuffer);
+        self.background_palettes.save_state(writer);
+        self.object_palettes.save_state(writer);
+        let registers = [
+            self.lcdc, self.stat, self.lyc, self.bgp, self.obp0, self.obp1,
+            self.scy, self.scx, self.wy, self.wx, self.opri
+        ];
+        registers.into_iter()
+            .for_each(|register| writer.write_u8(register));
+        writer.write_u8(self.window_line);
+    }
+
+    /// Restore the state written by `save_state`
+    pub fn load_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.mode = match reader.read_u8()? {
+            0 => PpuMode::HBlank,
+            1 => PpuMode::VBlank,
+            2 => PpuMode::OamScan,
+            3 => PpuMode::Drawing,
+            _ => return Err(SnapshotError::InvalidValue)
+        };
+        self.ly = reader.read_u8()?;
+        self.dot = reader.read_u16()?;
+        self.drawing_dots = reader.read_u16()?;
+        if self.ly >= LINES_PER_FRAME
+            || self.dot >= DOTS_PER_LINE
+            || !(DRAWING_DOTS..=MAX_DRAWING_DOTS).contains(&self.drawing_dots)
+        {
+            return Err(SnapshotError::InvalidValue);
+        }
+        self.stat_line = reader.read_bool()?;
+        reader.read_into(&mut self.vram)?;
+        self.vram_bank = reader.read_u8()?;
+        if self.vram_bank & !VBK_BANK != 0 {
+            return Err(SnapshotError::InvalidValue);
+        }
+        reader.read_into(&mut self.oam)?;
+        reader.read_into(&mut self.framebuffer)?;
+        self.background_palettes.load_state(reader)?;
+        self.object_palettes.load_state(reader)?;
+        let registers = [
+            &mut self.lcdc, &mut self.stat, &mut self.lyc, &mut self.bgp, &mut self.obp0,
+            &mut self.obp1, &mut self.scy, &mut self.scx, &mut self.wy, &mut self.wx,
+            &mut self.opri
+        ];
+        for register in registers {
+            *register = reader.read_u8()?;
+        }
+        self.stat &= STAT_WRITABLE;
+        self.opri &= OPRI_BY_X;
+        self.window_line = reader.read_u8()?;
+        // the window can't have drawn more lines than there are on the screen
+        if self.window_line as usize > SCREEN_HEIGHT {
+            return Err(SnapshotError::InvalidValue);
+        }
+
+        Ok(())
+    }
+
+    /// Find the pixel of the 256x256 background map which is drawn at a position on the screen.
+    /// The background wraps around, so scrolling past the right or bottom edge of the map shows
+    /// the left or top edge again.
+    ///
+    /// Parameters:
+    /// - `screen_x`: the column of the screen being drawn (0-159)
+    /// - `line`: the line of the screen being drawn (0-143)
+    ///
+    /// Returns the (x, y) position in the background map
+    pub fn background_position(&self, screen_x: u8, line: u8) -> (u8, u8) {
+        (screen_x.wrapping_add(self.scx), line.wrapping_add(self.scy))
+    }
+
+    /// Get every sprite in OAM, parsed into its individual fields and ordered by OAM index
+    pub fn sprite_attributes(&self) -> [SpriteAttr; OAM_ENTRIES] {
+        std::array::from_fn(|idx| {
+            let entry = &self.oam[idx * 4..idx * 4 + 4];
+            SpriteAttr::from([entry[0], entry[1], entry[2], entry[3]])
+        })
+    }
+
+    /// Advance the PPU by the given number of dots
+    ///
+    /// Parameters:
+    /// - `dots`: the number of dots (T-cycles) to run the PPU for
+    ///
+    /// Returns the interrupts requested while running, as a mask of bits in the IF register
+    pub fn tick(&mut self, dots: TCycles) -> u8 {
+        // the PPU is stopped while the LCD is off
+        if self.lcdc & LCDC_ENABLE == 0 {
+            return 0;
+        }
+
+        let mut interrupts = 0;
+        for _ in 0..dots.0 {
+            interrupts |= self.step_dot();
+        }
+
+        interrupts
+    }
+
+    fn step_dot(&mut self) -> u8 {
+        let mut interrupts = 0;
+        self.dot += 1;
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.ly = (self.ly + 1) % LINES_PER_FRAME;
+            interrupts |= self.start_line();
+        } else if self.ly < VBLANK_LINE {
+            // VBlank lines don't change modes partway through
+            if self.dot == OAM_SCAN_DOTS {
+                self.mode = PpuMode::Drawing;
+                self.drawing_dots = if self.accurate_mode_3 {
+                    self.mode_3_length()
+                } else {
+                    DRAWING_DOTS
+                };
+            } else if self.dot == OAM_SCAN_DOTS + self.drawing_dots {
+                self.mode = PpuMode::HBlank;
+                self.render_line();
+            }
+        }
+
+        interrupts | self.update_stat_line()
+    }
+
+    /// Get one of the CGB background palette colors as RGB555 (see `PaletteRam::color`)
+    pub fn background_palette_color(&self, palette: usize, color: usize) -> Option<u16> {
+        self.background_palettes.color(palette, color)
+    }
+
+    /// Get one of the CGB object palette colors as RGB555 (see `PaletteRam::color`)
+    pub fn object_palette_color(&self, palette: usize, color: usize) -> Option<u16> {
+        self.object_palettes.color(palette, color)
+    }
+
+    /// Whether the CPU can access the CGB palette RAM, which is locked while drawing unless the
+    /// LCD is turned off
+    fn palettes_accessible(&self) -> bool {
+        self.lcdc & LCDC_ENABLE == 0 || self.mode != PpuMode::Drawing
+    }
+
+    fn read_stat(&self) -> u8 {
+        let coincidence = if self.ly == self.lyc { STAT_COINCIDENCE } else { 0 };
+        STAT_UNUSED | self.stat | coincidence | self.mode as u8
+    }
+
+    /// Update whether any of the conditions enabled in STAT are met. The STAT interrupt is only
+    /// requested when this goes from false to true, so conditions which are met back to back
+    /// (like HBlank followed by an LYC match on the next line) only request it once.
+    fn update_stat_line(&mut self) -> u8 {
+        let mode_enabled = match self.mode {
+            PpuMode::HBlank => STAT_HBLANK_INTERRUPT,
+            // the OAM condition is also met for the first dot of line 144, even though there's
+            // no OAM scan at the start of VBlank
+            PpuMode::VBlank if self.ly == VBLANK_LINE && self.dot == 0 => {
+                STAT_VBLANK_INTERRUPT | STAT_OAM_INTERRUPT
+            },
+            PpuMode::VBlank => STAT_VBLANK_INTERRUPT,
+            PpuMode::OamScan => STAT_OAM_INTERRUPT,
+            PpuMode::Drawing => 0,
+        };
+        let line = self.stat & mode_enabled != 0
+            || (self.stat & STAT_LYC_INTERRUPT != 0 && self.ly == self.lyc);
+
+        let rising_edge = line && !self.stat_line;
+        self.stat_line = line;
+        if rising_edge { STAT_MASK } else { 0 }
+    }
+
+    /// Draw the current line into the framebuffer
+    fn render_line(&mut self) {
+        let sprites = if self.lcdc & LCDC_SPRITE_ENABLE != 0 {
+            self.line_sprites_by_priority()
+        } else {
+            Vec::new()
+        };
+
+        let window_visible = self.window_visible();
+        let row = self.ly as usize * SCREEN_WIDTH;
+        for x in 0..SCREEN_WIDTH {
+            let window_x = (x as u8 + WINDOW_X_OFFSET).checked_sub(self.wx)
+                .filter(|_| window_visible);
+            let background = if self.lcdc & LCDC_BG_WINDOW_ENABLE != 0 {
+                Some(window_x.map_or_else(|| self.background_color(x as u8), |window_x| {
+                    self.window_color(window_x)
+                }))
+            } else {
+                None
+            };
+
+            // on a DMG, turning off the background leaves it white instead of using color 0
+            let shade = self.sprite_shade(&sprites, x as u8, background.unwrap_or(0))
+                .unwrap_or_else(|| background.map_or(0, |color| apply_palette(self.bgp, color)));
+            self.framebuffer[row + x] = shade;
+        }
+
+        // lines where the window is hidden don't count, so it picks up where it left off
+        if window_visible {
+            self.window_line = self.window_line.saturating_add(1);
+        }
+    }
+
+    /// Whether any of the window is drawn on the current line. The window is hidden when either
+    /// it or the background is disabled in LCDC, or when it's positioned past the line or the
+    /// right edge of the screen.
+    fn window_visible(&self) -> bool {
+        let enabled = LCDC_WINDOW_ENABLE | LCDC_BG_WINDOW_ENABLE;
+        self.lcdc & enabled == enabled && self.wy <= self.ly && self.wx <= MAX_WINDOW_X
+    }
+
+    /// Get the color number (0-3, before applying the palette) of the window at the given column
+    /// of the window, on the window's current line
+    fn window_color(&self, window_x: u8) -> u8 {
+        let y = self.window_line;
+        let map = if self.lcdc & LCDC_WINDOW_TILE_MAP != 0 { TILE_MAP_1 } else { TILE_MAP_0 };
+        let tile = self.vram[map + (y as usize / 8) * TILE_MAP_WIDTH + window_x as usize / 8];
+
+        self.tile_color(self.background_tile_address(tile), window_x % 8, y % 8)
+    }
+
+    /// Get the shade of the sprite pixel drawn at the given column of the current line
+    ///
+    /// Parameters:
+    /// - `sprites`: the sprites on the current line, from the highest priority to the lowest
+    /// - `screen_x`: the column of the screen being drawn (0-159)
+    /// - `background`: the color number (0-3) of the background under the sprites
+    ///
+    /// Returns the shade of the sprite pixel, or None if the background should be drawn instead
+    fn sprite_shade(&self, sprites: &[SpriteAttr], screen_x: u8, background: u8) -> Option<u8> {
+        let height = self.sprite_height();
+        // color 0 is transparent, so the first sprite with any other color there is drawn, even
+        // if it ends up behind the background
+        let (sprite, color) = sprites.iter()
+            .find_map(|sprite| {
+                let column = sprite.tile_column(screen_x)?;
+                let row = sprite.tile_row(self.ly, height)?;
+                // tall sprites always start on an even tile, with the odd tile below it
+                let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+                let color = self.tile_color(tile as usize * TILE_BYTES, column, row);
+                (color != 0).then_some((sprite, color))
+            })?;
+
+        if sprite.flags.behind_background && background != 0 {
+            return None;
+        }
+        let palette = if sprite.flags.dmg_palette == 0 { self.obp0 } else { self.obp1 };
+        Some(apply_palette(palette, color))
+    }
+
+    /// Get the color number (0-3, before applying the palette) of the background at the given
+    /// column of the current line
+    fn background_color(&self, screen_x: u8) -> u8 {
+        let (x, y) = self.background_position(screen_x, self.ly);
+        let map = if self.lcdc & LCDC_BG_TILE_MAP != 0 { TILE_MAP_1 } else { TILE_MAP_0 };
+        let tile = self.vram[map + (y as usize / 8) * TILE_MAP_WIDTH + x as usize / 8];
+
+        self.tile_color(self.background_tile_address(tile), x % 8, y % 8)
+    }
+
+    /// Get where the data for a background tile starts in VRAM. Depending on LCDC, tile numbers
+    /// either count up from 0x8000, or are signed and count from 0x9000.
+    fn background_tile_address(&self, tile: u8) -> usize {
+        if self.lcdc & LCDC_TILE_DATA != 0 {
+            tile as usize * TILE_BYTES
+        } else {
+            (SIGNED_TILE_BASE + tile as i8 as i32 * TILE_BYTES as i32) as usize
+        }
+    }
+
+    /// Get the color number (0-3) of a single pixel in the tile at the given VRAM address. Rows
+    /// past 7 continue into the next tile, which is how 8x16 sprites are stored.
+    fn tile_color(&self, address: usize, column: u8, row: u8) -> u8 {
+        // each row is 2 bytes, holding the low bits and then the high bits of each color, with
+        // the leftmost pixel in bit 7
+        let low = self.vram[address + row as usize * 2];
+        let high = self.vram[address + row as usize * 2 + 1];
+        let bit = 7 - column;
+
+        (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+    }
+
+    /// Get the height of every sprite (8 or 16), which is selected by LCDC
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & LCDC_SPRITE_SIZE != 0 { 16 } else { 8 }
+    }
+
+    /// Get the sprites selected by the OAM scan for the current line, in OAM order. Only the
+    /// first 10 sprites which overlap the line are selected, even if some of them are
+    /// horizontally off-screen, and the rest are dropped.
+    fn line_sprites(&self) -> Vec<SpriteAttr> {
+        let height = self.sprite_height() as u16;
+        // sprite y positions are offset by 16 so they can be partially off the top of the screen
+        let line = self.ly as u16 + 16;
+
+        self.sprite_attributes()
+            .into_iter()
+            .filter(|sprite| line >= sprite.y as u16 && line < sprite.y as u16 + height)
+            .take(MAX_LINE_SPRITES)
+            .collect()
+    }
+
+    /// Get the sprites which overlap the current line, ordered from the highest drawing priority
+    /// to the lowest (so where sprites overlap, the first one in the list is drawn on top).
+    ///
+    /// Depending on OPRI, sprites are either prioritized by OAM index (CGB-style) or by their X
+    /// coordinate, with ties broken by OAM index (DMG-style).
+    pub fn line_sprites_by_priority(&self) -> Vec<SpriteAttr> {
+        let mut sprites = self.line_sprites();
+        if self.opri & OPRI_BY_X != 0 {
+            // the sort is stable, so sprites with the same X coordinate stay in OAM order
+            sprites.sort_by_key(|sprite| sprite.x);
+        }
+
+        sprites
+    }
+
+    /// Calculate how many dots mode 3 takes on the current line. This follows the penalties
+    /// described by the Pan Docs:
+    ///
+    /// - The PPU discards `SCX % 8` pixels at the start of the line for fine scrolling
+    /// - Starting to draw the window costs 6 dots
+    /// - Each sprite costs 6 dots to fetch, plus up to 5 more depending on how it lines up with
+    ///   the background tile its leftmost pixel is in (only the first sprite in each tile pays
+    ///   this)
+    ///
+    /// https://gbdev.io/pandocs/Rendering.html#mode-3-length
+    fn mode_3_length(&self) -> u16 {
+        let mut length = DRAWING_DOTS + (self.scx % 8) as u16;
+
+        if self.window_visible() {
+            length += WINDOW_PENALTY_DOTS;
+        }
+
+        if self.lcdc & LCDC_SPRITE_ENABLE == 0 {
+            return length;
+        }
+
+        let mut sprites = self.line_sprites();
+        // the penalties are applied as the PPU reaches each sprite, so go from left to right
+        sprites.sort_by_key(|sprite| sprite.x);
+        let mut tiles_considered: Vec<u8> = Vec::new();
+        for sprite in sprites.iter().filter(|sprite| sprite.x < 168) {
+            length += SPRITE_FETCH_DOTS;
+
+            // a sprite at x = 0 is entirely off of the screen, so it always pays the full penalty
+            if sprite.x == 0 {
+                length += MAX_SPRITE_ALIGN_DOTS;
+                continue;
+            }
+            // the background pixel under the leftmost pixel of the sprite
+            let pixel = sprite.x.wrapping_sub(8).wrapping_add(self.scx);
+            let tile = pixel / 8;
+            if !tiles_considered.contains(&tile) {
+                tiles_considered.push(tile);
+                length += MAX_SPRITE_ALIGN_DOTS.saturating_sub((pixel % 8) as u16);
+            }
+        }
+
+        length
+    }
+
+    /// Stop or restart the PPU when the LCD is turned off or on through LCDC. While it's off,
+    /// the PPU stays in mode 0 on line 0, and turning it back on starts a new frame from dot 0.
+    fn switch_lcd(&mut self, was_enabled: bool, enabled: bool) {
+        if was_enabled == enabled {
+            return;
+        }
+
+        self.ly = 0;
+        self.dot = 0;
+        self.stat_line = false;
+        if enabled {
+            self.start_line();
+        } else {
+            self.mode = PpuMode::HBlank;
+        }
+    }
+
+    fn start_line(&mut self) -> u8 {
+        // VBlank should only be requested on the transition into line 144, not on every line
+        // after it, so it happens exactly once per frame
+        if self.ly == VBLANK_LINE {
+            self.mode = PpuMode::VBlank;
+            return VBLANK_INTERRUPT;
+        }
+
+        if self.ly == 0 {
+            self.window_line = 0;
+        }
+        if self.ly < VBLANK_LINE {
+            self.mode = PpuMode::OamScan;
+        }
+        0
+    }
+}
+
+/// Get the shade (0-3) which a palette register (like BGP) maps the given color number to
+fn apply_palette(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0x03
+}
+
+/// Convert shades (like the ones in the framebuffer) into packed RGBA8888 pixels, which is the
+/// format most front-ends draw with
+///
+/// Parameters:
+/// - `shades`: one shade (0-3, from lightest to darkest) per pixel
+/// - `palette`: the color of each shade, as 0xRRGGBBAA (like `DMG_GREEN_PALETTE`)
+///
+/// Returns 4 bytes per pixel, in the order red, green, blue, alpha
+pub fn shades_to_rgba(shades: &[u8], palette: &[u32; 4]) -> Vec<u8> {
+    shades.iter()
+        .flat_map(|shade| palette[(shade & 0x03) as usize].to_be_bytes())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::GameBoySystem;
+    use crate::cpu::instructions::{Instruction, Operation};
+    use crate::cycles::{m_to_t, MCycles};
+    use crate::memory::DmgMemoryController;
+    use crate::memory::cartridge::MockCartridgeMapper;
+
+    use super::*;
+
+    /// Create a PPU with the LCD turned on, so that it starts running from the start of a frame
+    fn init_enabled_ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        assert!(ppu.write_register(LCDC, LCDC_ENABLE).is_ok(), "Should be able to write LCDC");
+        ppu
+    }
+
+    /// Run the PPU one dot at a time until it reaches the given line, returning the interrupts
+    /// requested by the final dot
+    fn run_to_line(ppu: &mut Ppu, line: u8) -> u8 {
+        let mut interrupts = 0;
+        while ppu.get_ly() != line {
+            interrupts = ppu.tick(TCycles(1));
+        }
+        interrupts
+    }
+
+    #[test]
+    fn test_oam_io() {
+        let mut ppu = Ppu::new();
+
+        let write_result = ppu.write_oam(0x9F, 0x42);
+        let overwrite_result = ppu.write_oam(0x9F, 0x28);
+        let read_result = ppu.read_oam(0x9F);
+
+        assert_eq!(write_result, Ok(0), "Should be able to write to OAM");
+        assert_eq!(overwrite_result, Ok(0x42), "Should return the previous OAM value");
+        assert_eq!(read_result, Some(0x28), "Should read the written value");
+    }
+
+    #[test]
+    fn test_load_vram() {
+        let mut ppu = Ppu::new();
+        assert!(ppu.write_register(VBK, 1).is_ok(), "Should be able to select bank 1");
+
+        let result = ppu.load_vram(0x1FFE, &[0x42, 0x28]);
+
+        assert!(result.is_ok(), "Should be able to load the end of VRAM");
+        assert_eq!(ppu.read_vram(0x1FFE), Some(0x42), "Should load the selected bank");
+        assert_eq!(ppu.read_vram(0x1FFF), Some(0x28), "Should load every byte");
+        assert!(ppu.write_register(VBK, 0).is_ok(), "Should be able to select bank 0");
+        assert_eq!(ppu.read_vram(0x1FFE), Some(0), "Bank 0 should be left alone");
+    }
+
+    #[test]
+    fn test_load_vram_too_long() {
+        let mut ppu = Ppu::new();
+
+        let result = ppu.load_vram(0x1FFF, &[0x42, 0x28]);
+        let past_end = ppu.load_vram(0x2000, &[]);
+
+        assert_eq!(result, Err(MemoryWriteError::OutOfBounds(0x2000)), "Bytes should fit");
+        assert_eq!(ppu.read_vram(0x1FFF), Some(0), "Nothing should be loaded");
+        assert_eq!(
+            past_end, Err(MemoryWriteError::OutOfBounds(0x2000)),
+            "The start should be in VRAM"
+        );
+    }
+
+    #[test]
+    fn test_load_oam() {
+        let mut ppu = Ppu::new();
+        let mut oam = [0; OAM_SIZE];
+        oam[0..4].copy_from_slice(&[0x10, 0x08, 0x42, 0x20]);
+
+        ppu.load_oam(&oam);
+
+        assert_eq!(ppu.sprite_attributes()[0].tile, 0x42, "The sprite should be in OAM");
+        assert_eq!(ppu.read_oam(0x03), Some(0x20), "Every byte should be loaded");
+    }
+
+    #[test]
+    fn test_oam_invalid_address() {
+        let mut ppu = Ppu::new();
+
+        let write_result = ppu.write_oam(0xA0, 0x42);
+        let read_result = ppu.read_oam(0xA0);
+
+        assert!(write_result.is_err(), "OAM is only 160 bytes long");
+        assert!(read_result.is_none(), "OAM is only 160 bytes long");
+    }
+
+    #[test]
+    fn test_sprite_attributes_parse_entry() {
+        let mut ppu = Ppu::new();
+        // entry 5 - y = 0x50, x = 0x28, tile 0x7F, behind BG + x flip + OBP1 + bank 1 + palette 6
+        let entry = [0x50, 0x28, 0x7F, 0b1011_1110];
+        for (idx, byte) in entry.iter().enumerate() {
+            assert!(ppu.write_oam((5 * 4 + idx) as u16, *byte).is_ok());
+        }
+
+        let sprites = ppu.sprite_attributes();
+
+        assert_eq!(
+            sprites[5],
+            SpriteAttr {
+                y: 0x50,
+                x: 0x28,
+                tile: 0x7F,
+                flags: SpriteFlags {
+                    behind_background: true,
+                    y_flip: false,
+                    x_flip: true,
+                    dmg_palette: 1,
+                    cgb_bank: 1,
+                    cgb_palette: 6,
+                }
+            },
+            "OAM entry should be parsed into its individual fields"
+        );
+        assert_eq!(sprites[4], SpriteAttr::default(), "Other entries should be unchanged");
+        assert_eq!(sprites[6], SpriteAttr::default(), "Other entries should be unchanged");
+    }
+
+    /// Run the PPU through the current line, returning the number of dots it spent in mode 3
+    fn measure_drawing_dots(ppu: &mut Ppu) -> u16 {
+        let line = ppu.get_ly();
+        let mut drawing_dots = 0;
+        while ppu.get_ly() == line {
+            ppu.tick(TCycles(1));
+            if ppu.get_mode() == PpuMode::Drawing {
+                drawing_dots += 1;
+            }
+        }
+        drawing_dots
+    }
+
+    /// Place the given number of 8x8 sprites on line 0, spread across the screen
+    fn place_line_0_sprites(ppu: &mut Ppu, count: usize) {
+        for idx in 0..count {
+            let entry = [16, 8 + (idx as u8 * 12), 0, 0];
+            for (offset, byte) in entry.iter().enumerate() {
+                assert!(ppu.write_oam((idx * 4 + offset) as u16, *byte).is_ok());
+            }
+        }
+    }
+
+    #[test]
+    fn test_register_io() {
+        let mut ppu = Ppu::new();
+
+        let write_result = ppu.write_register(SCX, 0x42);
+        let read_result = ppu.read_register(SCX);
+
+        assert_eq!(write_result, Ok(0), "Should be able to write to SCX");
+        assert_eq!(read_result, Some(0x42), "Should read back the written value");
+    }
+
+    #[test]
+    fn test_lcd_off_stops_ppu() {
+        let mut ppu = init_enabled_ppu();
+        assert!(ppu.write_register(STAT, 0x08).is_ok(), "Should be able to write STAT");
+        run_to_line(&mut ppu, 10);
+
+        assert!(ppu.write_register(LCDC, 0x00).is_ok(), "Should be able to turn the LCD off");
+        let interrupts = ppu.tick(TCycles(DOTS_PER_FRAME));
+
+        assert_eq!(interrupts, 0, "No interrupts should be requested while the LCD is off");
+        assert_eq!(ppu.get_ly(), 0, "LY should stay at 0 while the LCD is off");
+        assert_eq!(ppu.get_mode(), PpuMode::HBlank, "The PPU should stay in mode 0");
+    }
+
+    #[test]
+    fn test_lcd_on_restarts_frame() {
+        let mut ppu = init_enabled_ppu();
+        run_to_line(&mut ppu, 10);
+        ppu.tick(TCycles(100));
+        assert!(ppu.write_register(LCDC, 0x00).is_ok(), "Should be able to turn the LCD off");
+
+        assert!(ppu.write_register(LCDC, LCDC_ENABLE).is_ok(), "Should be able to turn it on");
+        let mode = ppu.get_mode();
+        ppu.tick(TCycles(OAM_SCAN_DOTS as u32));
+
+        assert_eq!(mode, PpuMode::OamScan, "Line 0 should start again");
+        assert_eq!(ppu.get_ly(), 0, "LY should start from 0");
+        assert_eq!(ppu.get_mode(), PpuMode::Drawing, "The line should restart from dot 0");
+    }
+
+    #[test]
+    fn test_ly_is_read_only() {
+        let mut ppu = init_enabled_ppu();
+        ppu.tick(TCycles(255));
+        ppu.tick(TCycles(255));
+
+        let write_result = ppu.write_register(LY, 0x42);
+        let read_result = ppu.read_register(LY);
+
+        assert_eq!(write_result, Ok(1), "Writing to LY should not fail");
+        assert_eq!(read_result, Some(1), "Writing to LY should be ignored");
+    }
+
+    #[test]
+    fn test_vram_banks() {
+        let mut ppu = Ppu::new();
+        assert_eq!(ppu.read_register(VBK), Some(0xFE), "Bank 0 should be selected by default");
+
+        assert_eq!(ppu.write_vram(0x1842, 0x28), Ok(0), "Should write to bank 0");
+        assert_eq!(ppu.write_register(VBK, 0xFF), Ok(0xFE), "Should switch to bank 1");
+        assert_eq!(ppu.read_vram(0x1842), Some(0), "Bank 1 should be separate from bank 0");
+        assert_eq!(ppu.write_vram(0x1842, 0x04), Ok(0), "Should write to bank 1");
+
+        assert_eq!(ppu.read_register(VBK), Some(0xFF), "Only bit 0 of VBK should be stored");
+        assert_eq!(ppu.read_vram(0x1842), Some(0x04), "Should read from bank 1");
+        assert_eq!(ppu.write_register(VBK, 0x02), Ok(0xFF), "Should switch back to bank 0");
+        assert_eq!(ppu.read_vram(0x1842), Some(0x28), "Bank 0 should be unchanged");
+    }
+
+    #[test]
+    fn test_vram_invalid_address() {
+        let mut ppu = Ppu::new();
+        assert!(ppu.write_register(VBK, 1).is_ok(), "Should switch to bank 1");
+
+        assert!(ppu.write_vram(0x2000, 0x42).is_err(), "Each bank is only 8 KiB long");
+        assert!(ppu.read_vram(0x2000).is_none(), "Each bank is only 8 KiB long");
+    }
+
+    #[test]
+    fn test_opri_io() {
+        let mut ppu = Ppu::new();
+
+        let default_result = ppu.read_register(OPRI);
+        let write_result = ppu.write_register(OPRI, 0x00);
+        let read_result = ppu.read_register(OPRI);
+
+        assert_eq!(default_result, Some(0xFF), "OPRI should default to DMG-style priority");
+        assert_eq!(write_result, Ok(0xFF), "Should return the previous OPRI value");
+        assert_eq!(read_result, Some(0xFE), "Only the lowest bit of OPRI should be writable");
+    }
+
+    #[test]
+    fn test_opri_changes_sprite_priority() {
+        let mut ppu = Ppu::new();
+        // two overlapping sprites on line 0, where the later entry is further left
+        let entries = [[16, 20, 0x01, 0], [16, 16, 0x02, 0]];
+        for (idx, byte) in entries.iter().flatten().enumerate() {
+            assert!(ppu.write_oam(idx as u16, *byte).is_ok());
+        }
+
+        let dmg_top = ppu.line_sprites_by_priority()[0];
+        assert!(ppu.write_register(OPRI, 0).is_ok());
+        let cgb_top = ppu.line_sprites_by_priority()[0];
+
+        assert_eq!(dmg_top.tile, 0x02, "The leftmost sprite should be on top by X priority");
+        assert_eq!(cgb_top.tile, 0x01, "The first OAM entry should be on top by OAM priority");
+    }
+
+    #[test]
+    fn test_background_wraps() {
+        let mut ppu = Ppu::new();
+        write_tile(&mut ppu, 0x0010, 0xFF, 0x00);
+        write_tile(&mut ppu, 0x0020, 0x00, 0xFF);
+        // tile 1 starts the first row of the map, and tile 2 ends it
+        assert!(ppu.write_vram(TILE_MAP_0 as u16, 1).is_ok(), "Should be able to set the map");
+        assert!(ppu.write_vram(TILE_MAP_0 as u16 + 31, 2).is_ok(), "Should set the map");
+        assert!(ppu.write_register(SCX, 250).is_ok(), "Should be able to write SCX");
+        assert!(ppu.write_register(LCDC, 0x91).is_ok(), "Should be able to write LCDC");
+        assert!(ppu.write_register(BGP, 0xE4).is_ok(), "Should be able to write BGP");
+
+        run_to_line(&mut ppu, 1);
+
+        let line = &ppu.framebuffer()[0..SCREEN_WIDTH];
+        assert_eq!(&line[0..6], &[2; 6], "x = 0-5 should draw the end of the last tile");
+        assert_eq!(&line[6..14], &[1; 8], "x = 6 should wrap around to the first tile");
+        assert_eq!(line[14], 0, "The second tile of the map is tile 0, which is blank");
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let mut ppu = Ppu::new();
+
+        assert!(ppu.write_register(0xFF00, 0x42).is_err(), "0xFF00 isn't a PPU register");
+        assert!(ppu.read_register(0xFF00).is_none(), "0xFF00 isn't a PPU register");
+    }
+
+    #[test]
+    fn test_fixed_mode_3_length() {
+        let mut ppu = Ppu::new();
+        assert!(ppu.write_register(LCDC, 0x83).is_ok());
+        assert!(ppu.write_register(SCX, 5).is_ok());
+        place_line_0_sprites(&mut ppu, 8);
+
+        let result = measure_drawing_dots(&mut ppu);
+
+        assert_eq!(result, DRAWING_DOTS, "Mode 3 should not vary unless enabled");
+    }
+
+    #[test]
+    fn test_accurate_mode_3_empty_line() {
+        let mut ppu = Ppu::new();
+        ppu.set_accurate_mode_3(true);
+        assert!(ppu.write_register(LCDC, 0x83).is_ok());
+
+        let result = measure_drawing_dots(&mut ppu);
+
+        assert_eq!(result, DRAWING_DOTS, "Mode 3 should not be lengthened on an empty line");
+    }
+
+    #[test]
+    fn test_accurate_mode_3_scroll_penalty() {
+        let mut ppu = Ppu::new();
+        ppu.set_accurate_mode_3(true);
+        assert!(ppu.write_register(LCDC, 0x83).is_ok());
+        assert!(ppu.write_register(SCX, 0x0D).is_ok());
+
+        let result = measure_drawing_dots(&mut ppu);
+
+        assert_eq!(result, DRAWING_DOTS + 5, "Mode 3 should be lengthened by SCX % 8");
+    }
+
+    #[test]
+    fn test_accurate_mode_3_window_penalty() {
+        let mut ppu = Ppu::new();
+        ppu.set_accurate_mode_3(true);
+        assert!(ppu.write_register(LCDC, 0xA1).is_ok());
+        assert!(ppu.write_register(WX, 7).is_ok());
+
+        let result = measure_drawing_dots(&mut ppu);
+
+        assert_eq!(result, DRAWING_DOTS + 6, "Drawing the window should take 6 more dots");
+    }
+
+    #[test]
+    fn test_accurate_mode_3_sprites_and_scroll() {
+        let mut empty_ppu = Ppu::new();
+        empty_ppu.set_accurate_mode_3(true);
+        assert!(empty_ppu.write_register(LCDC, 0x83).is_ok());
+        let mut busy_ppu = Ppu::new();
+        busy_ppu.set_accurate_mode_3(true);
+        assert!(busy_ppu.write_register(LCDC, 0x83).is_ok());
+        assert!(busy_ppu.write_register(SCX, 3).is_ok());
+        place_line_0_sprites(&mut busy_ppu, 8);
+
+        let empty_result = measure_drawing_dots(&mut empty_ppu);
+        let busy_result = measure_drawing_dots(&mut busy_ppu);
+
+        assert!(
+            busy_result > empty_result,
+            "Sprites and scrolling should lengthen mode 3 ({busy_result} <= {empty_result})"
+        );
+        assert!(
+            busy_result >= empty_result + 3 + 8 * SPRITE_FETCH_DOTS,
+            "Each sprite should take at least 6 dots to fetch"
+        );
+    }
+
+    #[test]
+    fn test_sprite_limit_per_line() {
+        let mut ppu = Ppu::new();
+        place_line_0_sprites(&mut ppu, 12);
+        assert!(ppu.write_register(OPRI, 0).is_ok());
+
+        let sprites = ppu.line_sprites_by_priority();
+
+        assert_eq!(sprites.len(), 10, "Only 10 sprites should be selected for a line");
+        for (idx, sprite) in sprites.iter().enumerate() {
+            assert_eq!(
+                sprite.x, 8 + (idx as u8 * 12),
+                "The first 10 sprites in OAM order should be selected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_accurate_mode_3_sprite_limit() {
+        let mut empty_ppu = Ppu::new();
+        let mut limit_ppu = Ppu::new();
+        let mut over_limit_ppu = Ppu::new();
+        for (ppu, count) in [(&mut empty_ppu, 0), (&mut limit_ppu, 10), (&mut over_limit_ppu, 12)] {
+            ppu.set_accurate_mode_3(true);
+            assert!(ppu.write_register(LCDC, 0x83).is_ok());
+            place_line_0_sprites(ppu, count);
+        }
+
+        let empty_result = measure_drawing_dots(&mut empty_ppu);
+        let limit_result = measure_drawing_dots(&mut limit_ppu);
+        let over_limit_result = measure_drawing_dots(&mut over_limit_ppu);
+
+        assert!(limit_result > empty_result, "Sprites on the line should lengthen mode 3");
+        assert_eq!(
+            over_limit_result, limit_result,
+            "Sprites past the 10 sprite limit should not lengthen mode 3"
+        );
+    }
+
+    #[test]
+    fn test_accurate_mode_3_shortens_hblank() {
+        let mut ppu = Ppu::new();
+        ppu.set_accurate_mode_3(true);
+        assert!(ppu.write_register(LCDC, 0x83).is_ok());
+        assert!(ppu.write_register(SCX, 7).is_ok());
+        place_line_0_sprites(&mut ppu, 4);
+
+        let mut line_dots = 0;
+        let mut hblank_dots = 0;
+        while ppu.get_ly() == 0 {
+            line_dots += 1;
+            ppu.tick(TCycles(1));
+            if ppu.get_mode() == PpuMode::HBlank {
+                hblank_dots += 1;
+            }
+        }
+
+        assert_eq!(line_dots, DOTS_PER_LINE, "A line should still take exactly 456 dots");
+        assert!(
+            hblank_dots < DOTS_PER_LINE - OAM_SCAN_DOTS - DRAWING_DOTS,
+            "HBlank should be shortened by the longer mode 3"
+        );
+    }
+
+    #[test]
+    fn test_accurate_mode_3_sprite_partially_off_screen() {
+        let mut ppu = Ppu::new();
+        ppu.set_accurate_mode_3(true);
+        assert!(ppu.write_register(LCDC, 0x83).is_ok());
+        for (offset, byte) in [16, 4, 0, 0].iter().enumerate() {
+            assert!(ppu.write_oam(offset as u16, *byte).is_ok());
+        }
+
+        let result = measure_drawing_dots(&mut ppu);
+
+        assert!(result > DRAWING_DOTS, "Sprites off the left edge should still be fetched");
+    }
+
+    #[test]
+    fn test_modes_in_visible_line() {
+        let mut ppu = init_enabled_ppu();
+
+        let oam_mode = ppu.get_mode();
+        ppu.tick(TCycles(OAM_SCAN_DOTS as u32));
+        let drawing_mode = ppu.get_mode();
+        ppu.tick(TCycles(DRAWING_DOTS as u32));
+        let hblank_mode = ppu.get_mode();
+
+        assert_eq!(oam_mode, PpuMode::OamScan, "Lines should start in OAM scan");
+        assert_eq!(drawing_mode, PpuMode::Drawing, "OAM scan should last 80 dots");
+        assert_eq!(hblank_mode, PpuMode::HBlank, "Drawing should last 172 dots");
+    }
+
+    #[test]
+    fn test_vblank_requested_entering_line_144() {
+        let mut ppu = init_enabled_ppu();
+
+        let before = ppu.tick(TCycles(255));
+        let interrupts = run_to_line(&mut ppu, VBLANK_LINE);
+
+        assert_eq!(before & VBLANK_INTERRUPT, 0, "VBlank should not be requested on line 0");
+        assert_eq!(
+            interrupts & VBLANK_INTERRUPT, VBLANK_INTERRUPT,
+            "VBlank should be requested on the first dot of line 144"
+        );
+        assert_eq!(ppu.get_mode(), PpuMode::VBlank, "PPU should be in VBlank on line 144");
+    }
+
+    #[test]
+    fn test_vblank_not_requested_again_during_vblank() {
+        let mut ppu = init_enabled_ppu();
+        run_to_line(&mut ppu, VBLANK_LINE);
+
+        let mut interrupts = 0;
+        while ppu.get_ly() != 0 {
+            assert_eq!(ppu.get_mode(), PpuMode::VBlank, "Lines 144-153 should all be VBlank");
+            interrupts |= ppu.tick(TCycles(1));
+        }
+
+        assert_eq!(
+            interrupts & VBLANK_INTERRUPT, 0,
+            "VBlank should not be requested again on lines 145-153"
+        );
+        assert_eq!(ppu.get_mode(), PpuMode::OamScan, "Line 0 should start with OAM scan");
+    }
+
+    #[test]
+    fn test_vblank_requested_once_per_frame() {
+        let mut ppu = init_enabled_ppu();
+
+        let mut requests = 0;
+        for _ in 0..(DOTS_PER_FRAME * 2) {
+            if ppu.tick(TCycles(1)) & VBLANK_INTERRUPT != 0 {
+                requests += 1;
+            }
+        }
+
+        assert_eq!(requests, 2, "VBlank should be requested exactly once per frame");
+    }
+
+    #[test]
+    fn test_instruction_cycles_advance_dots() {
+        let memory = DmgMemoryController::new(Box::new(MockCartridgeMapper::new()));
+        let mut dmg = GameBoySystem::new(Box::new(memory));
+        let mut ppu = init_enabled_ppu();
+
+        let result = dmg.execute_instruction(
+            Instruction { op: Operation::Jump(0x0150), cycles: 4 }
+        );
+        assert!(matches!(result, Ok(MCycles(4))), "JP should report 4 M-cycles");
+        ppu.tick(m_to_t(result.unwrap(), false));
+
+        assert_eq!(ppu.dot, 16, "4 M-cycles should advance the PPU by 16 dots at normal speed");
+    }
+
+    /// Store a tile whose rows all use the given low and high bytes at the given VRAM address
+    fn write_tile(ppu: &mut Ppu, address: u16, low: u8, high: u8) {
+        for row in 0..8 {
+            assert!(ppu.write_vram(address + row * 2, low).is_ok(), "Tile should be in VRAM");
+            assert!(ppu.write_vram(address + row * 2 + 1, high).is_ok(), "Tile should be in VRAM");
+        }
+    }
+
+    #[test]
+    fn test_render_background_tile() {
+        let mut ppu = Ppu::new();
+        write_tile(&mut ppu, 0x0010, 0xFF, 0x00);
+        assert!(ppu.write_vram(TILE_MAP_0 as u16, 1).is_ok(), "Should be able to set the map");
+        assert!(ppu.write_register(LCDC, 0x91).is_ok(), "Should be able to write LCDC");
+        assert!(ppu.write_register(BGP, 0xE4).is_ok(), "Should be able to write BGP");
+
+        run_to_line(&mut ppu, 1);
+
+        assert_eq!(&ppu.framebuffer()[0..8], &[1; 8], "Tile 1 should be drawn with color 1");
+        assert_eq!(ppu.framebuffer()[8], 0, "The next tile should be tile 0, which is blank");
+        assert_eq!(ppu.framebuffer()[SCREEN_WIDTH], 0, "Line 1 shouldn't be drawn yet");
+    }
+
+    #[test]
+    fn test_render_signed_tile_data() {
+        let mut ppu = Ppu::new();
+        // tile -1 sits right before 0x9000
+        write_tile(&mut ppu, 0x0FF0, 0x00, 0xFF);
+        assert!(ppu.write_vram(TILE_MAP_0 as u16, 0xFF).is_ok(), "Should be able to set the map");
+        assert!(ppu.write_register(LCDC, 0x81).is_ok(), "Should be able to write LCDC");
+        assert!(ppu.write_register(BGP, 0xE4).is_ok(), "Should be able to write BGP");
+
+        run_to_line(&mut ppu, 1);
+
+        assert_eq!(&ppu.framebuffer()[0..8], &[2; 8], "Tile -1 should be drawn with color 2");
+    }
+
+    #[test]
+    fn test_render_applies_palette() {
+        let mut ppu = Ppu::new();
+        write_tile(&mut ppu, 0x0000, 0xFF, 0xFF);
+        assert!(ppu.write_register(LCDC, 0x91).is_ok(), "Should be able to write LCDC");
+        // map color 3 to the lightest shade
+        assert!(ppu.write_register(BGP, 0x3F).is_ok(), "Should be able to write BGP");
+
+        run_to_line(&mut ppu, 1);
+
+        assert_eq!(ppu.framebuffer()[0], 0, "BGP should map color 3 to shade 0");
+        assert_eq!(ppu.framebuffer()[SCREEN_WIDTH - 1], 0, "The whole line should be drawn");
+    }
+
+    #[test]
+    fn test_render_background_disabled() {
+        let mut ppu = Ppu::new();
+        write_tile(&mut ppu, 0x0000, 0xFF, 0xFF);
+        assert!(ppu.write_register(LCDC, 0x90).is_ok(), "Should be able to write LCDC");
+        assert!(ppu.write_register(BGP, 0xFF).is_ok(), "Should be able to write BGP");
+
+        run_to_line(&mut ppu, 1);
+
+        assert_eq!(ppu.framebuffer()[0], 0, "A disabled background should be white");
+    }
+
+    /// Set up a PPU with a window using tile map 1 and a background using tile map 0, where the
+    /// background is filled with tile 2 (color 2) and tile 1 is placed in the corner of the window
+    fn init_window_ppu(window_tile: [u8; 8]) -> Ppu {
+        let mut ppu = Ppu::new();
+        for (row, low) in window_tile.into_iter().enumerate() {
+            assert!(ppu.write_vram(0x0010 + row as u16 * 2, low).is_ok(), "Tile should be in VRAM");
+        }
+        write_tile(&mut ppu, 0x0020, 0x00, 0xFF);
+        for index in 0..(TILE_MAP_WIDTH * 32) as u16 {
+            assert!(ppu.write_vram(TILE_MAP_0 as u16 + index, 2).is_ok(), "Should set the map");
+        }
+        assert!(ppu.write_vram(TILE_MAP_1 as u16, 1).is_ok(), "Should set the window map");
+        // LCD, window with map 1, window, unsigned tile data, background
+        assert!(ppu.write_register(LCDC, 0xF1).is_ok(), "Should be able to write LCDC");
+        assert!(ppu.write_register(BGP, 0xE4).is_ok(), "Should be able to write BGP");
+        ppu
+    }
+
+    #[test]
+    fn test_render_window() {
+        let mut ppu = init_window_ppu([0xFF; 8]);
+        assert!(ppu.write_register(WY, 2).is_ok(), "Should be able to write WY");
+        assert!(ppu.write_register(WX, 15).is_ok(), "Should be able to write WX");
+
+        run_to_line(&mut ppu, 4);
+
+        let line = |y: usize| &ppu.framebuffer()[y * SCREEN_WIDTH..(y + 1) * SCREEN_WIDTH];
+        assert_eq!(line(1), &[2; SCREEN_WIDTH], "The window should start at WY");
+        assert_eq!(&line(2)[0..8], &[2; 8], "The window should start at WX - 7");
+        assert_eq!(&line(2)[8..16], &[1; 8], "Tile 1 should be in the corner of the window");
+        assert_eq!(&line(2)[16..], &[0; SCREEN_WIDTH - 16], "The window covers the rest");
+        assert_eq!(&line(3)[8..16], &[1; 8], "The window should continue on the next line");
+    }
+
+    #[test]
+    fn test_render_window_disabled() {
+        let mut ppu = init_window_ppu([0xFF; 8]);
+        assert!(ppu.write_register(LCDC, 0xD1).is_ok(), "Should be able to write LCDC");
+        assert!(ppu.write_register(WX, 7).is_ok(), "Should be able to write WX");
+
+        run_to_line(&mut ppu, 1);
+
+        assert_eq!(&ppu.framebuffer()[0..8], &[2; 8], "Only the background should be drawn");
+    }
+
+    #[test]
+    fn test_window_line_counter() {
+        // only row 1 of the window's tile is colored
+        let mut ppu = init_window_ppu([0x00, 0xFF, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert!(ppu.write_register(WX, 7).is_ok(), "Should be able to write WX");
+
+        run_to_line(&mut ppu, 1);
+        let first_line = ppu.framebuffer()[0];
+        assert!(ppu.write_register(LCDC, 0xD1).is_ok(), "Hide the window");
+        run_to_line(&mut ppu, 5);
+        assert!(ppu.write_register(LCDC, 0xF1).is_ok(), "Show the window again");
+        run_to_line(&mut ppu, 6);
+        let hidden = ppu.framebuffer()[4 * SCREEN_WIDTH];
+        let resumed = ppu.framebuffer()[5 * SCREEN_WIDTH];
+        run_to_line(&mut ppu, 0);
+        run_to_line(&mut ppu, 2);
+        let next_frame = &ppu.framebuffer()[0..2 * SCREEN_WIDTH];
+
+        assert_eq!(first_line, 0, "Line 0 should draw row 0 of the window");
+        assert_eq!(hidden, 2, "The background should be drawn while the window is hidden");
+        assert_eq!(resumed, 1, "Line 5 should draw row 1, since the window skipped 4 lines");
+        assert_eq!(
+            (next_frame[0], next_frame[SCREEN_WIDTH]), (0, 1),
+            "The window should start again from row 0 in the next frame"
+        );
+    }
+
+    /// Save the state of a new PPU, so that tests can corrupt it
+    fn init_state() -> Vec<u8> {
+        let mut writer = SnapshotWriter::new();
+        Ppu::new().save_state(&mut writer);
+        writer.finish()
+    }
+
+    #[test]
+    fn test_load_state_timing_out_of_range() {
+        // the state starts with the mode, LY, the dot, and the length of mode 3
+        let corruptions: [(&str, usize, Vec<u8>); 4] = [
+            ("LY", 1, vec![LINES_PER_FRAME]),
+            ("dot", 2, DOTS_PER_LINE.to_le_bytes().to_vec()),
+            ("short mode 3", 4, (DRAWING_DOTS - 1).to_le_bytes().to_vec()),
+            ("long mode 3", 4, (MAX_DRAWING_DOTS + 1).to_le_bytes().to_vec()),
+        ];
+
+        for (name, offset, bytes) in corruptions {
+            let mut state = init_state();
+            state[offset..offset + bytes.len()].copy_from_slice(&bytes);
+
+            let result = Ppu::new().load_state(&mut SnapshotReader::new(&state));
+
+            assert_eq!(result, Err(SnapshotError::InvalidValue), "An invalid {name} should fail");
+        }
+    }
+
+    #[test]
+    fn test_load_state_window_line_out_of_range() {
+        let mut state = init_state();
+        // the window line is the last value in the state
+        *state.last_mut().unwrap() = SCREEN_HEIGHT as u8 + 1;
+
+        let result = Ppu::new().load_state(&mut SnapshotReader::new(&state));
+
+        assert_eq!(
+            result, Err(SnapshotError::InvalidValue),
+            "The window can't be past the bottom of the screen"
+        );
+    }
+
+    #[test]
+    fn test_shades_to_rgba() {
+        // a 2x2 image, with the lightest and darkest shades on the top row
+        let shades = [0, 3, 1, 2];
+        let palette = [0x11223344, 0x55667788, 0x99AABBCC, 0xDDEEFF00];
+
+        let rgba = shades_to_rgba(&shades, &palette);
+
+        assert_eq!(
+            rgba,
+            vec![
+                0x11, 0x22, 0x33, 0x44, 0xDD, 0xEE, 0xFF, 0x00,
+                0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC,
+            ],
+            "Each shade should become the bytes of its palette color in RGBA order"
+        );
+    }
+
+    #[test]
+    fn test_framebuffer_rgba() {
+        let mut ppu = Ppu::new();
+        write_tile(&mut ppu, 0x0010, 0xFF, 0x00);
+        assert!(ppu.write_vram(TILE_MAP_0 as u16, 1).is_ok(), "Should be able to set the map");
+        assert!(ppu.write_register(LCDC, 0x91).is_ok(), "Should be able to write LCDC");
+        assert!(ppu.write_register(BGP, 0xE4).is_ok(), "Should be able to write BGP");
+        run_to_line(&mut ppu, 1);
+
+        let rgba = ppu.framebuffer_rgba(&DMG_GREEN_PALETTE);
+
+        assert_eq!(rgba.len(), FRAMEBUFFER_SIZE * RGBA_PIXEL_SIZE, "Every pixel should be 4 bytes");
+        assert_eq!(rgba[0..4], [0x88, 0xC0, 0x70, 0xFF], "Tile 1 should use the second shade");
+        assert_eq!(rgba[32..36], [0xE0, 0xF8, 0xD0, 0xFF], "Tile 0 should use the lightest shade");
+    }
+
+    #[test]
+    fn test_palette_registers() {
+        let mut ppu = Ppu::new();
+        // auto increment from background palette 3, color 2
+        assert!(ppu.write_register(BCPS, 0x9C).is_ok(), "Should be able to write BCPS");
+        assert!(ppu.write_register(BCPD, 0xE0).is_ok(), "Should be able to write BCPD");
+        assert!(ppu.write_register(BCPD, 0x03).is_ok(), "Should be able to write BCPD");
+        assert!(ppu.write_register(OCPS, 0x8E).is_ok(), "Should be able to write OCPS");
+        assert!(ppu.write_register(OCPD, 0x1F).is_ok(), "Should be able to write OCPD");
+
+        assert_eq!(ppu.background_palette_color(3, 2), Some(0x03E0), "Should read back green");
+        assert_eq!(ppu.object_palette_color(1, 3), Some(0x001F), "Should read back red");
+        assert_eq!(ppu.background_palette_color(1, 3), Some(0), "Object colors are separate");
+        assert_eq!(ppu.read_register(BCPS), Some(0xDE), "BCPS should move past both bytes");
+        assert!(ppu.write_register(BCPS, 0x1D).is_ok(), "Should be able to write BCPS");
+        assert_eq!(ppu.read_register(BCPD), Some(0x03), "BCPD should read the selected byte");
+    }
+
+    #[test]
+    fn test_palettes_locked_while_drawing() {
+        let mut ppu = Ppu::new();
+        assert!(ppu.write_register(LCDC, 0x91).is_ok(), "Should be able to turn the LCD on");
+        assert!(ppu.write_register(BCPS, 0x80).is_ok(), "Should be able to write BCPS");
+        ppu.tick(TCycles(OAM_SCAN_DOTS as u32));
+
+        let read = ppu.read_register(BCPD);
+        assert!(ppu.write_register(BCPD, 0x42).is_ok(), "Writes while drawing are ignored");
+
+        assert_eq!(read, Some(0xFF), "Palette RAM can't be read while drawing");
+        assert_eq!(ppu.background_palette_color(0, 0), Some(0), "The write should be dropped");
+        assert_eq!(ppu.read_register(BCPS), Some(0xC1), "The index should still move");
+    }
+
+    #[test]
+    fn test_palettes_accessible_with_lcd_off() {
+        let mut ppu = Ppu::new();
+        assert!(ppu.write_register(LCDC, 0x00).is_ok(), "Should be able to turn the LCD off");
+        ppu.tick(TCycles(OAM_SCAN_DOTS as u32));
+        assert!(ppu.write_register(BCPS, 0x80).is_ok(), "Should be able to write BCPS");
+
+        assert!(ppu.write_register(BCPD, 0x42).is_ok(), "Should be able to write BCPD");
+
+        assert_eq!(
+            ppu.background_palette_color(0, 0), Some(0x42),
+            "Palette RAM should be accessible while the LCD is off"
+        );
+    }
+
+    #[test]
+    fn test_stat_mode_and_coincidence() {
+        let mut ppu = init_enabled_ppu();
+        assert!(ppu.write_register(LYC, 1).is_ok(), "Should be able to write LYC");
+
+        ppu.tick(TCycles(OAM_SCAN_DOTS as u32));
+        assert_eq!(ppu.read_register(STAT), Some(0x83), "STAT should report mode 3");
+
+        run_to_line(&mut ppu, 1);
+        assert_eq!(ppu.read_register(STAT), Some(0x86), "STAT should report LY == LYC");
+    }
+
+    #[test]
+    fn test_stat_only_keeps_interrupt_enables() {
+        let mut ppu = init_enabled_ppu();
+
+        assert!(ppu.write_register(STAT, 0xFF).is_ok(), "Should be able to write STAT");
+
+        assert_eq!(
+            ppu.read_register(STAT), Some(0xFE),
+            "Only the interrupt enables should be writable (mode 2, LY != LYC)"
+        );
+    }
+
+    #[test]
+    fn test_lyc_interrupt() {
+        let mut ppu = init_enabled_ppu();
+        assert!(ppu.write_register(LYC, 10).is_ok(), "Should be able to write LYC");
+        assert!(ppu.write_register(STAT, 0x40).is_ok(), "Should be able to write STAT");
+
+        let interrupts = run_to_line(&mut ppu, 10);
+        assert_eq!(interrupts & STAT_MASK, STAT_MASK, "Reaching LYC should request STAT");
+
+        let mut requests = 0;
+        for _ in 0..DOTS_PER_FRAME {
+            if ppu.tick(TCycles(1)) & STAT_MASK != 0 {
+                requests += 1;
+            }
+        }
+        assert_eq!(requests, 1, "The LYC interrupt should be requested once per frame");
+    }
+
+    /// Run the PPU one dot at a time until it reaches the given line, returning the number of
+    /// times the STAT interrupt was requested along the way
+    fn count_stat_requests(ppu: &mut Ppu, line: u8) -> u32 {
+        let mut requests = 0;
+        while ppu.get_ly() != line {
+            if ppu.tick(TCycles(1)) & STAT_MASK != 0 {
+                requests += 1;
+            }
+        }
+        requests
+    }
+
+    #[test]
+    fn test_lyc_coincidence_flag() {
+        let mut ppu = init_enabled_ppu();
+        assert!(ppu.write_register(LYC, 2).is_ok(), "Should be able to write LYC");
+
+        run_to_line(&mut ppu, 2);
+        let matching = ppu.read_register(STAT);
+        run_to_line(&mut ppu, 3);
+        let after = ppu.read_register(STAT);
+
+        assert_eq!(
+            matching.map(|stat| stat & STAT_COINCIDENCE), Some(STAT_COINCIDENCE),
+            "Should be set on line 2"
+        );
+        assert_eq!(after.map(|stat| stat & STAT_COINCIDENCE), Some(0), "Should clear on line 3");
+    }
+
+    #[test]
+    fn test_lyc_interrupt_disabled() {
+        let mut ppu = init_enabled_ppu();
+        assert!(ppu.write_register(LYC, 10).is_ok(), "Should be able to write LYC");
+
+        let requests = count_stat_requests(&mut ppu, 11);
+
+        assert_eq!(requests, 0, "LY == LYC shouldn't request STAT unless bit 6 is set");
+    }
+
+    #[test]
+    fn test_lyc_write_matching_ly() {
+        let mut ppu = init_enabled_ppu();
+        assert!(ppu.write_register(STAT, 0x40).is_ok(), "Should be able to write STAT");
+        assert!(ppu.write_register(LYC, 5).is_ok(), "Should be able to write LYC");
+        run_to_line(&mut ppu, 3);
+        ppu.tick(TCycles(10));
+
+        assert!(ppu.write_register(LYC, 3).is_ok(), "Should be able to write LYC");
+        let interrupts = ppu.tick(TCycles(1));
+
+        assert_eq!(interrupts & STAT_MASK, STAT_MASK, "Matching LY mid-line should request STAT");
+    }
+
+    #[test]
+    fn test_stat_blocking_hblank_then_lyc() {
+        let mut ppu = init_enabled_ppu();
+        assert!(ppu.write_register(LYC, 5).is_ok(), "Should be able to write LYC");
+        // HBlank and LYC
+        assert!(ppu.write_register(STAT, 0x48).is_ok(), "Should be able to write STAT");
+        run_to_line(&mut ppu, 4);
+
+        // the LYC match starts on the same dot that HBlank on line 4 ends
+        let requests = count_stat_requests(&mut ppu, 5);
+        let line_5_requests = count_stat_requests(&mut ppu, 6);
+
+        assert_eq!(requests, 1, "The LYC match should be blocked by HBlank on line 4");
+        assert_eq!(line_5_requests, 0, "HBlank on line 5 should be blocked by the LYC match");
+    }
+
+    #[test]
+    fn test_stat_blocking_oam_and_lyc() {
+        let mut ppu = init_enabled_ppu();
+        assert!(ppu.write_register(LYC, 5).is_ok(), "Should be able to write LYC");
+        // OAM scan and LYC
+        assert!(ppu.write_register(STAT, 0x60).is_ok(), "Should be able to write STAT");
+        run_to_line(&mut ppu, 4);
+        ppu.tick(TCycles(OAM_SCAN_DOTS as u32));
+
+        let interrupts = run_to_line(&mut ppu, 5);
+        let requests = count_stat_requests(&mut ppu, 6);
+
+        assert_eq!(interrupts & STAT_MASK, STAT_MASK, "Line 5 should request STAT");
+        assert_eq!(requests, 0, "OAM scan and LYC on the same line should only request it once");
+    }
+
+    #[test]
+    fn test_stat_mode_interrupts() {
+        // (STAT enables, requests in one visible line)
+        let cases = [(0x08, 1), (0x20, 1), (0x10, 0), (0x00, 0)];
+
+        for (enables, expected) in cases {
+            let mut ppu = init_enabled_ppu();
+            assert!(ppu.write_register(STAT, enables).is_ok(), "Should be able to write STAT");
+            run_to_line(&mut ppu, 1);
+
+            let requests = count_stat_requests(&mut ppu, 2);
+
+            assert_eq!(requests, expected, "Wrong number of requests with STAT = {enables:#X}");
+        }
+    }
+
+    #[test]
+    fn test_stat_vblank_interrupt() {
+        let mut ppu = init_enabled_ppu();
+        assert!(ppu.write_register(STAT, 0x10).is_ok(), "Should be able to write STAT");
+
+        let interrupts = run_to_line(&mut ppu, VBLANK_LINE);
+        let requests = count_stat_requests(&mut ppu, 0);
+
+        assert_eq!(interrupts & STAT_MASK, STAT_MASK, "Entering VBlank should request STAT");
+        assert_eq!(requests, 0, "VBlank should only request STAT once per frame");
+    }
+
+    #[test]
+    fn test_stat_oam_interrupt_at_vblank() {
+        let mut ppu = init_enabled_ppu();
+        assert!(ppu.write_register(STAT, 0x20).is_ok(), "Should be able to write STAT");
+        run_to_line(&mut ppu, VBLANK_LINE - 1);
+
+        let interrupts = run_to_line(&mut ppu, VBLANK_LINE);
+        let requests = count_stat_requests(&mut ppu, VBLANK_LINE + 1);
+
+        assert_eq!(interrupts & STAT_MASK, STAT_MASK, "The OAM source also fires on line 144");
+        assert_eq!(requests, 0, "The OAM source shouldn't fire again during VBlank");
+    }
+
+    /// Store a sprite in the given OAM entry, with the same layout as OAM (so the sprite's
+    /// top left corner is drawn at (x - 8, y - 16))
+    fn write_sprite(ppu: &mut Ppu, index: u16, y: u8, x: u8, tile: u8, flags: u8) {
+        for (offset, data) in [y, x, tile, flags].into_iter().enumerate() {
+            let result = ppu.write_oam(index * 4 + offset as u16, data);
+            assert!(result.is_ok(), "Sprite should be in OAM");
+        }
+    }
+
+    /// Set up a PPU with the background and 8x8 sprites enabled, and with palettes which map
+    /// each color number to the same shade
+    fn init_sprite_ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        assert!(ppu.write_register(LCDC, 0x93).is_ok(), "Should be able to write LCDC");
+        assert!(ppu.write_register(BGP, 0xE4).is_ok(), "Should be able to write BGP");
+        assert!(ppu.write_register(OBP0, 0xE4).is_ok(), "Should be able to write OBP0");
+        ppu
+    }
+
+    #[test]
+    fn test_render_sprite() {
+        let mut ppu = init_sprite_ppu();
+        // only the leftmost column of the tile is colored
+        write_tile(&mut ppu, 0x0010, 0x80, 0x00);
+        write_sprite(&mut ppu, 0, 18, 18, 1, 0x00);
+
+        run_to_line(&mut ppu, 3);
+
+        let line = 2 * SCREEN_WIDTH;
+        assert_eq!(ppu.framebuffer()[line + 10], 1, "The sprite should start at (10, 2)");
+        assert_eq!(ppu.framebuffer()[line + 11], 0, "Color 0 should be transparent");
+        assert_eq!(ppu.framebuffer()[SCREEN_WIDTH + 10], 0, "The sprite should start on line 2");
+    }
+
+    #[test]
+    fn test_render_sprite_x_flip() {
+        let mut ppu = init_sprite_ppu();
+        write_tile(&mut ppu, 0x0010, 0x80, 0x00);
+        write_sprite(&mut ppu, 0, 18, 18, 1, 0x20);
+
+        run_to_line(&mut ppu, 3);
+
+        let line = 2 * SCREEN_WIDTH;
+        assert_eq!(ppu.framebuffer()[line + 10], 0, "The colored column should be flipped");
+        assert_eq!(ppu.framebuffer()[line + 17], 1, "The colored column should be on the right");
+    }
+
+    #[test]
+    fn test_render_sprite_y_flip() {
+        let mut ppu = init_sprite_ppu();
+        // only the top row of the tile is colored
+        assert!(ppu.write_vram(0x0010, 0xFF).is_ok(), "Tile should be in VRAM");
+        write_sprite(&mut ppu, 0, 18, 18, 1, 0x40);
+
+        run_to_line(&mut ppu, 10);
+
+        assert_eq!(ppu.framebuffer()[2 * SCREEN_WIDTH + 10], 0, "The top row should be flipped");
+        assert_eq!(ppu.framebuffer()[9 * SCREEN_WIDTH + 10], 1, "The top row should be drawn last");
+    }
+
+    #[test]
+    fn test_render_tall_sprite() {
+        let mut ppu = init_sprite_ppu();
+        assert!(ppu.write_register(LCDC, 0x97).is_ok(), "Should be able to enable 8x16 sprites");
+        write_tile(&mut ppu, 0x0030, 0xFF, 0xFF);
+        // the lowest bit of the tile number is ignored, so this uses tile 2 and then tile 3
+        write_sprite(&mut ppu, 0, 16, 8, 3, 0x00);
+
+        run_to_line(&mut ppu, 9);
+
+        assert_eq!(ppu.framebuffer()[7 * SCREEN_WIDTH], 0, "Tile 2 should be drawn on top");
+        assert_eq!(ppu.framebuffer()[8 * SCREEN_WIDTH], 3, "Tile 3 should be drawn below it");
+    }
+
+    #[test]
+    fn test_render_sprite_behind_background() {
+        let mut ppu = init_sprite_ppu();
+        // the background is tile 0, which only has color 1 in its leftmost column
+        write_tile(&mut ppu, 0x0000, 0x80, 0x00);
+        write_tile(&mut ppu, 0x0010, 0xFF, 0xFF);
+        write_sprite(&mut ppu, 0, 16, 8, 1, 0x80);
+
+        run_to_line(&mut ppu, 1);
+
+        assert_eq!(ppu.framebuffer()[0], 1, "Background colors 1-3 should cover the sprite");
+        assert_eq!(ppu.framebuffer()[1], 3, "The sprite should cover background color 0");
+    }
+
+    #[test]
+    fn test_render_sprite_x_priority() {
+        let mut ppu = init_sprite_ppu();
+        assert!(ppu.write_register(OBP1, 0x00).is_ok(), "Should be able to write OBP1");
+        write_tile(&mut ppu, 0x0010, 0xFF, 0xFF);
+        // the second sprite is further left, so it's drawn on top where they overlap
+        write_sprite(&mut ppu, 0, 16, 12, 1, 0x00);
+        write_sprite(&mut ppu, 1, 16, 8, 1, 0x10);
+
+        run_to_line(&mut ppu, 1);
+
+        assert_eq!(ppu.framebuffer()[4], 0, "The leftmost sprite should be drawn using OBP1");
+        assert_eq!(ppu.framebuffer()[8], 3, "The other sprite should show past the overlap");
+    }
+}
@@ -0,0 +1,202 @@
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+// 8 palettes of 4 colors, where each color is 2 bytes
+pub const PALETTE_RAM_SIZE: usize = 64;
+pub const CGB_PALETTES: usize = 8;
+pub const COLORS_PER_PALETTE: usize = 4;
+const COLOR_BYTES: usize = 2;
+
+// Index register (BCPS/OCPS) bit masks
+const INDEX_AUTO_INCREMENT: u8 = 0x80;
+const INDEX_ADDRESS: u8 = 0x3F;
+// Bit 6 of the index register doesn't exist, so it always reads as 1
+const INDEX_UNUSED: u8 = 0x40;
+
+// Bit 15 of a color isn't used, so only 15 bits hold the color
+const RGB555_MASK: u16 = 0x7FFF;
+
+/// # PaletteRam
+/// One of the CGB's two sets of color palettes (one for the background and one for objects),
+/// which the CPU accesses a byte at a time through an index register (BCPS/OCPS) and a data
+/// register (BCPD/OCPD).
+///
+/// Each color is stored as little endian RGB555, with red in bits 0-4, green in bits 5-9, and
+/// blue in bits 10-14.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaletteRam {
+    index: u8, // the byte the data register reads and writes, along with the auto increment bit
+    ram: [u8; PALETTE_RAM_SIZE],
+}
+
+impl Default for PaletteRam {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PaletteRam {
+    pub fn new() -> Self {
+        PaletteRam {
+            index: 0,
+            ram: [0; PALETTE_RAM_SIZE],
+        }
+    }
+
+    /// Read the index register (BCPS/OCPS)
+    pub fn read_index(&self) -> u8 {
+        INDEX_UNUSED | self.index
+    }
+
+    /// Write the index register (BCPS/OCPS), which selects the byte the data register accesses
+    /// in bits 0-5, and makes every write to the data register move to the next byte if bit 7 is
+    /// set
+    ///
+    /// Returns the previous value of the register
+    pub fn write_index(&mut self, data: u8) -> u8 {
+        let old_value = self.read_index();
+        self.index = data & (INDEX_AUTO_INCREMENT | INDEX_ADDRESS);
+        old_value
+    }
+
+    /// Read the byte selected by the index register (BCPD/OCPD)
+    ///
+    /// Parameters:
+    /// - `accessible`: whether the CPU can access palette RAM, which it can't while the PPU is
+    ///   drawing (mode 3)
+    pub fn read_data(&self, accessible: bool) -> u8 {
+        if !accessible {
+            return 0xFF;
+        }
+        self.ram[(self.index & INDEX_ADDRESS) as usize]
+    }
+
+    /// Write the byte selected by the index register (BCPD/OCPD), then move to the next byte if
+    /// auto increment is enabled. The index moves even if the write itself is ignored, and wraps
+    /// back around to 0 after the last byte.
+    ///
+    /// Parameters:
+    /// - `data`: the byte to store
+    /// - `accessible`: whether the CPU can access palette RAM (see `read_data`)
+    ///
+    /// Returns the previous value of the byte
+    pub fn write_data(&mut self, data: u8, accessible: bool) -> u8 {
+        let address = (self.index & INDEX_ADDRESS) as usize;
+        let old_value = self.read_data(accessible);
+        if accessible {
+            self.ram[address] = data;
+        }
+        if self.index & INDEX_AUTO_INCREMENT != 0 {
+            let next = (address as u8 + 1) & INDEX_ADDRESS;
+            self.index = INDEX_AUTO_INCREMENT | next;
+        }
+        old_value
+    }
+
+    /// Get one of the colors in palette RAM
+    ///
+    /// Parameters:
+    /// - `palette`: the palette (0-7)
+    /// - `color`: the color number inside of the palette (0-3)
+    ///
+    /// Returns the color as RGB555, or None if either number is out of range
+    pub fn color(&self, palette: usize, color: usize) -> Option<u16> {
+        if palette >= CGB_PALETTES || color >= COLORS_PER_PALETTE {
+            return None;
+        }
+        let address = (palette * COLORS_PER_PALETTE + color) * COLOR_BYTES;
+        let color = u16::from_le_bytes([self.ram[address], self.ram[address + 1]]);
+
+        Some(color & RGB555_MASK)
+    }
+
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_u8(self.index);
+        writer.write_bytes(&self.ram);
+    }
+
+    pub fn load_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.index = reader.read_u8()?;
+        if self.index & INDEX_UNUSED != 0 {
+            return Err(SnapshotError::InvalidValue);
+        }
+        reader.read_into(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_register() {
+        let mut palettes = PaletteRam::new();
+
+        let old_value = palettes.write_index(0xFF);
+
+        assert_eq!(old_value, 0x40, "The unused bit should always read as 1");
+        assert_eq!(palettes.read_index(), 0xFF, "The index and auto increment should be kept");
+    }
+
+    #[test]
+    fn test_auto_increment() {
+        let mut palettes = PaletteRam::new();
+        // palette 2, color 1
+        palettes.write_index(INDEX_AUTO_INCREMENT | 0x12);
+
+        palettes.write_data(0x1F, true);
+        palettes.write_data(0x7C, true);
+
+        assert_eq!(palettes.read_index(), 0xD4, "The index should move past both bytes");
+        assert_eq!(palettes.color(2, 1), Some(0x7C1F), "The color should be stored low byte first");
+        assert_eq!(palettes.color(2, 0), Some(0), "The previous color shouldn't change");
+        assert_eq!(palettes.color(2, 2), Some(0), "The next color shouldn't change");
+    }
+
+    #[test]
+    fn test_no_auto_increment() {
+        let mut palettes = PaletteRam::new();
+        palettes.write_index(0x05);
+
+        palettes.write_data(0x11, true);
+        let old_value = palettes.write_data(0x22, true);
+
+        assert_eq!(old_value, 0x11, "Both writes should go to the same byte");
+        assert_eq!(palettes.read_index(), 0x45, "The index shouldn't move");
+        assert_eq!(palettes.read_data(true), 0x22, "The last write should be kept");
+    }
+
+    #[test]
+    fn test_auto_increment_wraps() {
+        let mut palettes = PaletteRam::new();
+        palettes.write_index(INDEX_AUTO_INCREMENT | 0x3F);
+
+        palettes.write_data(0x80, true);
+
+        assert_eq!(palettes.read_index(), 0xC0, "The index should wrap back around to 0");
+        assert_eq!(palettes.color(7, 3), Some(0), "Bit 15 of a color isn't used");
+    }
+
+    #[test]
+    fn test_inaccessible_while_drawing() {
+        let mut palettes = PaletteRam::new();
+        palettes.write_index(INDEX_AUTO_INCREMENT);
+        palettes.write_data(0x42, true);
+        palettes.write_index(INDEX_AUTO_INCREMENT);
+
+        let read = palettes.read_data(false);
+        let old_value = palettes.write_data(0x28, false);
+
+        assert_eq!(read, 0xFF, "Palette RAM can't be read while drawing");
+        assert_eq!(old_value, 0xFF, "Palette RAM can't be read while drawing");
+        assert_eq!(palettes.color(0, 0), Some(0x42), "The write should be ignored");
+        assert_eq!(palettes.read_index(), 0xC1, "The index should still move");
+    }
+
+    #[test]
+    fn test_color_out_of_range() {
+        let palettes = PaletteRam::new();
+
+        assert_eq!(palettes.color(CGB_PALETTES, 0), None, "There are only 8 palettes");
+        assert_eq!(palettes.color(0, COLORS_PER_PALETTE), None, "There are only 4 colors");
+    }
+}
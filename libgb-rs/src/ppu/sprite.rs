@@ -0,0 +1,148 @@
+/// # SpriteFlags
+/// The attribute byte (byte 3) of an OAM entry, split into its individual fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SpriteFlags {
+    pub behind_background: bool, // bit 7 - background colors 1-3 are drawn over the sprite
+    pub y_flip: bool, // bit 6
+    pub x_flip: bool, // bit 5
+    pub dmg_palette: u8, // bit 4 - 0 uses OBP0, 1 uses OBP1 (DMG only)
+    pub cgb_bank: u8, // bit 3 - the VRAM bank holding the sprite's tile (CGB only)
+    pub cgb_palette: u8, // bits 0-2 - which object palette to use (CGB only)
+}
+
+impl From<SpriteFlags> for u8 {
+    fn from(value: SpriteFlags) -> Self {
+        ((value.behind_background as u8) << 7)
+            | ((value.y_flip as u8) << 6)
+            | ((value.x_flip as u8) << 5)
+            | ((value.dmg_palette & 1) << 4)
+            | ((value.cgb_bank & 1) << 3)
+            | (value.cgb_palette & 7)
+    }
+}
+
+impl From<u8> for SpriteFlags {
+    fn from(value: u8) -> Self {
+        SpriteFlags {
+            behind_background: (value & 0x80) != 0,
+            y_flip: (value & 0x40) != 0,
+            x_flip: (value & 0x20) != 0,
+            dmg_palette: (value >> 4) & 1,
+            cgb_bank: (value >> 3) & 1,
+            cgb_palette: value & 7,
+        }
+    }
+}
+
+/// # SpriteAttr
+/// A single 4-byte entry in OAM (Object Attribute Memory) describing one sprite.
+///
+/// Note that the positions are stored the same way as in OAM, so a sprite's on-screen position
+/// is actually (x - 8, y - 16). This allows sprites to be partially scrolled off of the top and
+/// left edges of the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SpriteAttr {
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub flags: SpriteFlags,
+}
+
+impl From<[u8; 4]> for SpriteAttr {
+    /// Parse a sprite from the 4 bytes of an OAM entry
+    fn from(entry: [u8; 4]) -> Self {
+        SpriteAttr {
+            y: entry[0],
+            x: entry[1],
+            tile: entry[2],
+            flags: entry[3].into(),
+        }
+    }
+}
+
+impl SpriteAttr {
+    /// Find which column of the sprite's tile is drawn at a column of the screen. Sprites with
+    /// X < 8 are partially off of the left edge, so only their rightmost columns can be found.
+    ///
+    /// Parameters:
+    /// - `screen_x`: the column of the screen being drawn (0-159)
+    ///
+    /// Returns the column in the tile data (0-7, after applying X flip), or None if the sprite
+    /// doesn't cover that column of the screen
+    pub fn tile_column(&self, screen_x: u8) -> Option<u8> {
+        // the + 8 undoes the offset in the stored position, without going negative
+        let column = (screen_x as u16 + 8).checked_sub(self.x as u16)?;
+        if column >= 8 {
+            return None;
+        }
+
+        let column = column as u8;
+        Some(if self.flags.x_flip { 7 - column } else { column })
+    }
+
+    /// Find which row of the sprite's tile is drawn on a line of the screen. Sprites with Y < 16
+    /// are partially off of the top edge, so only their bottom rows can be found.
+    ///
+    /// Parameters:
+    /// - `line`: the line of the screen being drawn (0-143)
+    /// - `height`: the height of every sprite (8 or 16, depending on LCDC)
+    ///
+    /// Returns the row in the tile data (after applying Y flip), or None if the sprite doesn't
+    /// cover that line
+    pub fn tile_row(&self, line: u8, height: u8) -> Option<u8> {
+        // the + 16 undoes the offset in the stored position, without going negative
+        let row = (line as u16 + 16).checked_sub(self.y as u16)?;
+        if row >= height as u16 {
+            return None;
+        }
+
+        let row = row as u8;
+        Some(if self.flags.y_flip { height - 1 - row } else { row })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_round_trip() {
+        for value in 0..=0xFF_u8 {
+            let flags: SpriteFlags = value.into();
+            let result: u8 = flags.into();
+
+            assert_eq!(result, value, "Converting flags back to a byte should be lossless");
+        }
+    }
+
+    #[test]
+    fn test_left_edge_clipped_sprite() {
+        let sprite = SpriteAttr { x: 4, ..Default::default() };
+
+        let columns: Vec<Option<u8>> = (0..5).map(|x| sprite.tile_column(x)).collect();
+
+        assert_eq!(
+            columns, vec![Some(4), Some(5), Some(6), Some(7), None],
+            "Only the rightmost 4 columns should be on the screen"
+        );
+    }
+
+    #[test]
+    fn test_left_edge_clipped_flipped_sprite() {
+        let flags = SpriteFlags { x_flip: true, ..Default::default() };
+        let sprite = SpriteAttr { x: 4, flags, ..Default::default() };
+
+        assert_eq!(sprite.tile_column(0), Some(3), "Flipping should mirror the visible columns");
+        assert_eq!(sprite.tile_column(3), Some(0), "Flipping should mirror the visible columns");
+    }
+
+    #[test]
+    fn test_top_edge_clipped_sprite() {
+        let sprite = SpriteAttr { y: 14, ..Default::default() };
+
+        assert_eq!(sprite.tile_row(0, 8), Some(2), "The top 2 rows should be off of the screen");
+        assert_eq!(sprite.tile_row(5, 8), Some(7));
+        assert_eq!(sprite.tile_row(6, 8), None, "The sprite should end after 8 rows");
+        assert_eq!(sprite.tile_row(6, 16), Some(8), "Tall sprites should be 16 rows");
+    }
+}
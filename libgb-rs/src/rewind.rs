@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+
+use crate::GameBoySystem;
+use crate::snapshot::SnapshotError;
+
+/// # Rewind
+/// Keeps a ring of recent snapshots of a system (see `GameBoySystem::snapshot`) so that it can be
+/// taken back in time, like holding a rewind button in a front-end. `record` should be called
+/// once per frame, and a snapshot is taken every `interval` of those calls. Once `depth`
+/// snapshots are being kept, the oldest one is dropped to make room for the next.
+pub struct Rewind {
+    snapshots: VecDeque<Vec<u8>>, // the oldest snapshot is at the front
+    depth: usize, // the most snapshots which are kept at once
+    interval: u32, // the number of calls to `record` between snapshots
+    frames: u32, // calls to `record` since the last snapshot was taken
+}
+
+impl Rewind {
+    /// Create an empty rewind buffer
+    ///
+    /// Parameters:
+    /// - `depth`: the most snapshots to keep, so how far back the system can be rewound
+    /// - `interval`: how many frames to wait between snapshots (where 0 is the same as 1), such
+    ///   as 60 for a snapshot every second
+    pub fn new(depth: usize, interval: u32) -> Self {
+        Rewind {
+            snapshots: VecDeque::with_capacity(depth),
+            depth,
+            interval: interval.max(1),
+            frames: 0,
+        }
+    }
+
+    /// Count a frame of the system running, taking a snapshot of it if it's been `interval`
+    /// frames since the last one
+    ///
+    /// Parameters:
+    /// - `system`: the system to take the snapshot of
+    pub fn record(&mut self, system: &GameBoySystem) {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+
+        if self.depth == 0 {
+            return;
+        }
+        if self.snapshots.len() == self.depth {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(system.snapshot());
+    }
+
+    /// Take the system back to the most recent snapshot, which is then dropped so that the next
+    /// rewind goes back further
+    ///
+    /// Parameters:
+    /// - `system`: the system the snapshots were taken of
+    ///
+    /// Returns whether there was a snapshot to go back to, or a SnapshotError if it couldn't be
+    /// restored (see `GameBoySystem::restore`)
+    pub fn rewind(&mut self, system: &mut GameBoySystem) -> Result<bool, SnapshotError> {
+        let Some(snapshot) = self.snapshots.pop_back() else {
+            return Ok(false);
+        };
+        self.frames = 0;
+        system.restore(&snapshot)?;
+        Ok(true)
+    }
+
+    /// Get the number of snapshots which can be rewound to
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Check whether there aren't any snapshots to rewind to
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Drop every snapshot, like after loading a different save
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.frames = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CpuRegister;
+    use crate::memory::cartridge::ROM_BANK_SIZE;
+
+    use super::*;
+
+    /// Create a system with a ROM which keeps incrementing B (INC B; JR -3)
+    fn init_counter() -> GameBoySystem {
+        let mut rom = vec![0; 2 * ROM_BANK_SIZE];
+        rom[0x100..0x103].copy_from_slice(&[0x04, 0x18, 0xFD]);
+
+        let result = GameBoySystem::from_rom(rom);
+        assert!(result.is_ok(), "Should be able to load the ROM");
+        result.unwrap()
+    }
+
+    /// Run a number of instructions
+    fn run(dmg: &mut GameBoySystem, steps: usize) {
+        for _ in 0..steps {
+            assert!(dmg.step().is_ok(), "The program should run");
+        }
+    }
+
+    /// Get the registers the program changes, to compare against
+    fn registers(dmg: &GameBoySystem) -> (u16, u16, u16) {
+        (
+            dmg.registers().pc,
+            dmg.register_pair(CpuRegister::A, CpuRegister::F),
+            dmg.register_pair(CpuRegister::B, CpuRegister::C),
+        )
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut dmg = init_counter();
+        let mut rewind = Rewind::new(4, 1);
+        run(&mut dmg, 10);
+        rewind.record(&dmg);
+        let expected = registers(&dmg);
+
+        run(&mut dmg, 11);
+        assert_ne!(registers(&dmg), expected, "The program should have moved on");
+
+        assert_eq!(rewind.rewind(&mut dmg), Ok(true), "Should rewind to the snapshot");
+        assert_eq!(registers(&dmg), expected, "The registers should match the snapshot");
+        assert!(rewind.is_empty(), "The snapshot should be used up");
+    }
+
+    #[test]
+    fn test_rewind_empty() {
+        let mut dmg = init_counter();
+        let mut rewind = Rewind::new(4, 1);
+        run(&mut dmg, 10);
+        let expected = registers(&dmg);
+
+        assert_eq!(rewind.rewind(&mut dmg), Ok(false), "There's nothing to rewind to");
+        assert_eq!(registers(&dmg), expected, "The system shouldn't change");
+    }
+
+    #[test]
+    fn test_rewind_interval() {
+        let mut dmg = init_counter();
+        let mut rewind = Rewind::new(4, 3);
+        let mut recorded = Vec::new();
+        for frame in 1..=7 {
+            run(&mut dmg, 2);
+            rewind.record(&dmg);
+            if frame % 3 == 0 {
+                recorded.push(registers(&dmg));
+            }
+        }
+
+        assert_eq!(rewind.len(), 2, "A snapshot should be taken every 3 frames");
+        assert_eq!(rewind.rewind(&mut dmg), Ok(true));
+        assert_eq!(registers(&dmg), recorded[1], "Should go back to frame 6");
+        assert_eq!(rewind.rewind(&mut dmg), Ok(true));
+        assert_eq!(registers(&dmg), recorded[0], "Should go back to frame 3");
+    }
+
+    #[test]
+    fn test_rewind_depth() {
+        let mut dmg = init_counter();
+        let mut rewind = Rewind::new(2, 1);
+        let mut recorded = Vec::new();
+        for _ in 0..3 {
+            run(&mut dmg, 2);
+            rewind.record(&dmg);
+            recorded.push(registers(&dmg));
+        }
+
+        assert_eq!(rewind.len(), 2, "Only 2 snapshots should be kept");
+        assert_eq!(rewind.rewind(&mut dmg), Ok(true));
+        assert_eq!(rewind.rewind(&mut dmg), Ok(true));
+        assert_eq!(registers(&dmg), recorded[1], "The oldest snapshot should have been dropped");
+        assert_eq!(rewind.rewind(&mut dmg), Ok(false), "Nothing further back should be kept");
+    }
+}
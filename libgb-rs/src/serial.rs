@@ -0,0 +1,209 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::cycles::TCycles;
+use crate::interrupt::SERIAL_MASK;
+use crate::memory::MemoryWriteError;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+// Serial register addresses
+pub const SB: u16 = 0xFF01;
+pub const SC: u16 = 0xFF02;
+
+// SC bit masks
+const SC_TRANSFER_START: u8 = 0x80;
+const SC_INTERNAL_CLOCK: u8 = 0x01;
+// Only bits 0 and 7 of SC exist on a DMG, the rest always read as 1
+const SC_UNUSED: u8 = 0x7E;
+// The byte shifted in when nothing is connected to the link port
+const DISCONNECTED_BYTE: u8 = 0xFF;
+
+/// # SerialSink
+/// Something connected to the link port, which receives every byte the program sends.
+pub trait SerialSink {
+    /// Receive a byte sent over the link port
+    fn send(&mut self, byte: u8);
+}
+
+/// # VecSerialSink
+/// A SerialSink which collects every byte it receives. Clones share the same bytes, so a clone
+/// can be kept to read the output after the sink is given to a system.
+#[derive(Debug, Clone, Default)]
+pub struct VecSerialSink {
+    bytes: Rc<RefCell<Vec<u8>>>,
+}
+
+impl VecSerialSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get every byte which has been sent so far, in order
+    pub fn bytes(&self) -> Vec<u8> {
+        self.bytes.borrow().clone()
+    }
+}
+
+impl SerialSink for VecSerialSink {
+    fn send(&mut self, byte: u8) {
+        self.bytes.borrow_mut().push(byte);
+    }
+}
+
+/// # Serial
+/// The serial port of a Game Boy system. There is never anything on the other end of the link
+/// cable, so a transfer using the internal clock finishes right away: SB is sent to the sink (if
+/// there is one), 0xFF is shifted in, and a serial interrupt is requested.
+#[derive(Default)]
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    sink: Option<Box<dyn SerialSink>>,
+    pending_interrupts: u8, // interrupts requested by a transfer, reported on the next tick
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connect something to the link port to receive every byte sent by the program
+    pub fn set_sink(&mut self, sink: Box<dyn SerialSink>) {
+        self.sink = Some(sink);
+    }
+
+    /// Advance the serial port by the given number of T-cycles
+    ///
+    /// Returns the interrupts requested since the last tick, as a mask of bits in the IF register
+    pub fn tick(&mut self, _cycles: TCycles) -> u8 {
+        std::mem::take(&mut self.pending_interrupts)
+    }
+
+    /// Read one of the serial registers
+    ///
+    /// Parameters:
+    /// - `address`: the address of the register in memory (e.g. 0xFF01 for SB)
+    ///
+    /// Returns the value of the register, or None if the address isn't a serial register
+    pub fn read_register(&self, address: u16) -> Option<u8> {
+        match address {
+            SB => Some(self.sb),
+            SC => Some(SC_UNUSED | self.sc),
+            _ => None
+        }
+    }
+
+    /// Write to one of the serial registers. Starting a transfer with the internal clock sends
+    /// SB right away.
+    ///
+    /// Parameters:
+    /// - `address`: the address of the register in memory (e.g. 0xFF01 for SB)
+    /// - `data`: the value to write into the register
+    ///
+    /// Returns the previous value of the register, or a MemoryWriteError if the address isn't a
+    /// serial register
+    pub fn write_register(&mut self, address: u16, data: u8) -> Result<u8, MemoryWriteError> {
+        let old_value = self.read_register(address)
+            .ok_or(MemoryWriteError::OutOfBounds(address))?;
+
+        match address {
+            SB => self.sb = data,
+            SC => {
+                self.sc = data & !SC_UNUSED;
+                // with the external clock, the transfer waits for a partner which never comes
+                if self.sc == SC_TRANSFER_START | SC_INTERNAL_CLOCK {
+                    self.transfer();
+                }
+            }
+            _ => return Err(MemoryWriteError::OutOfBounds(address))
+        }
+
+        Ok(old_value)
+    }
+
+    /// Write the state of the serial port into a snapshot. The sink isn't part of the state,
+    /// so it stays connected when a snapshot is restored.
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_u8(self.sb);
+        writer.write_u8(self.sc);
+        writer.write_u8(self.pending_interrupts);
+    }
+
+    /// Restore the state written by `save_state`
+    pub fn load_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.sb = reader.read_u8()?;
+        self.sc = reader.read_u8()? & !SC_UNUSED;
+        self.pending_interrupts = reader.read_u8()?;
+        Ok(())
+    }
+
+    fn transfer(&mut self) {
+        if let Some(sink) = self.sink.as_mut() {
+            sink.send(self.sb);
+        }
+        self.sb = DISCONNECTED_BYTE;
+        self.sc &= !SC_TRANSFER_START;
+        self.pending_interrupts |= SERIAL_MASK;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CpuRegister;
+    use crate::interrupt::INTERRUPT_FLAG;
+    use crate::test_utils::init_program;
+
+    use super::*;
+
+    fn init_serial() -> (Serial, VecSerialSink) {
+        let sink = VecSerialSink::new();
+        let mut serial = Serial::new();
+        serial.set_sink(Box::new(sink.clone()));
+        (serial, sink)
+    }
+
+    #[test]
+    fn test_transfer_sends_byte() {
+        let (mut serial, sink) = init_serial();
+
+        assert!(serial.write_register(SB, 0x42).is_ok());
+        assert!(serial.write_register(SC, 0x81).is_ok());
+
+        assert_eq!(sink.bytes(), vec![0x42], "Starting a transfer should send SB");
+        assert_eq!(serial.read_register(SB), Some(0xFF), "Nothing should be received");
+        assert_eq!(serial.read_register(SC), Some(0x7F), "The transfer should be finished");
+        assert_eq!(serial.tick(TCycles(4)), SERIAL_MASK, "A serial interrupt is requested");
+        assert_eq!(serial.tick(TCycles(4)), 0, "The interrupt should only be requested once");
+    }
+
+    #[test]
+    fn test_external_clock_waits() {
+        let (mut serial, sink) = init_serial();
+
+        assert!(serial.write_register(SB, 0x42).is_ok());
+        assert!(serial.write_register(SC, 0x80).is_ok());
+
+        assert!(sink.bytes().is_empty(), "Nothing should be sent without a clock");
+        assert_eq!(serial.read_register(SC), Some(0xFE), "The transfer should still be waiting");
+        assert_eq!(serial.tick(TCycles(4)), 0, "No interrupt should be requested");
+    }
+
+    #[test]
+    fn test_program_serial_output() {
+        // LDH [SB], A; LDH [SC], A
+        let mut dmg = init_program(vec![0xE0, 0x01, 0xE0, 0x02]);
+        let sink = VecSerialSink::new();
+        dmg.set_serial_sink(sink.clone());
+
+        dmg.registers.set_register(CpuRegister::A, b'H');
+        assert!(dmg.step().is_ok());
+        dmg.registers.set_register(CpuRegister::A, 0x81);
+        assert!(dmg.step().is_ok());
+
+        assert_eq!(sink.bytes(), b"H", "The program should send 'H' over the link port");
+        assert_eq!(
+            dmg.memory().load_byte(INTERRUPT_FLAG), Some(SERIAL_MASK),
+            "Finishing the transfer should request a serial interrupt"
+        );
+    }
+}
@@ -0,0 +1,306 @@
+// The bytes every snapshot starts with, followed by the version of the format
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"GBRS";
+// This should be bumped whenever the layout of any component's state changes
+pub const SNAPSHOT_VERSION: u16 = 10;
+
+/// # SnapshotError
+/// The reasons a snapshot can't be restored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotError {
+    InvalidHeader, // the data doesn't start with the snapshot magic bytes
+    UnsupportedVersion(u16), // the snapshot was made by a different version of the format
+    UnexpectedEnd, // the data ends before the whole state has been read
+    TrailingData, // there are bytes left over after the whole state has been read
+    InvalidValue, // a value in the snapshot can't be represented (like a PPU mode of 7)
+    Incompatible, // the snapshot was taken with different hardware (like another cartridge)
+}
+
+/// # SnapshotWriter
+/// Builds up the bytes of a snapshot. Every number is stored in little endian order, and blocks
+/// of bytes are prefixed by their length so that they can be checked when they're read back.
+#[derive(Default)]
+pub struct SnapshotWriter {
+    bytes: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    /// Write a block of bytes, prefixed by its length
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Get every byte which has been written
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// # SnapshotReader
+/// Reads back the values written by a `SnapshotWriter`, in the same order they were written
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        SnapshotReader { data }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.data.len() < length {
+            return Err(SnapshotError::UnexpectedEnd);
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Returns an InvalidValue error for anything other than 0 or 1
+    pub fn read_bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::InvalidValue)
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SnapshotError> {
+        // take always returns exactly the requested length, so the conversion can't fail
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// Read a block of bytes written by `write_bytes`
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let length = self.read_u32()? as usize;
+        self.take(length)
+    }
+
+    /// Read a block of bytes written by `write_bytes` into the given buffer
+    ///
+    /// Returns an Incompatible error if the block isn't the same size as the buffer
+    pub fn read_into(&mut self, buffer: &mut [u8]) -> Result<(), SnapshotError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != buffer.len() {
+            return Err(SnapshotError::Incompatible);
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Make sure that everything has been read
+    ///
+    /// Returns a TrailingData error if there are any bytes left
+    pub fn finish(self) -> Result<(), SnapshotError> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(SnapshotError::TrailingData)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CpuRegister;
+    use crate::load::LoadOptions;
+    use crate::memory::cartridge::ROM_BANK_SIZE;
+    use crate::ppu::SCX;
+    use crate::test_utils::init_rom_program;
+    use crate::GameBoySystem;
+
+    use super::*;
+
+    const PROGRAM_START: usize = 0x150;
+
+    /// Create an MBC1 ROM which starts the timer, then keeps adding to a byte in WRAM and
+    /// copying TIMA into cartridge RAM
+    fn init_timer_system() -> GameBoySystem {
+        let mut rom = vec![0; PROGRAM_START];
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]); // JP 0x0150
+        rom[0x147] = 0x03; // MBC1 + RAM + battery
+        rom[0x149] = 0x02; // 1 RAM bank
+        rom.extend([
+            0x3E, 0x0A, // LD A, 0x0A
+            0xEA, 0x00, 0x00, // LD [0x0000], A (enable RAM)
+            0x3E, 0x05, // LD A, 0x05
+            0xE0, 0x07, // LDH [TAC], A
+            0x21, 0x00, 0xC0, // LD HL, 0xC000
+            0x7E, // loop: LD A, [HL]
+            0xC6, 0x03, // ADD A, 0x03
+            0x77, // LD [HL], A
+            0xF0, 0x05, // LDH A, [TIMA]
+            0xEA, 0x00, 0xA0, // LD [0xA000], A
+            0x18, 0xF5, // JR loop
+        ]);
+        rom.resize(2 * ROM_BANK_SIZE, 0);
+
+        let result = GameBoySystem::load(rom, LoadOptions::default());
+        assert!(result.is_ok(), "Should be able to load the ROM");
+        result.unwrap()
+    }
+
+    /// Run a number of instructions, recording the program counter and accumulator after each
+    fn run(dmg: &mut GameBoySystem, steps: usize) -> Vec<(u16, u16)> {
+        (0..steps)
+            .map(|_| {
+                assert!(dmg.step().is_ok(), "The program should run");
+                let registers = dmg.registers();
+                (registers.pc, registers.get_joined_registers(CpuRegister::A, CpuRegister::F))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_restore_system() {
+        let mut dmg = init_timer_system();
+        run(&mut dmg, 500);
+
+        let snapshot = dmg.snapshot();
+        let expected_trace = run(&mut dmg, 1000);
+        let expected_memory = dmg.memory().snapshot();
+
+        assert_eq!(dmg.restore(&snapshot), Ok(()), "Should be able to restore the snapshot");
+        assert_eq!(run(&mut dmg, 1000), expected_trace, "The program should run the same way");
+        assert_eq!(dmg.memory().snapshot(), expected_memory, "Memory should end up the same");
+        assert_eq!(dmg.snapshot(), dmg.snapshot(), "Taking a snapshot shouldn't change anything");
+    }
+
+    #[test]
+    fn test_restore_frame_boundary() {
+        let mut dmg = init_timer_system();
+        assert!(dmg.run_frame().is_ok(), "The program should run");
+
+        let snapshot = dmg.snapshot();
+        assert!(dmg.run_frame().is_ok(), "The program should run");
+        let expected_pc = dmg.registers().pc;
+        assert!(dmg.run_frame().is_ok(), "The program should run");
+
+        assert_eq!(dmg.restore(&snapshot), Ok(()), "Should be able to restore the snapshot");
+        assert!(dmg.run_frame().is_ok(), "The program should run");
+        assert_eq!(dmg.registers().pc, expected_pc, "The frame should end at the same place");
+    }
+
+    #[test]
+    fn test_save_state_framebuffer() {
+        let mut dmg = init_rom_program(&[
+            0xF0, 0x43, // loop: LDH A, [SCX]
+            0x3C, // INC A
+            0xE0, 0x43, // LDH [SCX], A
+            0x18, 0xF9, // JR loop
+        ]);
+        // every row of tile 0 has all 4 shades, so scrolling changes what's drawn
+        let tile: Vec<u8> = [0x0F, 0x33].repeat(8);
+        assert!(dmg.load_vram(0, &tile).is_ok(), "Should be able to load the tile");
+        for _ in 0..3 {
+            assert!(dmg.run_frame().is_ok(), "The program should run");
+        }
+
+        let state = dmg.save_state();
+        assert!(dmg.run_frame().is_ok(), "The program should run");
+        let expected = dmg.framebuffer().to_vec();
+        assert!(dmg.run_frame().is_ok(), "The program should run");
+        let scx = dmg.memory().load_byte(SCX);
+
+        assert_eq!(dmg.load_state(&state), Ok(()), "Should be able to load the state");
+        assert!(dmg.run_frame().is_ok(), "The program should run");
+        assert_ne!(dmg.memory().load_byte(SCX), scx, "The state should have been loaded");
+        assert!(expected.iter().any(|&shade| shade != expected[0]), "The frame shouldn't be blank");
+        assert_eq!(dmg.framebuffer(), expected, "The next frame should be drawn the same way");
+    }
+
+    #[test]
+    fn test_restore_invalid_snapshot() {
+        let mut dmg = init_timer_system();
+        let mut snapshot = dmg.snapshot();
+
+        assert_eq!(dmg.restore(b"GBRT"), Err(SnapshotError::InvalidHeader));
+        assert_eq!(
+            dmg.restore(&snapshot[..snapshot.len() - 1]), Err(SnapshotError::UnexpectedEnd),
+            "A truncated snapshot shouldn't be restored"
+        );
+
+        snapshot[4] = 0xFF;
+        assert_eq!(dmg.restore(&snapshot), Err(SnapshotError::UnsupportedVersion(0xFF)));
+    }
+
+    #[test]
+    fn test_restore_other_cartridge() {
+        let snapshot = init_timer_system().snapshot();
+        let mut rom = vec![0; 4 * ROM_BANK_SIZE];
+        rom[0x147] = 0x01; // MBC1
+        rom[0x148] = 0x01; // 4 ROM banks
+        let result = GameBoySystem::load(rom, LoadOptions::default());
+        assert!(result.is_ok(), "Should be able to load the ROM");
+        let mut dmg = result.unwrap();
+
+        assert_eq!(
+            dmg.restore(&snapshot), Err(SnapshotError::Incompatible),
+            "A snapshot shouldn't be restored onto a different cartridge"
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut writer = SnapshotWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789ABCDE);
+        writer.write_bytes(&[1, 2, 3]);
+        let bytes = writer.finish();
+
+        let mut reader = SnapshotReader::new(&bytes);
+        assert_eq!(reader.read_u8(), Ok(0x12), "Should read back a byte");
+        assert_eq!(reader.read_bool(), Ok(true), "Should read back a bool");
+        assert_eq!(reader.read_u16(), Ok(0x3456), "Should read back a u16");
+        assert_eq!(reader.read_u32(), Ok(0x789ABCDE), "Should read back a u32");
+        assert_eq!(reader.read_bytes(), Ok(&[1, 2, 3][..]), "Should read back a block");
+        assert_eq!(reader.finish(), Ok(()), "Everything should have been read");
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut reader = SnapshotReader::new(&[2, 0x34]);
+        assert_eq!(reader.read_bool(), Err(SnapshotError::InvalidValue), "2 isn't a bool");
+        assert_eq!(reader.read_u16(), Err(SnapshotError::UnexpectedEnd), "Only 1 byte is left");
+        assert_eq!(reader.finish(), Err(SnapshotError::TrailingData), "1 byte wasn't read");
+
+        let mut writer = SnapshotWriter::new();
+        writer.write_bytes(&[0; 4]);
+        let bytes = writer.finish();
+        let mut buffer = [0; 8];
+        assert_eq!(
+            SnapshotReader::new(&bytes).read_into(&mut buffer), Err(SnapshotError::Incompatible),
+            "A block should only be read into a buffer of the same size"
+        );
+    }
+}
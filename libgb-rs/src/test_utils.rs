@@ -0,0 +1,51 @@
+use crate::GameBoySystem;
+use crate::memory::DmgMemoryController;
+use crate::memory::cartridge::{MockCartridgeMapper, ROM_BANK_SIZE};
+use crate::memory::rtc::Clock;
+
+/// Create a system with a DMG's memory and a cartridge which can't be accessed
+pub fn init_system() -> GameBoySystem {
+    let memory = DmgMemoryController::new(Box::new(MockCartridgeMapper::new()));
+    GameBoySystem::new(Box::new(memory))
+}
+
+/// Create a system with a DMG's memory, running the given program from address 0 of the
+/// cartridge ROM, with a stack set up in HRAM
+///
+/// Parameters:
+/// - `program`: the start of the ROM, where every byte after it reads as 0 (NOP)
+pub fn init_program(program: Vec<u8>) -> GameBoySystem {
+    let mut cartridge = MockCartridgeMapper::new();
+    cartridge.expect_read_rom()
+        .returning(move |address| Some(program.get(address as usize).copied().unwrap_or(0)));
+    let mut dmg = GameBoySystem::new(Box::new(DmgMemoryController::new(Box::new(cartridge))));
+    dmg.registers.sp = 0xFFFE;
+    dmg
+}
+
+/// Create a system from a ROM which runs the given program from the cartridge entry point
+/// (0x100), starting in the state the boot ROM leaves behind (so with the LCD on)
+///
+/// Parameters:
+/// - `program`: the code at the entry point, where every byte after it reads as 0 (NOP)
+pub fn init_rom_program(program: &[u8]) -> GameBoySystem {
+    let mut rom = vec![0; 2 * ROM_BANK_SIZE];
+    rom[0x100..0x100 + program.len()].copy_from_slice(program);
+
+    let result = GameBoySystem::from_rom(rom);
+    assert!(result.is_ok(), "Should be able to load the ROM");
+    result.unwrap()
+}
+
+/// A clock which is stopped at the given UNIX time
+pub struct StoppedClock(pub u64);
+
+impl Clock for StoppedClock {
+    fn now_secs(&self) -> u64 {
+        0
+    }
+
+    fn unix_secs(&self) -> Option<u64> {
+        Some(self.0)
+    }
+}
@@ -0,0 +1,270 @@
+use crate::cycles::TCycles;
+use crate::interrupt::TIMER_MASK;
+use crate::memory::MemoryWriteError;
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+// Timer register addresses
+pub const DIV: u16 = 0xFF04;
+pub const TIMA: u16 = 0xFF05;
+pub const TMA: u16 = 0xFF06;
+pub const TAC: u16 = 0xFF07;
+
+// TAC bit masks
+const TAC_ENABLE: u8 = 0x04;
+const TAC_CLOCK_SELECT: u8 = 0x03;
+// Only the lower 3 bits of TAC exist, the rest always read as 1
+const TAC_UNUSED: u8 = 0xF8;
+
+/// # Timer
+/// The DIV/TIMA timer of a Game Boy system.
+///
+/// DIV is the upper 8 bits of a 16-bit divider which counts up every T-cycle. TIMA is
+/// incremented whenever the divider bit selected by TAC falls from 1 to 0 (while the timer is
+/// enabled), so anything which makes that bit fall (like resetting DIV) also increments TIMA.
+/// When TIMA overflows, it is reloaded from TMA and a timer interrupt is requested.
+pub struct Timer {
+    divider: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // interrupts requested by a register write, which are reported on the next tick
+    pending_interrupts: u8,
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Timer {
+            divider: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            pending_interrupts: 0,
+        }
+    }
+
+    /// Advance the timer by the given number of T-cycles
+    ///
+    /// Parameters:
+    /// - `cycles`: the number of T-cycles to run the timer for
+    ///
+    /// Returns the interrupts requested while running, as a mask of bits in the IF register
+    pub fn tick(&mut self, cycles: TCycles) -> u8 {
+        let mut interrupts = std::mem::take(&mut self.pending_interrupts);
+        for _ in 0..cycles.0 {
+            let old_signal = self.signal();
+            self.divider = self.divider.wrapping_add(1);
+            interrupts |= self.check_falling_edge(old_signal);
+        }
+
+        interrupts
+    }
+
+    /// Read one of the timer's registers
+    ///
+    /// Parameters:
+    /// - `address`: the address of the register in memory (e.g. 0xFF04 for DIV)
+    ///
+    /// Returns the value of the register, or None if the address isn't a timer register
+    pub fn read_register(&self, address: u16) -> Option<u8> {
+        match address {
+            DIV => Some((self.divider >> 8) as u8),
+            TIMA => Some(self.tima),
+            TMA => Some(self.tma),
+            TAC => Some(TAC_UNUSED | self.tac),
+            _ => None
+        }
+    }
+
+    /// Write to one of the timer's registers. Writing anything to DIV resets the whole divider
+    /// to 0.
+    ///
+    /// Parameters:
+    /// - `address`: the address of the register in memory (e.g. 0xFF04 for DIV)
+    /// - `data`: the value to write into the register
+    ///
+    /// Returns the previous value of the register, or a MemoryWriteError if the address isn't a
+    /// timer register
+    pub fn write_register(&mut self, address: u16, data: u8) -> Result<u8, MemoryWriteError> {
+        let old_value = self.read_register(address)
+            .ok_or(MemoryWriteError::OutOfBounds(address))?;
+        let old_signal = self.signal();
+
+        match address {
+            DIV => self.divider = 0,
+            TIMA => self.tima = data,
+            TMA => self.tma = data,
+            TAC => self.tac = data & !TAC_UNUSED,
+            _ => return Err(MemoryWriteError::OutOfBounds(address))
+        }
+        // resetting DIV or changing TAC can make the selected bit fall, which increments TIMA
+        self.pending_interrupts |= self.check_falling_edge(old_signal);
+
+        Ok(old_value)
+    }
+
+    /// Write the state of the timer into a snapshot
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_u16(self.divider);
+        writer.write_u8(self.tima);
+        writer.write_u8(self.tma);
+        writer.write_u8(self.tac);
+        writer.write_u8(self.pending_interrupts);
+    }
+
+    /// Restore the state written by `save_state`
+    pub fn load_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.divider = reader.read_u16()?;
+        self.tima = reader.read_u8()?;
+        self.tma = reader.read_u8()?;
+        self.tac = reader.read_u8()? & !TAC_UNUSED;
+        self.pending_interrupts = reader.read_u8()?;
+        Ok(())
+    }
+
+    /// The bit of the divider which TIMA counts, for each of the clock selections in TAC
+    fn selected_bit(&self) -> u16 {
+        match self.tac & TAC_CLOCK_SELECT {
+            0 => 1 << 9, // 4096 Hz
+            1 => 1 << 3, // 262144 Hz
+            2 => 1 << 5, // 65536 Hz
+            _ => 1 << 7, // 16384 Hz
+        }
+    }
+
+    /// Whether the selected divider bit is set while the timer is enabled
+    fn signal(&self) -> bool {
+        self.tac & TAC_ENABLE != 0 && self.divider & self.selected_bit() != 0
+    }
+
+    /// Increment TIMA if the signal went from 1 to 0, returning any interrupts requested
+    fn check_falling_edge(&mut self, old_signal: bool) -> u8 {
+        if !old_signal || self.signal() {
+            return 0;
+        }
+
+        let (tima, overflowed) = self.tima.overflowing_add(1);
+        if !overflowed {
+            self.tima = tima;
+            return 0;
+        }
+        self.tima = self.tma;
+        TIMER_MASK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init_timer(tac: u8) -> Timer {
+        let mut timer = Timer::new();
+        assert!(timer.write_register(TAC, tac).is_ok());
+        timer
+    }
+
+    /// Run an enabled timer for the given number of cycles, returning the value of TIMA
+    fn run_timer(clock_select: u8, cycles: u32) -> Option<u8> {
+        let mut timer = init_timer(TAC_ENABLE | clock_select);
+        timer.tick(TCycles(cycles));
+        timer.read_register(TIMA)
+    }
+
+    #[test]
+    fn test_tac_4096_hz() {
+        assert_eq!(run_timer(0, 1023), Some(0), "TIMA shouldn't increment before 1024 cycles");
+        assert_eq!(run_timer(0, 1024 * 3), Some(3), "TIMA should increment every 1024 cycles");
+    }
+
+    #[test]
+    fn test_tac_262144_hz() {
+        assert_eq!(run_timer(1, 15), Some(0), "TIMA shouldn't increment before 16 cycles");
+        assert_eq!(run_timer(1, 16 * 3), Some(3), "TIMA should increment every 16 cycles");
+    }
+
+    #[test]
+    fn test_tac_65536_hz() {
+        assert_eq!(run_timer(2, 63), Some(0), "TIMA shouldn't increment before 64 cycles");
+        assert_eq!(run_timer(2, 64 * 3), Some(3), "TIMA should increment every 64 cycles");
+    }
+
+    #[test]
+    fn test_tac_16384_hz() {
+        assert_eq!(run_timer(3, 255), Some(0), "TIMA shouldn't increment before 256 cycles");
+        assert_eq!(run_timer(3, 256 * 3), Some(3), "TIMA should increment every 256 cycles");
+    }
+
+    #[test]
+    fn test_timer_disabled() {
+        let mut timer = init_timer(0x01);
+
+        timer.tick(TCycles(1024));
+
+        assert_eq!(timer.read_register(TIMA), Some(0), "TIMA shouldn't count while disabled");
+        assert_eq!(timer.read_register(DIV), Some(4), "DIV should always count");
+    }
+
+    #[test]
+    fn test_tima_overflow() {
+        let mut timer = init_timer(TAC_ENABLE | 1);
+        assert!(timer.write_register(TIMA, 0xFF).is_ok());
+        assert!(timer.write_register(TMA, 0x42).is_ok());
+
+        let before_overflow = timer.tick(TCycles(15));
+        let overflow = timer.tick(TCycles(1));
+
+        assert_eq!(before_overflow, 0, "Nothing should be requested before overflowing");
+        assert_eq!(overflow, TIMER_MASK, "Overflowing should request a timer interrupt");
+        assert_eq!(timer.read_register(TIMA), Some(0x42), "TIMA should be reloaded from TMA");
+    }
+
+    #[test]
+    fn test_div_write_resets() {
+        let mut timer = Timer::new();
+        timer.tick(TCycles(0x1234));
+
+        let result = timer.write_register(DIV, 0x42);
+
+        assert_eq!(result, Ok(0x12), "Should return the previous DIV value");
+        assert_eq!(timer.read_register(DIV), Some(0), "Writing to DIV should reset it");
+    }
+
+    #[test]
+    fn test_div_write_falling_edge() {
+        let mut timer = init_timer(TAC_ENABLE | 3);
+        // bit 7 of the divider is set, but hasn't fallen yet
+        timer.tick(TCycles(128));
+
+        assert!(timer.write_register(DIV, 0).is_ok());
+
+        assert_eq!(
+            timer.read_register(TIMA), Some(1),
+            "Resetting DIV while the selected bit is set should increment TIMA"
+        );
+    }
+
+    #[test]
+    fn test_div_write_overflow_requests_interrupt() {
+        let mut timer = init_timer(TAC_ENABLE | 3);
+        assert!(timer.write_register(TIMA, 0xFF).is_ok());
+        timer.tick(TCycles(128));
+
+        assert!(timer.write_register(DIV, 0).is_ok());
+        let result = timer.tick(TCycles(1));
+
+        assert_eq!(result, TIMER_MASK, "An overflow from a DIV write should still be reported");
+        assert_eq!(timer.tick(TCycles(1)), 0, "The interrupt should only be reported once");
+    }
+
+    #[test]
+    fn test_tac_unused_bits() {
+        let timer = init_timer(0x05);
+
+        assert_eq!(timer.read_register(TAC), Some(0xFD), "Unused TAC bits should read as 1");
+    }
+}
@@ -1,17 +1,26 @@
-
 pub trait Merge<T, R> {
-    /// Combine this element with another type to create a single result
-    fn merge(self, b: T) -> R;
+    /// Combine this element with another type to create a single result, where this element
+    /// becomes the high (most significant) half and `low` becomes the low half. E.g.
+    /// `0x12.merge(0x34) == 0x1234`, which is the reverse of the little endian order the two
+    /// bytes are stored in memory.
+    fn merge(self, low: T) -> R;
 }
 
 pub trait Split<T> {
-    /// Divide this element into two smaller elements of another type
+    /// Divide this element into two smaller elements of another type, returned as
+    /// `(high, low)`, which is the same order that `merge` takes them in
     fn split(self) -> (T, T);
 }
 
 impl Merge<u8, u16> for u8 {
-    fn merge(self, b: u8) -> u16 {
-        return (b as u16) + ((self as u16) << 8);
+    fn merge(self, low: u8) -> u16 {
+        (low as u16) + ((self as u16) << 8)
+    }
+}
+
+impl Merge<u16, u32> for u16 {
+    fn merge(self, low: u16) -> u32 {
+        (low as u32) + ((self as u32) << 16)
     }
 }
 
@@ -23,3 +32,64 @@ impl Split<u8> for u16 {
         (left, right)
     }
 }
+
+impl Split<u16> for u32 {
+    fn split(self) -> (u16, u16) {
+        let left = (self >> 16) as u16;
+        let right = self as u16;
+
+        (left, right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_u8() {
+        assert_eq!(0x12u8.merge(0x34u8), 0x1234u16, "self should be the high byte");
+        assert_eq!(0x00u8.merge(0xFFu8), 0x00FFu16, "the argument should be the low byte");
+    }
+
+    #[test]
+    fn test_split_u16() {
+        assert_eq!(0x1234u16.split(), (0x12u8, 0x34u8), "the high byte should come first");
+    }
+
+    #[test]
+    fn test_merge_u16() {
+        assert_eq!(0x1234u16.merge(0x5678u16), 0x12345678u32, "self should be the high half");
+        assert_eq!(
+            0x0000u16.merge(0xFFFFu16), 0x0000FFFFu32,
+            "the argument should be the low half"
+        );
+    }
+
+    #[test]
+    fn test_split_u32() {
+        assert_eq!(
+            0x12345678u32.split(), (0x1234u16, 0x5678u16),
+            "the high half should come first"
+        );
+    }
+
+    #[test]
+    fn test_merge_little_endian_bytes() {
+        // an immediate 16-bit operand is stored low byte first, like 0x34 0x12 for 0x1234
+        let bytes = [0x34u8, 0x12u8];
+
+        let result = bytes[1].merge(bytes[0]);
+
+        assert_eq!(result, u16::from_le_bytes(bytes), "the second byte in memory is the high byte");
+    }
+
+    #[test]
+    fn test_split_round_trips() {
+        let (high, low) = 0xBEEFu16.split();
+        assert_eq!(high.merge(low), 0xBEEFu16, "splitting then merging should give back a u16");
+
+        let (high, low) = 0xDEADBEEFu32.split();
+        assert_eq!(high.merge(low), 0xDEADBEEFu32, "splitting then merging should give back a u32");
+    }
+}